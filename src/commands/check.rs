@@ -0,0 +1,145 @@
+use std::{error::Error, fs, path::PathBuf, process};
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Url};
+use serde_json::{json, Value};
+
+use crate::{
+    commands::{expand_paths, CheckFormat},
+    server::Server,
+};
+
+pub(crate) fn run(
+    patterns: &[String],
+    format: CheckFormat,
+    strict: bool,
+    unused_public: bool,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let server = Server::get_server();
+
+    let mut results: Vec<(PathBuf, Vec<Diagnostic>)> = vec![];
+    let mut codes = vec![];
+    for path in expand_paths(patterns) {
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let url = Url::from_file_path(&path).map_err(|_| format!("invalid path {:?}", path))?;
+        let Some(code) = server.get_code(&url) else {
+            err_to_console!("failed to read {}", path.display());
+            continue;
+        };
+        let diags = server.diagnose(&code);
+        results.push((path, diags));
+        codes.push(code);
+    }
+
+    if unused_public {
+        let mut unused = server.unused_public_symbols(&codes);
+        for ((_, diags), code) in results.iter_mut().zip(&codes) {
+            diags.extend(unused.remove(&code.borrow().url).unwrap_or_default());
+        }
+    }
+
+    match format {
+        CheckFormat::Human => print_human(&results),
+        CheckFormat::Json => println!("{}", serde_json::to_string_pretty(&json_output(&results))?),
+        CheckFormat::Sarif => {
+            println!("{}", serde_json::to_string_pretty(&sarif_output(&results))?)
+        }
+    }
+
+    let failed = results.iter().flat_map(|(_, diags)| diags).any(|diag| {
+        diag.severity == Some(DiagnosticSeverity::ERROR)
+            || (strict && diag.severity == Some(DiagnosticSeverity::WARNING))
+    });
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn severity_name(diag: &Diagnostic) -> &'static str {
+    match diag.severity {
+        Some(DiagnosticSeverity::ERROR) => "error",
+        Some(DiagnosticSeverity::WARNING) => "warning",
+        Some(DiagnosticSeverity::INFORMATION) => "info",
+        _ => "hint",
+    }
+}
+
+fn code_name(diag: &Diagnostic) -> String {
+    match &diag.code {
+        Some(NumberOrString::String(code)) => code.clone(),
+        Some(NumberOrString::Number(code)) => code.to_string(),
+        None => "openscad".to_owned(),
+    }
+}
+
+fn print_human(results: &[(PathBuf, Vec<Diagnostic>)]) {
+    let mut count = 0;
+    for (path, diags) in results {
+        for diag in diags {
+            println!(
+                "{}:{}:{}: {}: {}",
+                path.display(),
+                diag.range.start.line + 1,
+                diag.range.start.character + 1,
+                severity_name(diag),
+                diag.message
+            );
+            count += 1;
+        }
+    }
+    eprintln!("{} files checked, {} problems", results.len(), count);
+}
+
+fn json_output(results: &[(PathBuf, Vec<Diagnostic>)]) -> Value {
+    results
+        .iter()
+        .map(|(path, diags)| json!({ "path": path, "diagnostics": diags }))
+        .collect()
+}
+
+fn sarif_output(results: &[(PathBuf, Vec<Diagnostic>)]) -> Value {
+    let sarif_results: Vec<Value> = results
+        .iter()
+        .flat_map(|(path, diags)| {
+            diags.iter().map(move |diag| {
+                json!({
+                    "ruleId": code_name(diag),
+                    "level": match diag.severity {
+                        Some(DiagnosticSeverity::ERROR) => "error",
+                        Some(DiagnosticSeverity::WARNING) => "warning",
+                        _ => "note",
+                    },
+                    "message": { "text": diag.message },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": {
+                                "uri": Url::from_file_path(path).map(|url| url.to_string()).unwrap_or_default(),
+                            },
+                            "region": {
+                                "startLine": diag.range.start.line + 1,
+                                "startColumn": diag.range.start.character + 1,
+                                "endLine": diag.range.end.line + 1,
+                                "endColumn": diag.range.end.character + 1,
+                            },
+                        },
+                    }],
+                })
+            })
+        })
+        .collect();
+
+    json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "openscad-lsp",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                },
+            },
+            "results": sarif_results,
+        }],
+    })
+}
@@ -0,0 +1,217 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use lsp_types::Url;
+
+use crate::{
+    commands::DocFormat,
+    response_item::{Item, ItemKind, Param},
+    server::Server,
+    utils::*,
+};
+
+struct FileDoc {
+    rel_path: String,
+    out_path: PathBuf,
+    items: Vec<(String, String)>,
+}
+
+pub(crate) fn run(
+    dir: &str,
+    out: &str,
+    format: DocFormat,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let dir = fs::canonicalize(expand_path(dir))?;
+    let out = PathBuf::from(expand_path(out));
+    let ext = match format {
+        DocFormat::Markdown => "md",
+        DocFormat::Html => "html",
+    };
+
+    let server = Server::get_server();
+    let mut docs = vec![];
+    for path in find_scad_files(&dir) {
+        let url = Url::from_file_path(&path).map_err(|_| format!("invalid path {:?}", path))?;
+        let Some(code) = server.get_code(&url) else {
+            err_to_console!("failed to read {}", path.display());
+            continue;
+        };
+        code.borrow_mut().gen_top_level_items_if_needed();
+
+        let rel_path = path
+            .strip_prefix(&dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let out_path = out.join(&rel_path).with_extension(ext);
+
+        let bcode = code.borrow();
+        let items: Vec<_> = bcode
+            .root_items
+            .iter()
+            .flatten()
+            .map(|item| {
+                let item = item.borrow();
+                (item.name.to_string(), render_item(&item, format))
+            })
+            .collect();
+
+        let title = match format {
+            DocFormat::Markdown => format!("# {}\n\n", rel_path),
+            DocFormat::Html => format!("<h1>{}</h1>\n", escape_html(&rel_path)),
+        };
+        let body = items
+            .iter()
+            .map(|(_, text)| text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        write_file(&out_path, &wrap_page(&rel_path, &(title + &body), format))?;
+
+        docs.push(FileDoc {
+            rel_path,
+            out_path,
+            items,
+        });
+    }
+
+    let index_path = out.join(format!("index.{}", ext));
+    write_file(
+        &index_path,
+        &wrap_page("index", &render_index(&docs, &out, format), format),
+    )?;
+    log_to_console!("{} files documented in {}", docs.len(), out.display());
+    Ok(())
+}
+
+fn write_file(path: &Path, content: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn wrap_page(title: &str, body: &str, format: DocFormat) -> String {
+    match format {
+        DocFormat::Markdown => body.to_owned(),
+        DocFormat::Html => format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+            escape_html(title),
+            body
+        ),
+    }
+}
+
+fn render_params(params: &[Param], format: DocFormat) -> String {
+    if params.is_empty() {
+        return String::new();
+    }
+    match format {
+        DocFormat::Markdown => {
+            let mut text = "| parameter | default |\n| --- | --- |\n".to_owned();
+            for p in params {
+                text.push_str(&format!(
+                    "| `{}` | {} |\n",
+                    p.name,
+                    p.default
+                        .as_ref()
+                        .map_or(String::new(), |d| format!("`{}`", d))
+                ));
+            }
+            text + "\n"
+        }
+        DocFormat::Html => {
+            let mut text = "<table>\n<tr><th>parameter</th><th>default</th></tr>\n".to_owned();
+            for p in params {
+                text.push_str(&format!(
+                    "<tr><td><code>{}</code></td><td><code>{}</code></td></tr>\n",
+                    escape_html(&p.name),
+                    escape_html(p.default.as_deref().unwrap_or(""))
+                ));
+            }
+            text + "</table>\n"
+        }
+    }
+}
+
+fn render_item(item: &Item, format: DocFormat) -> String {
+    let (keyword, params) = match &item.kind {
+        ItemKind::Module { params, .. } => ("module ", params.as_slice()),
+        ItemKind::Function { params, .. } => ("function ", params.as_slice()),
+        _ => ("", [].as_slice()),
+    };
+    let label = item.label.clone().unwrap_or_else(|| item.make_label());
+    let doc = item.doc.as_deref().unwrap_or("").trim();
+
+    match format {
+        DocFormat::Markdown => {
+            let mut text = format!("## {}\n\n```scad\n{}{}\n```\n\n", item.name, keyword, label);
+            if !doc.is_empty() {
+                text.push_str(&format!("```\n{}\n```\n\n", doc));
+            }
+            text + &render_params(params, format)
+        }
+        DocFormat::Html => {
+            let mut text = format!(
+                "<h2 id=\"{0}\">{0}</h2>\n<pre><code>{1}{2}</code></pre>\n",
+                escape_html(&item.name),
+                keyword,
+                escape_html(&label)
+            );
+            if !doc.is_empty() {
+                text.push_str(&format!("<pre>{}</pre>\n", escape_html(doc)));
+            }
+            text + &render_params(params, format)
+        }
+    }
+}
+
+fn render_index(docs: &[FileDoc], out: &Path, format: DocFormat) -> String {
+    let link = |doc: &FileDoc| {
+        doc.out_path
+            .strip_prefix(out)
+            .unwrap_or(&doc.out_path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    };
+
+    match format {
+        DocFormat::Markdown => {
+            let mut text = "# Index\n\n".to_owned();
+            for doc in docs {
+                text.push_str(&format!("- [{}]({})\n", doc.rel_path, link(doc)));
+                for (name, _) in &doc.items {
+                    text.push_str(&format!("  - {}\n", name));
+                }
+            }
+            text
+        }
+        DocFormat::Html => {
+            let mut text = "<h1>Index</h1>\n<ul>\n".to_owned();
+            for doc in docs {
+                text.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a>\n<ul>\n",
+                    link(doc),
+                    escape_html(&doc.rel_path)
+                ));
+                for (name, _) in &doc.items {
+                    text.push_str(&format!(
+                        "<li><a href=\"{}#{1}\">{1}</a></li>\n",
+                        link(doc),
+                        escape_html(name)
+                    ));
+                }
+                text.push_str("</ul>\n</li>\n");
+            }
+            text + "</ul>\n"
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{error::Error, fs};
+
+use lsp_types::Url;
+
+use crate::{server::Server, utils::*};
+
+pub(crate) fn run(
+    path: &str,
+    query: &str,
+    as_json: bool,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let path = fs::canonicalize(expand_path(path))?;
+    let url = Url::from_file_path(&path).map_err(|_| format!("invalid path {:?}", path))?;
+
+    let examples = Server::get_server().library_examples(&[url], query);
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&examples)?);
+        return Ok(());
+    }
+
+    for example in &examples {
+        let file = example
+            .location
+            .uri
+            .to_file_path()
+            .map_or(example.location.uri.to_string(), |path| {
+                path.display().to_string()
+            });
+        let title = example
+            .title
+            .as_ref()
+            .map_or(String::new(), |title| format!(": {}", title));
+        println!(
+            "{}:{} {}{}",
+            file,
+            example.location.range.start.line + 1,
+            example.symbol,
+            title
+        );
+        for line in example.code.lines() {
+            println!("    {}", line);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::{error::Error, path::PathBuf};
+
+use clap::{Subcommand, ValueEnum};
+use regex::Regex;
+
+use crate::utils::*;
+
+pub(crate) mod check;
+pub(crate) mod doc;
+pub(crate) mod examples;
+pub(crate) mod symbols;
+
+#[derive(Clone, Subcommand)]
+pub(crate) enum Commands {
+    #[clap(about = "generate the api documentation of a library")]
+    Doc {
+        #[clap(help = "library directory")]
+        dir: String,
+
+        #[clap(long, default_value_t = String::from("docs"), help = "output directory")]
+        out: String,
+
+        #[clap(long, value_enum, default_value_t = DocFormat::Markdown)]
+        format: DocFormat,
+    },
+
+    #[clap(about = "print the symbols of files")]
+    Symbols {
+        #[clap(help = "file or directory")]
+        path: String,
+
+        #[clap(long, help = "print as json")]
+        json: bool,
+    },
+
+    #[clap(about = "print the examples of the doc comments of a library")]
+    Examples {
+        #[clap(help = "file or directory")]
+        path: String,
+
+        #[clap(default_value_t = String::new(), help = "symbol or title to search for")]
+        query: String,
+
+        #[clap(long, help = "print as json")]
+        json: bool,
+    },
+
+    #[clap(about = "check files, exits with 1 if any error is found")]
+    Check {
+        #[clap(required = true, help = "files, directories or glob patterns")]
+        paths: Vec<String>,
+
+        #[clap(long, value_enum, default_value_t = CheckFormat::Human)]
+        format: CheckFormat,
+
+        #[clap(long, help = "also fail on warnings")]
+        strict: bool,
+
+        #[clap(
+            long,
+            help = "report the top level modules and functions never used by the checked files"
+        )]
+        unused_public: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CheckFormat {
+    Human,
+    Json,
+    Sarif,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DocFormat {
+    Markdown,
+    Html,
+}
+
+pub(crate) fn run(command: Commands) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match command {
+        Commands::Doc { dir, out, format } => doc::run(&dir, &out, format),
+        Commands::Symbols { path, json } => symbols::run(&path, json),
+        Commands::Examples { path, query, json } => examples::run(&path, &query, json),
+        Commands::Check {
+            paths,
+            format,
+            strict,
+            unused_public,
+        } => check::run(&paths, format, strict, unused_public),
+    }
+}
+
+// Expand the glob patterns (`*`, `?` and `**`) and directories to the .scad files they match.
+pub(crate) fn expand_paths(patterns: &[String]) -> Vec<PathBuf> {
+    let mut result = vec![];
+    for pattern in patterns {
+        let pattern = expand_path(pattern);
+        let Some(wildcard) = pattern.find(['*', '?']) else {
+            result.extend(find_scad_files(&PathBuf::from(&pattern)));
+            continue;
+        };
+
+        let base = match pattern[..wildcard].rfind('/') {
+            Some(slash) => &pattern[..slash + 1],
+            None => "",
+        };
+        let mut re = String::from("^");
+        let mut chars = pattern[base.len()..].chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                }
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+
+        let Ok(re) = Regex::new(&re) else {
+            continue;
+        };
+        let base_dir = PathBuf::from(if base.is_empty() { "." } else { base });
+        for path in find_scad_files(&base_dir) {
+            let rel = path.strip_prefix(&base_dir).unwrap_or(&path);
+            if re.is_match(&rel.to_string_lossy().replace('\\', "/")) {
+                result.push(path);
+            }
+        }
+    }
+    result
+}
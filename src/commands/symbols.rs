@@ -0,0 +1,100 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use lsp_types::Url;
+use serde_json::{json, Value};
+
+use crate::{
+    response_item::{Item, ItemKind},
+    server::Server,
+    utils::*,
+};
+
+pub(crate) fn run(path: &str, as_json: bool) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let path = fs::canonicalize(expand_path(path))?;
+
+    let server = Server::get_server();
+    let mut files = vec![];
+    for path in find_scad_files(&path) {
+        let url = Url::from_file_path(&path).map_err(|_| format!("invalid path {:?}", path))?;
+        let Some(code) = server.get_code(&url) else {
+            err_to_console!("failed to read {}", path.display());
+            continue;
+        };
+        code.borrow_mut().gen_top_level_items_if_needed();
+
+        let bcode = code.borrow();
+        let items: Vec<_> = bcode
+            .root_items
+            .iter()
+            .flatten()
+            .map(|item| item_json(&item.borrow()))
+            .collect();
+        let includes: Vec<_> = bcode.includes.iter().flatten().map(url_path).collect();
+
+        files.push(json!({
+            "path": path,
+            "includes": includes,
+            "items": items,
+        }));
+    }
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&files)?);
+        return Ok(());
+    }
+
+    for file in &files {
+        println!("{}", file["path"].as_str().unwrap_or(""));
+        for inc in file["includes"].as_array().into_iter().flatten() {
+            println!("  include {}", inc.as_str().unwrap_or(""));
+        }
+        for item in file["items"].as_array().into_iter().flatten() {
+            println!(
+                "  {}:{} {} {}",
+                item["range"]["start"]["line"].as_u64().unwrap_or(0) + 1,
+                item["range"]["start"]["character"].as_u64().unwrap_or(0) + 1,
+                item["kind"].as_str().unwrap_or(""),
+                item["label"].as_str().unwrap_or(""),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn url_path(url: &Url) -> String {
+    url.to_file_path()
+        .unwrap_or_else(|_| PathBuf::from(url.path()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn item_json(item: &Item) -> Value {
+    let (kind, params) = match &item.kind {
+        ItemKind::Variable => ("variable", None),
+        ItemKind::Function { params, .. } => ("function", Some(params)),
+        ItemKind::Keyword(_) => ("keyword", None),
+        ItemKind::Module { params, .. } => ("module", Some(params)),
+    };
+
+    let mut value = json!({
+        "name": &*item.name,
+        "kind": kind,
+        "label": item.label.clone().unwrap_or_else(|| item.make_label()),
+        "range": item.range,
+        "doc": item.doc,
+    });
+
+    if let Some(params) = params {
+        value["params"] = params
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": &*p.name,
+                    "default": p.default,
+                    "range": p.range,
+                })
+            })
+            .collect();
+    }
+    value
+}
@@ -3,19 +3,29 @@
 
 #[macro_use]
 mod server;
+mod commands;
+mod tcp;
 
 use clap::Parser;
+use commands::Commands;
 use lsp_server::Connection;
 use server::*;
-use std::error::Error;
+use std::{error::Error, process};
+use tcp::Clients;
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[clap(name = "OpenSCAD-LSP")]
 #[clap(author, version, about)]
 pub(crate) struct Cli {
-    #[clap(short, long, default_value_t = String::from("3245"))]
+    #[clap(short, long, default_value_t = String::from("3245"), help = "0 to let the system choose a free port")]
     port: String,
 
+    #[clap(
+        long,
+        help = "write the port and the pid to this file, as json, by default to openscad-lsp.port of $XDG_RUNTIME_DIR or of the temporary directory with --port 0"
+    )]
+    port_file: Option<String>,
+
     #[clap(long, default_value_t = String::from("127.0.0.1"))]
     ip: String,
 
@@ -31,22 +41,96 @@ pub(crate) struct Cli {
     #[clap(long, help = "use stdio instead of tcp")]
     stdio: bool,
 
+    #[clap(
+        long,
+        help = "share a server between the editors: over tcp, exit if one is running, with --stdio, forward to it, started in the background if needed"
+    )]
+    single_instance: bool,
+
+    #[clap(
+        long,
+        help = "over tcp, exit after this many seconds without a client, 0 to disable [default: 300 with --single-instance]"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Clients::Single,
+        help = "over tcp, exit after the first client (single), accept the clients one after the other (sequential), or at the same time (concurrent)"
+    )]
+    clients: Clients,
+
     #[clap(long, help = "exclude default params in auto-completion")]
     ignore_default: bool,
 
-    #[clap(long, default_value_t = 3, help = "search depth")]
+    #[clap(long = "no-completion-parens", action = clap::ArgAction::SetFalse, help = "complete the modules and functions with their names only, without the parentheses and the params")]
+    completion_parens: bool,
+
+    // Includes are followed at any depth, kept for the existing configurations.
+    #[clap(long, default_value_t = 3, hide = true)]
     depth: i32,
+
+    #[clap(
+        long,
+        help = "documentation bundle (json) of a library, can be used multiple times"
+    )]
+    doc_bundle: Vec<String>,
+
+    #[clap(long, default_value_t = String::from("openscad"), help = "openscad executable file path")]
+    openscad_exe: String,
+
+    #[clap(long = "no-comment-continuation", action = clap::ArgAction::SetFalse, help = "don't continue doc comments on new lines")]
+    comment_continuation: bool,
+
+    #[clap(
+        long,
+        help = "complete with the names only, without snippets, documentation nor edits, for the minimal clients"
+    )]
+    plain_completions: bool,
+
+    #[clap(
+        long,
+        help = "render a thumbnail of the modules on hover with openscad, slow on large models"
+    )]
+    hover_thumbnails: bool,
+
+    #[clap(
+        long,
+        default_value_t = 1000,
+        help = "log a warning when a request takes longer (in milliseconds), 0 to disable"
+    )]
+    slow_request_ms: u64,
+
+    #[clap(subcommand)]
+    command: Option<Commands>,
 }
 
 fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
-    let args = Cli::parse();
+    let mut args = Cli::parse();
+
+    if let Some(command) = args.command.take() {
+        // The subcommands share the code of the server, without a client.
+        let (connection, _client) = Connection::memory();
+        Server::create_server(connection, args);
+        return commands::run(command);
+    }
 
-    let (connection, io_threads) = if args.stdio {
-        Connection::stdio()
+    if args.stdio && args.single_instance {
+        tcp::forward_to_shared_server(&args)?;
+        return Ok(());
+    }
+
+    let shutdown_requested = if args.stdio {
+        let (connection, io_threads) = Connection::stdio();
+        log_to_console!("Start successful");
+        let shutdown_requested = serve(connection, args)?;
+        io_threads.join()?;
+        shutdown_requested
     } else {
         log_to_console!("Start with socket");
-        match Connection::listen(format!("{}:{}", args.ip, args.port)) {
-            Ok(res) => res,
+        match tcp::serve_clients(args) {
+            Ok(shutdown_requested) => shutdown_requested,
             Err(err) => {
                 err_to_console!("{}", err);
                 return Ok(()); // return an error from main will print it to stderr
@@ -54,11 +138,21 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
         }
     };
 
-    log_to_console!("Start successful");
-    Server::create_server(connection, args);
-    Server::get_server().main_loop()?;
-    io_threads.join()?;
-
+    // Exit with 1 if the client didn't ask for a shutdown before exit, or disconnected.
     err_to_console!("exit");
-    Ok(())
+    process::exit(if shutdown_requested { 0 } else { 1 });
+}
+
+// Run a server for the client of the connection, until it exits or disconnects. Whether the
+// client asked for a shutdown before.
+pub(crate) fn serve(
+    connection: Connection,
+    args: Cli,
+) -> Result<bool, Box<dyn Error + Sync + Send>> {
+    Server::create_server(connection, args);
+    let result = Server::get_server().main_loop();
+    let shutdown_requested = Server::get_server().shutdown_requested;
+    // The connection is dropped with the server, so that the io threads can finish.
+    Server::destroy_server();
+    result.map(|_| shutdown_requested)
 }
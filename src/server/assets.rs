@@ -0,0 +1,302 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Url};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use tree_sitter::Node;
+use tree_sitter_traversal::{traverse, Order};
+
+use crate::{
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    server::Server,
+    utils::*,
+};
+
+// Modules reading an external file, and the name of the file parameter.
+const ASSET_MODULES: &[(&str, &str)] = &[("import", "file"), ("surface", "file")];
+
+// The larger images of the doc comments are linked as files instead of being inlined.
+const MAX_DATA_IMAGE_SIZE: u64 = 256 << 10;
+
+// How the relative image paths of the doc comments are rewritten, the doc_images setting. The
+// clients can't resolve them, they don't know the file of the documentation.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DocImages {
+    // file:// uris.
+    #[default]
+    File,
+    // data: uris, for the clients which don't show local files.
+    Data,
+    // Left as they are.
+    Off,
+}
+
+pub(crate) struct AssetInfo {
+    pub size: u64,
+    pub triangles: Option<usize>,
+}
+
+impl AssetInfo {
+    pub(crate) fn read(path: &Path) -> Option<Self> {
+        let size = fs::metadata(path).ok()?.len();
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        let triangles = match ext.as_deref() {
+            Some("stl") => stl_triangles(path, size),
+            Some("off") => off_faces(path),
+            _ => None,
+        };
+        Some(Self { size, triangles })
+    }
+
+    pub(crate) fn to_markdown(&self, path: &Path) -> String {
+        let mut text = format!("`{}`\n\n{}", path.display(), human_size(self.size));
+        if let Some(triangles) = self.triangles {
+            text.push_str(&format!(", {} triangles", triangles));
+        }
+        text
+    }
+}
+
+fn human_size(size: u64) -> String {
+    match size {
+        s if s >= 1 << 20 => format!("{:.1} MiB", s as f64 / (1 << 20) as f64),
+        s if s >= 1 << 10 => format!("{:.1} KiB", s as f64 / (1 << 10) as f64),
+        s => format!("{} bytes", s),
+    }
+}
+
+// A binary stl has a 80 bytes header, the triangle count and 50 bytes per triangle, anything else
+// is considered as an ascii stl.
+fn stl_triangles(path: &Path, size: u64) -> Option<usize> {
+    use std::io::Read;
+
+    let mut file = File::open(path).ok()?;
+    let mut header = [0u8; 84];
+    if size >= 84 && file.read_exact(&mut header).is_ok() {
+        let count = u32::from_le_bytes([header[80], header[81], header[82], header[83]]) as u64;
+        if 84 + count * 50 == size {
+            return Some(count as usize);
+        }
+    }
+
+    let reader = BufReader::new(File::open(path).ok()?);
+    Some(
+        reader
+            .split(b'\n')
+            .map_while(Result::ok)
+            .filter(|line| line.trim_ascii_start().starts_with(b"facet"))
+            .count(),
+    )
+}
+
+fn off_faces(path: &Path) -> Option<usize> {
+    let reader = BufReader::new(File::open(path).ok()?);
+    let mut lines = reader
+        .lines()
+        .map_while(Result::ok)
+        .map(|line| line.split('#').next().unwrap_or("").trim().to_owned())
+        .filter(|line| !line.is_empty());
+
+    let first = lines.next()?;
+    let counts = match first.strip_prefix("OFF") {
+        Some(rest) if !rest.trim().is_empty() => rest.to_owned(),
+        Some(_) => lines.next()?,
+        None => return None,
+    };
+    counts.split_whitespace().nth(1)?.parse().ok()
+}
+
+impl ParsedCode {
+    // The string node of the file argument, if `node` is a call of a module reading a file.
+    pub(crate) fn asset_argument<'a>(&self, node: &Node<'a>) -> Option<Node<'a>> {
+        if node.node_kind() != NodeKind::ModuleCall {
+            return None;
+        }
+        let name = node_text(&self.code, &node.child_by_field_name("name")?);
+        let &(_, param) = ASSET_MODULES.iter().find(|(module, _)| *module == name)?;
+        let args = node.child_by_field_name("arguments")?;
+
+        let mut cursor = args.walk();
+        let result = args
+            .named_children(&mut cursor)
+            .enumerate()
+            .find_map(|(i, arg)| match arg.node_kind() {
+                NodeKind::String if i == 0 => Some(arg),
+                NodeKind::Assignment => arg
+                    .child_by_field_name("left")
+                    .filter(|left| node_text(&self.code, left) == param)
+                    .and_then(|_| arg.child_by_field_name("right"))
+                    .filter(|right| right.node_kind() == NodeKind::String),
+                _ => None,
+            });
+        result
+    }
+
+    // Files are resolved relative to the current file, like OpenSCAD does.
+    pub(crate) fn asset_path(&self, string_node: &Node) -> Option<PathBuf> {
+        let text = node_text(&self.code, string_node).trim_matches('"');
+        if text.is_empty() {
+            return None;
+        }
+        let dir = self.url.to_file_path().ok()?.parent()?.to_owned();
+        Some(dir.join(text))
+    }
+
+    // The asset path of a string node if it's the file argument of import() or surface().
+    pub(crate) fn asset_path_at(&self, string_node: &Node) -> Option<PathBuf> {
+        let call = string_node
+            .parent()
+            .filter(|parent| parent.node_kind() == NodeKind::Assignment)
+            .unwrap_or(*string_node)
+            .parent()
+            .filter(|parent| parent.node_kind() == NodeKind::Arguments)?
+            .parent()?;
+        if self.asset_argument(&call)? != *string_node {
+            return None;
+        }
+        self.asset_path(string_node)
+    }
+
+    pub(crate) fn asset_diagnostics(&self) -> Vec<Diagnostic> {
+        traverse(self.tree.walk(), Order::Pre)
+            .filter_map(|node| self.asset_argument(&node))
+            .filter_map(|arg| {
+                let path = self.asset_path(&arg)?;
+                if path.exists() {
+                    return None;
+                }
+                Some(Diagnostic {
+                    range: arg.lsp_range(&self.code),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: Some(NumberOrString::String("missing-file".to_owned())),
+                    message: "file not found!".to_owned(),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+}
+
+impl Server {
+    // How the images of the documentation are rewritten: the doc_images setting, without the data
+    // uris unless `inline` since they are heavy in the completion lists, and whether the client
+    // allows the `<img>` tags in markdown.
+    pub(crate) fn doc_image_options(&self, inline: bool) -> (DocImages, bool) {
+        let mode = match self.doc_images {
+            DocImages::Data if !inline => DocImages::File,
+            mode => mode,
+        };
+        let html_allowed = self
+            .client_capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.markdown.as_ref())
+            .and_then(|markdown| markdown.allowed_tags.as_ref())
+            .is_none_or(|tags| tags.iter().any(|tag| tag.eq_ignore_ascii_case("img")));
+        (mode, html_allowed)
+    }
+}
+
+// The documentation `doc` of a symbol declared in `url`, with its relative images resolved
+// against the directory of the file, which the clients don't know. The `<img>` tags are made
+// markdown images when the client doesn't allow them.
+pub(crate) fn doc_images(
+    doc: String,
+    url: Option<&Url>,
+    (mode, html_allowed): (DocImages, bool),
+) -> String {
+    lazy_static! {
+        static ref MD_IMAGE_RE: Regex =
+            Regex::new(r#"!\[(?P<alt>[^\]]*)\]\((?P<src>[^)\s]+)(?P<title>\s+"[^"]*")?\)"#)
+                .unwrap();
+        static ref HTML_IMAGE_RE: Regex = Regex::new(r"<img\s[^>]*>").unwrap();
+        static ref ATTRIBUTE_RE: Regex =
+            Regex::new(r#"(?P<name>src|alt)\s*=\s*["'](?P<value>[^"']*)["']"#).unwrap();
+    }
+
+    if mode == DocImages::Off || (!doc.contains("![") && !doc.contains("<img")) {
+        return doc;
+    }
+    let dir = url
+        .and_then(|url| url.to_file_path().ok())
+        .and_then(|path| path.parent().map(Path::to_path_buf));
+    let Some(dir) = dir else {
+        return doc;
+    };
+
+    let doc = MD_IMAGE_RE.replace_all(&doc, |cap: &Captures| {
+        match image_uri(&dir, &cap["src"], mode) {
+            Some(uri) => format!(
+                "![{}]({}{})",
+                &cap["alt"],
+                uri,
+                cap.name("title").map_or("", |title| title.as_str())
+            ),
+            None => cap[0].to_owned(),
+        }
+    });
+    HTML_IMAGE_RE
+        .replace_all(&doc, |cap: &Captures| {
+            let attribute = |name: &str| {
+                ATTRIBUTE_RE
+                    .captures_iter(&cap[0])
+                    .find(|attr| &attr["name"] == name)
+                    .map(|attr| attr["value"].to_owned())
+            };
+            let Some(src) = attribute("src") else {
+                return cap[0].to_owned();
+            };
+            let uri = image_uri(&dir, &src, mode);
+            if html_allowed {
+                match uri {
+                    Some(uri) => cap[0].replacen(&src, &uri, 1),
+                    None => cap[0].to_owned(),
+                }
+            } else {
+                let alt = attribute("alt").unwrap_or_default();
+                format!("![{}]({})", alt, uri.unwrap_or(src))
+            }
+        })
+        .into_owned()
+}
+
+// The uri of the image `src` relative to `dir`, None if it's already a uri or doesn't exist.
+fn image_uri(dir: &Path, src: &str, mode: DocImages) -> Option<String> {
+    let is_relative = !src.contains(':') && !src.starts_with('/') && !src.starts_with('#');
+    if !is_relative {
+        return None;
+    }
+    let path = dir.join(src);
+    let size = fs::metadata(&path)
+        .ok()
+        .filter(|meta| meta.is_file())?
+        .len();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    let mime = match extension.as_deref() {
+        Some("png") => Some("image/png"),
+        Some("jpg" | "jpeg") => Some("image/jpeg"),
+        Some("gif") => Some("image/gif"),
+        Some("svg") => Some("image/svg+xml"),
+        Some("webp") => Some("image/webp"),
+        _ => None,
+    };
+    if let Some(mime) = mime.filter(|_| mode == DocImages::Data && size <= MAX_DATA_IMAGE_SIZE) {
+        if let Ok(data) = fs::read(&path) {
+            return Some(format!("data:{};base64,{}", mime, base64_encode(&data)));
+        }
+    }
+    Url::from_file_path(&path).ok().map(String::from)
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+pub(crate) struct BuiltinFlags {}
+impl BuiltinFlags {
+    pub(crate) const IS_OPREATOR: u16 = 1;
+    pub(crate) const IGNORE_PARAM_NAME: u16 = 1 << 1;
+    // Not an operator, but its children are kept, like `echo("a") cube(1);`.
+    pub(crate) const KEEPS_CHILDREN: u16 = 1 << 2;
+}
+
+// Metadata of a builtin module or function, from a `//!` annotation before the declaration:
+//
+//     //! operator, ignore-param-names, since=2021.01, category=2D, doc-url=https://...
+//     module offset(r, delta, chamfer) {}
+//
+// `deprecated` takes an optional message, `deprecated=use children()`, and the allowed values of a
+// parameter are given as `values.halign="left"|"center"|"right"`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BuiltinMeta {
+    pub flags: u16,
+    pub since: Option<String>,
+    pub deprecated: Option<String>,
+    pub category: Option<String>,
+    pub doc_url: Option<String>,
+    pub values: HashMap<String, Vec<String>>,
+}
+
+impl BuiltinMeta {
+    pub(crate) fn is_annotation(comment: &str) -> bool {
+        comment.starts_with("//!")
+    }
+
+    pub(crate) fn parse_annotation(comment: &str) -> Self {
+        let mut meta = Self::default();
+        meta.merge_annotation(comment);
+        meta
+    }
+
+    pub(crate) fn merge_annotation(&mut self, comment: &str) {
+        let text = comment.trim_start_matches("//!");
+        for entry in text.split(',') {
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().to_owned())),
+                None => (entry.trim(), None),
+            };
+            match key {
+                "operator" => self.flags |= BuiltinFlags::IS_OPREATOR,
+                "ignore-param-names" => self.flags |= BuiltinFlags::IGNORE_PARAM_NAME,
+                "children" => self.flags |= BuiltinFlags::KEEPS_CHILDREN,
+                "since" => self.since = value,
+                "deprecated" => self.deprecated = Some(value.unwrap_or_default()),
+                "category" => self.category = value,
+                "doc-url" => self.doc_url = value,
+                "" => {}
+                _ if key.starts_with("values.") => {
+                    self.values.insert(
+                        key["values.".len()..].to_owned(),
+                        value
+                            .unwrap_or_default()
+                            .split('|')
+                            .map(|value| value.trim().to_owned())
+                            .collect(),
+                    );
+                }
+                _ => {
+                    err_to_console!("unknown builtin annotation: {}", key);
+                }
+            }
+        }
+    }
+
+    // The flags of the former `builtin_flags(0000000000000001)` convention, in the body of the
+    // declaration.
+    pub(crate) fn parse_legacy_flags(body: &str) -> u16 {
+        lazy_static! {
+            static ref FLAG_RE: Regex =
+                Regex::new(r"(?m)builtin_flags\((?P<flags>[01]{16})\)").unwrap();
+        };
+
+        FLAG_RE
+            .captures(body)
+            .map_or(0, |cap| u16::from_str_radix(&cap["flags"], 2).unwrap())
+    }
+
+    pub(crate) fn to_markdown(&self) -> Option<String> {
+        let mut lines = vec![];
+        if let Some(message) = &self.deprecated {
+            if message.is_empty() {
+                lines.push("**deprecated**".to_owned());
+            } else {
+                lines.push(format!("**deprecated**: {}", message));
+            }
+        }
+        if let Some(since) = &self.since {
+            lines.push(format!("since OpenSCAD {}", since));
+        }
+        if let Some(url) = &self.doc_url {
+            lines.push(format!("[documentation]({})", url));
+        }
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("  \n"))
+        }
+    }
+}
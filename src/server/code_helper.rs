@@ -1,33 +1,73 @@
-use std::{cell::RefCell, fs::read_to_string, io, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::read_to_string,
+    io, mem,
+    rc::Rc,
+    time::Instant,
+};
 
 use lsp_types::Url;
 use tree_sitter::Node;
+use tree_sitter_traversal::{traverse, Order};
 
 use crate::{
-    parse_code::ParsedCode,
-    response_item::{Item, ItemKind},
+    handler::error::{HandlerError, HandlerResult},
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::{Owner, ParsedCode},
+    response_item::{Item, ItemKind, Param},
     server::Server,
     utils::*,
 };
 
+// The name of the module or function called by a `module_call` or `function_call` node.
+pub(crate) fn call_name_node<'a>(call: &Node<'a>) -> Option<Node<'a>> {
+    match call.node_kind() {
+        NodeKind::ModuleCall => call.child_by_field_name("name"),
+        NodeKind::FunctionCall => call.child_by_field_name("function"),
+        _ => None,
+    }
+}
+
 // Code-related helpers.
 impl Server {
     pub(crate) fn get_code(&mut self, uri: &Url) -> Option<Rc<RefCell<ParsedCode>>> {
-        match self.codes.get(uri) {
-            Some(x) => Some(Rc::clone(x)),
-            None => self.read_and_cache(uri.clone()).ok(),
+        let file = match self.codes.get(uri) {
+            // A file being used is not read again.
+            Some(x) if x.try_borrow_mut().map_or(true, |mut x| !x.is_stale()) => Some(Rc::clone(x)),
+            _ => self.read_and_cache(uri.clone()).ok(),
+        }?;
+        if let Ok(mut code) = file.try_borrow_mut() {
+            self.record_includes(&mut code);
         }
+        Some(file)
+    }
+
+    // The code of the document a request is about, the request fails if it can't be read.
+    pub(crate) fn request_code(&mut self, uri: &Url) -> HandlerResult<Rc<RefCell<ParsedCode>>> {
+        self.get_code(uri)
+            .ok_or_else(|| HandlerError::invalid_params(format!("failed to read {}", uri)))
     }
 
     pub(crate) fn insert_code(&mut self, url: Url, code: String) -> Rc<RefCell<ParsedCode>> {
+        // The least recently used disk files are dropped, they can be read again.
         while self.codes.len() > 1000 {
-            self.codes.pop_front();
+            let Some(oldest) = self
+                .codes
+                .iter()
+                .find(|(_, code)| matches!(code.borrow().owner, Owner::Disk { .. }))
+                .map(|(url, _)| url.clone())
+            else {
+                break;
+            };
+            self.codes.remove(&oldest);
         }
 
         let rc = Rc::new(RefCell::new(ParsedCode::new(
             code,
             url.clone(),
             self.library_locations.clone(),
+            self.doc_bundles.clone(),
         )));
         self.codes.insert(url, rc.clone());
         rc
@@ -39,17 +79,10 @@ impl Server {
         comparator: &dyn Fn(&str) -> bool,
         start_node: &Node,
         findall: bool,
-        depth: i32,
     ) -> Vec<Rc<RefCell<Item>>> {
         let mut result: Vec<Rc<RefCell<Item>>> = vec![];
-        if depth >= Server::get_server().args.depth {
-            return result;
-        }
 
         let mut include_vec = vec![];
-        if depth == 0 {
-            include_vec.push(Server::get_server().builtin_url.clone())
-        }
         if let Some(incs) = &code.includes {
             include_vec.extend(incs.clone());
         }
@@ -63,49 +96,39 @@ impl Server {
             let is_top_level_node = parent.unwrap().parent().is_none();
 
             loop {
-                if node.kind().is_include_statement() {
+                if node.node_kind().is_include_statement() {
                     code.get_include_url(&node).map(|inc| {
                         include_vec.push(inc);
                     });
                 }
 
+                // Variables of for/let/assign and the parameters of function literals.
+                if node.node_kind() == NodeKind::ParenthesizedAssignments
+                    || (node.node_kind() == NodeKind::ParametersDeclaration
+                        && node
+                            .parent()
+                            .is_some_and(|p| p.node_kind() == NodeKind::Function))
+                {
+                    for p in Param::parse_declaration(&code.code, &node) {
+                        if comparator(&p.name) {
+                            result.push(Rc::new(RefCell::new(p.to_item(&code.url))));
+                            if !findall {
+                                return result;
+                            }
+                        }
+                    }
+                }
+
                 if let Some(mut item) = Item::parse(&code.code, &node) {
                     if should_process_param {
-                        match &item.kind {
-                            ItemKind::Module { params, .. } => {
-                                for p in params {
-                                    if comparator(&p.name) {
-                                        result.push(Rc::new(RefCell::new(Item {
-                                            name: p.name.clone(),
-                                            kind: ItemKind::Variable,
-                                            range: p.range,
-                                            url: Some(code.url.clone()),
-                                            ..Default::default()
-                                        })));
-                                        if !findall {
-                                            return result;
-                                        }
-                                    }
-                                }
-                            }
-                            ItemKind::Function { flags: _, params } => {
-                                for p in params {
-                                    if comparator(&p.name) {
-                                        result.push(Rc::new(RefCell::new(Item {
-                                            name: p.name.clone(),
-                                            kind: ItemKind::Variable,
-                                            range: p.range,
-                                            url: Some(code.url.clone()),
-                                            ..Default::default()
-                                        })));
-                                        if !findall {
-                                            return result;
-                                        }
-                                    }
+                        for p in item.kind.params() {
+                            if comparator(&p.name) {
+                                result.push(Rc::new(RefCell::new(p.to_item(&code.url))));
+                                if !findall {
+                                    return result;
                                 }
                             }
-                            _ => {}
-                        };
+                        }
                     }
 
                     if !is_top_level_node && comparator(&item.name) {
@@ -140,43 +163,218 @@ impl Server {
             }
         }
 
-        for inc in include_vec {
-            let inccode = match self.get_code(&inc) {
-                Some(code) => code,
-                _ => return result,
+        // The builtins come last, so that they can be overridden by the user and library
+        // definitions.
+        include_vec.push(Server::get_server().builtin_url.clone());
+
+        // The included files in the order of a depth first search, each file once, with whether
+        // only its modules and functions are visible. A file is searched again if it's later
+        // included after being used.
+        let mut visited: HashMap<Url, bool> = HashMap::from([(code.url.clone(), false)]);
+        let mut pending: Vec<(Url, bool)> = include_vec
+            .into_iter()
+            .rev()
+            .map(|inc| {
+                let used = code.used.contains(&inc);
+                (inc, used)
+            })
+            .collect();
+        while let Some((inc, only_callables)) = pending.pop() {
+            match visited.get(&inc) {
+                Some(&visited_callables) if !visited_callables || only_callables => continue,
+                _ => {}
+            }
+            visited.insert(inc.clone(), only_callables);
+
+            let Some(inccode) = self.get_code(&inc) else {
+                continue;
+            };
+            let Ok(mut inccode) = inccode.try_borrow_mut() else {
+                continue;
             };
+            inccode.gen_top_level_items_if_needed();
 
-            if let Ok(mut inccode) = inccode.try_borrow_mut() {
-                inccode.gen_top_level_items_if_needed();
-                result.extend(self.find_identities(
-                    &inccode,
-                    &comparator,
-                    &inccode.tree.root_node(),
-                    findall,
-                    depth + 1,
-                ));
+            for item in inccode.root_items.iter().flatten() {
+                if only_callables && matches!(item.borrow().kind, ItemKind::Variable) {
+                    continue;
+                }
+                if comparator(&item.borrow().name) {
+                    result.push(item.clone());
+                    if !findall {
+                        return result;
+                    }
+                }
             }
 
-            if !result.is_empty() && !findall {
-                return result;
+            for next in inccode.includes.iter().flatten().rev() {
+                pending.push((next.clone(), only_callables || inccode.used.contains(next)));
             }
         }
 
         result
     }
 
+    // Keep the nearest definition of each name, `items` being ordered from the innermost scope
+    // like `find_identities` returns them. Modules, functions and variables have separate
+    // namespaces.
+    pub(crate) fn nearest_definitions(items: Vec<Rc<RefCell<Item>>>) -> Vec<Rc<RefCell<Item>>> {
+        let mut seen = HashSet::new();
+        items
+            .into_iter()
+            .filter(|item| {
+                let item = item.borrow();
+                seen.insert((item.name.clone(), mem::discriminant(&item.kind)))
+            })
+            .collect()
+    }
+
+    // The called module or function and its parameter, if `node` is the name of a named argument,
+    // `rounding` in `cyl(rounding = 2)`.
+    pub(crate) fn find_argument_param(
+        &mut self,
+        code: &ParsedCode,
+        node: &Node,
+    ) -> Option<(Rc<RefCell<Item>>, Param)> {
+        let assignment = node
+            .parent()
+            .filter(|parent| parent.node_kind() == NodeKind::Assignment)?;
+        if assignment.child_by_field_name("left")? != *node {
+            return None;
+        }
+        let call = assignment
+            .parent()
+            .filter(|parent| parent.node_kind() == NodeKind::Arguments)?
+            .parent()?;
+        let callee = node_text(&code.code, &call_name_node(&call)?).to_owned();
+        let param_name = node_text(&code.code, node);
+
+        let is_module = call.node_kind() == NodeKind::ModuleCall;
+        let item = self
+            .find_identities(code, &|name| name == callee, &call, true)
+            .into_iter()
+            .find(|item| match item.borrow().kind {
+                ItemKind::Module { .. } => is_module,
+                ItemKind::Function { .. } => !is_module,
+                _ => false,
+            })?;
+        let param = item
+            .borrow()
+            .kind
+            .params()
+            .iter()
+            .find(|p| &*p.name == param_name)
+            .cloned()?;
+        Some((item, param))
+    }
+
+    // The definitions of the identifier or special variable `node`, the nearest first. Special
+    // variables not set in the scope are looked up in the file and the files it includes, before
+    // the builtins.
+    pub(crate) fn find_definitions(
+        &mut self,
+        code: &ParsedCode,
+        node: &Node,
+        name: &str,
+    ) -> Vec<Rc<RefCell<Item>>> {
+        let mut items = self.find_identities(code, &|item_name| item_name == name, node, false);
+        if node.node_kind() == NodeKind::SpecialVariable
+            && items.first().is_none_or(|item| item.borrow().is_builtin)
+        {
+            let mut specials = self.find_special_variables(code, &|item_name| item_name == name);
+            specials.extend(items);
+            items = specials;
+        }
+        items
+    }
+
+    // The special variables set anywhere in the file or the files it includes, `$slop = 0.1` in a
+    // library module for instance, as they are dynamically scoped. The current file comes first.
+    pub(crate) fn find_special_variables(
+        &mut self,
+        code: &ParsedCode,
+        comparator: &dyn Fn(&str) -> bool,
+    ) -> Vec<Rc<RefCell<Item>>> {
+        let mut result = code.special_variable_items(comparator);
+        let includes = code.includes.clone().unwrap_or_default();
+        for file in self.included_codes(includes, &code.url) {
+            result.extend(file.borrow().special_variable_items(comparator));
+        }
+        result
+            .into_iter()
+            .map(|item| Rc::new(RefCell::new(item)))
+            .collect()
+    }
+
+    // The file read from the disk, unless the editor or the server owns its text.
     pub(crate) fn read_and_cache(&mut self, url: Url) -> io::Result<Rc<RefCell<ParsedCode>>> {
-        let text = read_to_string(url.to_file_path().unwrap())?;
+        if let Some(code) = self
+            .codes
+            .get(&url)
+            .filter(|code| !matches!(code.borrow().owner, Owner::Disk { .. }))
+        {
+            return Ok(Rc::clone(code));
+        }
+        let path = url
+            .to_file_path()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, url.to_string()))?;
+        // Huge or binary files are not parsed, an include may point anywhere.
+        let size = path.metadata()?.len();
+        if size > self.index_limits.max_file_size {
+            let message = format!(
+                "skipped {}, {} bytes is more than max_file_size",
+                path.display(),
+                size
+            );
+            self.warn_index_limit(message.clone());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let text = read_to_string(&path)?;
+        if text.contains('\0') {
+            let message = format!("skipped {}, a binary file", path.display());
+            self.warn_index_limit(message.clone());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
 
-        match self.codes.entry(url.clone()) {
-            linked_hash_map::Entry::Occupied(o) => {
-                if o.get().borrow().code != text {
-                    Ok(self.insert_code(url, text))
-                } else {
-                    Ok(Rc::clone(o.get()))
-                }
+        let owner = Owner::Disk {
+            modified: path.metadata()?.modified().ok(),
+            checked: Instant::now(),
+        };
+        let file = match self.codes.entry(url.clone()) {
+            linked_hash_map::Entry::Occupied(o) if o.get().borrow().code == text => {
+                Rc::clone(o.get())
             }
-            linked_hash_map::Entry::Vacant(_) => Ok(self.insert_code(url, text)),
-        }
+            _ => self.insert_code(url, text),
+        };
+        file.borrow_mut().owner = owner;
+        Ok(file)
+    }
+}
+
+impl ParsedCode {
+    fn special_variable_items(&self, comparator: &dyn Fn(&str) -> bool) -> Vec<Item> {
+        traverse(self.tree.walk(), Order::Pre)
+            .filter(|node| {
+                node.node_kind() == NodeKind::Assignment
+                    && node
+                        .parent()
+                        .is_some_and(|parent| parent.node_kind() != NodeKind::Arguments)
+            })
+            .filter_map(|node| {
+                let left = node
+                    .child_by_field_name("left")
+                    .filter(|left| left.node_kind() == NodeKind::SpecialVariable)?;
+                let name = node_text(&self.code, &left);
+                if !comparator(name) {
+                    return None;
+                }
+                Some(Item {
+                    name: intern(name),
+                    kind: ItemKind::Variable,
+                    range: node.lsp_range(&self.code),
+                    url: Some(self.url.clone()),
+                    ..Default::default()
+                })
+            })
+            .collect()
     }
 }
@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use lsp_types::Url;
+use tree_sitter::Node;
+
+use crate::{
+    code_helper::call_name_node,
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    response_item::ItemKind,
+    server::Server,
+    types::{definition_node, function_body},
+    utils::*,
+};
+
+// How deep calls of user defined functions are followed, recursive functions stop there.
+const MAX_CALL_DEPTH: u32 = 16;
+// The time spent folding the constants of a file.
+const EVALUATION_TIME: Duration = Duration::from_millis(50);
+
+// The value of a constant expression.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Undef,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Vector(Vec<Value>),
+}
+
+impl Value {
+    pub(crate) fn truth(&self) -> bool {
+        match self {
+            Value::Undef => false,
+            Value::Boolean(value) => *value,
+            Value::Number(value) => *value != 0.0,
+            Value::String(value) => !value.is_empty(),
+            Value::Vector(values) => !values.is_empty(),
+        }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    // The value written in OpenSCAD, also the key of the value in the memoized calls.
+    pub(crate) fn literal(&self) -> String {
+        match self {
+            Value::Undef => "undef".to_owned(),
+            Value::Boolean(value) => value.to_string(),
+            Value::Number(value) => value.to_string(),
+            Value::String(value) => format!("{:?}", value),
+            Value::Vector(values) => {
+                let values: Vec<String> = values.iter().map(Value::literal).collect();
+                format!("[{}]", values.join(","))
+            }
+        }
+    }
+
+    // The value of a json value, the objects have none.
+    pub(crate) fn from_json(value: &serde_json::Value) -> Option<Value> {
+        Some(match value {
+            serde_json::Value::Null => Value::Undef,
+            serde_json::Value::Bool(value) => Value::Boolean(*value),
+            serde_json::Value::Number(value) => Value::Number(value.as_f64()?),
+            serde_json::Value::String(value) => Value::String(value.clone()),
+            serde_json::Value::Array(values) => {
+                Value::Vector(values.iter().map(Value::from_json).collect::<Option<_>>()?)
+            }
+            serde_json::Value::Object(_) => return None,
+        })
+    }
+}
+
+// A number as printed by OpenSCAD, with 6 significant digits and without the fraction of the
+// integers.
+pub(crate) fn format_number(value: f64) -> String {
+    let value: f64 = format!("{:.5e}", value).parse().unwrap_or(value);
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+// The values of the parameters and the let variables.
+type Env = HashMap<String, Value>;
+
+// The state of the constant folding of a file: the results of the calls of user defined functions
+// by arguments, the time left, and the top level variables overridden for the file.
+pub(crate) struct Evaluation {
+    calls: HashMap<String, Option<Value>>,
+    deadline: Instant,
+    overrides: HashMap<String, Value>,
+}
+
+// The text of a string literal, with its escapes.
+fn parse_string(text: &str) -> String {
+    let text = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .unwrap_or(text);
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => {}
+        }
+    }
+    result
+}
+
+// The pure builtin functions of numbers.
+fn builtin_value(name: &str, args: &[Value]) -> Option<Value> {
+    let number = |i: usize| args.get(i).and_then(Value::number);
+    let unary = |f: fn(f64) -> f64| number(0).map(|value| Value::Number(f(value)));
+    match name {
+        "abs" => unary(f64::abs),
+        "sign" => unary(|x| if x == 0.0 { 0.0 } else { x.signum() }),
+        "ceil" => unary(f64::ceil),
+        "floor" => unary(f64::floor),
+        "round" => unary(f64::round),
+        "sqrt" => unary(f64::sqrt),
+        "exp" => unary(f64::exp),
+        "ln" => unary(f64::ln),
+        "log" => unary(f64::log10),
+        "sin" => unary(|x| x.to_radians().sin()),
+        "cos" => unary(|x| x.to_radians().cos()),
+        "tan" => unary(|x| x.to_radians().tan()),
+        "pow" => Some(Value::Number(number(0)?.powf(number(1)?))),
+        "atan2" => Some(Value::Number(number(0)?.atan2(number(1)?).to_degrees())),
+        "min" | "max" => {
+            let values = match args {
+                [Value::Vector(values)] => values.as_slice(),
+                _ => args,
+            };
+            let numbers = values
+                .iter()
+                .map(Value::number)
+                .collect::<Option<Vec<_>>>()?;
+            let fold = if name == "min" { f64::min } else { f64::max };
+            numbers.into_iter().reduce(fold).map(Value::Number)
+        }
+        "len" => match args.first()? {
+            Value::Vector(values) => Some(Value::Number(values.len() as f64)),
+            Value::String(text) => Some(Value::Number(text.chars().count() as f64)),
+            _ => Some(Value::Undef),
+        },
+        _ => None,
+    }
+}
+
+fn binary_value(operator: &str, left: Value, right: Value) -> Option<Value> {
+    use Value::*;
+    let value = match (operator, left, right) {
+        ("==", left, right) => Boolean(left == right),
+        ("!=", left, right) => Boolean(left != right),
+        ("<", Number(a), Number(b)) => Boolean(a < b),
+        (">", Number(a), Number(b)) => Boolean(a > b),
+        ("<=", Number(a), Number(b)) => Boolean(a <= b),
+        (">=", Number(a), Number(b)) => Boolean(a >= b),
+        ("<", String(a), String(b)) => Boolean(a < b),
+        (">", String(a), String(b)) => Boolean(a > b),
+        ("<=", String(a), String(b)) => Boolean(a <= b),
+        (">=", String(a), String(b)) => Boolean(a >= b),
+        ("+", Number(a), Number(b)) => Number(a + b),
+        ("-", Number(a), Number(b)) => Number(a - b),
+        ("*", Number(a), Number(b)) => Number(a * b),
+        ("/", Number(a), Number(b)) => Number(a / b),
+        ("%", Number(a), Number(b)) => Number(a % b),
+        ("^", Number(a), Number(b)) => Number(a.powf(b)),
+        (op @ ("+" | "-"), Vector(a), Vector(b)) => Vector(
+            a.into_iter()
+                .zip(b)
+                .map(|(a, b)| binary_value(op, a, b))
+                .collect::<Option<_>>()?,
+        ),
+        ("*" | "/", Vector(a), Number(b)) => Vector(
+            a.into_iter()
+                .map(|a| binary_value(operator, a, Number(b)))
+                .collect::<Option<_>>()?,
+        ),
+        ("*", Number(a), Vector(b)) => Vector(
+            b.into_iter()
+                .map(|b| binary_value("*", Number(a), b))
+                .collect::<Option<_>>()?,
+        ),
+        ("<" | ">" | "<=" | ">=", _, _) => Boolean(false),
+        (_, _, _) => Undef,
+    };
+    Some(value)
+}
+
+// Constant folding of expressions.
+impl Server {
+    pub(crate) fn evaluation(&self, url: &Url) -> Evaluation {
+        Evaluation {
+            calls: HashMap::new(),
+            deadline: Instant::now() + EVALUATION_TIME,
+            overrides: self.overrides.get(url).cloned().unwrap_or_default(),
+        }
+    }
+
+    // The value given to a top level variable by openscad/setOverrides, like `-D` does.
+    pub(crate) fn override_value(
+        &mut self,
+        evaluation: &Evaluation,
+        code: &ParsedCode,
+        node: &Node,
+    ) -> Option<Value> {
+        let text = node_text(&code.code, node);
+        let value = evaluation.overrides.get(text)?.clone();
+        let item = self
+            .find_identities(code, &|item_name| item_name == text, node, true)
+            .into_iter()
+            .find(|item| matches!(item.borrow().kind, ItemKind::Variable))?;
+        let item = item.borrow();
+        let file = self.get_code(item.url.as_ref()?)?;
+        let file = file.try_borrow().ok()?;
+        let definition = definition_node(&file, to_point(&file.code, item.range.start))?;
+        (definition.parent()?.node_kind() == NodeKind::SourceFile).then_some(value)
+    }
+
+    // The description of a range, `[0:5:100]`: its bounds and its number of elements when they
+    // are constant.
+    pub(crate) fn range_markdown(&mut self, code: &ParsedCode, range: &Node) -> String {
+        let mut evaluation = self.evaluation(&code.url);
+        let mut bound = |server: &mut Self, field: &str| {
+            let node = range.child_by_field_name(field)?;
+            let value = server.constant_value(&mut evaluation, code, &node, &Env::new(), 0);
+            Some((
+                node_text(&code.code, &node),
+                value.and_then(|value| value.number()),
+            ))
+        };
+        let start = bound(self, "start");
+        let step = bound(self, "increment");
+        let end = bound(self, "end");
+
+        let text = |bound: &Option<(&str, Option<f64>)>, default: &str| match bound {
+            Some((_, Some(value))) => format!("`{}`", format_number(*value)),
+            Some((text, None)) => format!("`{}`", text),
+            None => default.to_owned(),
+        };
+        let mut value = format!(
+            "range from {} to {}, step {}",
+            text(&start, "?"),
+            text(&end, "?"),
+            text(&step, "`1` (default)")
+        );
+
+        let step_field_missing = step.is_none();
+        let number = |bound: &Option<(&str, Option<f64>)>| bound.as_ref().map(|(_, value)| *value);
+        let (Some(Some(start)), Some(Some(end))) = (number(&start), number(&end)) else {
+            return value;
+        };
+        let step = match number(&step) {
+            Some(Some(step)) => step,
+            Some(None) => return value,
+            None => 1.0,
+        };
+        // `[10:0]` counts from 0 to 10, with a deprecation warning.
+        let (start, end) = if step_field_missing && start > end {
+            value.push_str(" (deprecated, the bounds are swapped)");
+            (end, start)
+        } else {
+            (start, end)
+        };
+        // OpenSCAD tolerates the rounding errors of the last element.
+        let count = if step > 0.0 && end >= start {
+            ((end - start) / step + 1e-9).floor() as u64 + 1
+        } else {
+            0
+        };
+        let elements = match count {
+            0 => "no element".to_owned(),
+            1 => format!("1 element: `{}`", format_number(start)),
+            _ => {
+                let last = start + (count - 1) as f64 * step;
+                let ellipsis = if count > 3 { ", …" } else { "" };
+                let second = if count > 2 {
+                    format!(", {}", format_number(start + step))
+                } else {
+                    String::new()
+                };
+                format!(
+                    "{} elements: `{}{}{}, {}`",
+                    count,
+                    format_number(start),
+                    second,
+                    ellipsis,
+                    format_number(last)
+                )
+            }
+        };
+        value.push_str(&format!("\n\n{}", elements));
+        value
+    }
+
+    // The value of `node` when it only depends on literals, on variables assigned once to a
+    // constant value, and on calls of functions of such values, including the user defined ones
+    // of the included files.
+    pub(crate) fn constant_value(
+        &mut self,
+        evaluation: &mut Evaluation,
+        code: &ParsedCode,
+        node: &Node,
+        env: &Env,
+        depth: u32,
+    ) -> Option<Value> {
+        let text = node_text(&code.code, node);
+        let mut value_of = |server: &mut Self, node: &Node| {
+            server.constant_value(evaluation, code, node, env, depth)
+        };
+        match node.node_kind() {
+            NodeKind::Undef => Some(Value::Undef),
+            NodeKind::Boolean => Some(Value::Boolean(text == "true")),
+            NodeKind::Number => text.parse().ok().map(Value::Number),
+            NodeKind::String => Some(Value::String(parse_string(text))),
+            NodeKind::ParenthesizedExpression => value_of(self, &node.named_child(0)?),
+            NodeKind::List => {
+                let mut values = vec![];
+                for child in node.named_children(&mut node.walk()) {
+                    match child.node_kind() {
+                        NodeKind::Comment => {}
+                        NodeKind::Each | NodeKind::ListComprehension | NodeKind::Range => {
+                            return None
+                        }
+                        _ => values.push(value_of(self, &child)?),
+                    }
+                }
+                Some(Value::Vector(values))
+            }
+            NodeKind::UnaryExpression => {
+                let operand = value_of(self, &node.named_child(0)?)?;
+                match (node.child(0)?.kind(), operand) {
+                    ("!", operand) => Some(Value::Boolean(!operand.truth())),
+                    ("+", operand @ Value::Number(_)) => Some(operand),
+                    ("-", Value::Number(value)) => Some(Value::Number(-value)),
+                    ("-", Value::Vector(values)) => {
+                        binary_value("*", Value::Number(-1.0), Value::Vector(values))
+                    }
+                    _ => Some(Value::Undef),
+                }
+            }
+            NodeKind::BinaryExpression => {
+                let operator = node.child(1)?.kind();
+                let left = value_of(self, &node.child_by_field_name("left")?)?;
+                let mut right = || value_of(self, &node.child_by_field_name("right")?);
+                match operator {
+                    "&&" if !left.truth() => Some(Value::Boolean(false)),
+                    "||" if left.truth() => Some(Value::Boolean(true)),
+                    "&&" | "||" => Some(Value::Boolean(right()?.truth())),
+                    _ => binary_value(operator, left, right()?),
+                }
+            }
+            NodeKind::TernaryExpression => {
+                let condition = value_of(self, &node.child_by_field_name("condition")?)?;
+                let branch = if condition.truth() {
+                    "consequence"
+                } else {
+                    "alternative"
+                };
+                value_of(self, &node.child_by_field_name(branch)?)
+            }
+            NodeKind::IndexExpression => {
+                let value = value_of(self, &node.child_by_field_name("value")?)?;
+                let index = value_of(self, &node.child_by_field_name("index")?)?;
+                let Value::Number(index) = index else {
+                    return Some(Value::Undef);
+                };
+                let index = (index >= 0.0).then_some(index as usize);
+                Some(match value {
+                    Value::Vector(values) => index
+                        .and_then(|index| values.into_iter().nth(index))
+                        .unwrap_or(Value::Undef),
+                    Value::String(text) => index
+                        .and_then(|index| text.chars().nth(index))
+                        .map_or(Value::Undef, |c| Value::String(c.to_string())),
+                    _ => Value::Undef,
+                })
+            }
+            NodeKind::LetExpression => {
+                let mut env = env.clone();
+                let assignments = node.named_child(0)?;
+                for assignment in assignments.named_children(&mut assignments.walk()) {
+                    if assignment.node_kind() != NodeKind::Assignment {
+                        continue;
+                    }
+                    let name = node_text(&code.code, &assignment.child_by_field_name("left")?);
+                    let right = assignment.child_by_field_name("right")?;
+                    let value = self.constant_value(evaluation, code, &right, &env, depth)?;
+                    env.insert(name.to_owned(), value);
+                }
+                let body = node.child_by_field_name("body")?;
+                self.constant_value(evaluation, code, &body, &env, depth)
+            }
+            NodeKind::Identifier => {
+                if let Some(value) = env.get(text) {
+                    return Some(value.clone());
+                }
+                if let Some(value) = self.override_value(evaluation, code, node) {
+                    return Some(value);
+                }
+                let (file, point) = self.constant_assignment(code, node)?;
+                let file = file.try_borrow().ok()?;
+                let value = definition_node(&file, point)?.child_by_field_name("right")?;
+                self.constant_value(evaluation, &file, &value, &Env::new(), depth + 1)
+            }
+            NodeKind::FunctionCall => self.constant_call(evaluation, code, node, env, depth),
+            _ => None,
+        }
+    }
+
+    fn constant_call(
+        &mut self,
+        evaluation: &mut Evaluation,
+        code: &ParsedCode,
+        node: &Node,
+        env: &Env,
+        depth: u32,
+    ) -> Option<Value> {
+        let name_node =
+            call_name_node(node).filter(|name| name.node_kind() == NodeKind::Identifier)?;
+        let name = node_text(&code.code, &name_node);
+        // A parameter holding a function literal isn't folded.
+        if env.contains_key(name) || depth > MAX_CALL_DEPTH || Instant::now() > evaluation.deadline
+        {
+            return None;
+        }
+
+        let mut positional = vec![];
+        let mut named = HashMap::new();
+        let arguments = node.child_by_field_name("arguments")?;
+        for arg in arguments.named_children(&mut arguments.walk()) {
+            match arg.node_kind() {
+                NodeKind::Comment => {}
+                NodeKind::Assignment => {
+                    let left = node_text(&code.code, &arg.child_by_field_name("left")?);
+                    let right = arg.child_by_field_name("right")?;
+                    let value = self.constant_value(evaluation, code, &right, env, depth)?;
+                    named.insert(left.to_owned(), value);
+                }
+                _ => positional.push(self.constant_value(evaluation, code, &arg, env, depth)?),
+            }
+        }
+
+        let item = self
+            .find_identities(code, &|item_name| item_name == name, node, true)
+            .into_iter()
+            .find(|item| matches!(item.borrow().kind, ItemKind::Function { .. }))?;
+        let item = item.borrow();
+        if item.is_builtin {
+            return named.is_empty().then(|| builtin_value(name, &positional))?;
+        }
+
+        let url = item.url.as_ref()?;
+        let mut key = format!(
+            "{}:{}:{}",
+            url, item.range.start.line, item.range.start.character
+        );
+        for value in &positional {
+            key.push_str(&format!(" {}", value.literal()));
+        }
+        let mut named_keys: Vec<_> = named.iter().collect();
+        named_keys.sort_by_key(|(name, _)| *name);
+        for (name, value) in named_keys {
+            key.push_str(&format!(" {}={}", name, value.literal()));
+        }
+        if let Some(value) = evaluation.calls.get(&key) {
+            return value.clone();
+        }
+
+        let file = self.get_code(url)?;
+        let file = file.try_borrow().ok()?;
+        let declaration = definition_node(&file, to_point(&file.code, item.range.start))
+            .filter(|node| node.node_kind() == NodeKind::FunctionDeclaration)?;
+        let params = declaration.child_by_field_name("parameters")?;
+        let mut call_env = Env::new();
+        let mut position = 0;
+        for param in params.named_children(&mut params.walk()) {
+            let (name, default) = match param.node_kind() {
+                NodeKind::Identifier => (node_text(&file.code, &param), None),
+                NodeKind::Assignment => (
+                    node_text(&file.code, &param.child_by_field_name("left")?),
+                    param.child_by_field_name("right"),
+                ),
+                _ => continue,
+            };
+            let value = match named
+                .remove(name)
+                .or_else(|| positional.get(position).cloned())
+            {
+                Some(value) => value,
+                None => match default {
+                    Some(default) => {
+                        self.constant_value(evaluation, &file, &default, &call_env, depth + 1)?
+                    }
+                    None => Value::Undef,
+                },
+            };
+            position += 1;
+            call_env.insert(name.to_owned(), value);
+        }
+
+        let body = function_body(&declaration)?;
+        let value = self.constant_value(evaluation, &file, &body, &call_env, depth + 1);
+        evaluation.calls.insert(key, value.clone());
+        value
+    }
+}
@@ -0,0 +1,133 @@
+use lsp_types::{Position, Range, TextEdit};
+use tree_sitter::Node;
+
+use crate::{
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    utils::*,
+};
+
+pub(crate) const DEFAULT_GROUP_NAME: &str = "Parameters";
+const END_CUSTOMIZER: &str = "module __end_customizer() {}";
+
+// A customizer tab comment, `/* [Size] */`.
+pub(crate) fn group_name<'a>(code: &'a str, node: &Node) -> Option<&'a str> {
+    if node.node_kind() != NodeKind::Comment {
+        return None;
+    }
+    node_text(code, node)
+        .strip_prefix("/*")?
+        .strip_suffix("*/")?
+        .trim()
+        .strip_prefix('[')?
+        .strip_suffix(']')
+        .map(str::trim)
+}
+
+// The edits putting the top level assignments of `range` under a new customizer tab `name`. The
+// customizer stops at the first module, a terminator module is added when the following
+// assignments would join the tab. None if there is no such assignment, or if they come after a
+// module or a function, where the customizer doesn't look.
+pub(crate) fn group_edits(code: &ParsedCode, range: Range, name: &str) -> Option<Vec<TextEdit>> {
+    if name.contains(']') || name.contains("*/") {
+        return None;
+    }
+
+    let root = code.tree.root_node();
+    let statements: Vec<Node> = root
+        .children(&mut root.walk())
+        .filter(|node| node.is_named())
+        .collect();
+    let selected: Vec<usize> = statements
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| {
+            node.node_kind() == NodeKind::Assignment
+                && node.start_position().row <= range.end.line as usize
+                && node.end_position().row >= range.start.line as usize
+        })
+        .map(|(i, _)| i)
+        .collect();
+    let (first, last) = (*selected.first()?, *selected.last()?);
+
+    let is_declaration = |node: &Node| {
+        matches!(
+            node.node_kind(),
+            NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration
+        )
+    };
+    if statements[..first].iter().any(is_declaration) {
+        return None;
+    }
+
+    // The description comment lines before the first assignment stay with it.
+    let mut start = first;
+    while start > 0 {
+        let prev = statements[start - 1];
+        let is_own_line =
+            start < 2 || statements[start - 2].end_position().row < prev.start_position().row;
+        if prev.node_kind() == NodeKind::Comment
+            && group_name(&code.code, &prev).is_none()
+            && prev.end_position().row + 1 == statements[start].start_position().row
+            && is_own_line
+        {
+            start -= 1;
+        } else {
+            break;
+        }
+    }
+    let header = Position::new(statements[start].start_position().row as u32, 0);
+    let mut edits = vec![TextEdit {
+        range: Range::new(header, header),
+        new_text: format!("/* [{}] */\n", name),
+    }];
+
+    let last_row = statements[last].end_position().row;
+    let next = statements[last + 1..].iter().find(|node| {
+        node.node_kind() != NodeKind::Comment || group_name(&code.code, node).is_some()
+    });
+    if next.is_some_and(|node| node.node_kind() == NodeKind::Assignment) {
+        let end = Position::new(last_row as u32 + 1, 0);
+        edits.push(TextEdit {
+            range: Range::new(end, end),
+            new_text: format!("\n{}\n", END_CUSTOMIZER),
+        });
+    }
+    Some(edits)
+}
+
+// A customizer tab, from its comment to its last assignment.
+pub(crate) struct Group {
+    pub name: String,
+    pub range: Range,
+}
+
+// The customizer tabs of the file, the customizer doesn't look past the first module or
+// function.
+pub(crate) fn groups(code: &ParsedCode) -> Vec<Group> {
+    let mut result: Vec<Group> = vec![];
+    let mut current: Option<Group> = None;
+    let root = code.tree.root_node();
+    for node in root.children(&mut root.walk()) {
+        if !node.is_named() {
+            continue;
+        }
+        if matches!(
+            node.node_kind(),
+            NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration
+        ) {
+            break;
+        }
+        if let Some(name) = group_name(&code.code, &node) {
+            result.extend(current.take());
+            current = Some(Group {
+                name: name.to_owned(),
+                range: node.lsp_range(&code.code),
+            });
+        } else if let Some(group) = current.as_mut().filter(|_| !node.node_kind().is_comment()) {
+            group.range.end = node.lsp_range(&code.code).end;
+        }
+    }
+    result.extend(current);
+    result
+}
@@ -0,0 +1,659 @@
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use lsp_types::{
+    notification::Notification, Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity,
+    DiagnosticTag, Location, NumberOrString, PublishDiagnosticsParams, Range,
+    TextDocumentIdentifier, Url,
+};
+use tree_sitter::Node;
+use tree_sitter_traversal::{traverse, Order};
+
+use crate::{
+    builtin_meta::BuiltinFlags,
+    code_helper::call_name_node,
+    handler::ext::{InactiveRegionsNotification, InactiveRegionsParams},
+    node_kind::{NodeKind, NodeKindExt},
+    openscad_cli::is_older_version,
+    parse_code::ParsedCode,
+    response_item::ItemKind,
+    server::Server,
+    syntax_rules::{empty_statement_diagnostics, fix_data},
+    types::DEAD_CODE,
+    utils::*,
+};
+
+// Builtin constants which are not declared in the builtin file.
+pub(crate) const BUILTIN_CONSTANTS: &[&str] = &["PI"];
+
+// The special variables set by OpenSCAD.
+pub(crate) const SPECIAL_VARIABLES: &[&str] = &[
+    "$fn",
+    "$fa",
+    "$fs",
+    "$t",
+    "$vpr",
+    "$vpt",
+    "$vpd",
+    "$vpf",
+    "$children",
+    "$preview",
+    "$parent_modules",
+];
+
+pub(crate) const UNDEFINED_IDENTIFIER: &str = "undefined-identifier";
+pub(crate) const UNKNOWN_SPECIAL_VARIABLE: &str = "unknown-special-variable";
+pub(crate) const IGNORED_CHILDREN: &str = "ignored-children";
+pub(crate) const UNUSED_VARIABLE: &str = "unused-variable";
+pub(crate) const LIBRARY_VERSION: &str = "library-version";
+pub(crate) const DEPRECATED: &str = "deprecated";
+pub(crate) const UNUSED_INCLUDE: &str = "unused-include";
+pub(crate) const NOT_INCLUDED: &str = "not-included";
+const TOO_MANY_PROBLEMS: &str = "too-many-problems";
+
+// Whether the identifier node is the name of a call of an item of `kind`.
+fn is_call_of(node: &Node, kind: &ItemKind) -> bool {
+    let call = node
+        .parent()
+        .filter(|parent| call_name_node(parent) == Some(*node));
+    matches!(
+        (call.map(|call| call.node_kind()), kind),
+        (Some(NodeKind::ModuleCall), ItemKind::Module { .. })
+            | (Some(NodeKind::FunctionCall), ItemKind::Function { .. })
+    )
+}
+
+// Whether the identifier node refers to a symbol, rather than declaring one.
+pub(crate) fn is_reference(node: &Node) -> bool {
+    let Some(parent) = node.parent() else {
+        return false;
+    };
+    let is_field = |field: &str| parent.child_by_field_name(field) == Some(*node);
+    match parent.node_kind() {
+        NodeKind::Assignment => !is_field("left"),
+        NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration => !is_field("name"),
+        NodeKind::ParametersDeclaration => false,
+        NodeKind::DotIndexExpression => !is_field("index"),
+        _ => true,
+    }
+}
+
+// At most `max` diagnostics, the most severe first, and a last one telling how many were left out,
+// at the first of them.
+fn limit_diagnostics(mut diagnostics: Vec<Diagnostic>, max: usize) -> Vec<Diagnostic> {
+    if max == 0 || diagnostics.len() <= max {
+        return diagnostics;
+    }
+    // A missing severity is up to the client, usually an error.
+    diagnostics.sort_by_key(|diag| diag.severity.unwrap_or(DiagnosticSeverity::ERROR));
+    let omitted = diagnostics.split_off(max);
+    diagnostics.push(Diagnostic {
+        range: omitted[0].range,
+        severity: Some(DiagnosticSeverity::INFORMATION),
+        code: Some(NumberOrString::String(TOO_MANY_PROBLEMS.to_owned())),
+        message: format!("{} more problems not shown", omitted.len()),
+        ..Default::default()
+    });
+    diagnostics
+}
+
+// The ranges of the dead code diagnostics, without the ones inside another.
+fn inactive_regions(diagnostics: &[Diagnostic]) -> Vec<Range> {
+    let dead_code = Some(NumberOrString::String(DEAD_CODE.to_owned()));
+    let mut ranges: Vec<Range> = diagnostics
+        .iter()
+        .filter(|diag| diag.code == dead_code)
+        .map(|diag| diag.range)
+        .collect();
+    ranges.sort_by_key(|range| (range.start, std::cmp::Reverse(range.end)));
+    let mut regions: Vec<Range> = vec![];
+    for range in ranges {
+        if regions.last().is_none_or(|last| range.end > last.end) {
+            regions.push(range);
+        }
+    }
+    regions
+}
+
+// The diagnostics pipeline, shared by the server and the check command.
+impl Server {
+    pub(crate) fn diagnose(&mut self, file: &Rc<RefCell<ParsedCode>>) -> Vec<Diagnostic> {
+        file.borrow_mut().gen_top_level_items_if_needed();
+        self.record_includes(&mut file.borrow_mut());
+        let bfile = file.borrow();
+
+        let mut diags = bfile.syntax_diagnostics();
+        let include_diags = bfile.include_diagnostics();
+        let has_missing_includes = !include_diags.is_empty();
+        diags.extend(include_diags);
+        // The lint diagnostics each walk the whole tree, too slow on each change of the large
+        // generated files.
+        if self.is_large_file(&bfile, self.open_documents.contains(&bfile.url)) {
+            return diags;
+        }
+        diags.extend(bfile.asset_diagnostics());
+
+        // Everything would be undefined without the missing files.
+        if !has_missing_includes {
+            diags.extend(self.undefined_identifier_diagnostics(&bfile));
+            diags.extend(self.unknown_special_variable_diagnostics(&bfile));
+            diags.extend(self.ignored_children_diagnostics(&bfile));
+            diags.extend(self.library_version_diagnostics(&bfile));
+            diags.extend(self.deprecated_diagnostics(&bfile));
+            diags.extend(self.unused_include_diagnostics(&bfile));
+        }
+        diags.extend(bfile.unused_variable_diagnostics());
+        diags.extend(self.type_diagnostics(&bfile));
+        diags.extend(self.dead_code_diagnostics(&bfile));
+        diags.extend(empty_statement_diagnostics(&bfile));
+        diags
+    }
+
+    pub(crate) fn publish_diagnostics(
+        &mut self,
+        file: &Rc<RefCell<ParsedCode>>,
+        version: Option<i32>,
+    ) {
+        let diagnostics = self.diagnose(file);
+        let uri = file.borrow().url.clone();
+        if self.inactive_regions_support() {
+            self.notify(lsp_server::Notification::new(
+                InactiveRegionsNotification::METHOD.into(),
+                InactiveRegionsParams {
+                    text_document: TextDocumentIdentifier::new(uri.clone()),
+                    regions: inactive_regions(&diagnostics),
+                },
+            ));
+        }
+        let diagnostics = limit_diagnostics(diagnostics, self.max_diagnostics);
+        self.notify(lsp_server::Notification::new(
+            "textDocument/publishDiagnostics".into(),
+            PublishDiagnosticsParams {
+                uri,
+                diagnostics,
+                version,
+            },
+        ));
+    }
+
+    // Refresh the diagnostics of the open files depending on the changed files.
+    pub(crate) fn publish_dependents_diagnostics(&mut self, changed: &[Url]) {
+        let mut files = HashSet::new();
+        for url in changed {
+            files.extend(self.dependents_of(url));
+        }
+
+        for url in files {
+            if !self.open_documents.contains(&url) {
+                continue;
+            }
+            if let Some(file) = self.codes.get(&url).cloned() {
+                self.publish_diagnostics(&file, None);
+            }
+        }
+    }
+
+    fn undefined_identifier_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let root = code.tree.root_node();
+        let globals: HashSet<Rc<str>> = self
+            .find_identities(code, &|_| true, &root, true)
+            .iter()
+            .map(|item| item.borrow().name.clone())
+            .collect();
+
+        let mut diags = vec![];
+        let mut library_items = None;
+        let mut not_included = vec![];
+        for node in traverse(code.tree.walk(), Order::Pre) {
+            // The identifiers inserted by the parser are reported as missing expressions.
+            if node.node_kind() != NodeKind::Identifier
+                || node.is_missing()
+                || !is_reference(&node)
+                || code.unclosed_include(&node).is_some()
+            {
+                continue;
+            }
+            let name = node_text(&code.code, &node);
+            if globals.contains(name) || BUILTIN_CONSTANTS.contains(&name) {
+                continue;
+            }
+            if !self
+                .find_identities(code, &|item_name| item_name == name, &node, false)
+                .is_empty()
+            {
+                continue;
+            }
+
+            let is_pasted = code
+                .pasted
+                .iter()
+                .any(|span| span.start <= node.start_byte() && node.end_byte() <= span.end);
+            if is_pasted {
+                let items = library_items.get_or_insert_with(|| self.library_items(code));
+                let target = items
+                    .iter()
+                    .map(|item| item.borrow())
+                    .find(|item| &*item.name == name && is_call_of(&node, &item.kind))
+                    .and_then(|item| item.url.clone());
+                if let Some(target) = target {
+                    not_included.push((node, target));
+                    continue;
+                }
+            }
+
+            let mut message = format!("undefined identifier `{}`", name);
+            let mut data = None;
+            if let Some(suggestion) = self.suggest_name(code, &node, name) {
+                message = format!("{}, did you mean `{}`?", message, suggestion);
+                data = Some(serde_json::json!({ "suggestion": suggestion }));
+            }
+            diags.push(Diagnostic {
+                range: node.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(UNDEFINED_IDENTIFIER.to_owned())),
+                message,
+                data,
+                ..Default::default()
+            });
+        }
+
+        // The library symbols called by the pasted code, all their files are included by the fix
+        // of each one.
+        let mut targets: Vec<Url> = vec![];
+        for (_, target) in &not_included {
+            if !targets.contains(target) {
+                targets.push(target.clone());
+            }
+        }
+        let title = match targets.len() {
+            1 => "Add 1 missing include".to_owned(),
+            len => format!("Add {} missing includes", len),
+        };
+        let data = self
+            .includes_edit(code, &targets)
+            .map(|edit| fix_data(title, vec![edit]));
+        for (node, target) in not_included {
+            let path = self
+                .include_path_to(&code.url, &target)
+                .unwrap_or_else(|| target.to_string());
+            diags.push(Diagnostic {
+                range: node.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::INFORMATION),
+                code: Some(NumberOrString::String(NOT_INCLUDED.to_owned())),
+                message: format!(
+                    "`{}` is defined in `{}`, which isn't included",
+                    node_text(&code.code, &node),
+                    path
+                ),
+                data: data.clone(),
+                ..Default::default()
+            });
+        }
+        diags
+    }
+
+    // The symbol in scope of the same kind as the undefined `name` at `node` which is the closest
+    // to it, `cylinder` for `cylnder`.
+    fn suggest_name(&mut self, code: &ParsedCode, node: &Node, name: &str) -> Option<String> {
+        let call = node
+            .parent()
+            .filter(|parent| call_name_node(parent) == Some(*node));
+        let items = self.find_identities(code, &|_| true, node, true);
+        let mut names: Vec<Rc<str>> = items
+            .iter()
+            .map(|item| item.borrow())
+            .filter(
+                |item| match (call.map(|call| call.node_kind()), &item.kind) {
+                    (Some(NodeKind::ModuleCall), ItemKind::Module { .. }) => true,
+                    (Some(NodeKind::FunctionCall), ItemKind::Function { .. }) => true,
+                    // A variable may hold a function literal.
+                    (Some(NodeKind::FunctionCall) | None, ItemKind::Variable) => true,
+                    _ => false,
+                },
+            )
+            .map(|item| item.name.clone())
+            .collect();
+        if call.is_none() {
+            names.extend(BUILTIN_CONSTANTS.iter().map(|name| intern(name)));
+        }
+        closest_name(name, names.iter().map(|name| &**name)).map(str::to_owned)
+    }
+
+    // Special variables which are read but neither known nor assigned in the file, the files it
+    // includes or the files including it, typos such as `$fA`.
+    fn unknown_special_variable_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let mut assigned = code.assigned_special_variables();
+        // Libraries may read the special variables set by the files using them.
+        let mut urls = code.includes.clone().unwrap_or_default();
+        urls.extend(self.dependents_of(&code.url));
+        for file in self.included_codes(urls, &code.url) {
+            assigned.extend(file.borrow().assigned_special_variables());
+        }
+
+        let mut diags = vec![];
+        for node in traverse(code.tree.walk(), Order::Pre) {
+            if node.node_kind() != NodeKind::SpecialVariable
+                || is_special_variable_assignment(&node)
+            {
+                continue;
+            }
+            let name = node_text(&code.code, &node);
+            if SPECIAL_VARIABLES.contains(&name) || assigned.contains(name) {
+                continue;
+            }
+
+            let mut message = format!("unknown special variable `{}`", name);
+            if let Some(known) = closest_name(name, SPECIAL_VARIABLES.iter().copied()) {
+                message = format!("{}, did you mean `{}`?", message, known);
+            }
+            diags.push(Diagnostic {
+                range: node.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(UNKNOWN_SPECIAL_VARIABLE.to_owned())),
+                message,
+                ..Default::default()
+            });
+        }
+        diags
+    }
+
+    // Children passed to a module which never uses children(), of the workspace, or a builtin
+    // annotated without `operator`.
+    fn ignored_children_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let mut diags = vec![];
+        for node in traverse(code.tree.walk(), Order::Pre) {
+            if node.node_kind() != NodeKind::ModuleCall
+                || node
+                    .parent()
+                    .is_none_or(|parent| parent.node_kind() != NodeKind::TransformChain)
+                || node
+                    .next_named_sibling()
+                    .is_none_or(|child| child.node_kind().is_comment())
+            {
+                continue;
+            }
+            let Some(name_node) = node.child_by_field_name("name") else {
+                continue;
+            };
+            let name = node_text(&code.code, &name_node);
+            let Some(module) = self
+                .find_identities(code, &|item_name| item_name == name, &node, true)
+                .into_iter()
+                .find(|item| matches!(item.borrow().kind, ItemKind::Module { .. }))
+            else {
+                continue;
+            };
+            let module = module.borrow();
+            let ItemKind::Module { flags, .. } = module.kind else {
+                continue;
+            };
+            // The builtins of a file without annotations can't tell their operators.
+            let keeps_children = BuiltinFlags::IS_OPREATOR | BuiltinFlags::KEEPS_CHILDREN;
+            if flags & keeps_children != 0 || (module.is_builtin && module.meta.is_none()) {
+                continue;
+            }
+
+            diags.push(Diagnostic {
+                range: name_node.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(IGNORED_CHILDREN.to_owned())),
+                message: if module.is_builtin {
+                    format!("`{}` takes no children, they are ignored", name)
+                } else {
+                    format!(
+                        "`{}` doesn't use children(), its children are ignored",
+                        name
+                    )
+                },
+                ..Default::default()
+            });
+        }
+        diags
+    }
+
+    // The included libraries older than the min_library_versions setting.
+    fn library_version_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        if self.min_library_versions.is_empty() {
+            return vec![];
+        }
+        let mut diags = vec![];
+        let root = code.tree.root_node();
+        for node in root.children(&mut root.walk()) {
+            if !node.node_kind().is_include_statement() {
+                continue;
+            }
+            let Some((_, target)) = code.resolve_include(&node) else {
+                continue;
+            };
+            for (name, version) in self.library_versions(&target, &code.url) {
+                let Some(required) = self.min_library_versions.get(&name) else {
+                    continue;
+                };
+                if !is_older_version(&version, required) {
+                    continue;
+                }
+                diags.push(Diagnostic {
+                    range: node.child(1).unwrap_or(node).lsp_range(&code.code),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    code: Some(NumberOrString::String(LIBRARY_VERSION.to_owned())),
+                    message: format!(
+                        "{} is {}, older than the required {}",
+                        name, version, required
+                    ),
+                    ..Default::default()
+                });
+            }
+        }
+        diags
+    }
+
+    // Calls of the deprecated builtins, with their declaration when it's in a file.
+    fn deprecated_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let mut diags = vec![];
+        for node in traverse(code.tree.walk(), Order::Pre) {
+            if !matches!(
+                node.node_kind(),
+                NodeKind::ModuleCall | NodeKind::FunctionCall
+            ) {
+                continue;
+            }
+            let Some(name_node) =
+                call_name_node(&node).filter(|name| name.node_kind() == NodeKind::Identifier)
+            else {
+                continue;
+            };
+            let name = node_text(&code.code, &name_node);
+            let is_module = node.node_kind() == NodeKind::ModuleCall;
+            let Some(item) = self
+                .find_identities(code, &|item_name| item_name == name, &node, true)
+                .into_iter()
+                .find(|item| match item.borrow().kind {
+                    ItemKind::Module { .. } => is_module,
+                    ItemKind::Function { .. } => !is_module,
+                    _ => false,
+                })
+            else {
+                continue;
+            };
+            let item = item.borrow();
+            let Some(reason) = item.meta.as_ref().and_then(|meta| meta.deprecated.as_ref()) else {
+                continue;
+            };
+
+            let mut message = format!("`{}` is deprecated", name);
+            if !reason.is_empty() {
+                message = format!("{}, {}", message, reason);
+            }
+            let builtin_file = self.get_code(&self.builtin_url.clone());
+            let declared_in_file =
+                !item.is_builtin || builtin_file.is_some_and(|file| file.borrow().external_builtin);
+            let related_information = item.url.clone().filter(|_| declared_in_file).map(|uri| {
+                vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri,
+                        range: item.range,
+                    },
+                    message: format!("`{}` is declared here", name),
+                }]
+            });
+            diags.push(Diagnostic {
+                range: name_node.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(DEPRECATED.to_owned())),
+                message,
+                related_information,
+                tags: Some(vec![DiagnosticTag::DEPRECATED]),
+                ..Default::default()
+            });
+        }
+        diags
+    }
+
+    // Include and use statements of files none of whose symbols are referenced. The included
+    // files drawing something, and the includes of files included by others, are needed anyway.
+    fn unused_include_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let referenced: HashSet<&str> = traverse(code.tree.walk(), Order::Pre)
+            .filter(|node| node.node_kind() == NodeKind::Identifier && is_reference(node))
+            .map(|node| node_text(&code.code, &node))
+            .collect();
+        let is_included = !self.dependents_of(&code.url).is_empty();
+
+        let mut diags = vec![];
+        let root = code.tree.root_node();
+        for node in root.children(&mut root.walk()) {
+            if !node.node_kind().is_include_statement() {
+                continue;
+            }
+            let is_use = node.child(0).is_some_and(|keyword| keyword.kind() == "use");
+            if !is_use && is_included {
+                continue;
+            }
+            let Some(target) = code.get_include_url(&node) else {
+                continue;
+            };
+            let files = self.included_codes(vec![target.clone()], &code.url);
+            if files.is_empty() {
+                continue;
+            }
+            let draws = |file: &ParsedCode| {
+                let root = file.tree.root_node();
+                let mut cursor = root.walk();
+                let draws = root.named_children(&mut cursor).any(|node| {
+                    !node.node_kind().is_comment()
+                        && !node.node_kind().is_include_statement()
+                        && !matches!(
+                            node.node_kind(),
+                            NodeKind::Assignment
+                                | NodeKind::ModuleDeclaration
+                                | NodeKind::FunctionDeclaration
+                        )
+                });
+                draws
+            };
+            let used = files.iter().any(|file| {
+                let file = file.borrow();
+                (!is_use && draws(&file))
+                    || file.root_items.iter().flatten().any(|item| {
+                        let item = item.borrow();
+                        let visible = !is_use || !matches!(item.kind, ItemKind::Variable);
+                        visible && referenced.contains(&*item.name)
+                    })
+            });
+            if used {
+                continue;
+            }
+
+            let path_node = node.child(1).unwrap_or(node);
+            diags.push(Diagnostic {
+                range: node.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::HINT),
+                code: Some(NumberOrString::String(UNUSED_INCLUDE.to_owned())),
+                message: format!("nothing from {} is used", node_text(&code.code, &path_node)),
+                related_information: Some(vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: target,
+                        range: Range::default(),
+                    },
+                    message: "the included file".to_owned(),
+                }]),
+                tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                ..Default::default()
+            });
+        }
+        diags
+    }
+}
+
+// Whether the special variable node is set, by an assignment, a parameter or a named argument.
+fn is_special_variable_assignment(node: &Node) -> bool {
+    node.parent()
+        .is_some_and(|parent| match parent.node_kind() {
+            NodeKind::Assignment => parent.child_by_field_name("left") == Some(*node),
+            NodeKind::ParametersDeclaration => true,
+            _ => false,
+        })
+}
+
+impl ParsedCode {
+    pub(crate) fn assigned_special_variables(&self) -> HashSet<String> {
+        traverse(self.tree.walk(), Order::Pre)
+            .filter(|node| {
+                node.node_kind() == NodeKind::SpecialVariable
+                    && is_special_variable_assignment(node)
+            })
+            .map(|node| node_text(&self.code, &node).to_owned())
+            .collect()
+    }
+
+    // Local variables of blocks and let() which are never read in their scope.
+    pub(crate) fn unused_variable_diagnostics(&self) -> Vec<Diagnostic> {
+        let mut diags = vec![];
+        for node in traverse(self.tree.walk(), Order::Pre) {
+            if node.node_kind() != NodeKind::Assignment {
+                continue;
+            }
+            let Some(parent) = node.parent() else {
+                continue;
+            };
+            let scope = match parent.node_kind() {
+                NodeKind::UnionBlock => parent,
+                NodeKind::ParenthesizedAssignments => match parent.parent() {
+                    Some(p)
+                        if matches!(
+                            p.node_kind(),
+                            NodeKind::LetBlock | NodeKind::LetExpression
+                        ) =>
+                    {
+                        p
+                    }
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let Some(left) = node.child_by_field_name("left") else {
+                continue;
+            };
+            if left.node_kind() != NodeKind::Identifier {
+                continue;
+            }
+
+            let name = node_text(&self.code, &left);
+            let used = traverse(scope.walk(), Order::Pre).any(|other| {
+                other.node_kind() == NodeKind::Identifier
+                    && other != left
+                    && node_text(&self.code, &other) == name
+                    && is_reference(&other)
+            });
+            if !used {
+                diags.push(Diagnostic {
+                    range: left.lsp_range(&self.code),
+                    severity: Some(DiagnosticSeverity::HINT),
+                    code: Some(NumberOrString::String(UNUSED_VARIABLE.to_owned())),
+                    message: format!("unused variable `{}`", name),
+                    tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                    ..Default::default()
+                });
+            }
+        }
+        diags
+    }
+}
@@ -0,0 +1,89 @@
+use std::{collections::HashMap, fs::read_to_string};
+
+use lsp_types::Url;
+use serde::Deserialize;
+
+// A pre-generated documentation bundle for a library, see README.md for the format.
+#[derive(Deserialize)]
+pub(crate) struct DocBundle {
+    pub library: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub symbols: Vec<BundleSymbol>,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub(crate) struct BundleSymbol {
+    pub name: String,
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub doc: String,
+    #[serde(default)]
+    pub examples: Vec<String>,
+}
+
+#[derive(Default, PartialEq)]
+pub(crate) struct DocBundles {
+    paths: Vec<String>,
+    // symbol name -> (library, symbol)
+    symbols: HashMap<String, Vec<(String, BundleSymbol)>>,
+}
+
+impl DocBundles {
+    pub(crate) fn load(&mut self, path: &str) -> Result<(), String> {
+        if self.paths.iter().any(|p| p == path) {
+            return Ok(());
+        }
+
+        let text = read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let bundle: DocBundle =
+            serde_json::from_str(&text).map_err(|err| format!("{}: {}", path, err))?;
+
+        log_to_console!(
+            "doc bundle: {} {} ({} symbols)",
+            bundle.library,
+            bundle.version.as_deref().unwrap_or(""),
+            bundle.symbols.len()
+        );
+
+        for sym in bundle.symbols {
+            self.symbols
+                .entry(sym.name.clone())
+                .or_default()
+                .push((bundle.library.clone(), sym));
+        }
+        self.paths.push(path.to_owned());
+        Ok(())
+    }
+
+    // Find the documentation of a symbol defined in the file `url`. The file must live under a
+    // directory named after the library, and if the bundle entry names a file, under that file.
+    pub(crate) fn lookup(&self, name: &str, url: &Url) -> Option<&BundleSymbol> {
+        let path = url.path();
+        self.symbols.get(name)?.iter().find_map(|(library, sym)| {
+            let lib_dir = format!("/{}/", library);
+            let lib_pos = path.find(&lib_dir)?;
+            match &sym.file {
+                Some(file) => {
+                    let rel = &path[lib_pos + lib_dir.len()..];
+                    (rel == file.trim_start_matches('/')).then_some(sym)
+                }
+                None => Some(sym),
+            }
+        })
+    }
+}
+
+impl BundleSymbol {
+    pub(crate) fn to_markdown(&self) -> String {
+        let mut doc = self.doc.trim_end().to_owned();
+        for example in &self.examples {
+            doc.push_str("\n\n```scad\n");
+            doc.push_str(example.trim_end());
+            doc.push_str("\n```");
+        }
+        doc
+    }
+}
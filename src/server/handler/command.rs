@@ -0,0 +1,360 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use lsp_types::{
+    ApplyWorkspaceEditParams, DiagnosticSeverity, ExecuteCommandParams, MessageType, Position,
+    PublishDiagnosticsParams, Range, ShowMessageParams, TextEdit, Url, WorkspaceEdit,
+};
+use serde_json::Value;
+
+use crate::{
+    customizer::{self, DEFAULT_GROUP_NAME},
+    handler::error::{HandlerError, HandlerResult},
+    refactor::Refactoring,
+    server::Server,
+    utils::*,
+};
+
+pub(crate) const RUN_CHECKS: &str = "openscad.runChecks";
+pub(crate) const OPEN_IN_APP: &str = "openscad.openInApp";
+pub(crate) const RELOAD_BUILTINS: &str = "openscad.reloadBuiltins";
+pub(crate) const INSERT_CUSTOMIZER_GROUP: &str = "openscad.insertCustomizerGroup";
+pub(crate) const FORMAT_WORKSPACE: &str = "openscad.formatWorkspace";
+pub(crate) const ADD_PARAMETER: &str = "openscad.addParameter";
+pub(crate) const REORDER_PARAMETERS: &str = "openscad.reorderParameters";
+
+// The number of files formatted by each workspace/applyEdit of openscad.formatWorkspace.
+const FORMAT_BATCH_SIZE: usize = 20;
+
+// The commands and the version of their arguments and results, increased when they change
+// incompatibly.
+pub(crate) const COMMANDS: &[(&str, u32)] = &[
+    (RUN_CHECKS, 1),
+    (OPEN_IN_APP, 1),
+    (RELOAD_BUILTINS, 1),
+    (INSERT_CUSTOMIZER_GROUP, 1),
+    (FORMAT_WORKSPACE, 1),
+    (ADD_PARAMETER, 1),
+    (REORDER_PARAMETERS, 1),
+];
+
+// workspace/executeCommand handlers.
+impl Server {
+    pub(crate) fn handle_execute_command(
+        &mut self,
+        params: ExecuteCommandParams,
+    ) -> HandlerResult<Value> {
+        match params.command.as_str() {
+            RUN_CHECKS => self.run_checks_command(&params.arguments),
+            OPEN_IN_APP => self.open_in_app_command(&params.arguments),
+            RELOAD_BUILTINS => self
+                .reload_builtins()
+                .map(|_| Value::Null)
+                .map_err(HandlerError::request_failed),
+            INSERT_CUSTOMIZER_GROUP => self.insert_customizer_group_command(&params.arguments),
+            FORMAT_WORKSPACE => self.format_workspace_command(),
+            ADD_PARAMETER => self.add_parameter_command(&params.arguments),
+            REORDER_PARAMETERS => self.reorder_parameters_command(&params.arguments),
+            _ => Err(HandlerError::invalid_params(format!(
+                "unknown command: {}",
+                params.command
+            ))),
+        }
+    }
+
+    pub(crate) fn show_message(&self, typ: MessageType, message: String) {
+        self.notify(lsp_server::Notification::new(
+            "window/showMessage".into(),
+            ShowMessageParams { typ, message },
+        ));
+    }
+
+    fn run_checks_command(&mut self, args: &[Value]) -> HandlerResult<Value> {
+        let uri = command_uri_arg(args)?;
+        let path = command_path_arg(args)?;
+
+        let file = self.request_code(&uri)?;
+
+        let progress = self.begin_progress("Running OpenSCAD checks");
+        let checks = self.run_openscad_checks(&path, &file.borrow());
+        self.end_progress(progress, None);
+        let checks = checks.map_err(HandlerError::request_failed)?;
+
+        let failures = checks
+            .iter()
+            .filter(|diag| diag.severity == Some(DiagnosticSeverity::ERROR))
+            .count();
+        let echoes = checks
+            .iter()
+            .filter(|diag| diag.severity == Some(DiagnosticSeverity::INFORMATION))
+            .count();
+
+        let mut diags = self.diagnose(&file);
+        diags.extend(checks);
+
+        self.notify(lsp_server::Notification::new(
+            "textDocument/publishDiagnostics".into(),
+            PublishDiagnosticsParams {
+                uri,
+                diagnostics: diags,
+                version: None,
+            },
+        ));
+
+        self.show_message(
+            if failures > 0 {
+                MessageType::ERROR
+            } else {
+                MessageType::INFO
+            },
+            format!("checks finished: {} failed, {} echo", failures, echoes),
+        );
+
+        Ok(Value::Null)
+    }
+
+    // Arguments: the document uri, the range of the assignments and the name of the tab.
+    fn insert_customizer_group_command(&mut self, args: &[Value]) -> HandlerResult<Value> {
+        let uri = command_uri_arg(args)?;
+        let range: Range = args
+            .get(1)
+            .and_then(|arg| serde_json::from_value(arg.clone()).ok())
+            .ok_or_else(|| HandlerError::invalid_params("the second argument should be a range"))?;
+        let name = args
+            .get(2)
+            .and_then(|arg| arg.as_str())
+            .unwrap_or(DEFAULT_GROUP_NAME);
+
+        let file = self.request_code(&uri)?;
+        let edits = customizer::group_edits(&file.borrow(), range, name).ok_or_else(|| {
+            HandlerError::request_failed("no top level assignment to group before the modules")
+        })?;
+
+        self.send_request(
+            "workspace/applyEdit",
+            ApplyWorkspaceEditParams {
+                label: Some("Insert customizer group".to_owned()),
+                edit: WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri, edits)])),
+                    ..Default::default()
+                },
+            },
+        );
+        Ok(Value::Null)
+    }
+
+    // Arguments: the document uri, the position of a module or function, its declaration or a
+    // call, the name and the default value of the new parameter, and whether the calls get it as
+    // a named argument.
+    fn add_parameter_command(&mut self, args: &[Value]) -> HandlerResult<Value> {
+        let uri = command_uri_arg(args)?;
+        let position: Position = args
+            .get(1)
+            .and_then(|arg| serde_json::from_value(arg.clone()).ok())
+            .ok_or_else(|| {
+                HandlerError::invalid_params("the second argument should be a position")
+            })?;
+        let name = args.get(2).and_then(Value::as_str).ok_or_else(|| {
+            HandlerError::invalid_params("the third argument should be the parameter name")
+        })?;
+        let default = args.get(3).and_then(Value::as_str).ok_or_else(|| {
+            HandlerError::invalid_params("the fourth argument should be the default value")
+        })?;
+        let update_calls = args.get(4).and_then(Value::as_bool).unwrap_or(false);
+
+        let refactoring = self.add_parameter_edits(&uri, position, name, default, update_calls)?;
+        self.apply_refactoring(
+            refactoring,
+            "with syntax errors, or where the default refers to other declarations, weren't updated",
+        );
+        Ok(Value::Null)
+    }
+
+    // Arguments: the document uri, the position of a module or function, its declaration or a
+    // call, and its parameters in their new order, `"name"` or `"name = default"`.
+    fn reorder_parameters_command(&mut self, args: &[Value]) -> HandlerResult<Value> {
+        let uri = command_uri_arg(args)?;
+        let position: Position = args
+            .get(1)
+            .and_then(|arg| serde_json::from_value(arg.clone()).ok())
+            .ok_or_else(|| {
+                HandlerError::invalid_params("the second argument should be a position")
+            })?;
+        let params: Vec<String> = args
+            .get(2)
+            .and_then(|arg| serde_json::from_value(arg.clone()).ok())
+            .ok_or_else(|| {
+                HandlerError::invalid_params("the third argument should be the parameters")
+            })?;
+
+        let refactoring = self.reorder_parameters_edits(&uri, position, &params)?;
+        self.apply_refactoring(
+            refactoring,
+            "with syntax errors, comments, too many arguments, or where a moved default refers to \
+             other declarations, weren't rewritten",
+        );
+        Ok(Value::Null)
+    }
+
+    // Apply the edits of a refactoring, and list the calls it skipped, `reason` tells why.
+    fn apply_refactoring(&mut self, refactoring: Refactoring, reason: &str) {
+        let Refactoring {
+            label,
+            changes,
+            skipped,
+        } = refactoring;
+        self.send_request(
+            "workspace/applyEdit",
+            ApplyWorkspaceEditParams {
+                edit: self.workspace_edit(changes, &label),
+                label: Some(label),
+            },
+        );
+        if skipped.is_empty() {
+            return;
+        }
+        let locations: Vec<String> = skipped
+            .iter()
+            .map(|location| {
+                let name = location
+                    .uri
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .unwrap_or(location.uri.as_str());
+                format!("{}:{}", name, location.range.start.line + 1)
+            })
+            .collect();
+        self.show_message(
+            MessageType::WARNING,
+            format!(
+                "{} calls {}: {}",
+                skipped.len(),
+                reason,
+                locations.join(", ")
+            ),
+        );
+    }
+
+    // Format the .scad files of the workspace folders, except the format_exclude patterns. The
+    // edits are applied by batches of files.
+    fn format_workspace_command(&mut self) -> HandlerResult<Value> {
+        let mut urls = vec![];
+        for root in &self.workspace_roots {
+            let Ok(dir) = root.to_file_path() else {
+                continue;
+            };
+            for path in find_scad_files(&dir) {
+                let relative = path.strip_prefix(&dir).unwrap_or(&path);
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                if self
+                    .format_exclude
+                    .iter()
+                    .any(|pattern| glob_match(pattern, &relative))
+                {
+                    continue;
+                }
+                urls.extend(Url::from_file_path(&path).ok());
+            }
+        }
+        if urls.is_empty() {
+            return Err(HandlerError::request_failed(
+                "no .scad file to format in the workspace folders",
+            ));
+        }
+
+        let progress = self.begin_progress("Formatting the workspace");
+        let (mut formatted, mut failed) = (0, vec![]);
+        let mut batch = HashMap::new();
+        for (i, url) in urls.iter().enumerate() {
+            let result = match self.get_code(url) {
+                Some(file) if self.is_large_file(&file.borrow(), false) => {
+                    Err("larger than large_file_size".to_owned())
+                }
+                Some(file) => {
+                    let file = file.borrow();
+                    self.format_text(url, &file).map(|code| {
+                        (!code.is_empty() && code != file.code).then(|| TextEdit {
+                            range: file.tree.root_node().lsp_range(&file.code),
+                            new_text: code,
+                        })
+                    })
+                }
+                None => Err("failed to read".to_owned()),
+            };
+            match result {
+                Ok(Some(edit)) => {
+                    batch.insert(url.clone(), vec![edit]);
+                }
+                Ok(None) => {}
+                Err(err) => failed.push(format!("{}: {}", url.path(), err)),
+            }
+
+            if batch.len() >= FORMAT_BATCH_SIZE || (i + 1 == urls.len() && !batch.is_empty()) {
+                formatted += batch.len();
+                self.send_request(
+                    "workspace/applyEdit",
+                    ApplyWorkspaceEditParams {
+                        label: Some("Format the workspace".to_owned()),
+                        edit: WorkspaceEdit {
+                            changes: Some(std::mem::take(&mut batch)),
+                            ..Default::default()
+                        },
+                    },
+                );
+            }
+            self.report_progress(progress.as_ref(), i + 1, urls.len());
+        }
+        self.end_progress(progress, None);
+
+        let mut summary = format!(
+            "formatted {} of {} files, {} unchanged",
+            formatted,
+            urls.len(),
+            urls.len() - formatted - failed.len()
+        );
+        if !failed.is_empty() {
+            summary = format!(
+                "{}, {} failed: {}",
+                summary,
+                failed.len(),
+                failed.join(", ")
+            );
+        }
+        self.show_message(
+            if failed.is_empty() {
+                MessageType::INFO
+            } else {
+                MessageType::WARNING
+            },
+            summary,
+        );
+        Ok(Value::Null)
+    }
+
+    fn open_in_app_command(&mut self, args: &[Value]) -> HandlerResult<Value> {
+        let path = command_path_arg(args)?;
+        if !path.exists() {
+            return Err(HandlerError::invalid_params(format!(
+                "{} does not exist",
+                path.display()
+            )));
+        }
+
+        let launched = self
+            .open_in_app(&path)
+            .map_err(HandlerError::request_failed)?;
+        Ok(Value::Bool(launched))
+    }
+}
+
+fn command_path_arg(args: &[Value]) -> HandlerResult<PathBuf> {
+    let uri = command_uri_arg(args)?;
+    uri.to_file_path()
+        .map_err(|_| HandlerError::invalid_params(format!("not a local file: {}", uri)))
+}
+
+fn command_uri_arg(args: &[Value]) -> HandlerResult<Url> {
+    args.first()
+        .and_then(|arg| arg.as_str())
+        .and_then(|uri| Url::parse(uri).ok())
+        .ok_or_else(|| HandlerError::invalid_params("the first argument should be a document uri"))
+}
@@ -0,0 +1,52 @@
+use lsp_server::{ErrorCode, ResponseError};
+use serde_json::Value;
+
+// The failure of a request handler, answered as a JSON-RPC error.
+#[derive(Debug)]
+pub(crate) struct HandlerError {
+    code: ErrorCode,
+    message: String,
+    data: Option<Value>,
+}
+
+pub(crate) type HandlerResult<T> = Result<T, HandlerError>;
+
+impl HandlerError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    // The params are malformed, or don't designate something the request applies to.
+    pub(crate) fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidParams, message)
+    }
+
+    // The request is valid but couldn't be completed, the message tells the user why.
+    pub(crate) fn request_failed(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::RequestFailed, message)
+    }
+
+    // The document changed since the request was made, the client may ask again.
+    pub(crate) fn content_modified(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::ContentModified, message)
+    }
+
+    pub(crate) fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+impl From<HandlerError> for ResponseError {
+    fn from(err: HandlerError) -> Self {
+        ResponseError {
+            code: err.code as i32,
+            message: err.message,
+            data: err.data,
+        }
+    }
+}
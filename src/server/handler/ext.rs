@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use lsp_types::{
+    notification::Notification,
+    request::{PrepareRenameRequest, References, Rename, Request},
+    DocumentSymbol, Location, PrepareRenameResponse, Range, ReferenceParams, RenameParams,
+    TextDocumentIdentifier, TextDocumentPositionParams, Url, WorkspaceEdit,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::handler::command::COMMANDS;
+
+// Requests which are not part of the LSP specification.
+
+pub(crate) enum StatusRequest {}
+
+impl Request for StatusRequest {
+    type Params = ();
+    type Result = Status;
+    const METHOD: &'static str = "openscad/status";
+}
+
+// The text of the builtin file, for the clients to show the one embedded in the binary, which
+// doesn't exist on disk.
+pub(crate) enum BuiltinContentsRequest {}
+
+impl Request for BuiltinContentsRequest {
+    type Params = TextDocumentIdentifier;
+    type Result = String;
+    const METHOD: &'static str = "openscad/builtinContents";
+}
+
+// The rename requests, with a `force` flag to rename a symbol of a library outside the
+// workspace, which is an error otherwise.
+pub(crate) enum ForceablePrepareRename {}
+
+impl Request for ForceablePrepareRename {
+    type Params = ForceableParams<TextDocumentPositionParams>;
+    type Result = Option<PrepareRenameResponse>;
+    const METHOD: &'static str = PrepareRenameRequest::METHOD;
+}
+
+pub(crate) enum ForceableRename {}
+
+impl Request for ForceableRename {
+    type Params = ForceableParams<RenameParams>;
+    type Result = Option<WorkspaceEdit>;
+    const METHOD: &'static str = Rename::METHOD;
+}
+
+// The modules, functions and blocks containing a position, from the outermost, for the
+// breadcrumbs of the editors.
+pub(crate) enum EnclosingSymbolRequest {}
+
+impl Request for EnclosingSymbolRequest {
+    type Params = TextDocumentPositionParams;
+    type Result = Vec<DocumentSymbol>;
+    const METHOD: &'static str = "openscad/enclosingSymbol";
+}
+
+// The definition and reference counts of the symbol at a position, from the indexed files, for
+// statuslines, without resolving each reference like the references request.
+pub(crate) enum SymbolInfoRequest {}
+
+impl Request for SymbolInfoRequest {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<SymbolInfo>;
+    const METHOD: &'static str = "openscad/symbolInfo";
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SymbolInfo {
+    pub name: String,
+    // "module", "function" or "variable".
+    pub kind: String,
+    // The top level declarations of the name.
+    pub definitions: usize,
+    // The uses of the name, in module calls for a module and function calls for a function.
+    pub references: usize,
+    // The files declaring the name at the top level.
+    pub files: Vec<Url>,
+}
+
+// The value of an OpenSCAD expression, evaluated by the OpenSCAD executable with the functions
+// of the document if any, for REPL panels.
+pub(crate) enum EvalRequest {}
+
+impl Request for EvalRequest {
+    type Params = EvalParams;
+    type Result = String;
+    const METHOD: &'static str = "openscad/eval";
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EvalParams {
+    pub expression: String,
+    // Its saved file, with `use`.
+    pub text_document: Option<TextDocumentIdentifier>,
+}
+
+// Set the top level variables of a document to values, like `openscad -D`, for the constant
+// evaluation and the OpenSCAD runs. Replaces the previous overrides, none removes them.
+pub(crate) enum SetOverridesRequest {}
+
+impl Request for SetOverridesRequest {
+    type Params = SetOverridesParams;
+    type Result = ();
+    const METHOD: &'static str = "openscad/setOverrides";
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SetOverridesParams {
+    pub text_document: TextDocumentIdentifier,
+    // The values by variable name, in JSON: null for undef, arrays for vectors.
+    #[serde(default)]
+    pub overrides: BTreeMap<String, Value>,
+}
+
+// The ranges of a document which are never evaluated with the constant values and the overrides,
+// for the editors to dim the inactive variants, like the inactive regions of clangd. Sent with the
+// diagnostics to the clients with the `inactiveRegions` experimental capability.
+pub(crate) enum InactiveRegionsNotification {}
+
+impl Notification for InactiveRegionsNotification {
+    type Params = InactiveRegionsParams;
+    const METHOD: &'static str = "openscad/inactiveRegions";
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct InactiveRegionsParams {
+    pub text_document: TextDocumentIdentifier,
+    pub regions: Vec<Range>,
+}
+
+// One level of the tree of the library locations, their directories, their .scad files and the
+// top level symbols of these files, for the library explorers. The children of `parent` are
+// expanded lazily, paginated with `offset` and `limit` for the large libraries.
+pub(crate) enum LibraryTreeRequest {}
+
+impl Request for LibraryTreeRequest {
+    type Params = LibraryTreeParams;
+    type Result = LibraryTree;
+    const METHOD: &'static str = "openscad/libraryTree";
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LibraryTreeParams {
+    // A library location, one of its directories or one of its files, the library locations when
+    // none.
+    pub parent: Option<Url>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LibraryTree {
+    pub children: Vec<LibraryNode>,
+    // The children of `parent` before the pagination.
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LibraryNode {
+    pub name: String,
+    // "library", "directory", "file", "module", "function" or "variable".
+    pub kind: String,
+    // The parent of the children, the file of a symbol.
+    pub uri: Url,
+    // The declaration of a symbol.
+    pub range: Option<Range>,
+    // The signature of a module or function, `cuboid(size, anchor=CENTER)`.
+    pub detail: Option<String>,
+    // The doc comment of a symbol.
+    pub documentation: Option<String>,
+    pub has_children: bool,
+}
+
+// The examples of the doc comments of the library symbols, `@example` blocks and BOSL2 style
+// `Example:` tags, whose symbol or title matches `query`, for the "insert example" palettes.
+pub(crate) enum LibraryExamplesRequest {}
+
+impl Request for LibraryExamplesRequest {
+    type Params = LibraryExamplesParams;
+    type Result = Vec<LibraryExample>;
+    const METHOD: &'static str = "openscad/libraryExamples";
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LibraryExamplesParams {
+    // Matched like the workspace symbols query, all the examples when empty.
+    #[serde(default)]
+    pub query: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LibraryExample {
+    pub symbol: String,
+    // The text after the tag, `Rounded` for `Example(3D): Rounded`.
+    pub title: Option<String>,
+    pub code: String,
+    // The declaration of the symbol.
+    pub location: Location,
+}
+
+// The references request, with a `scope` limiting the references to the current file, or to the
+// files it includes.
+pub(crate) enum ScopedReferences {}
+
+impl Request for ScopedReferences {
+    type Params = ScopedReferenceParams;
+    type Result = Option<Vec<Location>>;
+    const METHOD: &'static str = References::METHOD;
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ScopedReferenceParams {
+    #[serde(flatten)]
+    pub params: ReferenceParams,
+    #[serde(default)]
+    pub scope: ReferenceScope,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ReferenceScope {
+    File,
+    Includes,
+    #[default]
+    Workspace,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ForceableParams<T> {
+    #[serde(flatten)]
+    pub params: T,
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Status {
+    pub version: String,
+    // The path of the external builtin file, or "/builtin" for the one embedded in the binary.
+    pub builtin: String,
+    pub library_paths: Vec<String>,
+    pub indexed_files: usize,
+    pub open_files: usize,
+    pub symbols: usize,
+    // The source code and the symbols of the cached files, without the syntax trees.
+    pub memory_bytes: usize,
+    // The distinct symbol names, shared by the items.
+    pub interned_names: usize,
+    // The parsers of the pool shared by the files, and those not parsing.
+    pub parsers: usize,
+    pub idle_parsers: usize,
+    pub pending_messages: usize,
+    // The handled requests by method.
+    pub requests: BTreeMap<String, RequestMetrics>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RequestMetrics {
+    pub count: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+// The custom requests, and the params added to the standard ones, with the version of their
+// params and results, increased when they change incompatibly.
+const EXTENSIONS: &[(&str, u32)] = &[
+    (StatusRequest::METHOD, 1),
+    (BuiltinContentsRequest::METHOD, 1),
+    (EnclosingSymbolRequest::METHOD, 1),
+    (SymbolInfoRequest::METHOD, 1),
+    (EvalRequest::METHOD, 1),
+    (SetOverridesRequest::METHOD, 1),
+    (InactiveRegionsNotification::METHOD, 1),
+    (LibraryTreeRequest::METHOD, 1),
+    (LibraryExamplesRequest::METHOD, 1),
+    ("textDocument/prepareRename.force", 1),
+    ("textDocument/rename.force", 1),
+    ("textDocument/references.scope", 1),
+];
+
+// The `experimental` server capabilities, for the clients to detect the extensions and the
+// commands rather than guessing from the version of the server.
+pub(crate) fn experimental_capabilities() -> Value {
+    let versions = |entries: &[(&str, u32)]| -> BTreeMap<String, u32> {
+        entries
+            .iter()
+            .map(|&(name, version)| (name.to_owned(), version))
+            .collect()
+    };
+    json!({
+        "openscad": {
+            "version": env!("CARGO_PKG_VERSION"),
+            "requests": versions(EXTENSIONS),
+            "commands": versions(COMMANDS),
+        }
+    })
+}
@@ -1,37 +1,151 @@
-use std::error::Error;
+use std::{error::Error, time::Instant};
 
-use lsp_server::{ExtractError, Message, Response};
+use lsp_server::{ErrorCode, ExtractError, Message, RequestId, Response};
 use lsp_types::{
     notification::{
-        DidChangeConfiguration, DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
-        DidSaveTextDocument,
+        DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles, DidCloseTextDocument,
+        DidOpenTextDocument, DidSaveTextDocument, Exit, Notification, SetTrace,
+        WillSaveTextDocument,
     },
     request::{
-        Completion, DocumentSymbolRequest, Formatting, GotoDefinition, HoverRequest,
-        PrepareRenameRequest, Rename,
+        CodeActionRequest, CodeLensRequest, Completion, DocumentSymbolRequest, ExecuteCommand,
+        FoldingRangeRequest, Formatting, GotoDefinition, GotoImplementation, HoverRequest,
+        LinkedEditingRange, OnTypeFormatting, Request, ResolveCompletionItem, Shutdown,
+        SignatureHelpRequest, WillRenameFiles, WillSaveWaitUntil, WorkspaceSymbolRequest,
     },
+    LogMessageParams, LogTraceParams, MessageType, TraceValue, Url,
 };
-use serde_json::json;
+use serde::Serialize;
+use serde_json::{json, Value};
 
 use crate::{utils::*, Server};
 
+use self::error::{HandlerError, HandlerResult};
+use self::ext::{
+    BuiltinContentsRequest, EnclosingSymbolRequest, EvalRequest, ForceablePrepareRename,
+    ForceableRename, LibraryExamplesRequest, LibraryTreeRequest, ScopedReferences,
+    SetOverridesRequest, StatusRequest, SymbolInfoRequest,
+};
 use super::LoopAction;
 
+pub(crate) mod command;
+pub(crate) mod error;
+pub(crate) mod ext;
 pub(crate) mod notification;
 pub(crate) mod request;
 
+// The requests answered again from the cache when they are identical for the same document
+// version, clients may send them several times while rendering.
+const CACHED_METHODS: &[&str] = &[HoverRequest::METHOD, Completion::METHOD];
+// How long the responses are reused.
+const RESPONSE_CACHE_MS: u128 = 1000;
+const RESPONSE_CACHE_SIZE: usize = 16;
+// The requests taking longer are answered ContentModified when their document changed meanwhile.
+const LONG_REQUEST_MS: u128 = 200;
+
+#[derive(Default)]
+pub(crate) struct ResponseCache {
+    // The key of the responses, their time and the response.
+    entries: Vec<(String, Instant, Response)>,
+    // The id and the key of the request being handled, whose response is to be cached.
+    pending: Option<(RequestId, String)>,
+}
+
+// The response to a request, with the result of its handler or the JSON-RPC error it failed with.
+pub(crate) fn response<T: Serialize>(id: RequestId, result: HandlerResult<T>) -> Response {
+    let result = result.and_then(|value| {
+        serde_json::to_value(value).map_err(|err| HandlerError::request_failed(err.to_string()))
+    });
+    match result {
+        Ok(value) => Response {
+            id,
+            result: Some(value),
+            error: None,
+        },
+        Err(err) => Response {
+            id,
+            result: None,
+            error: Some(err.into()),
+        },
+    }
+}
+
 impl Server {
     pub(crate) fn respond(&self, mut resp: Response) {
         if resp.result.is_none() {
             resp.result = Some(json!(null))
         }
+        self.cache_response(&resp);
         // log_to_console!("{:?}\n\n", &resp);
+        self.trace_response(&resp);
         self.connection
             .sender
             .send(Message::Response(resp))
             .unwrap()
     }
 
+    // Answer a request with the result of its handler, or the JSON-RPC error it failed with.
+    fn respond_result<T: Serialize>(&self, id: RequestId, result: HandlerResult<T>) {
+        self.respond(response(id, result))
+    }
+
+    fn trace_response(&self, resp: &Response) {
+        if self.trace == TraceValue::Off {
+            return;
+        }
+        let Some((method, id, start, _)) = &self.current_request else {
+            return;
+        };
+        if id != &resp.id {
+            return;
+        }
+
+        let message = format!(
+            "Handled request '{} - ({})' in {}ms.",
+            method,
+            id,
+            start.elapsed().as_millis()
+        );
+        let verbose = (self.trace == TraceValue::Verbose).then(|| match &resp.error {
+            Some(err) => format!("Error: {}", err.message),
+            None => format!(
+                "Result: {} bytes",
+                resp.result.as_ref().map_or(0, |res| res.to_string().len())
+            ),
+        });
+        self.notify(lsp_server::Notification::new(
+            "$/logTrace".into(),
+            LogTraceParams { message, verbose },
+        ));
+    }
+
+    // Update the metrics of the method of the handled request, and warn if it was slow.
+    fn record_request(&mut self) {
+        let Some((method, _, start, uri)) = self.current_request.take() else {
+            return;
+        };
+        let elapsed = start.elapsed().as_millis() as u64;
+        let metrics = self.request_metrics.entry(method.clone()).or_default();
+        metrics.count += 1;
+        metrics.total_ms += elapsed;
+        metrics.max_ms = metrics.max_ms.max(elapsed);
+
+        if self.args.slow_request_ms > 0 && elapsed >= self.args.slow_request_ms {
+            let file = uri.map_or(String::new(), |uri| format!(" on {}", uri));
+            self.log_message(
+                MessageType::WARNING,
+                format!("slow request: {}{} took {}ms", method, file, elapsed),
+            );
+        }
+    }
+
+    pub(crate) fn log_message(&self, typ: MessageType, message: String) {
+        self.notify(lsp_server::Notification::new(
+            "window/logMessage".into(),
+            LogMessageParams { typ, message },
+        ));
+    }
+
     pub(crate) fn notify(&self, notif: lsp_server::Notification) {
         self.connection
             .sender
@@ -39,47 +153,240 @@ impl Server {
             .unwrap()
     }
 
+    pub(crate) fn send_request<P: Serialize>(&mut self, method: &str, params: P) {
+        self.next_request_id += 1;
+        self.connection
+            .sender
+            .send(Message::Request(lsp_server::Request::new(
+                RequestId::from(self.next_request_id),
+                method.to_owned(),
+                params,
+            )))
+            .unwrap()
+    }
+
+    // The method, the document version and the params of a request whose response can be reused,
+    // without the progress tokens which are different for each request.
+    fn response_cache_key(&self, req: &lsp_server::Request) -> Option<String> {
+        if !CACHED_METHODS.contains(&req.method.as_str()) {
+            return None;
+        }
+        let mut params = req.params.clone();
+        if let Value::Object(params) = &mut params {
+            params.remove("workDoneToken");
+            params.remove("partialResultToken");
+        }
+        let version = params
+            .pointer("/textDocument/uri")
+            .and_then(|uri| uri.as_str())
+            .and_then(|uri| Url::parse(uri).ok())
+            .and_then(|uri| self.codes.get(&uri))
+            .and_then(|code| code.borrow().version);
+        Some(format!("{} {:?} {}", req.method, version, params))
+    }
+
+    fn cached_response(&self, key: &str) -> Option<Response> {
+        let mut cache = self.response_cache.borrow_mut();
+        cache
+            .entries
+            .retain(|(_, time, _)| time.elapsed().as_millis() < RESPONSE_CACHE_MS);
+        cache
+            .entries
+            .iter()
+            .find(|(entry, _, _)| entry == key)
+            .map(|(_, _, resp)| resp.clone())
+    }
+
+    fn cache_response(&self, resp: &Response) {
+        let mut cache = self.response_cache.borrow_mut();
+        if cache.pending.as_ref().is_none_or(|(id, _)| id != &resp.id) {
+            return;
+        }
+        let (_, key) = cache.pending.take().unwrap();
+        if resp.error.is_some() {
+            return;
+        }
+        if cache.entries.len() >= RESPONSE_CACHE_SIZE {
+            cache.entries.remove(0);
+        }
+        cache.entries.push((key, Instant::now(), resp.clone()));
+    }
+
+    // Forget the cached responses, after the settings changed.
+    pub(crate) fn clear_response_cache(&self) {
+        self.response_cache.borrow_mut().entries.clear();
+    }
+
+    // Whether the request is about a document which is out of sync with the client.
+    fn is_out_of_sync(&self, params: &Value) -> bool {
+        params
+            .pointer("/textDocument/uri")
+            .and_then(|uri| uri.as_str())
+            .and_then(|uri| Url::parse(uri).ok())
+            .and_then(|uri| self.codes.get(&uri))
+            .is_some_and(|code| code.borrow().out_of_sync)
+    }
+
+    // Whether a newer version of the document of a long request was received meanwhile, the
+    // result would be for the previous text. The quick requests are answered for the version
+    // they were sent for, as the clients expect. The messages read are handled next.
+    fn is_modified_during_request(&mut self) -> bool {
+        let Some((_, _, start, Some(uri))) = self.current_request.as_ref() else {
+            return false;
+        };
+        if start.elapsed().as_millis() < LONG_REQUEST_MS {
+            return false;
+        }
+        let uri = uri.clone();
+        let Some(version) = Url::parse(&uri)
+            .ok()
+            .and_then(|url| self.codes.get(&url))
+            .and_then(|code| code.borrow().version)
+        else {
+            return false;
+        };
+        self.queued_messages
+            .extend(self.connection.receiver.try_iter());
+        self.queued_messages.iter().any(|msg| {
+            matches!(msg, Message::Notification(noti)
+                if noti.method == DidChangeTextDocument::METHOD
+                    && noti.params.pointer("/textDocument/uri").and_then(Value::as_str)
+                        == Some(uri.as_str())
+                    && noti.params.pointer("/textDocument/version").and_then(Value::as_i64)
+                        .is_some_and(|changed| changed > i64::from(version)))
+        })
+    }
+
     pub(crate) fn handle_message(
         &mut self,
         msg: Message,
     ) -> Result<LoopAction, Box<dyn Error + Sync + Send>> {
         match msg {
             Message::Request(req) => {
-                if self.connection.handle_shutdown(&req)? {
-                    return Ok(LoopAction::Exit);
+                if req.method == Shutdown::METHOD {
+                    self.shutdown();
+                    self.respond(Response::new_ok(req.id, ()));
+                    return Ok(LoopAction::Continue);
+                }
+                if self.shutdown_requested {
+                    self.respond(Response::new_err(
+                        req.id,
+                        ErrorCode::InvalidRequest as i32,
+                        "the server is shut down".to_owned(),
+                    ));
+                    return Ok(LoopAction::Continue);
+                }
+                if self.is_out_of_sync(&req.params) {
+                    self.respond_result::<()>(
+                        req.id,
+                        Err(HandlerError::content_modified(
+                            "the document is out of sync",
+                        )),
+                    );
+                    return Ok(LoopAction::Continue);
                 }
+                let uri = req
+                    .params
+                    .pointer("/textDocument/uri")
+                    .and_then(|uri| uri.as_str())
+                    .map(str::to_owned);
+                self.current_request =
+                    Some((req.method.clone(), req.id.clone(), Instant::now(), uri));
 
+                if let Some(key) = self.response_cache_key(&req) {
+                    if let Some(mut resp) = self.cached_response(&key) {
+                        resp.id = req.id;
+                        self.respond(resp);
+                        self.record_request();
+                        return Ok(LoopAction::Continue);
+                    }
+                    self.response_cache.borrow_mut().pending = Some((req.id.clone(), key));
+                }
+
+                // The params which can't be read are answered as invalid. The handlers marked
+                // `background` get the id of the request, they answer it from another thread.
                 macro_rules! proc_req {
                     ($request:ident, $req_type:ty, $method:ident) => {
+                        proc_req!($request, $req_type, |id, params| {
+                            let mut result = self.$method(params);
+                            if self.is_modified_during_request() {
+                                result = Err(HandlerError::content_modified(
+                                    "the document changed during the request",
+                                ));
+                            }
+                            self.respond_result(id, result);
+                        })
+                    };
+                    ($request:ident, $req_type:ty, $method:ident, background) => {
+                        proc_req!($request, $req_type, |id, params| self.$method(id, params))
+                    };
+                    ($request:ident, $req_type:ty, |$id:ident, $params:ident| $handle:expr) => {{
+                        let id = $request.id.clone();
                         match cast_request::<$req_type>($request) {
-                            Ok((id, params)) => {
-                                self.$method(id, params);
+                            Ok(($id, $params)) => {
+                                $handle;
+                                self.record_request();
                                 return Ok(LoopAction::Continue);
                             }
                             Err(error) => match error {
                                 ExtractError::MethodMismatch(req) => req,
                                 ExtractError::JsonError { method, error } => {
                                     err_to_console!("method: {} error: {}\n", method, error);
+                                    self.respond_result::<()>(
+                                        id,
+                                        Err(HandlerError::invalid_params(error.to_string())),
+                                    );
+                                    self.record_request();
                                     return Ok(LoopAction::Continue);
                                 }
                             },
                         }
-                    };
+                    }};
                 }
 
                 let req = proc_req!(req, HoverRequest, handle_hover);
                 let req = proc_req!(req, Completion, handle_completion);
+                let req = proc_req!(req, ResolveCompletionItem, handle_completion_resolve);
+                let req = proc_req!(req, SignatureHelpRequest, handle_signature_help);
                 let req = proc_req!(req, GotoDefinition, handle_definition);
+                let req = proc_req!(req, GotoImplementation, handle_implementation);
                 let req = proc_req!(req, DocumentSymbolRequest, handle_document_symbols);
+                let req = proc_req!(req, FoldingRangeRequest, handle_folding_range);
                 let req = proc_req!(req, Formatting, handle_formatting);
-                let req = proc_req!(req, PrepareRenameRequest, handle_prepare_rename);
-                let req = proc_req!(req, Rename, handle_rename);
+                let req = proc_req!(req, WillSaveWaitUntil, handle_will_save_wait_until);
+                let req = proc_req!(req, ForceablePrepareRename, handle_prepare_rename);
+                let req = proc_req!(req, ForceableRename, handle_rename);
+                let req = proc_req!(req, CodeActionRequest, handle_code_action);
+                let req = proc_req!(req, CodeLensRequest, handle_code_lens);
+                let req = proc_req!(req, ExecuteCommand, handle_execute_command);
+                let req = proc_req!(req, OnTypeFormatting, handle_on_type_formatting);
+                let req = proc_req!(req, LinkedEditingRange, handle_linked_editing_range);
+                let req = proc_req!(req, WillRenameFiles, handle_will_rename_files);
+                let req = proc_req!(req, ScopedReferences, handle_references);
+                let req = proc_req!(req, WorkspaceSymbolRequest, handle_workspace_symbols);
+                let req = proc_req!(req, StatusRequest, handle_status);
+                let req = proc_req!(req, EvalRequest, handle_eval, background);
+                let req = proc_req!(req, SetOverridesRequest, handle_set_overrides);
+                let req = proc_req!(req, EnclosingSymbolRequest, handle_enclosing_symbol);
+                let req = proc_req!(req, SymbolInfoRequest, handle_symbol_info);
+                let req = proc_req!(req, BuiltinContentsRequest, handle_builtin_contents);
+                let req = proc_req!(req, LibraryTreeRequest, handle_library_tree);
+                let req = proc_req!(req, LibraryExamplesRequest, handle_library_examples);
                 err_to_console!("unknown request: {:?}", req);
+                self.respond(Response::new_err(
+                    req.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("unknown request: {}", req.method),
+                ));
             }
             Message::Response(resp) => {
                 err_to_console!("got response: {:?}", resp);
             }
             Message::Notification(noti) => {
+                if noti.method == Exit::METHOD {
+                    return Ok(LoopAction::Exit);
+                }
+
                 macro_rules! proc {
                     ($noti:ident, $noti_type:ty, $method:ident) => {
                         match cast_notification::<$noti_type>($noti) {
@@ -100,9 +407,12 @@ impl Server {
 
                 let noti = proc!(noti, DidOpenTextDocument, handle_did_open_text_document);
                 let noti = proc!(noti, DidChangeTextDocument, handle_did_change_text_document);
+                let noti = proc!(noti, WillSaveTextDocument, handle_will_save_text_document);
                 let noti = proc!(noti, DidSaveTextDocument, handle_did_save_text_document);
                 let noti = proc!(noti, DidCloseTextDocument, handle_did_close_text_document);
                 let noti = proc!(noti, DidChangeConfiguration, handle_did_change_config);
+                let noti = proc!(noti, DidChangeWatchedFiles, handle_did_change_watched_files);
+                let noti = proc!(noti, SetTrace, handle_set_trace);
 
                 err_to_console!("unknown notification: {:?}", noti);
             }
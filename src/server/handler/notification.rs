@@ -1,22 +1,34 @@
-use std::env;
+use std::{path::Path, rc::Rc, time::Instant};
 
 use lsp_types::{
-    Diagnostic, DiagnosticSeverity, DidChangeConfigurationParams, DidChangeTextDocumentParams,
-    DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
-    PublishDiagnosticsParams,
+    DiagnosticSeverity, DidChangeConfigurationParams, DidChangeTextDocumentParams,
+    DidChangeWatchedFilesParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, FileChangeType, MessageType, PublishDiagnosticsParams,
+    SetTraceParams, Url, WillSaveTextDocumentParams,
 };
-use serde::Deserialize;
 
-use crate::{server::Server, utils::*};
+use crate::server::{parse_code::Owner, project_config::project_save_config, Server};
 
 // Notification handlers.
 impl Server {
     pub(crate) fn handle_did_open_text_document(&mut self, params: DidOpenTextDocumentParams) {
         let DidOpenTextDocumentParams { text_document: doc } = params;
-        if self.codes.contains_key(&doc.uri) {
-            return;
+        self.open_documents.insert(doc.uri.clone());
+        // The file may have been read from the disk for an include, the editor may have unsaved
+        // changes.
+        let cached = self.codes.get(&doc.uri).cloned();
+        let file = match cached.clone() {
+            Some(file) if !file.borrow().out_of_sync && file.borrow().code == doc.text => file,
+            _ => self.insert_code(doc.uri.clone(), doc.text),
+        };
+        file.borrow_mut().version = Some(doc.version);
+        file.borrow_mut().owner = Owner::Client;
+        file.borrow_mut().unsaved.clear();
+        self.publish_diagnostics(&file, Some(doc.version));
+        // The files including it used the text of the disk.
+        if cached.is_some_and(|cached| !Rc::ptr_eq(&cached, &file)) {
+            self.publish_dependents_diagnostics(&[doc.uri]);
         }
-        self.insert_code(doc.uri, doc.text);
     }
 
     pub(crate) fn handle_did_change_text_document(&mut self, params: DidChangeTextDocumentParams) {
@@ -26,118 +38,227 @@ impl Server {
         } = params;
 
         let pc = match self.codes.get_refresh(&text_document.uri) {
-            Some(x) => x,
+            Some(x) => x.clone(),
             None => {
                 err_to_console!("unknown document {}", text_document.uri);
                 return;
             }
         };
 
-        pc.borrow_mut().edit(&content_changes);
-
-        let mut diags: Vec<_> = error_nodes(pc.borrow().tree.walk())
-            .into_iter()
-            .map(|node| Diagnostic {
-                range: node.lsp_range(),
-                severity: Some(DiagnosticSeverity::ERROR),
-                message: if node.is_missing() {
-                    format!("missing {}", node.kind())
-                } else {
-                    "syntax error".to_owned()
-                },
-                ..Default::default()
-            })
-            .collect();
-
-        if content_changes.len() == 1 {
-            if let Some(range) = content_changes[0].range {
-                let bpc = pc.borrow();
-                let pos = to_point(range.start);
-                let mut cursor = bpc.tree.root_node().walk();
-                cursor.goto_first_child_for_point(pos);
-                let node = cursor.node();
-                let kind = node.kind();
-                // let text = node_text(&bpc.code, &node);
-
-                if kind.is_include_statement() && bpc.get_include_url(&node).is_none() {
-                    let mut range = node.child(1).unwrap().lsp_range();
-                    range.start.character += 1;
-                    range.end.character -= 1;
-                    diags.push(Diagnostic {
-                        range,
-                        severity: Some(DiagnosticSeverity::ERROR),
-                        message: "file not found!".to_owned(),
-                        ..Default::default()
-                    });
-                }
+        let has_full_text = content_changes.iter().any(|change| change.range.is_none());
+        let mut consistent = pc.borrow_mut().edit(&content_changes);
+        if let Some(version) = pc.borrow().version {
+            if text_document.version <= version && !has_full_text {
+                err_to_console!(
+                    "version {} of {} after {}",
+                    text_document.version,
+                    text_document.uri,
+                    version
+                );
+                consistent = false;
             }
         }
 
+        let was_out_of_sync = pc.borrow().out_of_sync;
+        let out_of_sync = !consistent || (was_out_of_sync && !has_full_text);
+        {
+            let mut bpc = pc.borrow_mut();
+            bpc.version = Some(text_document.version);
+            bpc.out_of_sync = out_of_sync;
+        }
+
+        if !out_of_sync {
+            self.publish_diagnostics(&pc, Some(text_document.version));
+            return;
+        }
+
+        // The diagnostics would be wrong, the features are suppressed until the client sends the
+        // full text again.
         self.notify(lsp_server::Notification::new(
             "textDocument/publishDiagnostics".into(),
             PublishDiagnosticsParams {
-                uri: text_document.uri,
-                diagnostics: diags,
+                uri: text_document.uri.clone(),
+                diagnostics: vec![],
                 version: Some(text_document.version),
             },
         ));
+        if !was_out_of_sync {
+            self.show_message(
+                MessageType::WARNING,
+                format!(
+                    "{} is out of sync with the editor, reopen it to continue.",
+                    text_document.uri
+                ),
+            );
+        }
     }
 
     pub(crate) fn handle_did_change_config(&mut self, params: DidChangeConfigurationParams) {
-        #[derive(Deserialize)]
-        pub(crate) struct Openscad {
-            search_paths: Option<String>,
-            fmt_style: Option<String>,
-            fmt_exe: Option<String>,
-            default_param: Option<bool>,
-        }
+        self.apply_settings(params.settings);
+    }
 
-        #[derive(Deserialize)]
-        pub(crate) struct Settings {
-            openscad: Openscad,
+    // Warn before saving a file with syntax errors or missing includes, with the save_checks
+    // setting or the `[save]` table of the project, so that the problems `check` would report
+    // are seen before committing.
+    pub(crate) fn handle_will_save_text_document(&mut self, params: WillSaveTextDocumentParams) {
+        let uri = params.text_document.uri;
+        let Some(file) = self.codes.get(&uri).cloned() else {
+            return;
+        };
+        if file.borrow().out_of_sync || !self.save_checks(&uri) {
+            return;
         }
 
-        let settings = match serde_json::from_value::<Settings>(params.settings) {
-            Ok(settings) => Some(settings),
-            Err(err) => {
-                err_to_console!("{}", err.to_string());
-                return;
+        let (syntax_errors, missing_includes) = {
+            let bfile = file.borrow();
+            let is_error = |diag: &lsp_types::Diagnostic| {
+                diag.severity.unwrap_or(DiagnosticSeverity::ERROR) == DiagnosticSeverity::ERROR
+            };
+            let syntax: Vec<_> = bfile
+                .syntax_diagnostics()
+                .into_iter()
+                .filter(is_error)
+                .collect();
+            let includes: Vec<_> = bfile
+                .include_diagnostics()
+                .into_iter()
+                .filter(is_error)
+                .collect();
+            (syntax, includes)
+        };
+        let Some(first) = syntax_errors
+            .iter()
+            .chain(&missing_includes)
+            .min_by_key(|diag| diag.range.start)
+        else {
+            return;
+        };
+
+        let count = |count: usize, name: &str| match count {
+            0 => None,
+            1 => Some(format!("1 {}", name)),
+            _ => Some(format!("{} {}s", count, name)),
+        };
+        let problems: Vec<String> = [
+            count(syntax_errors.len(), "syntax error"),
+            count(missing_includes.len(), "missing include"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let name = uri
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default();
+        self.show_message(
+            MessageType::WARNING,
+            format!(
+                "{} is saved with {}, the first at line {}: {}",
+                name,
+                problems.join(" and "),
+                first.range.start.line + 1,
+                first.message
+            ),
+        );
+    }
+
+    // Whether the saves are checked: the `[save]` table of the project wins over the setting.
+    fn save_checks(&self, uri: &Url) -> bool {
+        let path = uri.to_file_path().ok();
+        let config = path
+            .as_deref()
+            .and_then(Path::parent)
+            .and_then(project_save_config);
+        match config {
+            Some(Ok(config)) => config.checks.unwrap_or(self.save_checks),
+            Some(Err(err)) => {
+                err_to_console!("{}", err);
+                self.save_checks
             }
+            None => self.save_checks,
+        }
+    }
+
+    pub(crate) fn handle_did_save_text_document(&mut self, params: DidSaveTextDocumentParams) {
+        if let Some(file) = self.codes.get(&params.text_document.uri) {
+            file.borrow_mut().unsaved.clear();
+        }
+        self.publish_dependents_diagnostics(&[params.text_document.uri]);
+    }
+
+    pub(crate) fn handle_did_close_text_document(&mut self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        self.open_documents.remove(&uri);
+        self.overrides.remove(&uri);
+        let Some(file) = self.codes.get(&uri).cloned() else {
+            return;
         };
+        if file.borrow().owner != Owner::Client {
+            return;
+        }
 
-        if let Some(settings) = settings {
-            // self.extend_libs(settings.search_paths);
-            let paths: Vec<String> = settings
-                .openscad
-                .search_paths
-                .map(|paths| {
-                    env::split_paths(&paths)
-                        .filter_map(|buf| buf.into_os_string().into_string().ok())
-                        .collect::<Vec<String>>()
-                })
-                .unwrap_or_default();
-
-            self.extend_libs(paths);
-
-            if let Some(style) = settings.openscad.fmt_style {
-                if !style.trim().is_empty() && self.args.fmt_style != style {
-                    self.args.fmt_style = style;
-                }
+        // The unsaved changes are discarded, the disk has the text again.
+        {
+            let mut bfile = file.borrow_mut();
+            bfile.version = None;
+            bfile.owner = Owner::Disk {
+                modified: None,
+                checked: Instant::now(),
+            };
+        }
+        match self.read_and_cache(uri.clone()) {
+            Ok(read) if Rc::ptr_eq(&read, &file) => {}
+            Ok(_) => self.publish_dependents_diagnostics(&[uri]),
+            Err(_) => {
+                self.codes.remove(&uri);
+                self.publish_dependents_diagnostics(&[uri]);
             }
+        }
+    }
 
-            if let Some(fmt_exe) = settings.openscad.fmt_exe {
-                if !fmt_exe.trim().is_empty() && self.args.fmt_exe != fmt_exe {
-                    self.args.fmt_exe = fmt_exe;
+    pub(crate) fn handle_did_change_watched_files(&mut self, params: DidChangeWatchedFilesParams) {
+        let mut changed = vec![];
+        let mut created_or_deleted = false;
+        for event in params.changes {
+            // The content of the open files is owned by the editor.
+            if self
+                .codes
+                .get(&event.uri)
+                .is_some_and(|file| file.borrow().owner == Owner::Client)
+            {
+                continue;
+            }
+            if event.uri == self.builtin_url {
+                if let Err(err) = self.reload_builtins() {
+                    err_to_console!("{}", err);
+                }
+                continue;
+            }
+            if event.typ == FileChangeType::CHANGED {
+                if self.codes.contains_key(&event.uri) {
+                    let _ = self.read_and_cache(event.uri.clone());
                 }
+            } else {
+                self.codes.remove(&event.uri);
+                created_or_deleted = true;
             }
+            changed.push(event.uri);
+        }
 
-            if let Some(default_param) = settings.openscad.default_param {
-                self.args.ignore_default = !default_param;
+        // The include paths may resolve to other files now.
+        if created_or_deleted {
+            for url in self.open_documents.clone() {
+                if let Some(file) = self.codes.get(&url).cloned() {
+                    file.borrow_mut().changed = true;
+                    self.publish_diagnostics(&file, None);
+                }
             }
+        } else {
+            self.publish_dependents_diagnostics(&changed);
         }
     }
 
-    pub(crate) fn handle_did_save_text_document(&mut self, _params: DidSaveTextDocumentParams) {}
-
-    pub(crate) fn handle_did_close_text_document(&mut self, _params: DidCloseTextDocumentParams) {}
+    pub(crate) fn handle_set_trace(&mut self, params: SetTraceParams) {
+        self.trace = params.value;
+    }
 }
@@ -1,335 +1,571 @@
 use std::{
     cell::{Ref, RefCell},
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{Read, Write},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     rc::Rc,
 };
 
-use lsp_server::{RequestId, Response, ResponseError};
+use lsp_server::{Message, RequestId};
 use lsp_types::{
-    CompletionItem, CompletionItemKind, CompletionList, CompletionParams, CompletionResponse,
-    DocumentFormattingParams, DocumentSymbolParams, DocumentSymbolResponse, Documentation,
-    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
-    InsertTextFormat, InsertTextMode, Location, MarkupContent, Range, RenameParams,
-    SymbolInformation, TextDocumentPositionParams, TextEdit, WorkspaceEdit,
+    request::{GotoImplementationParams, GotoImplementationResponse},
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, CodeLens, CodeLensParams,
+    Command as LspCommand, CompletionItem, CompletionItemKind, CompletionItemLabelDetails,
+    CompletionItemTag, CompletionList, CompletionParams, CompletionResponse,
+    DocumentFormattingParams, DocumentOnTypeFormattingParams, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, Documentation, FoldingRange, FoldingRangeParams, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, InsertTextFormat, InsertTextMode,
+    LinkedEditingRangeParams, LinkedEditingRanges, Location, MarkupContent, NumberOrString,
+    Position, Range, RenameFilesParams, RenameParams, SignatureHelp, SignatureHelpParams,
+    SymbolKind, TextDocumentIdentifier, TextDocumentPositionParams, TextEdit, Url,
+    WillSaveTextDocumentParams, WorkspaceEdit, WorkspaceSymbolParams, WorkspaceSymbolResponse,
 };
 
-use tree_sitter::{Node, Point};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::{json, Value};
+use tree_sitter::Node;
 use tree_sitter_traversal::{traverse, Order};
 
 use crate::{
-    response_item::{Item, ItemKind},
-    server::{parse_code::ParsedCode, Server},
+    assets::{doc_images, AssetInfo},
+    code_helper::call_name_node,
+    customizer::{self, DEFAULT_GROUP_NAME},
+    diagnostics::{is_reference, BUILTIN_CONSTANTS, UNDEFINED_IDENTIFIER},
+    handler::{
+        command::RUN_CHECKS,
+        error::{HandlerError, HandlerResult},
+        ext::{
+            EvalParams, ForceableParams, LibraryExample, LibraryExamplesParams, LibraryNode,
+            LibraryTree, LibraryTreeParams, ReferenceScope, ScopedReferenceParams,
+            SetOverridesParams, Status, SymbolInfo,
+        },
+        response,
+    },
+    openscad_cli::is_older_version,
+    response_item::{escape_snippet, Item, ItemKind},
+    server::{
+        constants,
+        node_kind::{NodeKind, NodeKindExt},
+        parse_code::{parser_counts, ParsedCode},
+        project_config::{project_format_config, PROJECT_CONFIG_FILE},
+        settings::FormatOnSave,
+        workspace::node_namespace,
+        Server,
+    },
+    signature::construct_signature,
     utils::*,
 };
 
-fn get_node_at_point<'a>(parsed_code: &'a Ref<'_, ParsedCode>, point: Point) -> Node<'a> {
-    let mut cursor = parsed_code.tree.root_node().walk();
-    while cursor.goto_first_child_for_point(point).is_some() {}
-    cursor.node()
-}
+// Formatting files of at least this many lines is reported as a progress.
+const LARGE_FORMATTING_LINES: usize = 2000;
 
-// Request handlers.
-impl Server {
-    pub(crate) fn handle_prepare_rename(
-        &mut self,
-        id: RequestId,
-        params: TextDocumentPositionParams,
-    ) {
-        let uri = params.text_document.uri;
+fn get_node_at_position<'a>(parsed_code: &'a Ref<'_, ParsedCode>, pos: Position) -> Node<'a> {
+    node_at_position(&parsed_code.code, &parsed_code.tree.root_node(), pos)
+}
 
-        let file = match self.get_code(&uri) {
-            Some(code) => code,
-            _ => return,
+// The searched locations of an include path, the first existing one is used.
+fn include_search_markdown(code: &ParsedCode, incstat: &Node) -> String {
+    let resolved = code.resolve_include(incstat).map(|(_, url)| url);
+    let mut text = "search order:\n".to_owned();
+    for (i, (_, url)) in code.include_candidates(incstat).iter().enumerate() {
+        let path = url
+            .to_file_path()
+            .map_or_else(|_| url.to_string(), |path| path.display().to_string());
+        let mark = if Some(url) == resolved.as_ref() {
+            " **(used)**"
+        } else {
+            ""
         };
-        file.borrow_mut().gen_top_level_items_if_needed();
-        let bfile = file.borrow();
+        text.push_str(&format!("\n{}. `{}`{}", i + 1, path, mark));
+    }
+    text
+}
 
-        let node = get_node_at_point(&bfile, to_point(params.position));
-        if node.kind() != "identifier" {
-            self.respond(Response {
-                id,
-                result: None,
-                error: None,
+// The parameter declaration named `name` of the innermost module or function containing `node`.
+fn find_param_declaration<'a>(
+    code: &str,
+    node: Node<'a>,
+    name: &str,
+) -> Option<(Node<'a>, Node<'a>)> {
+    let mut parent = node.parent();
+    while let Some(decl) = parent {
+        if matches!(
+            decl.node_kind(),
+            NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration
+        ) {
+            let params = decl.child_by_field_name("parameters")?;
+            let param = params.named_children(&mut params.walk()).find_map(|p| {
+                let ident = match p.node_kind() {
+                    NodeKind::Identifier => p,
+                    NodeKind::Assignment => p.child_by_field_name("left")?,
+                    _ => return None,
+                };
+                (node_text(code, &ident) == name).then_some(ident)
             });
-            return;
+            if let Some(param) = param {
+                return Some((decl, param));
+            }
         }
-        let ident_name = node_text(&bfile.code, &node);
-        let identifier_definition =
-            self.find_identities(&file.borrow(), &|name| name == ident_name, &node, false, 0);
-
-        let definition = if let Some(def) = identifier_definition.first() {
-            def
-        } else {
-            self.respond(Response {
-                id,
-                result: None,
-                error: None,
-            });
-            return;
-        };
+        parent = decl.parent();
+    }
+    None
+}
 
-        let url = if let Some(url) = definition.borrow().url.clone() {
-            url
-        } else {
-            self.respond(Response {
-                id,
-                result: None,
-                error: None,
-            });
-            return;
-        };
+// The identifiers of `scope` named like `definition`, except in the nested scopes assigning a
+// variable of the same name.
+fn scope_references(code: &str, scope: Node, definition: Node) -> Vec<Range> {
+    let name = node_text(code, &definition);
+    let mut node_iter = traverse(scope.walk(), Order::Post);
+    let mut ranges = vec![];
+    while let Some(node) = node_iter.next() {
+        let is_identifier_instance =
+            node.node_kind() != NodeKind::Identifier || node_text(code, &node) != name;
+        if is_identifier_instance {
+            continue;
+        }
 
-        if url != uri {
-            self.respond(Response {
-                id,
-                result: None,
-                error: Some(ResponseError {
-                    code: 0,
-                    message:
-                        "Sorry, but renaming symbols defined in another file is not yet supported"
-                            .to_string(),
-                    data: None,
-                }),
-            });
-            return;
+        let is_assignment = node
+            .parent()
+            .is_some_and(|node| node.node_kind() == NodeKind::Assignment);
+        let is_assignment_in_subscope = is_assignment && node != definition;
+        if is_assignment_in_subscope {
+            // Unwrap is ok because an identifier node whould always have a parent scope.
+            let scope = find_node_scope(node).unwrap();
+            // Consume iterator until it reaches the parent scope
+            while node_iter.next().is_some_and(|next| scope != next) {}
+            continue;
         }
 
-        self.respond(Response {
-            id,
-            result: Some(
-                serde_json::to_value(Range {
-                    start: to_position(node.start_position()),
-                    end: to_position(node.end_position()),
-                })
-                .unwrap(),
-            ),
-            error: None,
-        })
+        ranges.push(node.lsp_range(code));
     }
-    pub(crate) fn handle_rename(&mut self, id: RequestId, params: RenameParams) {
-        let uri = params.text_document_position.text_document.uri;
-        let ident_new_name = params.new_name;
+    ranges
+}
 
-        let file = match self.get_code(&uri) {
-            Some(code) => code,
-            _ => return,
-        };
-        file.borrow_mut().gen_top_level_items_if_needed();
-        let bfile = file.borrow();
+// The range snippet completed after the `[` of a loop variable, `for (i = [`.
+fn range_completion(code: &str, offset: usize) -> Option<CompletionItem> {
+    lazy_static! {
+        static ref LOOP_START_RE: Regex =
+            Regex::new(r"\bfor\s*\(\s*(?:[A-Za-z_$]\w*\s*=[^;]*,\s*)?[A-Za-z_$]\w*\s*=\s*\[\s*$")
+                .unwrap();
+    }
+    let line_start = code[..offset].rfind('\n').map_or(0, |i| i + 1);
+    if !LOOP_START_RE.is_match(&code[line_start..offset]) {
+        return None;
+    }
+    // The editor may have closed the bracket.
+    let closing = if code[offset..].trim_start().starts_with(']') {
+        ""
+    } else {
+        "]"
+    };
+    Some(CompletionItem {
+        label: "[start:step:end]".to_owned(),
+        kind: Some(CompletionItemKind::SNIPPET),
+        detail: Some("range".to_owned()),
+        filter_text: Some(String::new()),
+        sort_text: Some(" ".to_owned()),
+        preselect: Some(true),
+        insert_text: Some(format!("${{1:0}}:${{2:1}}:${{3:10}}{}", closing)),
+        insert_text_format: Some(InsertTextFormat::SNIPPET),
+        ..Default::default()
+    })
+}
 
-        let (ident_initial_name, parent_scope, ident_initial_node) = {
-            let node = get_node_at_point(&bfile, to_point(params.text_document_position.position));
-            if node.kind() != "identifier" {
-                self.respond(Response {
-                    id,
-                    result: None,
-                    error: Some(ResponseError {
-                        code: -32600, // Invalid Request error
-                        message: "No identifier at given position".to_string(),
-                        data: None,
-                    }),
-                });
-                return;
-            }
-            let ident_initial_name = node_text(&bfile.code, &node);
-            let identifier_definition = self.find_identities(
-                &file.borrow(),
-                &|name| name == ident_initial_name,
-                &node,
-                false,
-                0,
-            );
+// The names of the completions, without the snippets, the ranges and the symbols of the libraries
+// which need an include statement.
+fn plain_completions(response: CompletionResponse) -> CompletionResponse {
+    let (CompletionResponse::Array(items) | CompletionResponse::List(CompletionList { items, .. })) =
+        response;
+    let items = items
+        .into_iter()
+        .filter(|item| {
+            item.kind != Some(CompletionItemKind::SNIPPET)
+                && item
+                    .data
+                    .as_ref()
+                    .is_none_or(|data| data.get("include").is_none())
+        })
+        .map(|item| CompletionItem {
+            label: item.label,
+            kind: item.kind,
+            ..Default::default()
+        })
+        .collect();
+    CompletionResponse::List(CompletionList {
+        is_incomplete: true,
+        items,
+    })
+}
 
-            let definition = if let Some(def) = identifier_definition.first() {
-                def
-            } else {
-                self.respond(Response {
-                    id,
-                    result: None,
-                    error: Some(ResponseError {
-                        code: 0,
-                        message: "No definition found for this identifier".to_string(),
-                        data: None,
-                    }),
-                });
-                return;
-            };
+// Request handlers.
+impl Server {
+    // The identifier at `pos` and its definition, or why it can't be renamed. The symbols of the
+    // libraries outside the workspace are only renamed with `force`.
+    fn rename_target(
+        &mut self,
+        file: &Rc<RefCell<ParsedCode>>,
+        pos: Position,
+        force: bool,
+    ) -> HandlerResult<(Range, Rc<RefCell<Item>>)> {
+        file.borrow_mut().gen_top_level_items_if_needed();
+        let bfile = file.borrow();
+        let node = get_node_at_position(&bfile, pos);
+        if node.node_kind() != NodeKind::Identifier {
+            return Err(HandlerError::invalid_params(
+                "No identifier at given position",
+            ));
+        }
+        let name = node_text(&bfile.code, &node);
+        let definition = self
+            .find_identities(&bfile, &|ident| ident == name, &node, false)
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                HandlerError::request_failed("No definition found for this identifier")
+            })?;
+
+        let url = {
+            let item = definition.borrow();
+            item.url
+                .clone()
+                .filter(|url| !item.is_builtin && url != &self.builtin_url)
+        };
+        let Some(url) = url else {
+            return Err(HandlerError::request_failed(format!(
+                "cannot rename builtin `{}`",
+                name
+            )));
+        };
+        if !force && url != bfile.url && self.is_outside_workspace(&url) {
+            // The client can ask again with `force`.
+            return Err(HandlerError::request_failed(format!(
+                "`{}` is defined in a library outside the workspace, rename anyway?",
+                name
+            ))
+            .with_data(json!({ "uri": url, "force": true })));
+        }
+        Ok((node.lsp_range(&bfile.code), definition))
+    }
 
-            let url = if let Some(url) = definition.borrow().url.clone() {
-                url
-            } else {
-                self.respond(Response {
-                    id,
-                    result: None,
-                    error: Some(ResponseError {
-                        code: 0,
-                        message: "Cannot rename builtin".to_string(),
-                        data: None,
-                    }),
-                });
-                return;
-            };
+    // The symbol of the `kind` named `new_name` which the renamed identifiers would
+    // refer to, or shadow, in the edited files.
+    fn rename_conflict(
+        &mut self,
+        changes: &HashMap<Url, Vec<TextEdit>>,
+        kind: &str,
+        new_name: &str,
+    ) -> Option<String> {
+        let message = |location: &str| {
+            format!(
+                "`{}` is already a {} ({}), renaming would change what it refers to",
+                new_name, kind, location
+            )
+        };
+        if kind == "variable" && BUILTIN_CONSTANTS.contains(&new_name) {
+            return Some(message("the builtins"));
+        }
 
-            if url != uri {
-                self.respond(Response {
-                    id,
-                    result: None,
-                    error: Some(ResponseError {
-                        code: 0,
-                        message: "Sorry, but renaming symbols defined in another file is not yet supported".to_string(),
-                        data: None,
-                    }),
-                });
-                return;
+        let mut urls: Vec<&Url> = changes.keys().collect();
+        urls.sort();
+        for url in urls {
+            let file = self.get_code(url)?;
+            let bfile = file.borrow();
+            for edit in &changes[url] {
+                let node = get_node_at_position(&bfile, edit.range.start);
+                let items = self.find_identities(&bfile, &|name| name == new_name, &node, true);
+                let Some(item) = items
+                    .into_iter()
+                    .find(|item| item.borrow().kind.name() == kind)
+                else {
+                    continue;
+                };
+                let item = item.borrow();
+                let location = match &item.url {
+                    Some(url) if !item.is_builtin && url != &self.builtin_url => {
+                        let name = url.to_file_path().ok().and_then(|path| {
+                            Some(path.file_name()?.to_string_lossy().into_owned())
+                        });
+                        format!(
+                            "{}:{}",
+                            name.unwrap_or_else(|| url.to_string()),
+                            item.range.start.line + 1
+                        )
+                    }
+                    _ => "the builtins".to_owned(),
+                };
+                return Some(message(&location));
             }
+        }
+        None
+    }
 
-            let definition_node = get_node_at_point(
-                &bfile,
-                to_point(identifier_definition[0].borrow().range.start),
-            );
+    // The identifiers referring to the symbol `name` declared at `range` of `url`: those of the
+    // scope of its declaration, and for a top level symbol those of the files including its file,
+    // only in the files `only` if given.
+    pub(crate) fn symbol_references(
+        &mut self,
+        url: &Url,
+        name: &str,
+        range: Range,
+        include_declaration: bool,
+        only: Option<&HashSet<Url>>,
+    ) -> Vec<Location> {
+        let mut result = vec![];
+        let mut is_top_level = false;
+        if let Some(def_file) = self.get_code(url) {
+            let bfile = def_file.borrow();
+            // The keyword starting a module or function declaration, or the assigned variable.
+            let node = get_node_at_position(&bfile, range.start);
+            let definition_node = node
+                .parent()
+                .and_then(|decl| decl.child_by_field_name("name"))
+                .filter(|_| node.node_kind() != NodeKind::Identifier)
+                .unwrap_or(node);
             // unwrap here is fine because an identifier node should always have a parent scope
             let parent_scope = find_node_scope(definition_node).unwrap();
+            is_top_level = parent_scope.node_kind() == NodeKind::SourceFile;
+            if only.is_none_or(|only| only.contains(url)) {
+                let declaration = definition_node.lsp_range(&bfile.code);
+                result.extend(
+                    scope_references(&bfile.code, parent_scope, definition_node)
+                        .into_iter()
+                        .filter(|range| include_declaration || *range != declaration)
+                        .map(|range| Location {
+                            uri: url.clone(),
+                            range,
+                        }),
+                );
+            }
+        }
+        // The top level symbols are also used by the files including theirs.
+        if is_top_level {
+            let references = self.dependent_references(url, name, range, only);
+            result.extend(references);
+        }
+        result.sort_by(|a, b| (&a.uri, a.range.start).cmp(&(&b.uri, b.range.start)));
+        result.dedup();
+        result
+    }
 
-            (ident_initial_name, parent_scope, definition_node)
-        };
+    pub(crate) fn handle_prepare_rename(
+        &mut self,
+        params: ForceableParams<TextDocumentPositionParams>,
+    ) -> HandlerResult<Range> {
+        let file = self.request_code(&params.params.text_document.uri)?;
 
-        let mut node_iter = traverse(parent_scope.walk(), Order::Post);
-        let mut changes = vec![];
-        while let Some(node) = node_iter.next() {
-            let is_identifier_instance =
-                node.kind() != "identifier" || node_text(&bfile.code, &node) != ident_initial_name;
-            if is_identifier_instance {
-                continue;
-            }
+        let (range, _) = self.rename_target(&file, params.params.position, params.force)?;
+        Ok(range)
+    }
 
-            let is_assignment = node
-                .parent()
-                .is_some_and(|node| node.kind() == "assignment");
-            let is_assignment_in_subscope = is_assignment && node != ident_initial_node;
-            if is_assignment_in_subscope {
-                // Unwrap is ok because an identifier node whould always have a parent scope.
-                let scope = find_node_scope(node).unwrap();
-                // Consume iterator until it reaches the parent scope
-                while node_iter.next().is_some_and(|next| scope != next) {}
-                continue;
-            }
+    pub(crate) fn handle_rename(
+        &mut self,
+        params: ForceableParams<RenameParams>,
+    ) -> HandlerResult<WorkspaceEdit> {
+        let position = params.params.text_document_position;
+        let ident_new_name = params.params.new_name;
+
+        let file = self.request_code(&position.text_document.uri)?;
+
+        let (_, definition) = self.rename_target(&file, position.position, params.force)?;
+        let (name, range, url) = {
+            let item = definition.borrow();
+            // The url is checked by rename_target.
+            (item.name.clone(), item.range, item.url.clone().unwrap())
+        };
 
-            changes.push(TextEdit {
-                range: Range {
-                    start: to_position(node.start_position()),
-                    end: to_position(node.end_position()),
-                },
-                new_text: ident_new_name.to_string(),
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for location in self.symbol_references(&url, &name, range, true, None) {
+            changes.entry(location.uri).or_default().push(TextEdit {
+                range: location.range,
+                new_text: ident_new_name.clone(),
             });
         }
+        for edits in changes.values_mut() {
+            edits.sort_by_key(|edit| (edit.range.start, edit.range.end));
+            edits.dedup();
+        }
 
-        let result = WorkspaceEdit {
-            changes: Some({
-                let mut h = HashMap::new();
-                h.insert(uri, changes);
-                h
-            }),
-            ..Default::default()
-        };
+        let kind = definition.borrow().kind.name();
+        if let Some(message) = self.rename_conflict(&changes, kind, &ident_new_name) {
+            return Err(HandlerError::request_failed(message));
+        }
 
-        self.respond(Response {
-            id,
-            result: Some(serde_json::to_value(result).unwrap()),
-            error: None,
-        });
+        let label = format!("Rename `{}` to `{}`", name, ident_new_name);
+        Ok(self.workspace_edit(changes, &label))
     }
-    pub(crate) fn handle_hover(&mut self, id: RequestId, params: HoverParams) {
+
+    pub(crate) fn handle_hover(&mut self, params: HoverParams) -> HandlerResult<Option<Hover>> {
         let uri = &params.text_document_position_params.text_document.uri;
         let pos = params.text_document_position_params.position;
 
-        let file = match self.get_code(uri) {
-            Some(code) => code,
-            _ => return,
-        };
+        let file = self.request_code(uri)?;
 
         file.borrow_mut().gen_top_level_items_if_needed();
 
-        let point = to_point(pos);
         let bfile = file.borrow();
-        let mut cursor = bfile.tree.root_node().walk();
-        while cursor.goto_first_child_for_point(point).is_some() {}
-
-        let node = cursor.node();
+        let node = get_node_at_position(&bfile, pos);
 
-        let kind = node.kind();
+        let kind = node.node_kind();
         let name = String::from(node_text(&bfile.code, &node));
 
+        let argument_param = if kind == NodeKind::Identifier {
+            self.find_argument_param(&bfile, &node)
+        } else {
+            None
+        };
+
         let result = match kind {
-            "identifier" => {
-                let items = self.find_identities(
-                    &file.borrow(),
-                    &|item_name| item_name == name,
-                    &node,
-                    false,
-                    0,
-                );
-                items.first().map(|item| Hover {
+            NodeKind::Identifier if argument_param.is_some() => {
+                argument_param.map(|(item, param)| Hover {
                     contents: HoverContents::Markup(MarkupContent {
                         kind: lsp_types::MarkupKind::Markdown,
-                        value: item.borrow_mut().get_hover(),
+                        value: param.make_hover(&item.borrow_mut().get_label()),
                     }),
-                    range: None,
+                    range: Some(node.lsp_range(&bfile.code)),
                 })
             }
-            _ => None,
+            NodeKind::Identifier | NodeKind::SpecialVariable => {
+                let items = self.find_definitions(&file.borrow(), &node, &name);
+                items.first().map(|item| {
+                    let hover = item.borrow_mut().get_hover();
+                    let mut value = doc_images(
+                        hover,
+                        item.borrow().url.as_ref(),
+                        self.doc_image_options(true),
+                    );
+                    let label = match item.borrow().kind {
+                        ItemKind::Variable => "type",
+                        _ => "returns",
+                    };
+                    if let Some(value_type) = self.item_type(item) {
+                        value = format!("{}\n---\n\n{}: `{}`\n", value, label, value_type);
+                    }
+                    if let Some(thumbnail) = self.item_thumbnail(item) {
+                        value = format!("{}\n---\n\n![{}]({})\n", value, name, thumbnail);
+                    }
+                    let call = node.parent().filter(|call| {
+                        call_name_node(call).is_some_and(|name_node| name_node == node)
+                    });
+                    if let Some(preview) = call.and_then(|call| self.format_preview(&bfile, &call))
+                    {
+                        let label = if name == "str" { "result" } else { "prints" };
+                        value = format!("{}\n---\n\n{}: `{}`\n", value, label, preview);
+                    }
+                    Hover {
+                        contents: HoverContents::Markup(MarkupContent {
+                            kind: lsp_types::MarkupKind::Markdown,
+                            value,
+                        }),
+                        range: None,
+                    }
+                })
+            }
+            NodeKind::String => bfile.asset_path_at(&node).map(|path| Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: lsp_types::MarkupKind::Markdown,
+                    value: match AssetInfo::read(&path) {
+                        Some(info) => info.to_markdown(&path),
+                        None => format!("`{}`\n\nfile not found", path.display()),
+                    },
+                }),
+                range: Some(node.lsp_range(&bfile.code)),
+            }),
+            NodeKind::Other if node.kind() == "assert" => node
+                .parent()
+                .and_then(|call| self.format_preview(&bfile, &call))
+                .map(|preview| Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: lsp_types::MarkupKind::Markdown,
+                        value: format!("fails with: `{}`", preview),
+                    }),
+                    range: Some(node.lsp_range(&bfile.code)),
+                }),
+            NodeKind::IncludePath => node.parent().map(|incstat| {
+                let mut value = include_search_markdown(&bfile, &incstat);
+                if let Some((_, target)) = bfile.resolve_include(&incstat) {
+                    for (name, version) in self.library_versions(&target, &bfile.url) {
+                        value += &format!("\n\n`{}`: {}", name, version);
+                    }
+                }
+                Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: lsp_types::MarkupKind::Markdown,
+                        value,
+                    }),
+                    range: Some(node.lsp_range(&bfile.code)),
+                }
+            }),
+            _ => std::iter::successors(Some(node), |node| node.parent())
+                .find(|ancestor| ancestor.node_kind() == NodeKind::Range)
+                .map(|range| Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: lsp_types::MarkupKind::Markdown,
+                        value: self.range_markdown(&bfile, &range),
+                    }),
+                    range: Some(range.lsp_range(&bfile.code)),
+                }),
         };
 
-        let result = result.map(|r| serde_json::to_value(r).unwrap());
-        self.respond(Response {
-            id,
-            result,
-            error: None,
-        });
+        Ok(result)
     }
 
-    pub(crate) fn handle_definition(&mut self, id: RequestId, params: GotoDefinitionParams) {
+    pub(crate) fn handle_definition(
+        &mut self,
+        params: GotoDefinitionParams,
+    ) -> HandlerResult<Option<GotoDefinitionResponse>> {
         let uri = &params.text_document_position_params.text_document.uri;
         let pos = params.text_document_position_params.position;
 
-        let file = match self.get_code(uri) {
-            Some(code) => code,
-            _ => return,
-        };
+        let file = self.request_code(uri)?;
 
         file.borrow_mut().gen_top_level_items_if_needed();
 
-        let point = to_point(pos);
         let bfile = file.borrow();
-        let mut cursor = bfile.tree.root_node().walk();
-        while cursor.goto_first_child_for_point(point).is_some() {}
+        let node = get_node_at_position(&bfile, pos);
 
-        let node = cursor.node();
-
-        let kind = node.kind();
+        let kind = node.node_kind();
         let name = String::from(node_text(&bfile.code, &node));
 
+        let argument_param = if kind == NodeKind::Identifier {
+            self.find_argument_param(&bfile, &node)
+        } else {
+            None
+        };
+
         let result = match kind {
-            "identifier" => {
-                let items = self.find_identities(
-                    &file.borrow(),
-                    &|item_name| item_name == name,
-                    &node,
-                    false,
-                    0,
-                );
+            NodeKind::Identifier if argument_param.is_some() => {
+                argument_param.and_then(|(item, param)| {
+                    let url = item.borrow().url.clone()?;
+                    Some(vec![Location {
+                        uri: url,
+                        range: param.range,
+                    }])
+                })
+            }
+            NodeKind::Identifier | NodeKind::SpecialVariable => {
+                let items = self.find_definitions(&file.borrow(), &node, &name);
                 let locs = items
                     .iter()
-                    .filter(|item| item.borrow().name == name && item.borrow().url.is_some())
-                    .map(|item| Location {
-                        uri: item.borrow().url.as_ref().unwrap().clone(),
-                        range: item.borrow().range,
+                    .map(|item| item.borrow())
+                    .filter(|item| *item.name == name)
+                    .filter_map(|item| {
+                        // The embedded builtin file is read with `openscad/builtinContents`.
+                        let uri = match &item.url {
+                            Some(url) => url.clone(),
+                            None if item.is_builtin => self.builtin_url.clone(),
+                            None => return None,
+                        };
+                        Some(Location {
+                            uri,
+                            range: item.range,
+                        })
                     })
                     .collect::<Vec<Location>>();
                 Some(locs)
             }
-            "include_path" => {
+            NodeKind::IncludePath => {
                 let mut res = None;
                 if let Some(incs) = &(file.borrow().includes) {
                     let include_path = name
@@ -356,251 +592,518 @@ impl Server {
                 };
                 res
             }
+            NodeKind::String => bfile
+                .asset_path_at(&node)
+                .filter(|path| path.exists())
+                .and_then(|path| Url::from_file_path(path).ok())
+                .map(|uri| {
+                    vec![Location {
+                        uri,
+                        range: Range::default(),
+                    }]
+                }),
             _ => None,
         };
 
-        let result = result.map(GotoDefinitionResponse::Array);
-        let result = serde_json::to_value(result).unwrap();
-
-        self.respond(Response {
-            id,
-            result: Some(result),
-            error: None,
-        });
+        Ok(result.map(GotoDefinitionResponse::Array))
     }
 
-    pub(crate) fn handle_completion(&mut self, id: RequestId, params: CompletionParams) {
+    pub(crate) fn handle_completion(
+        &mut self,
+        params: CompletionParams,
+    ) -> HandlerResult<CompletionResponse> {
         let uri = &params.text_document_position.text_document.uri;
         let pos = params.text_document_position.position;
-        let file = match self.get_code(uri) {
-            Some(code) => code,
-            _ => return,
-        };
+        let file = self.request_code(uri)?;
 
         file.borrow_mut().gen_top_level_items_if_needed();
 
-        let mut point = to_point(pos);
+        let bfile = file.borrow();
+        let node = node_before_position(&bfile.code, &bfile.tree.root_node(), pos);
+        let name = node_text(&bfile.code, &node);
 
-        if point.column > 0 {
-            point.column -= 1;
-        } else {
-            point.row -= 1;
+        let mut items = self.find_identities(&file.borrow(), &|_| true, &node, true);
+
+        // The call whose arguments the cursor is in, not after their `)`.
+        let call = std::iter::successors(Some(node), |node| node.parent())
+            .find(|ancestor| ancestor.node_kind() == NodeKind::Arguments)
+            .filter(|arguments| {
+                !(node.kind() == ")" && node.parent() == Some(*arguments) && !node.is_missing())
+            })
+            .and_then(|arguments| arguments.parent())
+            .filter(|call| {
+                matches!(
+                    call.node_kind(),
+                    NodeKind::ModuleCall | NodeKind::FunctionCall
+                )
+            });
+        if let Some(node) = call {
+            call_name_node(&node)
+                .map(|child| node_text(&bfile.code, &child))
+                .map(|name| {
+                    let fun_items = self.find_identities(
+                        &file.borrow(),
+                        &|item_name| item_name == name,
+                        &node,
+                        false,
+                    );
+
+                    if !fun_items.is_empty() {
+                        let item = &fun_items[0];
+
+                        let param_items = item
+                            .borrow()
+                            .kind
+                            .params()
+                            .iter()
+                            .map(|p| Rc::new(RefCell::new(p.to_item(&bfile.url))))
+                            .collect::<Vec<_>>();
+
+                        items.extend(param_items);
+                    }
+                });
         }
 
-        let bfile = file.borrow();
-        let mut cursor = bfile.tree.root_node().walk();
+        items.extend(self.find_special_variables(&bfile, &|_| true));
+        let items = Self::nearest_definitions(items);
 
-        while cursor.goto_first_child_for_point(point).is_some() {}
+        // The path typed in an include statement, which is a syntax error until its `>`.
+        let include_path = if node.node_kind() == NodeKind::IncludePath
+            || node
+                .prev_sibling()
+                .is_some_and(|sib| sib.kind() == "include" || sib.kind() == "use")
+        {
+            Some(
+                name.trim_start_matches(&['<', '\n'][..])
+                    .trim_end_matches(&['>', '\n'][..]),
+            )
+        } else {
+            bfile.unclosed_include(&node).map(|(_, path)| path)
+        };
 
-        let node = cursor.node();
-        let name = node_text(&bfile.code, &node);
+        let result = if let Some(typed) = include_path {
+            let mut items: Vec<CompletionItem> = bfile
+                .get_include_completion(typed)
+                .iter()
+                .map(|file_name| CompletionItem {
+                    label: file_name.clone(),
+                    kind: Some(CompletionItemKind::FILE),
+                    filter_text: Some(typed.to_owned()),
+                    insert_text: Some(file_name.clone()),
+                    insert_text_format: Some(InsertTextFormat::PLAIN_TEXT),
+                    insert_text_mode: Some(InsertTextMode::ADJUST_INDENTATION),
+                    ..Default::default()
+                })
+                .collect();
+            // Without a directory yet, the files deeper in the workspace and the libraries are
+            // offered with their whole path.
+            if !typed.contains('/') {
+                let paths = self.project_include_paths(&bfile.url, typed);
+                items.extend(paths.into_iter().map(|(path, is_library)| CompletionItem {
+                    label: path.clone(),
+                    kind: Some(CompletionItemKind::FILE),
+                    detail: Some(if is_library { "library" } else { "workspace" }.to_owned()),
+                    // After the entries of the directories.
+                    sort_text: Some(format!("~{}", path)),
+                    filter_text: Some(typed.to_owned()),
+                    insert_text: Some(path),
+                    insert_text_format: Some(InsertTextFormat::PLAIN_TEXT),
+                    insert_text_mode: Some(InsertTextMode::ADJUST_INDENTATION),
+                    ..Default::default()
+                }));
+            }
+            CompletionResponse::List(CompletionList {
+                is_incomplete: true,
+                items,
+            })
+        } else {
+            let label_details_support = self.completion_label_details_support();
+            let completion_parens = self.args.completion_parens;
+            let doc_image_options = self.doc_image_options(false);
+            let make_item = |item: &Rc<RefCell<Item>>| {
+                // The label is the name, the signature and the file or the category of the
+                // builtins go to the details.
+                let signature = item.borrow_mut().get_label();
+                let name = item.borrow().name.to_string();
+                let params = signature.strip_prefix(&name).unwrap_or_default().to_owned();
+                let snippet = item.borrow_mut().get_snippet();
+                // Without the parentheses, typing `(` accepts the name. The snippet has them.
+                let (insert_text, insert_text_format, commit_characters) = match item.borrow().kind
+                {
+                    ItemKind::Variable => (
+                        snippet,
+                        InsertTextFormat::PLAIN_TEXT,
+                        Some(vec![",".to_owned()]),
+                    ),
+                    ItemKind::Function { .. } | ItemKind::Module { .. } if !completion_parens => (
+                        name.clone(),
+                        InsertTextFormat::PLAIN_TEXT,
+                        Some(vec!["(".to_owned()]),
+                    ),
+                    _ => (snippet, InsertTextFormat::SNIPPET, None),
+                };
+                let origin = match item.borrow().category() {
+                    Some(category) => Some(category.to_owned()),
+                    None => item.borrow().url.as_ref().and_then(|url| {
+                        let path = url.to_file_path().ok()?;
+                        Some(path.file_name()?.to_string_lossy().into_owned())
+                    }),
+                };
+                let since = item
+                    .borrow()
+                    .meta
+                    .as_ref()
+                    .and_then(|meta| meta.since.clone());
+                // The documentation of the builtins is only made for the resolved items.
+                let is_builtin = item.borrow().is_builtin;
+                let documentation = (!is_builtin).then(|| {
+                    let hover = item.borrow_mut().get_hover();
+                    doc_images(hover, item.borrow().url.as_ref(), doc_image_options)
+                });
+                let mut data = serde_json::Map::new();
+                if let Some(since) = since {
+                    data.insert("since".to_owned(), json!(since));
+                }
+                if is_builtin && !matches!(item.borrow().kind, ItemKind::Keyword(_)) {
+                    data.insert("builtin".to_owned(), json!(&*item.borrow().name));
+                }
+                CompletionItem {
+                    label: name,
+                    label_details: label_details_support.then(|| CompletionItemLabelDetails {
+                        detail: (!params.is_empty()).then_some(params),
+                        description: origin.clone(),
+                    }),
+                    detail: Some(match origin {
+                        Some(origin) => format!("{} · {}", signature, origin),
+                        None => signature,
+                    }),
+                    kind: Some(item.borrow().kind.completion_kind()),
+                    filter_text: Some(item.borrow().name.to_string()),
+                    insert_text: Some(insert_text),
+                    insert_text_format: Some(insert_text_format),
+                    insert_text_mode: Some(InsertTextMode::ADJUST_INDENTATION),
+                    commit_characters,
+                    tags: item
+                        .borrow()
+                        .is_deprecated()
+                        .then(|| vec![CompletionItemTag::DEPRECATED]),
+                    documentation: documentation.map(|doc| {
+                        Documentation::MarkupContent(MarkupContent {
+                            kind: lsp_types::MarkupKind::Markdown,
+                            value: doc,
+                        })
+                    }),
+                    data: (!data.is_empty()).then_some(Value::Object(data)),
+                    ..Default::default()
+                }
+            };
 
-        let mut items = self.find_identities(&file.borrow(), &|_| true, &node, true, 0);
-
-        let kind = node.kind();
-        if let Some(parent) = &node.parent().and_then(|parent| parent.parent()) {
-            let kind = parent.kind();
-            let mut node = None;
-            if kind == "arguments" {
-                if let Some(callable) = parent.parent() {
-                    let kind = callable.kind();
-                    if kind == "module_call" || kind == "function_call" {
-                        node = Some(callable);
-                    }
+            let mut result: Vec<CompletionItem> = items.iter().map(make_item).collect();
+
+            // The examples of the builtin documentation, inserted as they are.
+            for item in &items {
+                let item = item.borrow();
+                if !item.is_builtin {
+                    continue;
+                }
+                for (i, example) in item.examples().into_iter().enumerate() {
+                    let label = match i {
+                        0 => format!("{} (example)", item.name),
+                        _ => format!("{} (example {})", item.name, i + 1),
+                    };
+                    result.push(CompletionItem {
+                        label,
+                        kind: Some(CompletionItemKind::SNIPPET),
+                        detail: Some("example".to_owned()),
+                        filter_text: Some(item.name.to_string()),
+                        insert_text: Some(escape_snippet(&example.code)),
+                        insert_text_format: Some(InsertTextFormat::SNIPPET),
+                        insert_text_mode: Some(InsertTextMode::ADJUST_INDENTATION),
+                        documentation: Some(Documentation::MarkupContent(MarkupContent {
+                            kind: lsp_types::MarkupKind::Markdown,
+                            value: format!("```scad\n{}\n```", example.code),
+                        })),
+                        ..Default::default()
+                    });
                 }
             }
 
-            if kind == "module_call" || kind == "function_call" {
-                node = Some(*parent);
+            if let Some(item) = find_offset(&bfile.code, pos)
+                .and_then(|offset| range_completion(&bfile.code, offset))
+            {
+                result.push(item);
             }
 
-            if let Some(node) = node {
-                node.child_by_field_name("name")
-                    .map(|child| node_text(&bfile.code, &child))
-                    .map(|name| {
-                        let fun_items = self.find_identities(
-                            &file.borrow(),
-                            &|item_name| item_name == name,
-                            &node,
-                            false,
-                            0,
-                        );
-
-                        if !fun_items.is_empty() {
-                            let item = &fun_items[0];
-
-                            let param_items = match &item.borrow().kind {
-                                ItemKind::Module { params, .. } => {
-                                    let mut result = vec![];
-                                    for p in params {
-                                        result.push(Rc::new(RefCell::new(Item {
-                                            name: p.name.clone(),
-                                            kind: ItemKind::Variable,
-                                            range: p.range,
-                                            url: Some(bfile.url.clone()),
-                                            ..Default::default()
-                                        })));
-                                    }
-                                    result
-                                }
-                                ItemKind::Function { flags: _, params } => {
-                                    let mut result = vec![];
-                                    for p in params {
-                                        result.push(Rc::new(RefCell::new(Item {
-                                            name: p.name.clone(),
-                                            kind: ItemKind::Variable,
-                                            range: p.range,
-                                            url: Some(bfile.url.clone()),
-                                            ..Default::default()
-                                        })));
-                                    }
-                                    result
-                                }
-                                _ => {
-                                    vec![]
-                                }
-                            };
-
-                            items.extend(param_items);
-                        }
-                    });
+            // The symbols of the libraries which aren't included, the include statement is added
+            // when the item is resolved.
+            let mut names: HashSet<Rc<str>> = items
+                .iter()
+                .map(|item| item.borrow().name.clone())
+                .collect();
+            for item in self.library_items(&bfile) {
+                let Some(url) = item.borrow().url.clone() else {
+                    continue;
+                };
+                if !names.insert(item.borrow().name.clone()) {
+                    continue;
+                }
+                let mut completion = make_item(&item);
+                completion.data = Some(json!({ "uri": bfile.url, "include": url }));
+                result.push(completion);
             }
-        }
 
-        let result = if kind == "include_path"
-            || node
-                .prev_sibling()
-                .map(|sib| {
-                    if sib.kind() == "include" || sib.kind() == "use" {
-                        Some(true)
-                    } else {
-                        None
-                    }
-                })
-                .is_some()
-        {
             CompletionResponse::List(CompletionList {
                 is_incomplete: true,
-                items: bfile
-                    .get_include_completion(&node)
-                    .iter()
-                    .map(|file_name| CompletionItem {
-                        label: file_name.clone(),
-                        kind: Some(CompletionItemKind::FILE),
-                        filter_text: Some(name.to_owned()),
-                        insert_text: Some(file_name.clone()),
-                        insert_text_format: Some(InsertTextFormat::PLAIN_TEXT),
-                        insert_text_mode: Some(InsertTextMode::ADJUST_INDENTATION),
-                        ..Default::default()
-                    })
-                    .collect(),
+                items: result,
             })
-        } else {
-            CompletionResponse::List(CompletionList {
-                is_incomplete: true,
-                items: items
+        };
+
+        if self.plain_completions() {
+            return Ok(plain_completions(result));
+        }
+        Ok(result)
+    }
+
+    // Notes about the OpenSCAD version required by a builtin, and the include statement added for
+    // a library symbol.
+    pub(crate) fn handle_completion_resolve(
+        &mut self,
+        mut item: CompletionItem,
+    ) -> HandlerResult<CompletionItem> {
+        let data = item.data.clone().unwrap_or_default();
+        let mut notes = vec![];
+
+        if let Some(since) = data.get("since").and_then(Value::as_str) {
+            let version = self.openscad_version();
+            if version.is_none_or(|version| is_older_version(&version, since)) {
+                notes.push(format!("requires OpenSCAD ≥ {}", since));
+            }
+        }
+
+        let url = |key: &str| {
+            data.get(key)
+                .and_then(Value::as_str)
+                .and_then(|url| Url::parse(url).ok())
+        };
+        if let (Some(uri), Some(target)) = (url("uri"), url("include")) {
+            if let Some(edit) = self.include_edit(&uri, &target) {
+                notes.push(format!("will add {}", edit.new_text.trim_end()));
+                item.additional_text_edits = Some(vec![edit]);
+            }
+        }
+
+        if let Some(name) = data.get("builtin").and_then(Value::as_str) {
+            let builtin = self.get_code(&self.builtin_url.clone()).and_then(|file| {
+                file.borrow_mut().gen_top_level_items_if_needed();
+                let file = file.borrow();
+                file.root_items
                     .iter()
-                    .map(|item| {
-                        let label = item.borrow_mut().get_label();
-                        let snippet = item.borrow_mut().get_snippet();
-                        CompletionItem {
-                            label,
-                            kind: Some(item.borrow().kind.completion_kind()),
-                            filter_text: Some(item.borrow().name.to_owned()),
-                            insert_text: Some(snippet),
-                            insert_text_format: Some(match item.borrow().kind {
-                                ItemKind::Variable => InsertTextFormat::PLAIN_TEXT,
-                                _ => InsertTextFormat::SNIPPET,
-                            }),
-                            insert_text_mode: Some(InsertTextMode::ADJUST_INDENTATION),
-                            documentation: item.borrow().hover.as_ref().map(|doc| {
-                                Documentation::MarkupContent(MarkupContent {
-                                    kind: lsp_types::MarkupKind::Markdown,
-                                    value: doc.to_owned(),
-                                })
-                            }),
-                            ..Default::default()
-                        }
+                    .flatten()
+                    .find(|builtin| {
+                        let builtin = builtin.borrow();
+                        &*builtin.name == name && Some(builtin.kind.completion_kind()) == item.kind
                     })
-                    .collect(),
-            })
-        };
+                    .cloned()
+            });
+            if let Some(builtin) = builtin {
+                item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+                    kind: lsp_types::MarkupKind::Markdown,
+                    value: {
+                        let hover = builtin.borrow_mut().get_hover();
+                        doc_images(
+                            hover,
+                            builtin.borrow().url.as_ref(),
+                            self.doc_image_options(true),
+                        )
+                    },
+                }));
+            }
+        }
 
-        let result = serde_json::to_value(result).unwrap();
-        self.respond(Response {
-            id,
-            result: Some(result),
-            error: None,
-        });
+        if !notes.is_empty() {
+            item.detail = Some(
+                item.detail
+                    .into_iter()
+                    .chain(notes)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+
+        Ok(item)
     }
 
-    pub(crate) fn handle_document_symbols(&mut self, id: RequestId, params: DocumentSymbolParams) {
+    pub(crate) fn handle_document_symbols(
+        &mut self,
+        params: DocumentSymbolParams,
+    ) -> HandlerResult<Option<DocumentSymbolResponse>> {
         let uri = &params.text_document.uri;
-        let file = match self.get_code(uri) {
-            Some(code) => code,
-            _ => return,
-        };
+        let file = self.request_code(uri)?;
 
+        let hierarchical = self.hierarchical_document_symbol_support();
         let mut bfile = file.borrow_mut();
         bfile.gen_top_level_items_if_needed();
-        if let Some(items) = &bfile.root_items {
-            let result: Vec<SymbolInformation> = items
-                .iter()
-                .filter_map(|item| {
-                    item.borrow().url.as_ref().map(|url| {
-                        #[allow(deprecated)]
-                        SymbolInformation {
-                            name: item.borrow().name.to_owned(),
-                            kind: item.borrow().get_symbol_kind(),
-                            tags: None,
-                            deprecated: None,
-                            location: Location {
-                                uri: url.clone(),
-                                range: item.borrow().range,
-                            },
-                            container_name: None,
-                        }
-                    })
+        let Some(items) = &bfile.root_items else {
+            return Ok(None);
+        };
+        let result = if hierarchical {
+            // The customizer tabs contain their parameters.
+            let mut groups: Vec<DocumentSymbol> = customizer::groups(&bfile)
+                .into_iter()
+                .map(|group| {
+                    #[allow(deprecated)]
+                    DocumentSymbol {
+                        name: group.name,
+                        detail: Some("customizer tab".to_owned()),
+                        kind: SymbolKind::NAMESPACE,
+                        tags: None,
+                        deprecated: None,
+                        range: group.range,
+                        selection_range: group.range,
+                        children: Some(vec![]),
+                    }
                 })
                 .collect();
+            let mut symbols = vec![];
+            for item in items {
+                let mut symbol = item.borrow().document_symbol();
+                if matches!(item.borrow().kind, ItemKind::Module { .. }) {
+                    symbol.children = bfile.module_symbols(symbol.range);
+                }
+                let group = groups.iter_mut().find(|group| {
+                    group.range.start <= symbol.range.start && symbol.range.end <= group.range.end
+                });
+                match group {
+                    Some(group) => group.children.get_or_insert_with(Vec::new).push(symbol),
+                    None => symbols.push(symbol),
+                }
+            }
+            symbols.extend(groups);
+            symbols.sort_by_key(|symbol| symbol.range.start);
+            DocumentSymbolResponse::Nested(symbols)
+        } else {
+            DocumentSymbolResponse::Flat(
+                items
+                    .iter()
+                    .map(|item| item.borrow().symbol_information(uri))
+                    .collect(),
+            )
+        };
+        Ok(Some(result))
+    }
 
-            let result = DocumentSymbolResponse::Flat(result);
-
-            let result = serde_json::to_value(result).unwrap();
-            self.respond(Response {
-                id,
-                result: Some(result),
-                error: None,
-            });
-        }
+    pub(crate) fn handle_folding_range(
+        &mut self,
+        params: FoldingRangeParams,
+    ) -> HandlerResult<Vec<FoldingRange>> {
+        let file = self.request_code(&params.text_document.uri)?;
+        let result = file.borrow().folding_ranges();
+        Ok(result)
     }
 
-    pub(crate) fn handle_formatting(&mut self, id: RequestId, params: DocumentFormattingParams) {
+    pub(crate) fn handle_formatting(
+        &mut self,
+        params: DocumentFormattingParams,
+    ) -> HandlerResult<Option<Vec<TextEdit>>> {
         let uri = &params.text_document.uri;
 
-        let file = match self.get_code(uri) {
-            Some(code) => code,
-            _ => return,
-        };
+        let file = self.request_code(uri)?;
 
-        let internal_err = |err: String| {
-            self.respond(Response {
-                id: id.clone(),
-                result: None,
-                error: Some(ResponseError {
-                    code: -32603,
-                    message: err,
-                    data: None,
-                }),
-            });
+        if self.is_large_file(&file.borrow(), true) {
+            return Ok(None);
+        }
+
+        let is_large = file.borrow().code.lines().count() >= LARGE_FORMATTING_LINES;
+        let progress = if is_large {
+            self.begin_progress("Formatting")
+        } else {
+            None
         };
+        let result = self.format_text(uri, &file.borrow());
+        self.end_progress(progress, None);
+        // Nothing is changed when the formatter printed nothing.
+        let code = result.map_err(HandlerError::request_failed)?;
+        Ok((!code.is_empty()).then(|| line_edits(&file.borrow().code, &code)))
+    }
+
+    pub(crate) fn handle_will_save_wait_until(
+        &mut self,
+        params: WillSaveTextDocumentParams,
+    ) -> HandlerResult<Option<Vec<TextEdit>>> {
+        let uri = params.text_document.uri;
+        match self.format_on_save {
+            FormatOnSave::Off => Ok(None),
+            FormatOnSave::Document => self.handle_formatting(DocumentFormattingParams {
+                text_document: TextDocumentIdentifier::new(uri),
+                options: Default::default(),
+                work_done_progress_params: Default::default(),
+            }),
+            FormatOnSave::Modified => {
+                let file = self.request_code(&uri)?;
+                if self.is_large_file(&file.borrow(), true) {
+                    return Ok(None);
+                }
+                let edits = self
+                    .format_modified(&uri, &file.borrow())
+                    .map_err(HandlerError::request_failed)?;
+                Ok(Some(edits))
+            }
+        }
+    }
+
+    // The edits formatting the top level statements touched by the unsaved edits, instead of
+    // replacing the whole document: it is formatted once, and only the blocks of changed lines on
+    // these statements are kept.
+    fn format_modified(&self, uri: &Url, file: &ParsedCode) -> Result<Vec<TextEdit>, String> {
+        let root = file.tree.root_node();
+        let modified: Vec<(u32, u32)> = root
+            .children(&mut root.walk())
+            .filter(|node| {
+                file.unsaved
+                    .iter()
+                    .any(|range| range.start <= node.end_byte() && node.start_byte() <= range.end)
+            })
+            .map(|node| {
+                (
+                    node.start_position().row as u32,
+                    node.end_position().row as u32,
+                )
+            })
+            .collect();
+        if modified.is_empty() {
+            return Ok(vec![]);
+        }
 
+        let code = self.format_text(uri, file)?;
+        if code.is_empty() {
+            return Ok(vec![]);
+        }
+        let edits = line_edits(&file.code, &code)
+            .into_iter()
+            .flat_map(split_lines)
+            .filter(|edit| {
+                let Range { start, end } = edit.range;
+                // The last line replaced by the edit, an insertion is on the line of its start.
+                let last = match end.character == 0 && end.line > start.line {
+                    true => end.line - 1,
+                    false => end.line,
+                };
+                modified
+                    .iter()
+                    .any(|&(first_row, last_row)| start.line <= last_row && first_row <= last)
+            })
+            .collect();
+        Ok(edits)
+    }
+
+    // The code formatted by the formatter executable, empty if it printed nothing.
+    pub(crate) fn format_text(&self, uri: &Url, file: &ParsedCode) -> Result<String, String> {
         let mut code = String::new();
         let mut last_pos = 0;
-        for_each_child(&mut (file.borrow().tree.walk()), |cursor| {
+        for_each_child(&mut (file.tree.walk()), |cursor| {
             let node = cursor.node();
 
-            let code_str = &file.borrow().code;
+            let code_str = &file.code;
 
             if node.start_byte() > last_pos {
                 let mut sub = &code_str[last_pos..node.start_byte()];
@@ -609,59 +1112,713 @@ impl Server {
                 code.push_str(sub);
             }
 
-            if node.kind().is_include_statement() {
+            if node.node_kind().is_include_statement() {
                 code.push_str("#include <");
             }
             code.push_str(node_text(code_str, &node));
 
             last_pos = node.end_byte();
         });
+        // The final newline.
+        code.push_str(file.code[last_pos..].trim_matches(' ').trim_matches('\t'));
+
+        let code = self.run_formatter(uri, &code)?;
+        Ok(code.replace("#include <", ""))
+    }
 
-        let path = uri.to_file_path().unwrap();
+    // The output of the formatter executable of the project of `uri` for `code`.
+    fn run_formatter(&self, uri: &Url, code: &str) -> Result<String, String> {
+        let path = uri
+            .to_file_path()
+            .map_err(|_| format!("not a local file: {}", uri))?;
         let path = path.parent().unwrap();
 
-        let child = match Command::new(&self.args.fmt_exe)
-            .arg(format!("-style={}", self.args.fmt_style))
+        // The project configuration wins over the settings. Its executable comes with the files of
+        // the project, it is only run when the user trusts it with the project_fmt_exe setting.
+        let config = project_format_config(path).transpose()?.unwrap_or_default();
+        let style = config.style.as_ref().unwrap_or(&self.args.fmt_style);
+        if config.exe.is_some() && !self.project_fmt_exe {
+            err_to_console!(
+                "the formatter of {} is ignored without the project_fmt_exe setting",
+                PROJECT_CONFIG_FILE
+            );
+        }
+        let exe = config
+            .exe
+            .as_ref()
+            .filter(|_| self.project_fmt_exe)
+            .unwrap_or(&self.args.fmt_exe);
+        let child = Command::new(resolve_exe(exe))
+            .arg(format!("-style={}", style))
             .arg("-assume-filename=foo.scad")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .current_dir(path)
             .spawn()
+            .map_err(|err| format!("{}: {}", exe, &err.to_string()))?;
+
+        child
+            .stdin
+            .unwrap()
+            .write_all(code.as_bytes())
+            .map_err(|why| why.to_string())?;
+
+        let mut code = String::new();
+        child
+            .stdout
+            .unwrap()
+            .read_to_string(&mut code)
+            .map_err(|why| why.to_string())?;
+        Ok(code)
+    }
+
+    pub(crate) fn handle_signature_help(
+        &mut self,
+        params: SignatureHelpParams,
+    ) -> HandlerResult<Option<SignatureHelp>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let file = self.request_code(uri)?;
+        let bfile = file.borrow();
+        let position = params.text_document_position_params.position;
+        let result = find_offset(&bfile.code, position).and_then(|offset| {
+            // From the start of the top level statement, the brackets before it are closed.
+            let root = bfile.tree.root_node();
+            let start = root
+                .children(&mut root.walk())
+                .find(|node| node.start_byte() <= offset && offset <= node.end_byte())
+                .map_or(0, |node| node.start_byte());
+            construct_signature(&bfile.code[start..offset])
+        });
+        Ok(result)
+    }
+
+    pub(crate) fn handle_code_action(
+        &mut self,
+        params: CodeActionParams,
+    ) -> HandlerResult<Vec<CodeActionOrCommand>> {
+        let uri = params.text_document.uri;
+        let file = self.request_code(&uri)?;
+
+        let mut actions = vec![];
+        if let Some(edits) =
+            customizer::group_edits(&file.borrow(), params.range, DEFAULT_GROUP_NAME)
         {
-            Ok(res) => res,
-            Err(err) => {
-                internal_err(format!("{}: {}", &self.args.fmt_exe, &err.to_string()));
-                return;
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Put the parameters in a customizer group".to_owned(),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(self.workspace_edit(
+                    HashMap::from([(uri.clone(), edits)]),
+                    "Put the parameters in a customizer group",
+                )),
+                ..Default::default()
+            }));
+        }
+
+        for diag in params.context.diagnostics {
+            // The fixes of the syntax errors and hints, with their edits.
+            let fix = diag
+                .data
+                .as_ref()
+                .and_then(|data| data.get("fix"))
+                .and_then(|fix| {
+                    let title = fix.get("title")?.as_str()?.to_owned();
+                    let edits: Vec<TextEdit> =
+                        serde_json::from_value(fix.get("edits")?.clone()).ok()?;
+                    Some((title, edits))
+                });
+            if let Some((title, edits)) = fix {
+                actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                    title: title.clone(),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diag]),
+                    edit: Some(self.workspace_edit(HashMap::from([(uri.clone(), edits)]), &title)),
+                    is_preferred: Some(true),
+                    ..Default::default()
+                }));
+                continue;
             }
+            if diag.code != Some(NumberOrString::String(UNDEFINED_IDENTIFIER.to_owned())) {
+                continue;
+            }
+            let Some(suggestion) = diag
+                .data
+                .as_ref()
+                .and_then(|data| data.get("suggestion"))
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+            else {
+                continue;
+            };
+            let edit = TextEdit {
+                range: diag.range,
+                new_text: suggestion.clone(),
+            };
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Change to `{}`", suggestion),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diag]),
+                edit: Some(self.workspace_edit(
+                    HashMap::from([(uri.clone(), vec![edit])]),
+                    &format!("Change to `{}`", suggestion),
+                )),
+                is_preferred: Some(true),
+                ..Default::default()
+            }));
+        }
+
+        Ok(actions)
+    }
+
+    pub(crate) fn handle_code_lens(
+        &mut self,
+        params: CodeLensParams,
+    ) -> HandlerResult<Vec<CodeLens>> {
+        let uri = params.text_document.uri;
+        let file = self.request_code(&uri)?;
+
+        let bfile = file.borrow();
+        let root = bfile.tree.root_node();
+        let has_checks = root
+            .children(&mut root.walk())
+            .any(|node| match node.node_kind() {
+                NodeKind::AssertStatement => true,
+                NodeKind::TransformChain => node
+                    .named_child(0)
+                    .filter(|call| call.node_kind() == NodeKind::ModuleCall)
+                    .and_then(|call| call.child_by_field_name("name"))
+                    .map(|name| matches!(node_text(&bfile.code, &name), "echo" | "assert"))
+                    .unwrap_or(false),
+                _ => false,
+            });
+
+        let lenses = if has_checks {
+            vec![CodeLens {
+                range: Range::default(),
+                command: Some(LspCommand {
+                    title: "Run checks".to_owned(),
+                    command: RUN_CHECKS.to_owned(),
+                    arguments: Some(vec![serde_json::to_value(&uri).unwrap()]),
+                }),
+                data: None,
+            }]
+        } else {
+            vec![]
         };
 
-        if let Err(why) = child.stdin.unwrap().write_all(code.as_bytes()) {
-            internal_err(why.to_string());
-            return;
+        Ok(lenses)
+    }
+
+    // Continue the `//` and `/** */` doc comments when a new line is inserted.
+    pub(crate) fn handle_on_type_formatting(
+        &mut self,
+        params: DocumentOnTypeFormattingParams,
+    ) -> HandlerResult<Vec<TextEdit>> {
+        lazy_static! {
+            static ref LINE_COMMENT_RE: Regex = Regex::new(r"^(\s*//+)(\s*)\S").unwrap();
+            static ref BLOCK_START_RE: Regex = Regex::new(r"^(\s*)/\*\*").unwrap();
+            static ref BLOCK_LINE_RE: Regex = Regex::new(r"^(\s*)\*(\s|$)").unwrap();
+        };
+
+        let uri = &params.text_document_position.text_document.uri;
+        let pos = params.text_document_position.position;
+        let file = self.request_code(uri)?;
+
+        let bfile = file.borrow();
+        let mut edits = vec![];
+        if self.args.comment_continuation && params.ch == "\n" && pos.line > 0 {
+            let mut lines = bfile.code.lines().skip(pos.line as usize - 1);
+            let prev = lines.next().unwrap_or("");
+            let current = lines.next().unwrap_or("");
+            let cursor = find_offset(current, Position::new(0, pos.character)).unwrap_or(0);
+            let before_cursor = &current[..cursor];
+
+            let leader = if !before_cursor.trim().is_empty() {
+                None
+            } else if let Some(cap) = LINE_COMMENT_RE.captures(prev) {
+                Some(format!("{}{}", &cap[1], &cap[2]))
+            } else if prev.contains("*/") {
+                None
+            } else if let Some(cap) = BLOCK_START_RE.captures(prev) {
+                Some(format!("{} * ", &cap[1]))
+            } else {
+                BLOCK_LINE_RE
+                    .captures(prev)
+                    .map(|cap| format!("{}* ", &cap[1]))
+            };
+
+            if let Some(leader) = leader {
+                edits.push(TextEdit {
+                    range: Range {
+                        start: lsp_types::Position {
+                            line: pos.line,
+                            character: 0,
+                        },
+                        end: pos,
+                    },
+                    new_text: leader,
+                });
+            }
+        }
+
+        Ok(edits)
+    }
+
+    // A parameter and its uses in the body of the module or function, if nothing else in the
+    // body declares the same name.
+    pub(crate) fn handle_linked_editing_range(
+        &mut self,
+        params: LinkedEditingRangeParams,
+    ) -> HandlerResult<Option<LinkedEditingRanges>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let file = self.request_code(uri)?;
+        let bfile = file.borrow();
+
+        let node = get_node_at_position(&bfile, params.text_document_position_params.position);
+
+        let mut result = None;
+        if node.node_kind() == NodeKind::Identifier {
+            let name = node_text(&bfile.code, &node);
+            if let Some((decl, param)) = find_param_declaration(&bfile.code, node, name) {
+                let mut ranges = vec![param.lsp_range(&bfile.code)];
+                let mut ambiguous = false;
+                for other in traverse(decl.walk(), Order::Pre) {
+                    if other.node_kind() != NodeKind::Identifier
+                        || other == param
+                        || node_text(&bfile.code, &other) != name
+                    {
+                        continue;
+                    }
+                    let is_named_argument = other
+                        .parent()
+                        .and_then(|p| p.parent())
+                        .is_some_and(|p| p.node_kind() == NodeKind::Arguments);
+                    if is_reference(&other) {
+                        ranges.push(other.lsp_range(&bfile.code));
+                    } else if !is_named_argument {
+                        // declared again in the body
+                        ambiguous = true;
+                        break;
+                    }
+                }
+
+                if !ambiguous && (node == param || ranges.contains(&node.lsp_range(&bfile.code))) {
+                    result = Some(LinkedEditingRanges {
+                        ranges,
+                        word_pattern: Some("[A-Za-z_][A-Za-z0-9_]*".to_owned()),
+                    });
+                }
+            }
         }
 
-        let mut code = String::new();
+        Ok(result)
+    }
 
-        match child.stdout.unwrap().read_to_string(&mut code) {
-            Err(why) => {
-                internal_err(why.to_string());
+    pub(crate) fn handle_will_rename_files(
+        &mut self,
+        params: RenameFilesParams,
+    ) -> HandlerResult<Option<WorkspaceEdit>> {
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for file in params.files {
+            let (Ok(old), Ok(new)) = (Url::parse(&file.old_uri), Url::parse(&file.new_uri)) else {
+                continue;
+            };
+            for (url, edits) in self.include_rename_edits(&old, &new) {
+                changes.entry(url).or_default().extend(edits);
             }
-            Ok(size) => {
-                if size > 0 {
-                    code = code.replace("#include <", "");
-                    let result = [TextEdit {
-                        range: file.borrow().tree.root_node().lsp_range(),
-                        new_text: code.to_owned(),
-                    }];
-
-                    let result = serde_json::to_value(result).unwrap();
-                    self.respond(Response {
-                        id,
-                        result: Some(result),
-                        error: None,
+        }
+
+        let result = (!changes.is_empty())
+            .then(|| self.workspace_edit(changes, "Update the include/use statements"));
+
+        Ok(result)
+    }
+
+    fn item_thumbnail(&mut self, item: &Rc<RefCell<Item>>) -> Option<String> {
+        if !self.args.hover_thumbnails {
+            return None;
+        }
+        let item = item.borrow();
+        if item.is_builtin || !matches!(item.kind, ItemKind::Module { .. }) {
+            return None;
+        }
+        let url = item.url.as_ref()?;
+        let path = url.to_file_path().ok()?;
+        let code = self.get_code(url)?.borrow().code.clone();
+        self.module_thumbnail(&path, &code, &item.name)
+    }
+
+    // References of a file: the include/use statements of the files including it, when invoked on
+    // an include path, or anywhere in the file out of an identifier. On an identifier, the
+    // references of its symbol, limited to the files of the `scope`.
+    pub(crate) fn handle_references(
+        &mut self,
+        params: ScopedReferenceParams,
+    ) -> HandlerResult<Option<Vec<Location>>> {
+        let uri = &params.params.text_document_position.text_document.uri;
+        let file = self.request_code(uri)?;
+
+        let only = match params.scope {
+            ReferenceScope::File => Some(HashSet::from([uri.clone()])),
+            ReferenceScope::Includes => Some(
+                self.included_codes(vec![uri.clone()], &self.builtin_url.clone())
+                    .iter()
+                    .map(|code| code.borrow().url.clone())
+                    .collect(),
+            ),
+            ReferenceScope::Workspace => None,
+        };
+
+        let (target, symbol) = {
+            file.borrow_mut().gen_top_level_items_if_needed();
+            let bfile = file.borrow();
+            let node = get_node_at_position(&bfile, params.params.text_document_position.position);
+            match node.node_kind() {
+                NodeKind::IncludePath => (
+                    node.parent()
+                        .and_then(|incstat| bfile.resolve_include(&incstat))
+                        .map(|(_, url)| url),
+                    None,
+                ),
+                NodeKind::Identifier => {
+                    let name = node_text(&bfile.code, &node);
+                    let definition = self
+                        .find_identities(&bfile, &|ident| ident == name, &node, false)
+                        .into_iter()
+                        .next();
+                    let symbol = definition.and_then(|definition| {
+                        let item = definition.borrow();
+                        let url = item
+                            .url
+                            .clone()
+                            .filter(|url| !item.is_builtin && url != &self.builtin_url)?;
+                        Some((url, item.name.clone(), item.range))
                     });
+                    (None, symbol)
                 }
+                _ => (Some(uri.clone()), None),
+            }
+        };
+
+        let include_declaration = params.params.context.include_declaration;
+        let token = params.params.partial_result_params.partial_result_token;
+        let result = match (target, symbol) {
+            (Some(target), _) => Some(self.include_references(&target, token.as_ref())),
+            (_, Some((url, name, range))) => {
+                Some(self.symbol_references(&url, &name, range, include_declaration, only.as_ref()))
+            }
+            _ => None,
+        };
+        let result = result.map(|locations| {
+            locations
+                .into_iter()
+                .filter(|location| {
+                    only.as_ref()
+                        .is_none_or(|only| only.contains(&location.uri))
+                })
+                .collect::<Vec<_>>()
+        });
+        Ok(result)
+    }
+
+    // The modules, or functions, of the workspace with the name of the one at the position.
+    pub(crate) fn handle_implementation(
+        &mut self,
+        params: GotoImplementationParams,
+    ) -> HandlerResult<Option<GotoImplementationResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let file = self.request_code(uri)?;
+
+        let target = {
+            let bfile = file.borrow();
+            let pos = params.text_document_position_params.position;
+            let node = get_node_at_position(&bfile, pos);
+            let is_module = node.parent().and_then(|parent| match parent.node_kind() {
+                NodeKind::ModuleCall | NodeKind::ModuleDeclaration => Some(true),
+                NodeKind::FunctionCall | NodeKind::FunctionDeclaration => Some(false),
+                _ => None,
+            });
+            is_module
+                .filter(|_| node.node_kind() == NodeKind::Identifier)
+                .map(|is_module| (node_text(&bfile.code, &node).to_owned(), is_module))
+        };
+
+        let result = target
+            .map(|(name, is_module)| self.implementations(&name, is_module))
+            .map(GotoImplementationResponse::Array);
+        Ok(result)
+    }
+
+    pub(crate) fn handle_workspace_symbols(
+        &mut self,
+        params: WorkspaceSymbolParams,
+    ) -> HandlerResult<WorkspaceSymbolResponse> {
+        let token = params.partial_result_params.partial_result_token;
+        let result = self.workspace_symbols(&params.query, token.as_ref());
+        Ok(WorkspaceSymbolResponse::Flat(result))
+    }
+
+    pub(crate) fn handle_status(&mut self, _params: ()) -> HandlerResult<Status> {
+        let mut symbols = 0;
+        let mut memory_bytes = 0;
+        for code in self.codes.values() {
+            let code = code.borrow();
+            symbols += code.root_items.as_ref().map_or(0, |items| items.len());
+            memory_bytes += code.code.len();
+            for item in code.root_items.iter().flatten() {
+                memory_bytes += item.borrow().memory_size();
             }
         }
+        memory_bytes += interned_names() * std::mem::size_of::<Rc<str>>();
+
+        let (parsers, idle_parsers) = parser_counts();
+        let status = Status {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            builtin: self.args.builtin.clone(),
+            library_paths: self
+                .library_locations
+                .borrow()
+                .iter()
+                .map(|url| url.to_string())
+                .collect(),
+            indexed_files: self.codes.len(),
+            open_files: self.open_documents.len(),
+            symbols,
+            memory_bytes,
+            interned_names: interned_names(),
+            parsers,
+            idle_parsers,
+            pending_messages: self.connection.receiver.len() + self.queued_messages.len(),
+            requests: self.request_metrics.clone(),
+        };
+
+        Ok(status)
+    }
+
+    // OpenSCAD runs in the background, the other messages are handled meanwhile.
+    pub(crate) fn handle_eval(&mut self, id: RequestId, params: EvalParams) {
+        let library = params
+            .text_document
+            .and_then(|doc| doc.uri.to_file_path().ok())
+            .filter(|path| path.is_file());
+        let sender = self.connection.sender.clone();
+        self.evaluate_in_background(params.expression, library, move |result| {
+            let result = result.map_err(HandlerError::request_failed);
+            // The client may be gone.
+            let _ = sender.send(Message::Response(response(id, result)));
+        });
+    }
+
+    pub(crate) fn handle_set_overrides(&mut self, params: SetOverridesParams) -> HandlerResult<()> {
+        let uri = params.text_document.uri;
+        let mut overrides = HashMap::new();
+        for (name, value) in params.overrides {
+            let mut chars = name.chars();
+            let is_identifier = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_identifier {
+                return Err(HandlerError::invalid_params(format!(
+                    "`{}` is not a variable name",
+                    name
+                )));
+            }
+            let value = constants::Value::from_json(&value).ok_or_else(|| {
+                HandlerError::invalid_params(format!("`{}` has no OpenSCAD value", name))
+            })?;
+            overrides.insert(name, value);
+        }
+        if overrides.is_empty() {
+            self.overrides.remove(&uri);
+        } else {
+            self.overrides.insert(uri.clone(), overrides);
+        }
+
+        // The hovers and the diagnostics show the constant values.
+        self.clear_response_cache();
+        if self.open_documents.contains(&uri) {
+            if let Some(file) = self.codes.get(&uri).cloned() {
+                let version = file.borrow().version;
+                self.publish_diagnostics(&file, version);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn handle_enclosing_symbol(
+        &mut self,
+        params: TextDocumentPositionParams,
+    ) -> HandlerResult<Vec<DocumentSymbol>> {
+        let file = self.request_code(&params.text_document.uri)?;
+
+        let code = file.borrow();
+        Ok(code.enclosing_symbols(to_point(&code.code, params.position)))
+    }
+
+    pub(crate) fn handle_symbol_info(
+        &mut self,
+        params: TextDocumentPositionParams,
+    ) -> HandlerResult<Option<SymbolInfo>> {
+        let file = self.request_code(&params.text_document.uri)?;
+
+        let symbol = {
+            let bfile = file.borrow();
+            let node = get_node_at_position(&bfile, params.position);
+            (node.node_kind() == NodeKind::Identifier).then(|| {
+                (
+                    node_text(&bfile.code, &node).to_owned(),
+                    node_namespace(&node),
+                )
+            })
+        };
+        let info = symbol.map(|(name, kind)| {
+            let (definitions, references) = self.symbol_usage(&name, kind);
+            let mut files: Vec<Url> = definitions.iter().map(|loc| loc.uri.clone()).collect();
+            files.sort();
+            files.dedup();
+            SymbolInfo {
+                name,
+                kind: kind.to_owned(),
+                definitions: definitions.len(),
+                references,
+                files,
+            }
+        });
+        Ok(info)
     }
+
+    pub(crate) fn handle_builtin_contents(
+        &mut self,
+        params: TextDocumentIdentifier,
+    ) -> HandlerResult<String> {
+        let file = (params.uri == self.builtin_url)
+            .then(|| self.get_code(&params.uri))
+            .flatten();
+        let file = file.ok_or_else(|| {
+            HandlerError::invalid_params(format!("{} is not the builtin file", params.uri))
+        })?;
+
+        let code = file.borrow().code.clone();
+        Ok(code)
+    }
+
+    pub(crate) fn handle_library_tree(
+        &mut self,
+        params: LibraryTreeParams,
+    ) -> HandlerResult<LibraryTree> {
+        let libs = self.library_locations.borrow().clone();
+        let children = match &params.parent {
+            None => libs
+                .iter()
+                .filter(|url| url.to_file_path().is_ok_and(|path| path.is_dir()))
+                .map(|url| LibraryNode {
+                    name: url
+                        .to_file_path()
+                        .map_or(url.to_string(), |path| path.display().to_string()),
+                    kind: "library".to_owned(),
+                    uri: url.clone(),
+                    range: None,
+                    detail: None,
+                    documentation: None,
+                    has_children: true,
+                })
+                .collect(),
+            Some(parent) => {
+                let path = parent
+                    .to_file_path()
+                    .ok()
+                    .filter(|_| {
+                        libs.iter()
+                            .any(|lib| parent.as_str().starts_with(lib.as_str()))
+                    })
+                    .ok_or_else(|| {
+                        HandlerError::invalid_params(format!("{} is not in a library", parent))
+                    })?;
+                if path.is_dir() {
+                    library_directory_nodes(&path)
+                } else {
+                    self.library_symbol_nodes(parent)?
+                }
+            }
+        };
+
+        let total = children.len();
+        let children = children
+            .into_iter()
+            .skip(params.offset)
+            .take(params.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(LibraryTree { children, total })
+    }
+
+    pub(crate) fn handle_library_examples(
+        &mut self,
+        params: LibraryExamplesParams,
+    ) -> HandlerResult<Vec<LibraryExample>> {
+        let libs = self.library_locations.borrow().clone();
+        Ok(self.library_examples(&libs, &params.query))
+    }
+
+    // The top level symbols of a library file, in their order.
+    fn library_symbol_nodes(&mut self, url: &Url) -> HandlerResult<Vec<LibraryNode>> {
+        let file = self.request_code(url)?;
+        let mut file = file.borrow_mut();
+        file.gen_top_level_items_if_needed();
+        let nodes = file
+            .root_items
+            .iter()
+            .flatten()
+            .map(|item| {
+                let mut item = item.borrow_mut();
+                let detail = match item.kind {
+                    ItemKind::Module { .. } | ItemKind::Function { .. } => Some(item.get_label()),
+                    _ => None,
+                };
+                LibraryNode {
+                    name: item.name.to_string(),
+                    kind: item.kind.name().to_owned(),
+                    uri: url.clone(),
+                    range: Some(item.range),
+                    detail,
+                    documentation: item.doc.as_deref().map(|doc| doc.trim().to_owned()),
+                    has_children: false,
+                }
+            })
+            .collect();
+        Ok(nodes)
+    }
+}
+
+// The sub directories and the .scad files of a library directory, the directories first, the
+// hidden entries are skipped.
+fn library_directory_nodes(dir: &Path) -> Vec<LibraryNode> {
+    let mut entries: Vec<(bool, String, PathBuf)> = dir
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?.to_owned();
+            let is_dir = path.is_dir();
+            let is_listed = !name.starts_with('.')
+                && (is_dir || path.extension().is_some_and(|ext| ext == "scad"));
+            is_listed.then_some((!is_dir, name, path))
+        })
+        .collect();
+    entries.sort();
+    entries
+        .into_iter()
+        .filter_map(|(is_file, name, path)| {
+            Some(LibraryNode {
+                name,
+                kind: if is_file { "file" } else { "directory" }.to_owned(),
+                uri: Url::from_file_path(path).ok()?,
+                range: None,
+                detail: None,
+                documentation: None,
+                has_children: true,
+            })
+        })
+        .collect()
 }
@@ -1,36 +1,133 @@
 #[macro_use]
 pub(crate) mod utils;
+pub(crate) mod assets;
+pub(crate) mod builtin_meta;
 pub(crate) mod code_helper;
+pub(crate) mod constants;
+pub(crate) mod customizer;
+pub(crate) mod diagnostics;
+pub(crate) mod doc_bundle;
 pub(crate) mod handler;
+pub(crate) mod node_kind;
+pub(crate) mod openscad_cli;
 pub(crate) mod parse_code;
+pub(crate) mod progress;
+pub(crate) mod project_config;
+pub(crate) mod refactor;
 pub(crate) mod response_item;
+pub(crate) mod settings;
+pub(crate) mod signature;
+pub(crate) mod syntax_rules;
+pub(crate) mod types;
+pub(crate) mod workspace;
 
 use directories::UserDirs;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::fs::read_to_string;
-use std::{cell::RefCell, env, path::PathBuf, rc::Rc};
+use std::fs::{self, read_to_string};
+use std::process::Child;
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    cell::{Cell, RefCell},
+    env,
+    path::PathBuf,
+    ptr,
+    rc::Rc,
+};
 
 use linked_hash_map::LinkedHashMap;
-use lsp_server::Connection;
+use lsp_server::{Connection, Message, RequestId};
 use lsp_types::{
-    HoverProviderCapability, OneOf, RenameOptions, ServerCapabilities, TextDocumentSyncCapability,
-    TextDocumentSyncKind, Url, WorkDoneProgressOptions,
+    ClientCapabilities, CodeActionProviderCapability, CodeLensOptions, CompletionOptions,
+    DocumentOnTypeFormattingOptions, ExecuteCommandOptions, FileOperationFilter,
+    FileOperationPattern, FileOperationRegistrationOptions, FoldingRangeProviderCapability,
+    HoverProviderCapability, ImplementationProviderCapability,
+    LinkedEditingRangeServerCapabilities, OneOf, RenameOptions, ServerCapabilities,
+    SignatureHelpOptions, TextDocumentSyncCapability, TextDocumentSyncKind,
+    TextDocumentSyncOptions, TextDocumentSyncSaveOptions, TraceValue, Url, WorkDoneProgressOptions,
+    WorkspaceFileOperationsServerCapabilities, WorkspaceServerCapabilities,
 };
 
-use crate::parse_code::ParsedCode;
+use crate::assets::DocImages;
+use crate::doc_bundle::DocBundles;
+use crate::handler::command::COMMANDS;
+use crate::handler::ext::{experimental_capabilities, RequestMetrics};
+use crate::handler::ResponseCache;
+use crate::openscad_cli::Thumbnails;
+use crate::parse_code::{Owner, ParsedCode};
+use crate::settings::FormatOnSave;
+use crate::utils::{expand_path, resolve_path, IndexLimits};
 use crate::Cli;
 
 const BUILTINS_SCAD: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/builtins"));
 const BUILTIN_PATH: &str = "/builtin";
+const BUILTIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// The default large_file_size, generated polygon dumps are often bigger.
+const LARGE_FILE_SIZE: u64 = 1 << 20;
+// The default max_diagnostics, pasting something else than OpenSCAD code makes an error per token.
+const MAX_DIAGNOSTICS: usize = 500;
 
 pub(crate) struct Server {
     pub library_locations: Rc<RefCell<Vec<Url>>>,
+    // The search_paths setting.
+    pub search_paths: Vec<String>,
 
     pub connection: Connection,
     pub codes: LinkedHashMap<Url, Rc<RefCell<ParsedCode>>>,
     pub args: Cli,
+    // The loaded documentation bundles, shared with the parsed files.
+    pub doc_bundles: Rc<RefCell<DocBundles>>,
+    // The doc_bundles setting.
+    pub doc_bundle_paths: Vec<String>,
+    pub openscad_apps: HashMap<PathBuf, Child>,
+    pub thumbnails: Thumbnails,
+    // The version of the OpenSCAD executable, detected on first use.
+    pub openscad_version: Option<Option<String>>,
+    // The min_library_versions setting, the minimum version of the library version constants.
+    pub min_library_versions: HashMap<String, String>,
+    // The format_exclude setting, the glob patterns of the files openscad.formatWorkspace skips.
+    pub format_exclude: Vec<String>,
+    // The max_indexed_files, max_file_size and max_indexed_bytes settings.
+    pub index_limits: IndexLimits,
+    // The files skipped because of the limits which were logged, each is logged once.
+    pub index_warnings: HashSet<String>,
+    // The large_file_size setting, the size in bytes above which the files aren't formatted nor
+    // linted, and the files the user was told about.
+    pub large_file_size: u64,
+    pub large_files: HashSet<Url>,
+    // The max_diagnostics setting, the diagnostics published per file, 0 for no limit.
+    pub max_diagnostics: usize,
+    pub doc_images: DocImages,
+    pub format_on_save: FormatOnSave,
+    // The save_checks setting, overridden by the project configuration.
+    pub save_checks: bool,
+    // The project_fmt_exe setting, whether the formatter of the project configuration is run.
+    pub project_fmt_exe: bool,
+    // The variables set with openscad/setOverrides, by document.
+    pub overrides: HashMap<Url, HashMap<String, constants::Value>>,
+    pub workspace_roots: Vec<Url>,
+    pub client_capabilities: ClientCapabilities,
+    pub open_documents: HashSet<Url>,
+    // The files including or using each file.
+    pub dependents: HashMap<Url, HashSet<Url>>,
+    pub trace: TraceValue,
+    pub shutdown_requested: bool,
+    pub request_metrics: BTreeMap<String, RequestMetrics>,
 
     builtin_url: Url,
+    builtin_modified: Option<SystemTime>,
+    builtin_polled: Instant,
+    next_request_id: i32,
+    next_progress_id: u32,
+    // The method, id, start time and document of the request being handled, for the traces and
+    // the metrics.
+    current_request: Option<(String, RequestId, Instant, Option<String>)>,
+    // The recent hover and completion responses, reused for the identical requests. Filled when
+    // responding, which doesn't need the server to be mutable.
+    response_cache: RefCell<ResponseCache>,
+    // The messages received while a request was handled, handled before the next ones.
+    queued_messages: VecDeque<Message>,
+    watchers_registered: bool,
 }
 
 pub(crate) enum LoopAction {
@@ -38,58 +135,185 @@ pub(crate) enum LoopAction {
     Continue,
 }
 
-static mut GLOBAL_SERVER: Option<Server> = None;
+thread_local! {
+    // The server of the thread, the concurrent tcp clients have their own.
+    static GLOBAL_SERVER: Cell<*mut Server> = const { Cell::new(ptr::null_mut()) };
+}
 
 // Miscellaneous high-level logic.
 impl Server {
     pub(crate) fn create_server(connection: Connection, args: Cli) {
-        unsafe {
-            GLOBAL_SERVER = Some(Server::new(connection, args));
-        }
+        Self::destroy_server();
+        let server = Box::into_raw(Box::new(Server::new(connection, args)));
+        GLOBAL_SERVER.with(|global| global.set(server));
     }
 
     pub(crate) fn get_server<'a>() -> &'a mut Server {
-        unsafe {
-            return GLOBAL_SERVER.as_mut().unwrap();
+        GLOBAL_SERVER.with(|global| unsafe { global.get().as_mut().unwrap() })
+    }
+
+    // Drop the server and its connection, so that the io threads can finish.
+    pub(crate) fn destroy_server() {
+        let server = GLOBAL_SERVER.with(|global| global.replace(ptr::null_mut()));
+        if !server.is_null() {
+            drop(unsafe { Box::from_raw(server) });
         }
     }
 
     fn new(connection: Connection, args: Cli) -> Self {
-        let builtin_path = PathBuf::from(&args.builtin);
-
         let mut args = args;
 
         let mut code = BUILTINS_SCAD.to_owned();
 
         let mut external = false;
-        match read_to_string(builtin_path) {
-            Err(err) => {
-                err_to_console!("failed to read external file of builtin-function, {:?}. will use the content included in binary.", err);
-                args.builtin = BUILTIN_PATH.to_owned();
-            }
-            Ok(builtin_str) => {
+        let builtin_path = if args.builtin.trim().is_empty() {
+            None
+        } else {
+            resolve_path(&args.builtin, "builtin file")
+        };
+        match builtin_path.map(|path| (read_to_string(&path), path)) {
+            Some((Ok(builtin_str), path)) => {
                 code = builtin_str;
                 external = true;
+                args.builtin = path.to_string_lossy().into_owned();
             }
+            Some((Err(err), _)) => {
+                err_to_console!("failed to read external file of builtin-function, {:?}. will use the content included in binary.", err);
+                args.builtin = BUILTIN_PATH.to_owned();
+            }
+            None => args.builtin = BUILTIN_PATH.to_owned(),
         }
 
         let url = Url::parse(&format!("file://{}", &args.builtin)).unwrap();
 
         let mut instance = Self {
             library_locations: Rc::new(RefCell::new(vec![])),
+            search_paths: vec![],
             connection,
             codes: Default::default(),
             args,
+            doc_bundles: Default::default(),
+            doc_bundle_paths: vec![],
+            openscad_apps: Default::default(),
+            thumbnails: Default::default(),
+            openscad_version: None,
+            min_library_versions: HashMap::new(),
+            format_exclude: vec![],
+            index_limits: Default::default(),
+            index_warnings: Default::default(),
+            large_file_size: LARGE_FILE_SIZE,
+            large_files: Default::default(),
+            max_diagnostics: MAX_DIAGNOSTICS,
+            doc_images: Default::default(),
+            format_on_save: Default::default(),
+            save_checks: false,
+            project_fmt_exe: false,
+            overrides: Default::default(),
+            workspace_roots: vec![],
+            client_capabilities: Default::default(),
+            open_documents: Default::default(),
+            dependents: Default::default(),
+            trace: TraceValue::Off,
+            shutdown_requested: false,
+            request_metrics: Default::default(),
             builtin_url: url.to_owned(),
+            builtin_modified: None,
+            builtin_polled: Instant::now(),
+            next_request_id: 0,
+            next_progress_id: 0,
+            current_request: None,
+            response_cache: Default::default(),
+            queued_messages: VecDeque::new(),
+            watchers_registered: false,
         };
-        let rc = instance.insert_code(url, code);
+        instance.insert_builtins(code, external);
+
+        instance.make_library_locations();
+        instance.load_doc_bundles();
+
+        instance
+    }
 
+    fn insert_builtins(&mut self, code: String, external: bool) {
+        if external {
+            self.builtin_modified = self
+                .builtin_url
+                .to_file_path()
+                .ok()
+                .and_then(|path| fs::metadata(path).ok())
+                .and_then(|meta| meta.modified().ok());
+        }
+
+        let rc = self.insert_code(self.builtin_url.clone(), code);
         rc.borrow_mut().is_builtin = true;
         rc.borrow_mut().external_builtin = external;
+        rc.borrow_mut().owner = Owner::Server;
+    }
 
-        instance.make_library_locations();
+    // Re-read the external builtin file, the open files are re-diagnosed with the new symbols.
+    pub(crate) fn reload_builtins(&mut self) -> Result<(), String> {
+        if self.args.builtin == BUILTIN_PATH {
+            return Err("the builtin file included in the binary is used".to_owned());
+        }
+        let code = read_to_string(&self.args.builtin)
+            .map_err(|err| format!("failed to read {}: {}", self.args.builtin, err))?;
+        self.insert_builtins(code, true);
+        log_to_console!("reloaded the builtin file {}", self.args.builtin);
+
+        for url in self.open_documents.clone() {
+            if let Some(file) = self.codes.get(&url).cloned() {
+                self.publish_diagnostics(&file, None);
+            }
+        }
+        Ok(())
+    }
 
-        instance
+    // Reload the external builtin file if it was modified since it was read.
+    fn poll_builtins(&mut self) {
+        if self.args.builtin == BUILTIN_PATH
+            || self.builtin_polled.elapsed() < BUILTIN_POLL_INTERVAL
+        {
+            return;
+        }
+        self.builtin_polled = Instant::now();
+
+        let modified = fs::metadata(&self.args.builtin)
+            .ok()
+            .and_then(|meta| meta.modified().ok());
+        if modified.is_some() && modified != self.builtin_modified {
+            if let Err(err) = self.reload_builtins() {
+                err_to_console!("{}", err);
+            }
+        }
+    }
+
+    // Load the bundles of --doc-bundle and of the doc_bundles setting again, the removed or
+    // edited ones are taken into account.
+    pub(crate) fn load_doc_bundles(&mut self) {
+        let mut bundles = DocBundles::default();
+        for path in self
+            .args
+            .doc_bundle
+            .iter()
+            .chain(self.doc_bundle_paths.iter())
+        {
+            let path = expand_path(path);
+            if path.trim().is_empty() {
+                continue;
+            }
+            if let Err(err) = bundles.load(&path) {
+                err_to_console!("failed to load doc bundle {}", err);
+            }
+        }
+
+        if *self.doc_bundles.borrow() == bundles {
+            return;
+        }
+        *self.doc_bundles.borrow_mut() = bundles;
+
+        for code in self.codes.values() {
+            code.borrow_mut().changed = true;
+        }
     }
 
     pub(crate) fn user_defined_library_locations() -> Vec<String> {
@@ -101,6 +325,7 @@ impl Server {
         }
     }
 
+    #[allow(clippy::join_absolute_paths)]
     pub(crate) fn built_in_library_location() -> Option<String> {
         if let Some(userdir) = UserDirs::new() {
             let lib_path = if cfg!(target_os = "windows") {
@@ -140,78 +365,157 @@ impl Server {
         }
     }
 
+    // The library locations in the search order of OpenSCAD: OPENSCADPATH, then the built-in
+    // locations. The configured search paths are searched like OPENSCADPATH entries.
     pub(crate) fn make_library_locations(&mut self) {
-        let mut ret = Self::user_defined_library_locations();
-        ret.extend(Self::built_in_library_location());
-        ret.extend(Self::installation_library_location());
-
-        self.extend_libs(ret);
-    }
-
-    pub(crate) fn extend_libs(&mut self, userlibs: Vec<String>) {
-        let ret: Vec<Url> = userlibs
-            .into_iter()
-            .map(|lib| shellexpand::tilde(&lib).to_string())
-            .filter_map(|p| {
-                if p.is_empty() {
-                    return None;
-                }
-
-                let mut path = format!("file://{}", p);
-                if !path.ends_with('/') {
-                    path.push('/');
-                }
-
-                if let Ok(uri) = Url::parse(&path) {
-                    if let Ok(path) = uri.to_file_path() {
-                        if path.exists() {
-                            return Some(uri);
-                        }
-                    }
-                };
-
-                None
-            })
+        let mut ret: Vec<PathBuf> = Self::user_defined_library_locations()
+            .iter()
+            .chain(self.search_paths.iter())
+            .filter(|lib| !lib.trim().is_empty())
+            .filter_map(|lib| resolve_path(lib, "search path"))
             .collect();
+        ret.extend(
+            Self::built_in_library_location()
+                .into_iter()
+                .chain(Self::installation_library_location())
+                .map(PathBuf::from)
+                .filter(|path| path.exists()),
+        );
+
+        let mut libs: Vec<Url> = vec![];
+        for uri in ret
+            .into_iter()
+            .filter_map(|path| Url::from_directory_path(path).ok())
+        {
+            if !libs.contains(&uri) {
+                libs.push(uri);
+            }
+        }
 
-        if !ret.is_empty() {
+        if *self.library_locations.borrow() == libs {
+            return;
+        }
+
+        if !libs.is_empty() {
             eprintln!();
             log_to_console!("search paths:");
 
-            for lib in ret {
-                log_to_console!("{}", &lib);
-                if !self.library_locations.borrow().contains(&lib) {
-                    self.library_locations.borrow_mut().push(lib);
-                }
+            for lib in &libs {
+                log_to_console!("{}", lib);
             }
 
             eprintln!();
         }
+
+        *self.library_locations.borrow_mut() = libs;
+
+        // The include paths may resolve to other files.
+        for code in self.codes.values() {
+            code.borrow_mut().changed = true;
+        }
+    }
+
+    pub(crate) fn set_search_paths(&mut self, paths: Vec<String>) {
+        self.search_paths = paths;
+        self.make_library_locations();
     }
 
     pub(crate) fn main_loop(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
         let caps = serde_json::to_value(ServerCapabilities {
-            text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                TextDocumentSyncKind::INCREMENTAL,
+            text_document_sync: Some(TextDocumentSyncCapability::Options(
+                TextDocumentSyncOptions {
+                    open_close: Some(true),
+                    change: Some(TextDocumentSyncKind::INCREMENTAL),
+                    will_save: Some(true),
+                    will_save_wait_until: Some(true),
+                    save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                },
             )),
-            completion_provider: Some(Default::default()),
+            completion_provider: Some(CompletionOptions {
+                resolve_provider: Some(true),
+                ..Default::default()
+            }),
             definition_provider: Some(OneOf::Left(true)),
+            implementation_provider: Some(ImplementationProviderCapability::Simple(true)),
+            references_provider: Some(OneOf::Left(true)),
             hover_provider: Some(HoverProviderCapability::Simple(true)),
             document_symbol_provider: Some(OneOf::Left(true)),
+            folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+            workspace_symbol_provider: Some(OneOf::Left(true)),
             document_formatting_provider: Some(OneOf::Left(true)),
             rename_provider: Some(OneOf::Right(RenameOptions {
                 prepare_provider: Some(true),
                 work_done_progress_options: WorkDoneProgressOptions::default(),
             })),
+            document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                first_trigger_character: "\n".to_owned(),
+                more_trigger_character: None,
+            }),
+            linked_editing_range_provider: Some(LinkedEditingRangeServerCapabilities::Simple(true)),
+            signature_help_provider: Some(SignatureHelpOptions {
+                trigger_characters: Some(
+                    ["(", "[", ",", ":", ";", "="]
+                        .iter()
+                        .map(|c| c.to_string())
+                        .collect(),
+                ),
+                retrigger_characters: None,
+                work_done_progress_options: Default::default(),
+            }),
+            code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+            code_lens_provider: Some(CodeLensOptions {
+                resolve_provider: Some(false),
+            }),
+            execute_command_provider: Some(ExecuteCommandOptions {
+                commands: COMMANDS.iter().map(|&(cmd, _)| cmd.to_owned()).collect(),
+                work_done_progress_options: WorkDoneProgressOptions::default(),
+            }),
+            workspace: Some(WorkspaceServerCapabilities {
+                workspace_folders: None,
+                file_operations: Some(WorkspaceFileOperationsServerCapabilities {
+                    will_rename: Some(FileOperationRegistrationOptions {
+                        filters: vec![FileOperationFilter {
+                            scheme: Some("file".to_owned()),
+                            pattern: FileOperationPattern {
+                                glob: "**".to_owned(),
+                                ..Default::default()
+                            },
+                        }],
+                    }),
+                    ..Default::default()
+                }),
+            }),
+            experimental: Some(experimental_capabilities()),
             ..Default::default()
         })?;
-        self.connection.initialize(caps)?;
-        while let Ok(msg) = self.connection.receiver.recv() {
-            match self.handle_message(msg)? {
-                LoopAction::Continue => {}
-                LoopAction::Exit => break,
+        let params = self.connection.initialize(caps)?;
+        self.set_workspace(params);
+        self.register_file_watchers();
+        loop {
+            let msg = match self.queued_messages.pop_front() {
+                Some(msg) => Ok(msg),
+                None => self.connection.receiver.recv_timeout(BUILTIN_POLL_INTERVAL),
+            };
+            match msg {
+                Ok(msg) => match self.handle_message(msg)? {
+                    LoopAction::Continue => {}
+                    LoopAction::Exit => break,
+                },
+                // The client disconnected without exit.
+                Err(err) if err.is_disconnected() => break,
+                Err(_) => {}
             }
+            self.poll_builtins();
         }
         Ok(())
     }
+
+    // Release the state of the subsystems, the client is still connected.
+    pub(crate) fn shutdown(&mut self) {
+        self.shutdown_requested = true;
+        self.unregister_file_watchers();
+        self.codes.clear();
+        self.dependents.clear();
+        self.open_documents.clear();
+    }
 }
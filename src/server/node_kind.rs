@@ -0,0 +1,227 @@
+use lazy_static::lazy_static;
+use tree_sitter::Node;
+
+// The named node kinds of the tree-sitter-openscad grammar, so that a kind renamed upstream is a
+// compile error here instead of a string silently never matching across the modules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum NodeKind {
+    Arguments,
+    AssertExpression,
+    AssertStatement,
+    AssignBlock,
+    Assignment,
+    BinaryExpression,
+    Boolean,
+    Comment,
+    ConditionUpdateClause,
+    DotIndexExpression,
+    Each,
+    ForBlock,
+    ForClause,
+    Function,
+    FunctionCall,
+    FunctionDeclaration,
+    Identifier,
+    IfBlock,
+    IfClause,
+    IncludePath,
+    IncludeStatement,
+    IndexExpression,
+    IntersectionForBlock,
+    LetBlock,
+    LetExpression,
+    List,
+    ListComprehension,
+    Modifier,
+    ModifierChain,
+    ModuleCall,
+    ModuleDeclaration,
+    Number,
+    ParametersDeclaration,
+    ParenthesizedAssignments,
+    ParenthesizedExpression,
+    Range,
+    SourceFile,
+    SpecialVariable,
+    String,
+    TernaryExpression,
+    TransformChain,
+    UnaryExpression,
+    Undef,
+    UnionBlock,
+    UseStatement,
+    // The anonymous tokens, `(`, `+`, the keywords, and the errors.
+    Other,
+}
+
+impl NodeKind {
+    pub(crate) const ALL: &'static [NodeKind] = &[
+        NodeKind::Arguments,
+        NodeKind::AssertExpression,
+        NodeKind::AssertStatement,
+        NodeKind::AssignBlock,
+        NodeKind::Assignment,
+        NodeKind::BinaryExpression,
+        NodeKind::Boolean,
+        NodeKind::Comment,
+        NodeKind::ConditionUpdateClause,
+        NodeKind::DotIndexExpression,
+        NodeKind::Each,
+        NodeKind::ForBlock,
+        NodeKind::ForClause,
+        NodeKind::Function,
+        NodeKind::FunctionCall,
+        NodeKind::FunctionDeclaration,
+        NodeKind::Identifier,
+        NodeKind::IfBlock,
+        NodeKind::IfClause,
+        NodeKind::IncludePath,
+        NodeKind::IncludeStatement,
+        NodeKind::IndexExpression,
+        NodeKind::IntersectionForBlock,
+        NodeKind::LetBlock,
+        NodeKind::LetExpression,
+        NodeKind::List,
+        NodeKind::ListComprehension,
+        NodeKind::Modifier,
+        NodeKind::ModifierChain,
+        NodeKind::ModuleCall,
+        NodeKind::ModuleDeclaration,
+        NodeKind::Number,
+        NodeKind::ParametersDeclaration,
+        NodeKind::ParenthesizedAssignments,
+        NodeKind::ParenthesizedExpression,
+        NodeKind::Range,
+        NodeKind::SourceFile,
+        NodeKind::SpecialVariable,
+        NodeKind::String,
+        NodeKind::TernaryExpression,
+        NodeKind::TransformChain,
+        NodeKind::UnaryExpression,
+        NodeKind::Undef,
+        NodeKind::UnionBlock,
+        NodeKind::UseStatement,
+    ];
+
+    // The kind in the grammar.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            NodeKind::Arguments => "arguments",
+            NodeKind::AssertExpression => "assert_expression",
+            NodeKind::AssertStatement => "assert_statement",
+            NodeKind::AssignBlock => "assign_block",
+            NodeKind::Assignment => "assignment",
+            NodeKind::BinaryExpression => "binary_expression",
+            NodeKind::Boolean => "boolean",
+            NodeKind::Comment => "comment",
+            NodeKind::ConditionUpdateClause => "condition_update_clause",
+            NodeKind::DotIndexExpression => "dot_index_expression",
+            NodeKind::Each => "each",
+            NodeKind::ForBlock => "for_block",
+            NodeKind::ForClause => "for_clause",
+            NodeKind::Function => "function",
+            NodeKind::FunctionCall => "function_call",
+            NodeKind::FunctionDeclaration => "function_declaration",
+            NodeKind::Identifier => "identifier",
+            NodeKind::IfBlock => "if_block",
+            NodeKind::IfClause => "if_clause",
+            NodeKind::IncludePath => "include_path",
+            NodeKind::IncludeStatement => "include_statement",
+            NodeKind::IndexExpression => "index_expression",
+            NodeKind::IntersectionForBlock => "intersection_for_block",
+            NodeKind::LetBlock => "let_block",
+            NodeKind::LetExpression => "let_expression",
+            NodeKind::List => "list",
+            NodeKind::ListComprehension => "list_comprehension",
+            NodeKind::Modifier => "modifier",
+            NodeKind::ModifierChain => "modifier_chain",
+            NodeKind::ModuleCall => "module_call",
+            NodeKind::ModuleDeclaration => "module_declaration",
+            NodeKind::Number => "number",
+            NodeKind::ParametersDeclaration => "parameters_declaration",
+            NodeKind::ParenthesizedAssignments => "parenthesized_assignments",
+            NodeKind::ParenthesizedExpression => "parenthesized_expression",
+            NodeKind::Range => "range",
+            NodeKind::SourceFile => "source_file",
+            NodeKind::SpecialVariable => "special_variable",
+            NodeKind::String => "string",
+            NodeKind::TernaryExpression => "ternary_expression",
+            NodeKind::TransformChain => "transform_chain",
+            NodeKind::UnaryExpression => "unary_expression",
+            NodeKind::Undef => "undef",
+            NodeKind::UnionBlock => "union_block",
+            NodeKind::UseStatement => "use_statement",
+            NodeKind::Other => "",
+        }
+    }
+
+    pub(crate) fn is_include_statement(self) -> bool {
+        matches!(self, NodeKind::IncludeStatement | NodeKind::UseStatement)
+    }
+
+    pub(crate) fn is_comment(self) -> bool {
+        self == NodeKind::Comment
+    }
+}
+
+impl From<&str> for NodeKind {
+    fn from(kind: &str) -> Self {
+        NodeKind::ALL
+            .iter()
+            .find(|node_kind| node_kind.as_str() == kind)
+            .copied()
+            .unwrap_or(NodeKind::Other)
+    }
+}
+
+lazy_static! {
+    // The kind of each kind id of the grammar, the anonymous ones are `Other` even when named like
+    // a named one, like the `function` keyword.
+    static ref KINDS_BY_ID: Vec<NodeKind> = {
+        let language = tree_sitter_openscad::language();
+        (0..language.node_kind_count() as u16)
+            .map(|id| match language.node_kind_for_id(id) {
+                Some(kind) if language.node_kind_is_named(id) => NodeKind::from(kind),
+                _ => NodeKind::Other,
+            })
+            .collect()
+    };
+}
+
+pub(crate) trait NodeKindExt {
+    fn node_kind(&self) -> NodeKind;
+}
+
+impl NodeKindExt for Node<'_> {
+    fn node_kind(&self) -> NodeKind {
+        KINDS_BY_ID
+            .get(self.kind_id() as usize)
+            .copied()
+            .unwrap_or(NodeKind::Other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each kind must still be a named node type of the grammar after updating it.
+    #[test]
+    fn node_kinds_are_in_grammar() {
+        let node_types: Vec<serde_json::Value> =
+            serde_json::from_str(tree_sitter_openscad::NODE_TYPES).unwrap();
+        let language = tree_sitter_openscad::language();
+        for kind in NodeKind::ALL {
+            let in_node_types = node_types
+                .iter()
+                .any(|node_type| node_type["type"] == kind.as_str() && node_type["named"] == true);
+            assert!(in_node_types, "{} is not in node-types.json", kind.as_str());
+            assert_ne!(
+                language.id_for_node_kind(kind.as_str(), true),
+                0,
+                "{} is not in the grammar",
+                kind.as_str()
+            );
+        }
+    }
+}
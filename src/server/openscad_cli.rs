@@ -0,0 +1,500 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    env, fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt;
+
+use lazy_static::lazy_static;
+use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range, Url};
+use regex::Regex;
+
+use crate::{
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    server::Server,
+    utils::{base64_encode, node_text, resolve_exe},
+};
+
+// A slow model is stopped after the timeout, the renderings run in background threads, at most
+// `MAX_THUMBNAIL_RENDERS` at a time.
+const THUMBNAIL_TIMEOUT: Duration = Duration::from_secs(10);
+const THUMBNAIL_SIZE: u32 = 256;
+const MAX_THUMBNAILS: usize = 100;
+const MAX_THUMBNAIL_RENDERS: usize = 2;
+// The expressions are evaluated in the message loop too.
+const EVAL_TIMEOUT: Duration = Duration::from_secs(5);
+// The name of the echoed value of an evaluated expression.
+const EVAL_NAME: &str = "__openscad_lsp_eval";
+
+// Helpers running the OpenSCAD executable.
+impl Server {
+    pub(crate) fn openscad_command(&self) -> Command {
+        Command::new(resolve_exe(&self.args.openscad_exe))
+    }
+
+    // The `-D` options of the variables overridden for the file with openscad/setOverrides.
+    fn override_args(&self, path: &Path) -> Vec<String> {
+        let Some(overrides) = Url::from_file_path(path)
+            .ok()
+            .and_then(|url| self.overrides.get(&url))
+        else {
+            return vec![];
+        };
+        let mut args: Vec<String> = overrides
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value.literal()))
+            .collect();
+        args.sort();
+        args.into_iter()
+            .flat_map(|define| ["-D".to_owned(), define])
+            .collect()
+    }
+
+    // Launch the OpenSCAD GUI with the file, returns false if the file is already opened by a
+    // running instance launched from here.
+    pub(crate) fn open_in_app(&mut self, path: &Path) -> Result<bool, String> {
+        if let Some(child) = self.openscad_apps.get_mut(path) {
+            if let Ok(None) = child.try_wait() {
+                return Ok(false);
+            }
+        }
+        self.openscad_apps
+            .retain(|_, child| matches!(child.try_wait(), Ok(None)));
+
+        let overrides = self.override_args(path);
+        let mut cmd = if cfg!(target_os = "macos") && self.args.openscad_exe == "openscad" {
+            // The app bundle is usually not in PATH, `open` also brings it to the front.
+            let mut cmd = Command::new("open");
+            cmd.arg("-a").arg("OpenSCAD").arg(path);
+            if !overrides.is_empty() {
+                cmd.arg("--args");
+            }
+            cmd
+        } else {
+            let mut cmd = self.openscad_command();
+            cmd.arg(path);
+            cmd
+        };
+
+        cmd.args(overrides)
+            .current_dir(path.parent().unwrap_or(Path::new(".")))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        #[cfg(target_os = "windows")]
+        {
+            const DETACHED_PROCESS: u32 = 0x00000008;
+            cmd.creation_flags(DETACHED_PROCESS);
+        }
+
+        let child = cmd
+            .spawn()
+            .map_err(|err| format!("{}: {}", &self.args.openscad_exe, err))?;
+        self.openscad_apps.insert(path.to_owned(), child);
+        Ok(true)
+    }
+
+    // Run the file headless, the assertion failures, warnings and echo outputs are returned as
+    // diagnostics of the file. The lines are those of the saved file, it must have no unsaved
+    // changes.
+    pub(crate) fn run_openscad_checks(
+        &self,
+        path: &Path,
+        code: &ParsedCode,
+    ) -> Result<Vec<Diagnostic>, String> {
+        if fs::read_to_string(path).map_err(|err| err.to_string())? != code.code {
+            return Err("the file has unsaved changes, save it to run the checks".to_owned());
+        }
+        let out_file = temp_base("check").with_extension("echo");
+
+        let output = self
+            .openscad_command()
+            .arg("-o")
+            .arg(&out_file)
+            .args(self.override_args(path))
+            .arg(path)
+            .current_dir(path.parent().unwrap_or(Path::new(".")))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|err| format!("{}: {}", &self.args.openscad_exe, err))?;
+        let _ = std::fs::remove_file(&out_file);
+
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        Ok(parse_openscad_output(
+            &String::from_utf8_lossy(&output.stderr),
+            file_name,
+            code,
+        ))
+    }
+
+    // The version printed by `openscad --version`, `OpenSCAD version 2021.01`.
+    pub(crate) fn openscad_version(&mut self) -> Option<String> {
+        if self.openscad_version.is_none() {
+            let version = self
+                .openscad_command()
+                .arg("--version")
+                .stdin(Stdio::null())
+                .output()
+                .ok()
+                .and_then(|output| {
+                    let text = String::from_utf8_lossy(&output.stderr).into_owned()
+                        + &String::from_utf8_lossy(&output.stdout);
+                    text.split_whitespace()
+                        .skip_while(|word| *word != "version")
+                        .nth(1)
+                        .map(str::to_owned)
+                });
+            self.openscad_version = Some(version);
+        }
+        self.openscad_version.clone().flatten()
+    }
+
+    // Evaluate `expression` in a background thread, OpenSCAD may take seconds to start. `done` is
+    // called from that thread with the result.
+    pub(crate) fn evaluate_in_background(
+        &self,
+        expression: String,
+        library: Option<PathBuf>,
+        done: impl FnOnce(Result<String, String>) + Send + 'static,
+    ) {
+        let command = self.openscad_command();
+        thread::spawn(move || {
+            done(evaluate_expression(
+                command,
+                &expression,
+                library.as_deref(),
+            ))
+        });
+    }
+
+    // A png thumbnail of the module of the file, called with its default arguments, as a data uri.
+    // The results are cached by content, a module is rendered again only when its file changes.
+    // The rendering runs in the background from a copy of `code`, none until it is done.
+    pub(crate) fn module_thumbnail(
+        &mut self,
+        path: &Path,
+        code: &str,
+        module: &str,
+    ) -> Option<String> {
+        let mut hasher = DefaultHasher::new();
+        (path, code, module).hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut state = self.thumbnails.0.lock().unwrap();
+        if let Some(thumbnail) = state.rendered.get(&key) {
+            return thumbnail.clone();
+        }
+        if state.rendering.contains(&key) || state.rendering.len() >= MAX_THUMBNAIL_RENDERS {
+            return None;
+        }
+        state.rendering.insert(key);
+        drop(state);
+
+        let thumbnails = self.thumbnails.clone();
+        let exe = resolve_exe(&self.args.openscad_exe);
+        let (path, code, module) = (path.to_owned(), code.to_owned(), module.to_owned());
+        thread::spawn(move || {
+            let thumbnail = render_thumbnail(&exe, &path, &code, &module);
+            if let Err(err) = &thumbnail {
+                err_to_console!("failed to render the thumbnail of {}: {}", module, err);
+            }
+            let mut state = thumbnails.0.lock().unwrap();
+            state.rendering.remove(&key);
+            if state.rendered.len() >= MAX_THUMBNAILS {
+                state.rendered.clear();
+            }
+            state.rendered.insert(key, thumbnail.ok());
+        });
+        None
+    }
+}
+
+// The rendered module thumbnails, shared with the threads rendering them.
+#[derive(Clone, Default)]
+pub(crate) struct Thumbnails(Arc<Mutex<ThumbnailState>>);
+
+#[derive(Default)]
+struct ThumbnailState {
+    // The data uris by hash of the file, its content and the module, None if the rendering failed.
+    rendered: HashMap<u64, Option<String>>,
+    rendering: HashSet<u64>,
+}
+
+// The value of `expression` as printed by echo(), with the functions of the `library` file.
+// The warnings are errors.
+fn evaluate_expression(
+    mut command: Command,
+    expression: &str,
+    library: Option<&Path>,
+) -> Result<String, String> {
+    let base = temp_base("eval");
+    let scad_file = base.with_extension("scad");
+    let echo_file = base.with_extension("echo");
+    let log_file = base.with_extension("log");
+
+    // `use` doesn't run the top level statements of the file.
+    let header = library.map_or(String::new(), |path| format!("use <{}>\n", path.display()));
+    fs::write(
+        &scad_file,
+        format!("{}echo({} = ({}\n));\n", header, EVAL_NAME, expression),
+    )
+    .map_err(|err| err.to_string())?;
+    let log = fs::File::create(&log_file).map_err(|err| err.to_string())?;
+
+    let exe = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .arg("--hardwarnings")
+        .arg("-o")
+        .arg(&echo_file)
+        .arg(&scad_file)
+        .current_dir(library.and_then(Path::parent).unwrap_or(&env::temp_dir()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .spawn()
+        .map_err(|err| format!("{}: {}", exe, err))?;
+    let status = wait_timeout(&mut child, EVAL_TIMEOUT);
+
+    let echo = fs::read_to_string(&echo_file).unwrap_or_default();
+    let log = fs::read_to_string(&log_file).unwrap_or_default();
+    for file in [&scad_file, &echo_file, &log_file] {
+        let _ = fs::remove_file(file);
+    }
+    status?;
+
+    let prefix = format!("ECHO: {} = ", EVAL_NAME);
+    let errors: Vec<&str> = log
+        .lines()
+        .filter(|line| line.starts_with("ERROR:") || line.starts_with("WARNING:"))
+        .collect();
+    match echo.lines().find_map(|line| line.strip_prefix(&prefix)) {
+        Some(value) if errors.is_empty() => Ok(value.to_owned()),
+        _ if !errors.is_empty() => Err(errors.join("\n")),
+        _ => Err("openscad didn't print the value".to_owned()),
+    }
+}
+
+// A temporary file name without extension, unique to the call: the servers of
+// `--clients concurrent` are threads of the same process.
+fn temp_base(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    env::temp_dir().join(format!(
+        "openscad-lsp-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Render `module` of the file at `path` whose content is `code`. An unsaved content is rendered
+// from a copy, whose relative includes are found through OPENSCADPATH.
+fn render_thumbnail(exe: &str, path: &Path, code: &str, module: &str) -> Result<String, String> {
+    let base = temp_base("thumbnail");
+    let copy_file = base.with_extension("copy.scad");
+    let scad_file = base.with_extension("scad");
+    let png_file = base.with_extension("png");
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    // The saved file is used as is, its imports stay relative to its directory.
+    let is_saved = fs::read_to_string(path).is_ok_and(|saved| saved == code);
+    let source = if is_saved { path } else { &copy_file };
+    // `use` doesn't render the top level objects of the file.
+    let written = (if is_saved {
+        Ok(())
+    } else {
+        fs::write(&copy_file, code)
+    })
+    .and_then(|_| {
+        fs::write(
+            &scad_file,
+            format!("use <{}>\n{}();\n", source.display(), module),
+        )
+    });
+    let search_path = env::join_paths(
+        std::iter::once(dir.to_owned()).chain(
+            env::var_os("OPENSCADPATH")
+                .iter()
+                .flat_map(env::split_paths),
+        ),
+    )
+    .map_err(|err| err.to_string());
+
+    let status = written
+        .map_err(|err| err.to_string())
+        .and_then(|_| {
+            Command::new(exe)
+                .arg("-o")
+                .arg(&png_file)
+                .arg(format!("--imgsize={},{}", THUMBNAIL_SIZE, THUMBNAIL_SIZE))
+                .arg("--viewall")
+                .arg("--autocenter")
+                .arg(&scad_file)
+                .env("OPENSCADPATH", search_path?)
+                .current_dir(dir)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(|err| format!("{}: {}", exe, err))
+        })
+        .and_then(|mut child| wait_timeout(&mut child, THUMBNAIL_TIMEOUT));
+
+    let png = status.and_then(|status| {
+        if !status.success() {
+            return Err(format!("openscad exited with {}", status));
+        }
+        fs::read(&png_file).map_err(|err| err.to_string())
+    });
+    for file in [&copy_file, &scad_file, &png_file] {
+        let _ = fs::remove_file(file);
+    }
+    Ok(format!("data:image/png;base64,{}", base64_encode(&png?)))
+}
+
+// Wait for `child` to exit, killing it after `timeout`.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus, String> {
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) if start.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err("timed out".to_owned());
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+}
+
+// The echo outputs don't have a location, they are assigned in order to the top level echo()
+// calls, the remaining ones are put on the first line.
+fn parse_openscad_output(output: &str, file_name: &str, code: &ParsedCode) -> Vec<Diagnostic> {
+    lazy_static! {
+        static ref LOCATION_RE: Regex =
+            Regex::new(r#"in file "?(?P<file>[^,"]+)"?, line (?P<line>\d+)"#).unwrap();
+    };
+
+    let line_range = |line: usize| Range {
+        start: Position {
+            line: line as u32,
+            character: 0,
+        },
+        end: Position {
+            line: line as u32,
+            character: code
+                .code
+                .lines()
+                .nth(line)
+                .map_or(0, |text| text.encode_utf16().count() as u32),
+        },
+    };
+
+    // The top level statements starting with an echo() call, `echo(x);` or `echo(x) cube(x);`.
+    let root = code.tree.root_node();
+    let mut echo_lines = root
+        .named_children(&mut root.walk())
+        .filter(|node| node.node_kind() == NodeKind::TransformChain)
+        .filter_map(|node| node.named_child(0))
+        .filter(|call| {
+            call.node_kind() == NodeKind::ModuleCall
+                && call
+                    .child_by_field_name("name")
+                    .is_some_and(|name| node_text(&code.code, &name) == "echo")
+        })
+        .map(|call| call.start_position().row)
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let mut result = vec![];
+    for text in output.lines() {
+        let (severity, message) = if let Some(msg) = text.strip_prefix("ECHO: ") {
+            (DiagnosticSeverity::INFORMATION, msg)
+        } else if let Some(msg) = text.strip_prefix("WARNING: ") {
+            (DiagnosticSeverity::WARNING, msg)
+        } else if let Some(msg) = text.strip_prefix("ERROR: ") {
+            (DiagnosticSeverity::ERROR, msg)
+        } else {
+            continue;
+        };
+
+        let line = if severity == DiagnosticSeverity::INFORMATION {
+            echo_lines.next().unwrap_or(0)
+        } else {
+            LOCATION_RE
+                .captures(message)
+                .filter(|cap| Path::new(&cap["file"]).ends_with(file_name))
+                .and_then(|cap| cap["line"].parse::<usize>().ok())
+                .map_or(0, |line| line.saturating_sub(1))
+        };
+
+        result.push(Diagnostic {
+            range: line_range(line),
+            severity: Some(severity),
+            source: Some("openscad".to_owned()),
+            message: message.to_owned(),
+            ..Default::default()
+        });
+    }
+    result
+}
+
+// Whether the version `version` (`2019.05`, `2021.01.24`) is older than `than`.
+pub(crate) fn is_older_version(version: &str, than: &str) -> bool {
+    let parse = |version: &str| -> Vec<u32> {
+        version
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|part| part.parse().ok())
+            .collect()
+    };
+    parse(version) < parse(than)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn echo_outputs_on_top_level_echoes() {
+        let text = "module m() {\n    echo(\"in m\");\n}\necho(1);\n  echo(2) m();\n";
+        let code = ParsedCode::new(
+            text.to_owned(),
+            Url::parse("file:///tmp/t.scad").unwrap(),
+            Rc::new(RefCell::new(vec![])),
+            Default::default(),
+        );
+        let output = "ECHO: 1\nECHO: 2\nECHO: \"in m\"\n\
+                      WARNING: Ignoring unknown variable 'x' in file t.scad, line 2\n";
+        let lines: Vec<(u32, DiagnosticSeverity)> = parse_openscad_output(output, "t.scad", &code)
+            .iter()
+            .map(|diag| (diag.range.start.line, diag.severity.unwrap()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (3, DiagnosticSeverity::INFORMATION),
+                (4, DiagnosticSeverity::INFORMATION),
+                (0, DiagnosticSeverity::INFORMATION),
+                (1, DiagnosticSeverity::WARNING),
+            ]
+        );
+    }
+}
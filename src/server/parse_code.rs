@@ -1,13 +1,33 @@
-use std::{cell::RefCell, path::PathBuf, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use lazy_static::lazy_static;
-use lsp_types::{TextDocumentContentChangeEvent, Url};
-use tree_sitter::{InputEdit, Node, Point, Tree, TreeCursor};
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, FoldingRange, FoldingRangeKind, NumberOrString,
+    SymbolKind, TextDocumentContentChangeEvent, TextEdit, Url,
+};
+use tree_sitter::{InputEdit, Node, Parser, Point, Tree, TreeCursor};
+use tree_sitter_traversal::{traverse, Order};
 
+use crate::builtin_meta::BuiltinMeta;
+use crate::customizer;
+use crate::diagnostics::SPECIAL_VARIABLES;
+use crate::doc_bundle::DocBundles;
+use crate::node_kind::{NodeKind, NodeKindExt};
 use crate::response_item::{Item, ItemKind};
+use crate::syntax_rules::{error_hint, fix_data};
 use crate::utils::*;
 use regex::Regex;
 
+// The length of the names of the enclosing blocks, their header.
+const MAX_SYMBOL_HEADER: usize = 60;
+
 const KEYWORDS: &[(&str, &str)] = &[
     ("else", "else {  $0\n}"),
     ("false", "false"),
@@ -23,89 +43,345 @@ const KEYWORDS: &[(&str, &str)] = &[
     ("each", "each ${1:LIST}$0"),
 ];
 
+thread_local! {
+    // The parsers are shared by the files, a parser keeps large buffers between the parses.
+    static PARSERS: RefCell<Vec<Parser>> = const { RefCell::new(vec![]) };
+    static PARSER_COUNT: Cell<usize> = const { Cell::new(0) };
+}
+
+// Parse with an idle parser of the pool, created if they're all busy.
+pub(crate) fn parse(code: &str, old_tree: Option<&Tree>) -> Tree {
+    let mut parser = PARSERS
+        .with(|parsers| parsers.borrow_mut().pop())
+        .unwrap_or_else(|| {
+            PARSER_COUNT.with(|count| count.set(count.get() + 1));
+            let mut parser = Parser::new();
+            parser
+                .set_language(tree_sitter_openscad::language())
+                .expect("Error loading openscad grammar");
+            parser
+        });
+    let tree = parser.parse(code, old_tree).unwrap();
+    PARSERS.with(|parsers| parsers.borrow_mut().push(parser));
+    tree
+}
+
+// The number of parsers created, and of those idle in the pool.
+pub(crate) fn parser_counts() -> (usize, usize) {
+    (
+        PARSER_COUNT.with(Cell::get),
+        PARSERS.with(|parsers| parsers.borrow().len()),
+    )
+}
+
+// Who has the authoritative text of a cached file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Owner {
+    // Open in the editor, changed only by its notifications, never read from the disk.
+    Client,
+    // Read from the disk, read again when its modification time changes or a watcher reports it.
+    Disk {
+        modified: Option<SystemTime>,
+        checked: Instant,
+    },
+    // The builtin file, reloaded on its own.
+    Server,
+}
+
+// The modification time of a disk file is checked at most this often.
+const DISK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) const SYNTAX_ERROR: &str = "syntax-error";
+
+// The parent of a node in the subtrees with errors, `Node::parent` is wrong for the zero width
+// nodes inserted by the parser.
+fn error_parent<'a>(ancestor: Node<'a>, node: &Node) -> Option<Node<'a>> {
+    for child in ancestor.children(&mut ancestor.walk()) {
+        if child == *node {
+            return Some(ancestor);
+        }
+        let contains =
+            child.start_byte() <= node.start_byte() && node.end_byte() <= child.end_byte();
+        if contains && child.has_error() {
+            if let Some(parent) = error_parent(child, node) {
+                return Some(parent);
+            }
+        }
+    }
+    None
+}
+
+// The message of a node inserted by the parser to recover from an error, from the token and what
+// it would close or end.
+fn missing_message(root: Node, node: &Node) -> String {
+    let parent = error_parent(root, node).map_or(NodeKind::Other, |parent| parent.node_kind());
+    let context = match (node.kind(), parent) {
+        (";", _) => "after the statement",
+        (")", NodeKind::Arguments) => "to close the argument list",
+        (")", NodeKind::ParametersDeclaration) => "to close the parameter list",
+        (")", NodeKind::ParenthesizedExpression) => "to close the parentheses",
+        (")", NodeKind::ParenthesizedAssignments) => "to close the assignments",
+        (")", _) => "",
+        ("]", NodeKind::List) => "to close the vector",
+        ("]", NodeKind::Range) => "to close the range",
+        ("]", NodeKind::IndexExpression) => "to close the index",
+        ("]", _) => "",
+        ("}", _) => "to close the block",
+        // The named nodes stand for what is expected rather than a token.
+        (_, NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration) if node.is_named() => {
+            return "missing name".to_owned();
+        }
+        _ if node.is_named() => return "missing expression".to_owned(),
+        _ => "",
+    };
+    match context {
+        "" => format!("missing `{}`", node.kind()),
+        context => format!("missing `{}` {}", node.kind(), context),
+    }
+}
+
 pub(crate) struct ParsedCode {
-    pub parser: tree_sitter::Parser,
     pub code: String,
     pub tree: Tree,
     pub url: Url,
     pub root_items: Option<Vec<Rc<RefCell<Item>>>>,
+    // The text each item of `root_items` depends on, from the end of the previous statement to the
+    // start of the next one, moved along with the edits. None once edited.
+    item_spans: Vec<Option<Range<usize>>>,
+    // The bytes edited, or whose syntax changed, since `root_items` was generated.
+    edited: Vec<Range<usize>>,
+    // The bytes edited since the file was opened or saved, formatted on save with the
+    // format_on_save setting.
+    pub unsaved: Vec<Range<usize>>,
+    // The text inserted by the pastes, the edits inserting several lines, for the includes of
+    // the library symbols they call.
+    pub pasted: Vec<Range<usize>>,
     pub includes: Option<Vec<Url>>,
+    // Whether `includes` was generated again since the server recorded the dependents.
+    pub includes_changed: bool,
+    // The files of `includes` which are only used, not included: their variables are not visible.
+    pub used: HashSet<Url>,
     pub is_builtin: bool,
     pub external_builtin: bool,
     pub changed: bool,
     pub libs: Rc<RefCell<Vec<Url>>>,
+    pub doc_bundles: Rc<RefCell<DocBundles>>,
+    // The version of the client for open files.
+    pub version: Option<i32>,
+    // The content may differ from the client's after an inconsistent change, until the full text
+    // is received again.
+    pub out_of_sync: bool,
+    pub owner: Owner,
 }
 
 impl ParsedCode {
-    pub(crate) fn new(code: String, url: Url, libs: Rc<RefCell<Vec<Url>>>) -> Self {
-        let mut parser = tree_sitter::Parser::new();
-        parser
-            .set_language(tree_sitter_openscad::language())
-            .expect("Error loading openscad grammar");
-        let tree = parser.parse(&code, None).unwrap();
+    pub(crate) fn new(
+        code: String,
+        url: Url,
+        libs: Rc<RefCell<Vec<Url>>>,
+        doc_bundles: Rc<RefCell<DocBundles>>,
+    ) -> Self {
+        let tree = parse(&code, None);
         Self {
-            parser,
             code,
             tree,
             url,
             root_items: None,
+            item_spans: vec![],
+            edited: vec![],
+            unsaved: vec![],
+            pasted: vec![],
             includes: None,
+            includes_changed: false,
+            used: HashSet::new(),
             is_builtin: false,
             external_builtin: false,
             libs,
+            doc_bundles,
             changed: true,
+            version: None,
+            out_of_sync: false,
+            owner: Owner::Disk {
+                modified: None,
+                checked: Instant::now(),
+            },
+        }
+    }
+
+    // Whether the file was modified on the disk since it was read, for the files the editor
+    // doesn't own.
+    pub(crate) fn is_stale(&mut self) -> bool {
+        let Owner::Disk { modified, checked } = &mut self.owner else {
+            return false;
+        };
+        if checked.elapsed() < DISK_POLL_INTERVAL {
+            return false;
         }
+        *checked = Instant::now();
+        let current = self
+            .url
+            .to_file_path()
+            .ok()
+            .and_then(|path| path.metadata().ok())
+            .and_then(|meta| meta.modified().ok());
+        current != *modified
     }
 
-    pub(crate) fn edit(&mut self, events: &[TextDocumentContentChangeEvent]) {
+    // Apply the changes of a didChange notification, returns false if a range didn't match the
+    // text, which is then reparsed from scratch.
+    pub(crate) fn edit(&mut self, events: &[TextDocumentContentChangeEvent]) -> bool {
+        let mut consistent = true;
         let mut old_tree = Some(&mut self.tree);
         for event in events {
-            if let Some(range) = event.range {
-                let start_ofs = find_offset(&self.code, range.start).unwrap();
-                let end_ofs = find_offset(&self.code, range.end).unwrap();
-                self.code.replace_range(start_ofs..end_ofs, &event.text);
-
-                let new_end_position = match event.text.rfind('\n') {
-                    Some(ind) => {
-                        let num_newlines = event.text.bytes().filter(|&c| c == b'\n').count();
-                        Point {
-                            row: range.start.line as usize + num_newlines,
-                            column: event.text.len() - ind,
-                        }
-                    }
-                    None => Point {
-                        row: range.start.line as usize,
-                        column: range.start.character as usize + event.text.len(),
-                    },
-                };
+            let Some(range) = event.range else {
+                old_tree = None;
+                self.code = event.text.clone();
+                self.unsaved.clear();
+                self.unsaved.push(0..self.code.len());
+                self.pasted.clear();
+                self.changed = true;
+                consistent = true;
+                continue;
+            };
+
+            let offsets = find_offset(&self.code, range.start)
+                .zip(find_offset(&self.code, range.end))
+                .filter(|(start, end)| start <= end);
+            let Some((start_ofs, end_ofs)) = offsets else {
+                err_to_console!("invalid edit range {:?} of {}", range, self.url);
+                old_tree = None;
+                self.changed = true;
+                consistent = false;
+                continue;
+            };
+
+            let start_position = offset_to_point(&self.code, start_ofs);
+            let old_end_position = offset_to_point(&self.code, end_ofs);
+            self.code.replace_range(start_ofs..end_ofs, &event.text);
+            move_spans(
+                &mut self.item_spans,
+                &mut self.edited,
+                start_ofs..end_ofs,
+                event.text.len(),
+            );
+            move_spans(
+                &mut [],
+                &mut self.unsaved,
+                start_ofs..end_ofs,
+                event.text.len(),
+            );
+            move_pasted(&mut self.pasted, start_ofs..end_ofs, &event.text);
 
-                old_tree.as_mut().unwrap().edit(&InputEdit {
+            let new_end_position = match event.text.rfind('\n') {
+                Some(ind) => Point {
+                    row: start_position.row + event.text.bytes().filter(|&c| c == b'\n').count(),
+                    column: event.text.len() - ind - 1,
+                },
+                None => Point {
+                    row: start_position.row,
+                    column: start_position.column + event.text.len(),
+                },
+            };
+
+            if let Some(tree) = old_tree.as_mut() {
+                tree.edit(&InputEdit {
                     start_byte: start_ofs,
                     old_end_byte: end_ofs,
                     new_end_byte: start_ofs + event.text.len(),
-                    start_position: to_point(range.start),
-                    old_end_position: to_point(range.end),
+                    start_position,
+                    old_end_position,
                     new_end_position,
                 });
-            } else {
-                old_tree = None;
-                self.code = event.text.clone();
-                break;
             }
         }
 
         let old_tree = old_tree.map(|t| &(*t));
-        let new_tree = self.parser.parse(&self.code, old_tree).unwrap();
+        let new_tree = parse(&self.code, old_tree);
+        // An unclosed comment or string may change the statements after the edits.
+        let reparsed: Vec<Range<usize>> = old_tree
+            .map(|old_tree| {
+                old_tree
+                    .changed_ranges(&new_tree)
+                    .map(|range| range.start_byte..range.end_byte)
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.edited.extend(reparsed);
         self.tree = new_tree;
 
-        self.changed = true;
+        consistent
+    }
+
+    // The include/use statement being typed, `include <BOSL2/st` is an error until its `>`, and
+    // the path typed so far. `node` is the error or one of its children.
+    pub(crate) fn unclosed_include<'a>(&self, node: &Node<'a>) -> Option<(Node<'a>, &str)> {
+        let error = Some(*node)
+            .filter(Node::is_error)
+            .or_else(|| node.parent().filter(Node::is_error))?;
+        let keyword = error.child(0)?;
+        let bracket = error.child(1)?;
+        if !matches!(keyword.kind(), "include" | "use")
+            || bracket.kind() != "<"
+            || error.start_position().row != error.end_position().row
+        {
+            return None;
+        }
+        let path = &self.code[bracket.end_byte()..error.end_byte()];
+        (!path.contains('>')).then_some((error, path))
+    }
+
+    pub(crate) fn syntax_diagnostics(&self) -> Vec<Diagnostic> {
+        error_nodes(self.tree.walk())
+            .into_iter()
+            .map(|node| match self.unclosed_include(&node) {
+                // Not an error while the path is typed.
+                Some(_) => Diagnostic {
+                    range: node.lsp_range(&self.code),
+                    severity: Some(DiagnosticSeverity::HINT),
+                    code: Some(NumberOrString::String(SYNTAX_ERROR.to_owned())),
+                    message: "unclosed include path, missing `>`".to_owned(),
+                    ..Default::default()
+                },
+                None if node.is_missing() => Diagnostic {
+                    range: node.lsp_range(&self.code),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: Some(NumberOrString::String(SYNTAX_ERROR.to_owned())),
+                    message: missing_message(self.tree.root_node(), &node),
+                    // The missing token is inserted by the quick fix.
+                    data: (!node.is_named()).then(|| {
+                        let title = format!("Insert `{}`", node.kind());
+                        let start = node.lsp_range(&self.code).start;
+                        let range = lsp_types::Range::new(start, start);
+                        let new_text = node.kind().to_owned();
+                        fix_data(title, vec![TextEdit { range, new_text }])
+                    }),
+                    ..Default::default()
+                },
+                None => match error_hint(self, &node) {
+                    Some(hint) => Diagnostic {
+                        range: hint.range,
+                        severity: Some(DiagnosticSeverity::ERROR),
+                        code: Some(NumberOrString::String(SYNTAX_ERROR.to_owned())),
+                        message: hint.message,
+                        data: hint.fix.map(|(title, edits)| fix_data(title, edits)),
+                        ..Default::default()
+                    },
+                    None => Diagnostic {
+                        range: node.lsp_range(&self.code),
+                        severity: Some(DiagnosticSeverity::ERROR),
+                        code: Some(NumberOrString::String(SYNTAX_ERROR.to_owned())),
+                        message: "syntax error".to_owned(),
+                        ..Default::default()
+                    },
+                },
+            })
+            .collect()
     }
 
     pub(crate) fn gen_top_level_items_if_needed(&mut self) {
-        if self.root_items.is_some() && !self.changed {
+        if self.root_items.is_some() && !self.changed && self.edited.is_empty() {
             return;
         }
-        self.changed = false;
         self.gen_top_level_items();
     }
 
@@ -124,33 +400,100 @@ impl ParsedCode {
     }
 
     pub(crate) fn gen_top_level_items(&mut self) {
+        // The items whose text wasn't edited are kept, only their lines may change.
+        let mut reusable: HashMap<usize, (Range<usize>, Rc<RefCell<Item>>)> = HashMap::new();
+        if !self.changed && !self.is_builtin {
+            let spans = self.item_spans.iter();
+            for (span, item) in spans.zip(self.root_items.iter().flatten()) {
+                let Some(span) = span else {
+                    continue;
+                };
+                if !self
+                    .edited
+                    .iter()
+                    .any(|edited| span.start <= edited.end && edited.start <= span.end)
+                {
+                    reusable.insert(span.start, (span.clone(), item.clone()));
+                }
+            }
+        }
+        self.changed = false;
+        self.edited.clear();
+
+        let root = self.tree.root_node();
+        let statements: Vec<Node> = root
+            .children(&mut root.walk())
+            .filter(|node| node.is_named() && !node.node_kind().is_comment())
+            .collect();
+        let statement_spans: HashMap<usize, Range<usize>> = statements
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let start = i.checked_sub(1).map_or(0, |i| statements[i].end_byte());
+                // The comments on the line of the item are its doc too.
+                let line_end = self.code[node.start_byte()..]
+                    .find('\n')
+                    .map_or(self.code.len(), |end| node.start_byte() + end);
+                let end = statements
+                    .get(i + 1)
+                    .map_or(self.code.len(), |next| next.start_byte().max(line_end));
+                (node.id(), start..end)
+            })
+            .collect();
+
         let mut cursor: TreeCursor = self.tree.walk();
-        let mut ret: Vec<Item> = vec![];
+        let mut ret: Vec<Rc<RefCell<Item>>> = vec![];
+        let mut spans: Vec<Option<Range<usize>>> = vec![];
         let mut inc = vec![];
+        let mut used = HashSet::new();
+        let mut included = HashSet::new();
 
         let mut doc: Option<String> = None;
         let mut doc_node: Option<Node> = None;
+        let mut meta: Option<BuiltinMeta> = None;
         let mut last_code_line: usize = 0;
+        let mut last_reused = false;
 
         for_each_child(&mut cursor, |cursor| {
             let node = &cursor.node();
-            if node.kind().is_comment() {
+            let text = node_text(&self.code, node);
+            if self.is_builtin && node.node_kind().is_comment() && BuiltinMeta::is_annotation(text)
+            {
                 if last_code_line > 0 && node.start_position().row == last_code_line {
-                    let last = ret.last_mut().unwrap();
+                    let mut last = ret.last().unwrap().borrow_mut();
+                    let mut last_meta = last.meta.take().unwrap_or_default();
+                    last_meta.merge_annotation(text);
+                    last.set_meta(last_meta);
+                    last.hover = None;
+                } else {
+                    match meta.as_mut() {
+                        Some(meta) => meta.merge_annotation(text),
+                        None => meta = Some(BuiltinMeta::parse_annotation(text)),
+                    }
+                }
+                return;
+            }
+
+            if node.node_kind().is_comment() {
+                if last_code_line > 0 && node.start_position().row == last_code_line {
+                    // The kept items have their comment already.
+                    if last_reused {
+                        return;
+                    }
+                    let mut last = ret.last().unwrap().borrow_mut();
                     let doc_str = node_text(&self.code, node);
                     let newdoc = self.extract_doc(doc_str, self.is_builtin);
 
-                    if last.doc.is_some() {
-                        last.doc.as_mut().unwrap().push_str("  \n");
-                        last.doc.as_mut().unwrap().push_str(&newdoc);
+                    if let Some(last_doc) = last.doc.as_mut() {
+                        last_doc.push_str("  \n");
+                        last_doc.push_str(&newdoc);
                     } else {
                         let mut doc = "".to_owned();
                         doc.push_str("  \n");
                         doc.push_str(&newdoc);
                         last.doc = Some(doc);
                     }
-                    last.label = Some(last.make_label());
-                    last.hover = Some(last.make_hover());
+                    last.hover = None;
                     return;
                 }
 
@@ -166,7 +509,20 @@ impl ParsedCode {
                 }
                 doc_node = Some(*node);
             } else {
-                if let Some(mut item) = Item::parse(&self.code, node) {
+                let span = statement_spans.get(&node.id()).cloned();
+                let reused = span
+                    .as_ref()
+                    .and_then(|span| reusable.remove(&span.start))
+                    .filter(|(old, item)| {
+                        Some(old) == span.as_ref() && move_item(&self.code, item, node)
+                    })
+                    .map(|(_, item)| item);
+                if let Some(item) = reused {
+                    last_code_line = item.borrow().range.start.line as usize;
+                    last_reused = true;
+                    ret.push(item);
+                    spans.push(span);
+                } else if let Some(mut item) = Item::parse(&self.code, node) {
                     item.is_builtin = self.is_builtin;
                     if !self.is_builtin || self.external_builtin {
                         item.url = Some(self.url.clone());
@@ -174,61 +530,102 @@ impl ParsedCode {
                     item.doc = doc
                         .as_ref()
                         .map(|doc| self.extract_doc(doc, self.is_builtin));
-                    item.label = Some(item.make_label());
-                    item.hover = Some(item.make_hover());
+                    if let Some(meta) = meta.take() {
+                        item.set_meta(meta);
+                    }
+                    if !self.is_builtin {
+                        item.bundle_doc = self
+                            .doc_bundles
+                            .borrow()
+                            .lookup(&item.name, &self.url)
+                            .map(|sym| sym.to_markdown());
+                    }
+                    // The label and the hover are made when they are first requested.
                     last_code_line = item.range.start.line as usize;
-                    ret.push(item);
-                } else if node.kind().is_include_statement() {
+                    last_reused = false;
+                    ret.push(Rc::new(RefCell::new(item)));
+                    spans.push(span);
+                } else if node.node_kind().is_include_statement() {
                     self.get_include_url(node).map(|url| {
+                        if node.node_kind() == NodeKind::UseStatement {
+                            used.insert(url.clone());
+                        } else {
+                            included.insert(url.clone());
+                        }
                         inc.push(url);
                     });
                 }
 
                 doc = None;
                 doc_node = None;
+                meta = None;
             }
         });
 
         if self.is_builtin {
-            ret.extend(KEYWORDS.iter().map(|&(name, comp)| Item {
-                name: name.to_owned(),
-                kind: ItemKind::Keyword(comp.to_owned()),
-                ..Default::default()
+            ret.extend(SPECIAL_VARIABLES.iter().map(|&name| {
+                Rc::new(RefCell::new(Item {
+                    name: intern(name),
+                    kind: ItemKind::Variable,
+                    ..Default::default()
+                }))
+            }));
+            ret.extend(KEYWORDS.iter().map(|&(name, comp)| {
+                Rc::new(RefCell::new(Item {
+                    name: intern(name),
+                    kind: ItemKind::Keyword(comp.to_owned()),
+                    ..Default::default()
+                }))
             }));
         }
 
-        let mut items = vec![];
-        for mut item in ret {
-            item.is_builtin = self.is_builtin;
-            items.push(Rc::new(RefCell::new(item)));
+        for item in &ret {
+            item.borrow_mut().is_builtin = self.is_builtin;
         }
 
-        self.root_items = Some(items);
+        self.root_items = Some(ret);
+        self.item_spans = spans;
         self.includes = Some(inc);
+        self.includes_changed = !self.is_builtin;
+        self.used = used.difference(&included).cloned().collect();
     }
 
     pub(crate) fn get_include_url(&self, incstat_node: &Node) -> Option<Url> {
-        let mut res = None;
-        let include_path = node_text(&self.code, &incstat_node.child(1).unwrap())
+        self.resolve_include(incstat_node).map(|(_, url)| url)
+    }
+
+    // The url of the included file, and the url it was resolved relative to: the current file or
+    // one of the library locations.
+    pub(crate) fn resolve_include(&self, incstat_node: &Node) -> Option<(Url, Url)> {
+        self.include_candidates(incstat_node)
+            .into_iter()
+            .find(|(_, url)| url.to_file_path().is_ok_and(|path| path.exists()))
+    }
+
+    // The locations searched for an include path, in the order of OpenSCAD: relative to the
+    // current file, then the library locations.
+    pub(crate) fn include_candidates(&self, incstat_node: &Node) -> Vec<(Url, Url)> {
+        let Some(path_node) = incstat_node.child(1) else {
+            return vec![];
+        };
+        let include_path = node_text(&self.code, &path_node)
             .trim_start_matches(&['<', '\n'][..])
             .trim_end_matches(&['>', '\n'][..]);
 
         if include_path.is_empty() {
-            return None;
+            return vec![];
         }
 
         let mut urls = vec![&self.url];
         let libs = self.libs.borrow();
         urls.extend(libs.iter());
 
-        for url in urls {
-            match url.join(include_path) {
+        let mut result = vec![];
+        for base in urls {
+            match base.join(include_path) {
                 Ok(url) => {
-                    if let Ok(path) = url.to_file_path() {
-                        if path.exists() {
-                            res = Some(url);
-                            return res;
-                        }
+                    if !result.iter().any(|(_, other)| other == &url) {
+                        result.push((base.clone(), url));
                     }
                 }
                 Err(err) => {
@@ -236,14 +633,33 @@ impl ParsedCode {
                 }
             }
         }
-        res
+        result
     }
 
-    pub(crate) fn get_include_completion(&self, inc_path: &Node) -> Vec<String> {
+    pub(crate) fn include_diagnostics(&self) -> Vec<Diagnostic> {
+        include_statements(self.tree.walk())
+            .into_iter()
+            .filter(|node| node.child(1).is_some())
+            .filter(|node| self.get_include_url(node).is_none())
+            .map(|node| {
+                let mut range = node.child(1).unwrap().lsp_range(&self.code);
+                range.start.character += 1;
+                range.end.character = range.end.character.saturating_sub(1);
+                Diagnostic {
+                    range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: Some(NumberOrString::String("missing-include".to_owned())),
+                    message: "file not found!".to_owned(),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    // The entries of the directory of the include path being typed, `path` without the brackets,
+    // relative to the file and the library locations.
+    pub(crate) fn get_include_completion(&self, path: &str) -> Vec<String> {
         let mut result = vec![];
-        let path = node_text(&self.code, inc_path)
-            .trim_start_matches(&['<', '\n'][..])
-            .trim_end_matches(&['>', '\n'][..]);
 
         let dir;
         let mut filename = String::from("");
@@ -293,4 +709,328 @@ impl ParsedCode {
 
         result
     }
+
+    // The modules, functions and variables declared in the statements of `node`, with the
+    // symbols of the nested modules as children.
+    fn nested_symbols(&self, node: &Node) -> Vec<DocumentSymbol> {
+        let mut symbols = vec![];
+        for child in node.named_children(&mut node.walk()) {
+            // The loop and let variables, the arguments and the parameters.
+            if matches!(
+                child.node_kind(),
+                NodeKind::Arguments
+                    | NodeKind::ParenthesizedAssignments
+                    | NodeKind::ParametersDeclaration
+            ) {
+                continue;
+            }
+            let Some(item) = Item::parse(&self.code, &child) else {
+                symbols.extend(self.nested_symbols(&child));
+                continue;
+            };
+            let mut symbol = item.document_symbol();
+            let name = child
+                .child_by_field_name("name")
+                .or_else(|| child.child_by_field_name("left"));
+            if let Some(name) = name {
+                symbol.selection_range = name.lsp_range(&self.code);
+            }
+            if let Some(body) = child.child_by_field_name("body") {
+                let children = self.nested_symbols(&body);
+                symbol.children = (!children.is_empty()).then_some(children);
+            }
+            symbols.push(symbol);
+        }
+        symbols
+    }
+
+    // The symbols declared in the top level module at `range`.
+    pub(crate) fn module_symbols(&self, range: lsp_types::Range) -> Option<Vec<DocumentSymbol>> {
+        let root = self.tree.root_node();
+        let module = root.named_children(&mut root.walk()).find(|node| {
+            node.node_kind() == NodeKind::ModuleDeclaration && node.lsp_range(&self.code) == range
+        })?;
+        let children = self.nested_symbols(&module.child_by_field_name("body")?);
+        (!children.is_empty()).then_some(children)
+    }
+
+    // The modules, functions and blocks containing `point`, from the outermost: `module m()`,
+    // `for (i = [0:3])`, `translate([i, 0, 0])`, `if (i > 1)`, `else`.
+    pub(crate) fn enclosing_symbols(&self, point: Point) -> Vec<DocumentSymbol> {
+        let Some(node) = self
+            .tree
+            .root_node()
+            .descendant_for_point_range(point, point)
+        else {
+            return vec![];
+        };
+        // The text before the body of a block, on one line.
+        let header = |node: &Node, body: &Node| {
+            let text = &self.code[node.start_byte()..body.start_byte()];
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            match text.char_indices().nth(MAX_SYMBOL_HEADER) {
+                Some((i, _)) => format!("{}…", &text[..i]),
+                None => text,
+            }
+        };
+
+        let mut symbols = vec![];
+        let mut child = node;
+        for ancestor in std::iter::successors(node.parent(), |node| node.parent()) {
+            let body = ancestor.child_by_field_name("body");
+            let symbol = match ancestor.node_kind() {
+                NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration => {
+                    let Some(name) = ancestor.child_by_field_name("name") else {
+                        child = ancestor;
+                        continue;
+                    };
+                    let kind = match ancestor.node_kind() {
+                        NodeKind::ModuleDeclaration => SymbolKind::MODULE,
+                        _ => SymbolKind::FUNCTION,
+                    };
+                    Some((
+                        node_text(&self.code, &name).to_owned(),
+                        kind,
+                        name.lsp_range(&self.code),
+                    ))
+                }
+                NodeKind::ForBlock | NodeKind::IntersectionForBlock | NodeKind::LetBlock
+                    if body == Some(child) =>
+                {
+                    let name = header(&ancestor, &child);
+                    Some((name, SymbolKind::NAMESPACE, ancestor.lsp_range(&self.code)))
+                }
+                NodeKind::IfBlock if ancestor.child_by_field_name("consequence") == Some(child) => {
+                    let name = header(&ancestor, &child);
+                    Some((name, SymbolKind::NAMESPACE, ancestor.lsp_range(&self.code)))
+                }
+                NodeKind::IfBlock
+                    if child.is_named()
+                        && ancestor.child_by_field_name("condition") != Some(child) =>
+                {
+                    Some((
+                        "else".to_owned(),
+                        SymbolKind::NAMESPACE,
+                        child.lsp_range(&self.code),
+                    ))
+                }
+                // The children of a module call.
+                NodeKind::TransformChain if child.node_kind() != NodeKind::ModuleCall => {
+                    let call = ancestor
+                        .named_child(0)
+                        .filter(|call| call.node_kind() == NodeKind::ModuleCall);
+                    call.map(|call| {
+                        let name = header(&ancestor, &child);
+                        (name, SymbolKind::OBJECT, call.lsp_range(&self.code))
+                    })
+                }
+                _ => None,
+            };
+            if let Some((name, kind, selection_range)) = symbol {
+                #[allow(deprecated)]
+                symbols.push(DocumentSymbol {
+                    name,
+                    detail: None,
+                    kind,
+                    tags: None,
+                    deprecated: None,
+                    range: ancestor.lsp_range(&self.code),
+                    selection_range,
+                    children: None,
+                });
+            }
+            child = ancestor;
+        }
+        symbols.reverse();
+        symbols
+    }
+
+    // The blocks, argument and parameter lists, lists and comments spanning several lines, the
+    // runs of include/use statements, and the customizer tabs as regions.
+    pub(crate) fn folding_ranges(&self) -> Vec<FoldingRange> {
+        let mut result = vec![];
+        let range = |start: usize, end: usize, kind: Option<FoldingRangeKind>| FoldingRange {
+            start_line: start as u32,
+            start_character: None,
+            end_line: end as u32,
+            end_character: None,
+            kind,
+            collapsed_text: None,
+        };
+
+        for node in traverse(self.tree.walk(), Order::Pre) {
+            let (start, end) = (node.start_position().row, node.end_position().row);
+            match node.node_kind() {
+                // The line of the closing bracket stays visible.
+                NodeKind::UnionBlock
+                | NodeKind::Arguments
+                | NodeKind::ParametersDeclaration
+                | NodeKind::List
+                    if end > start + 1 =>
+                {
+                    result.push(range(start, end - 1, None));
+                }
+                NodeKind::Comment if end > start => {
+                    result.push(range(start, end, Some(FoldingRangeKind::Comment)));
+                }
+                _ => {}
+            }
+        }
+        // `cube([` opens two lists on the same lines.
+        result.dedup_by_key(|range| (range.start_line, range.end_line));
+
+        let root = self.tree.root_node();
+        let mut imports: Vec<(usize, usize)> = vec![];
+        let mut after_include = false;
+        for node in root
+            .children(&mut root.walk())
+            .filter(|node| node.is_named() && !node.node_kind().is_comment())
+        {
+            let is_include = node.node_kind().is_include_statement();
+            let row = node.start_position().row;
+            match imports.last_mut() {
+                Some((_, end)) if is_include && after_include => *end = row,
+                _ if is_include => imports.push((row, row)),
+                _ => {}
+            }
+            after_include = is_include;
+        }
+        for (start, end) in imports {
+            if end > start {
+                result.push(range(start, end, Some(FoldingRangeKind::Imports)));
+            }
+        }
+
+        for group in customizer::groups(self) {
+            if group.range.end.line > group.range.start.line {
+                result.push(range(
+                    group.range.start.line as usize,
+                    group.range.end.line as usize,
+                    Some(FoldingRangeKind::Region),
+                ));
+            }
+        }
+        result
+    }
+
+    // The top level library version constants, `BOSL_VERSION = [2, 0, 716];`, as `2.0.716`.
+    pub(crate) fn version_constants(&self) -> Vec<(String, String)> {
+        let root = self.tree.root_node();
+        root.children(&mut root.walk())
+            .filter(|node| node.node_kind() == NodeKind::Assignment)
+            .filter_map(|node| {
+                let name = node_text(&self.code, &node.child_by_field_name("left")?);
+                if !name.ends_with("_VERSION") {
+                    return None;
+                }
+                let value = node.child_by_field_name("right")?;
+                let version = match value.node_kind() {
+                    NodeKind::Number => node_text(&self.code, &value).to_owned(),
+                    NodeKind::String => node_text(&self.code, &value).trim_matches('"').to_owned(),
+                    NodeKind::List => value
+                        .named_children(&mut value.walk())
+                        .filter(|part| !part.node_kind().is_comment())
+                        .map(|part| match part.node_kind() {
+                            NodeKind::Number => Some(node_text(&self.code, &part)),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<_>>>()?
+                        .join("."),
+                    _ => return None,
+                };
+                Some((name.to_owned(), version))
+            })
+            .collect()
+    }
+}
+
+// Move a kept item to the line of its node, false if its column changed too.
+fn move_item(code: &str, item: &Rc<RefCell<Item>>, node: &Node) -> bool {
+    let mut item = item.borrow_mut();
+    let start = node.lsp_range(code).start;
+    if item.range.start.character != start.character {
+        return false;
+    }
+    let delta = start.line as i64 - item.range.start.line as i64;
+    let move_range = |range: &mut lsp_types::Range| {
+        range.start.line = (range.start.line as i64 + delta) as u32;
+        range.end.line = (range.end.line as i64 + delta) as u32;
+    };
+    move_range(&mut item.range);
+    if let ItemKind::Module { params, .. } | ItemKind::Function { params, .. } = &mut item.kind {
+        for param in params {
+            move_range(&mut param.range);
+        }
+    }
+    true
+}
+
+// Move the item spans and the edited ranges after an edit replacing `range` by `len` bytes.
+fn move_spans(
+    item_spans: &mut [Option<Range<usize>>],
+    edited: &mut Vec<Range<usize>>,
+    range: Range<usize>,
+    len: usize,
+) {
+    let touches = |span: &Range<usize>| span.start <= range.end && range.start <= span.end;
+    let moved = |span: &Range<usize>| span.start + len - range.len()..span.end + len - range.len();
+
+    for span in item_spans.iter_mut() {
+        *span = match span.take() {
+            Some(span) if touches(&span) => None,
+            Some(span) if span.start > range.end => Some(moved(&span)),
+            span => span,
+        };
+    }
+
+    let mut merged = range.start..range.start + len;
+    let mut result = vec![];
+    for span in edited.drain(..) {
+        if touches(&span) {
+            merged.start = merged.start.min(span.start);
+            if span.end > range.end {
+                merged.end = merged.end.max(moved(&span).end);
+            }
+        } else if span.start > range.end {
+            result.push(moved(&span));
+        } else {
+            result.push(span);
+        }
+    }
+    result.push(merged);
+    *edited = result;
+}
+
+// Move the pasted spans after the edit of `range`, a paste itself when it inserts several lines.
+// The spans it touches are extended to it, the typing fixing a pasted call is part of the paste.
+fn move_pasted(pasted: &mut Vec<Range<usize>>, range: Range<usize>, text: &str) {
+    if text.trim().contains('\n') {
+        move_spans(&mut [], pasted, range, text.len());
+        return;
+    }
+    let delta = |offset: usize| offset + text.len() - range.len();
+    for span in pasted.iter_mut() {
+        if span.start > range.end {
+            *span = delta(span.start)..delta(span.end);
+        } else if span.end >= range.start {
+            *span = span.start.min(range.start)..delta(span.end.max(range.end));
+        }
+    }
+}
+
+// The include/use statements, the expressions which can't contain statements aren't visited, so
+// that the large generated vectors are quick to check.
+fn include_statements(mut cursor: TreeCursor) -> Vec<Node> {
+    fn helper<'a>(ret: &mut Vec<Node<'a>>, cursor: &mut TreeCursor<'a>) {
+        let node = cursor.node();
+        match node.node_kind() {
+            kind if kind.is_include_statement() => ret.push(node),
+            NodeKind::Assignment | NodeKind::Arguments | NodeKind::ParametersDeclaration => {}
+            _ => for_each_child(cursor, |cursor| helper(ret, cursor)),
+        }
+    }
+
+    let mut ret = vec![];
+    helper(&mut ret, &mut cursor);
+    ret
 }
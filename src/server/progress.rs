@@ -0,0 +1,97 @@
+use lsp_types::{
+    NumberOrString, ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgress,
+    WorkDoneProgressBegin, WorkDoneProgressCreateParams, WorkDoneProgressEnd,
+    WorkDoneProgressReport,
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::server::Server;
+
+// Server initiated work done progress, the token is None when the client doesn't support it so
+// that the callers don't have to check.
+impl Server {
+    pub(crate) fn begin_progress(&mut self, title: &str) -> Option<ProgressToken> {
+        let supported = self
+            .client_capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        if !supported {
+            return None;
+        }
+
+        self.next_progress_id += 1;
+        let token = NumberOrString::String(format!("openscad-lsp/{}", self.next_progress_id));
+        self.send_request(
+            "window/workDoneProgress/create",
+            WorkDoneProgressCreateParams {
+                token: token.clone(),
+            },
+        );
+        self.send_progress(
+            &token,
+            WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: title.to_owned(),
+                cancellable: Some(false),
+                message: None,
+                percentage: Some(0),
+            }),
+        );
+        Some(token)
+    }
+
+    pub(crate) fn report_progress(&self, token: Option<&ProgressToken>, done: usize, total: usize) {
+        let Some(token) = token else {
+            return;
+        };
+        self.send_progress(
+            token,
+            WorkDoneProgress::Report(WorkDoneProgressReport {
+                cancellable: Some(false),
+                message: Some(format!("{}/{} files", done, total)),
+                percentage: Some((done * 100 / total.max(1)) as u32),
+            }),
+        );
+    }
+
+    pub(crate) fn end_progress(&self, token: Option<ProgressToken>, message: Option<String>) {
+        let Some(token) = token else {
+            return;
+        };
+        self.send_progress(
+            &token,
+            WorkDoneProgress::End(WorkDoneProgressEnd { message }),
+        );
+    }
+
+    // Send `batch` as a partial result when the client gave a token, otherwise add it to the final
+    // `result`.
+    pub(crate) fn partial_result<T: Serialize>(
+        &self,
+        token: Option<&ProgressToken>,
+        batch: Vec<T>,
+        result: &mut Vec<T>,
+    ) {
+        match token {
+            Some(_) if batch.is_empty() => {}
+            Some(token) => self.notify(lsp_server::Notification::new(
+                "$/progress".into(),
+                json!({ "token": token, "value": batch }),
+            )),
+            None => result.extend(batch),
+        }
+    }
+
+    fn send_progress(&self, token: &ProgressToken, value: WorkDoneProgress) {
+        self.notify(lsp_server::Notification::new(
+            "$/progress".into(),
+            ProgressParams {
+                token: token.clone(),
+                value: ProgressParamsValue::WorkDone(value),
+            },
+        ));
+    }
+}
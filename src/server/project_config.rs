@@ -0,0 +1,231 @@
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+// The project configuration file, shared by the contributors of a project so that their editor
+// settings don't matter.
+pub(crate) const PROJECT_CONFIG_FILE: &str = ".openscad-lsp.toml";
+
+// The `[format]` table of the project configuration, it takes precedence over the settings.
+//
+// ```toml
+// [format]
+// style = "file"
+// exe = "tools/clang-format"
+// ```
+#[derive(Default)]
+pub(crate) struct FormatConfig {
+    pub style: Option<String>,
+    // Relative to the configuration file when it's a path.
+    pub exe: Option<String>,
+}
+
+// The `[save]` table of the project configuration, it takes precedence over the settings.
+//
+// ```toml
+// [save]
+// checks = true
+// ```
+#[derive(Default)]
+pub(crate) struct SaveConfig {
+    // Warn before saving a file with syntax errors or missing includes.
+    pub checks: Option<bool>,
+}
+
+#[derive(Default)]
+struct ProjectConfig {
+    format: FormatConfig,
+    save: SaveConfig,
+}
+
+// The configuration file of the nearest directory from `dir`, up to the root.
+fn find_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+// A TOML string, basic or literal with the common escapes, and the text after it.
+fn parse_string(value: &str) -> Option<(String, &str)> {
+    if let Some(literal) = value.strip_prefix('\'') {
+        let (text, rest) = literal.split_once('\'')?;
+        return Some((text.to_owned(), rest));
+    }
+    let mut chars = value.strip_prefix('"')?.char_indices();
+    let mut result = String::new();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '"' => return Some((result, chars.as_str())),
+            '\\' => match chars.next()?.1 {
+                'n' => result.push('\n'),
+                't' => result.push('\t'),
+                c @ ('"' | '\\') => result.push(c),
+                _ => return None,
+            },
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+// A TOML boolean, and the text after it.
+fn parse_boolean(value: &str) -> Option<(bool, &str)> {
+    if let Some(rest) = value.strip_prefix("true") {
+        return Some((true, rest));
+    }
+    value.strip_prefix("false").map(|rest| (false, rest))
+}
+
+// The keys of the `[format]` and `[save]` tables, the other tables are left to other tools. Only
+// the `key = "string"` and `key = boolean` lines and the comments are supported.
+fn parse_config(text: &str) -> Result<ProjectConfig, String> {
+    let mut config = ProjectConfig::default();
+    let mut table = "";
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            // `[format]`, `[ format ] # comment` or `["format"]`.
+            let name = header
+                .split('#')
+                .next()
+                .and_then(|header| header.trim_end().strip_suffix(']'))
+                .map(|name| name.trim().trim_matches(|c| c == '"' || c == '\''));
+            table = match name {
+                Some("format") => "format",
+                Some("save") => "save",
+                _ => "",
+            };
+            continue;
+        }
+        if table.is_empty() {
+            continue;
+        }
+
+        let error = |message: &str| format!("line {}: {}", i + 1, message);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected `key = value`"))?;
+        let is_end = |rest: &str| rest.trim().is_empty() || rest.trim().starts_with('#');
+        let string = || {
+            parse_string(value.trim())
+                .filter(|(_, rest)| is_end(rest))
+                .map(|(value, _)| value)
+                .ok_or_else(|| error("expected a string"))
+        };
+        let boolean = || {
+            parse_boolean(value.trim())
+                .filter(|(_, rest)| is_end(rest))
+                .map(|(value, _)| value)
+                .ok_or_else(|| error("expected a boolean"))
+        };
+        match (table, key.trim()) {
+            ("format", "style") => config.format.style = Some(string()?),
+            ("format", "exe") => config.format.exe = Some(string()?),
+            ("save", "checks") => config.save.checks = Some(boolean()?),
+            (table, key) => return Err(error(&format!("unknown key {}.{}", table, key))),
+        }
+    }
+    Ok(config)
+}
+
+// The configuration of the project of the files of `dir` and its file, None without a
+// configuration file.
+fn project_config(dir: &Path) -> Option<(PathBuf, Result<ProjectConfig, String>)> {
+    let path = find_config(dir)?;
+    let config = read_to_string(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| parse_config(&text))
+        .map_err(|err| format!("{}: {}", path.display(), err));
+    Some((path, config))
+}
+
+// The save configuration of the project of the files of `dir`, None without a configuration
+// file.
+pub(crate) fn project_save_config(dir: &Path) -> Option<Result<SaveConfig, String>> {
+    let (_, config) = project_config(dir)?;
+    Some(config.map(|config| config.save))
+}
+
+// The formatter configuration of the project of the files of `dir`, None without a configuration
+// file.
+pub(crate) fn project_format_config(dir: &Path) -> Option<Result<FormatConfig, String>> {
+    let (path, config) = project_config(dir)?;
+    let config = config.map(|config| {
+        let mut config = config.format;
+        let config_dir = path.parent().unwrap_or(dir);
+        config.exe = config.exe.map(|exe| {
+            let is_relative_path =
+                exe.contains(std::path::is_separator) && Path::new(&exe).is_relative();
+            if is_relative_path && !exe.starts_with('~') {
+                config_dir.join(exe).to_string_lossy().into_owned()
+            } else {
+                exe
+            }
+        });
+        config
+    });
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tables() {
+        let config = parse_config(
+            "# project\n\
+             [format] # team style\n\
+             style = \"{BasedOnStyle: LLVM, IndentWidth: 4}\" # four spaces\n\
+             exe = 'tools\\clang-format'\n\
+             \n\
+             [ save ]\n\
+             checks = true\n\
+             [other]\n\
+             key = [1, 2]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.format.style.as_deref(),
+            Some("{BasedOnStyle: LLVM, IndentWidth: 4}")
+        );
+        assert_eq!(config.format.exe.as_deref(), Some("tools\\clang-format"));
+        assert_eq!(config.save.checks, Some(true));
+
+        let config =
+            parse_config("[\"format\"]\nstyle = \"a\\\"b\"\n[save]#\nchecks = false").unwrap();
+        assert_eq!(config.format.style.as_deref(), Some("a\"b"));
+        assert_eq!(config.save.checks, Some(false));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| parse_config(text).err();
+        assert_eq!(
+            error("[format]\nstyle = file").as_deref(),
+            Some("line 2: expected a string")
+        );
+        assert_eq!(
+            error("[save]\n\nchecks = \"yes\"").as_deref(),
+            Some("line 3: expected a boolean")
+        );
+        assert_eq!(
+            error("[format]\nstyle = \"file\" extra").as_deref(),
+            Some("line 2: expected a string")
+        );
+        assert_eq!(
+            error("[format]\nindent = \"4\"").as_deref(),
+            Some("line 2: unknown key format.indent")
+        );
+        assert_eq!(
+            error("[format]\nstyle").as_deref(),
+            Some("line 2: expected `key = value`")
+        );
+        // The keys of the other tables and before the first table are left to other tools.
+        assert!(error("key = 1\n[[tools]]\nstyle = 2").is_none());
+    }
+}
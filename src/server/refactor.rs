@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use lsp_types::{Location, Position, Range, TextEdit, Url};
+use regex::Regex;
+use tree_sitter::Node;
+use tree_sitter_traversal::{traverse, Order};
+
+use crate::{
+    code_helper::call_name_node,
+    handler::error::{HandlerError, HandlerResult},
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::{parse, ParsedCode},
+    response_item::ItemKind,
+    server::Server,
+    utils::*,
+};
+
+// The module or function whose signature a refactoring changes.
+pub(crate) struct Callable {
+    pub name: String,
+    pub url: Url,
+    // The range of its declaration, the start of the `module` or `function` keyword.
+    pub range: Range,
+}
+
+// The edits of a refactoring, and the calls it couldn't update.
+pub(crate) struct Refactoring {
+    pub label: String,
+    pub changes: HashMap<Url, Vec<TextEdit>>,
+    pub skipped: Vec<Location>,
+}
+
+// The declaration of the module or function at `range`, from its item.
+pub(crate) fn declaration_node<'a>(code: &'a ParsedCode, range: Range) -> Option<Node<'a>> {
+    let point = to_point(&code.code, range.start);
+    let mut node = code
+        .tree
+        .root_node()
+        .descendant_for_point_range(point, point)?;
+    loop {
+        if matches!(
+            node.node_kind(),
+            NodeKind::ModuleDeclaration | NodeKind::FunctionDeclaration
+        ) {
+            return Some(node);
+        }
+        node = node.parent()?;
+    }
+}
+
+// The name of a parameter or named argument, `a` of `a` and `a = 1`.
+pub(crate) fn list_entry_name<'a>(code: &'a str, node: &Node) -> Option<&'a str> {
+    match node.node_kind() {
+        NodeKind::Identifier | NodeKind::SpecialVariable => Some(node_text(code, node)),
+        NodeKind::Assignment => Some(node_text(code, &node.child_by_field_name("left")?)),
+        _ => None,
+    }
+}
+
+// The parameters of a declaration or the arguments of a call, without the comments.
+pub(crate) fn list_entries<'a>(list: &Node<'a>) -> Vec<Node<'a>> {
+    list.named_children(&mut list.walk())
+        .filter(|node| !node.node_kind().is_comment())
+        .collect()
+}
+
+// An edit appending `text` to the parenthesized list of parameters or arguments `list`, after its
+// last entry, or its trailing comma.
+pub(crate) fn append_edit(code: &str, list: &Node, text: &str) -> Option<TextEdit> {
+    let mut children: Vec<Node> = list.children(&mut list.walk()).collect();
+    let close = children.pop().filter(|node| node.kind() == ")")?;
+    let last = children
+        .into_iter()
+        .rev()
+        .find(|node| !node.node_kind().is_comment())?;
+    let (offset, new_text) = match last.kind() {
+        "(" => (close.start_byte(), text.to_owned()),
+        "," => (last.end_byte(), format!(" {}", text)),
+        _ => (last.end_byte(), format!(", {}", text)),
+    };
+    let position = offset_to_position(code, offset);
+    Some(TextEdit {
+        range: Range::new(position, position),
+        new_text,
+    })
+}
+
+// The parameters of the declaration of `callable` in `code`, an error if they have a syntax error.
+fn declaration_parameters<'a>(
+    code: &'a ParsedCode,
+    callable: &Callable,
+) -> HandlerResult<Node<'a>> {
+    declaration_node(code, callable.range)
+        .and_then(|decl| decl.child_by_field_name("parameters"))
+        .filter(|params| !params.has_error())
+        .ok_or_else(|| HandlerError::request_failed("the declaration has a syntax error"))
+}
+
+// The arguments of the call of `name` whose name is at `range`, if it is still there and they
+// have no syntax error.
+fn call_arguments<'a>(code: &'a ParsedCode, range: Range, name: &str) -> Option<Node<'a>> {
+    let start = find_offset(&code.code, range.start)?;
+    let node = code
+        .tree
+        .root_node()
+        .descendant_for_byte_range(start, start + name.len())?;
+    let call = node.parent()?;
+    if node.byte_range() != (start..start + name.len())
+        || call_name_node(&call) != Some(node)
+        || node_text(&code.code, &node) != name
+    {
+        return None;
+    }
+    call.child_by_field_name("arguments")
+        .filter(|args| !args.has_error())
+}
+
+// The identifiers used by an expression, the variables and the called functions.
+fn expression_identifiers(code: &str, expression: &Node) -> Vec<String> {
+    let mut names: Vec<String> = traverse(expression.walk(), Order::Pre)
+        .filter(|node| node.node_kind() == NodeKind::Identifier)
+        .map(|node| node_text(code, &node).to_owned())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// The parameter declared by `text`, `name` or `name = default`, with the identifiers used by its
+// default. None unless `text` is a single parameter, without comments or syntax errors.
+fn parse_parameter(text: &str) -> Option<(SignatureParam, Vec<String>)> {
+    let code = format!("function f({}) = 0;", text);
+    let tree = parse(&code, None);
+    let root = tree.root_node();
+    let params = root
+        .named_child(0)
+        .filter(|decl| decl.node_kind() == NodeKind::FunctionDeclaration)?
+        .child_by_field_name("parameters")?;
+    if root.has_error() || root.named_child_count() != 1 || params.named_child_count() != 1 {
+        return None;
+    }
+    let param = params.named_child(0)?;
+    let default = param.child_by_field_name("right");
+    Some((
+        SignatureParam {
+            name: list_entry_name(&code, &param)?.to_owned(),
+            default: default.map(|default| node_text(&code, &default).to_owned()),
+        },
+        default.map_or(vec![], |default| expression_identifiers(&code, &default)),
+    ))
+}
+
+// A parameter of a new signature, `name` or `name = default`.
+struct SignatureParam {
+    name: String,
+    default: Option<String>,
+}
+
+// A default removed from the declaration, which the calls relying on it get, with the identifiers
+// it uses and the declarations they refer to from the declaration.
+struct MovedDefault {
+    name: String,
+    identifiers: Vec<String>,
+    bindings: Vec<Option<Location>>,
+}
+
+impl SignatureParam {
+    fn parse(text: &str) -> Option<Self> {
+        parse_parameter(text).map(|(param, _)| param)
+    }
+
+    fn text(&self) -> String {
+        match &self.default {
+            Some(default) => format!("{} = {}", self.name, default),
+            None => self.name.clone(),
+        }
+    }
+}
+
+// Signature refactorings.
+impl Server {
+    // The module or function declared or called at `position`, declared in the workspace.
+    pub(crate) fn callable_at(&mut self, url: &Url, position: Position) -> HandlerResult<Callable> {
+        let file = self.request_code(url)?;
+        file.borrow_mut().gen_top_level_items_if_needed();
+        let code = file.borrow();
+        let node = node_at_position(&code.code, &code.tree.root_node(), position);
+        if node.node_kind() != NodeKind::Identifier {
+            return Err(HandlerError::invalid_params(
+                "no module or function at the position",
+            ));
+        }
+        let name = node_text(&code.code, &node);
+        let item = self
+            .find_identities(&code, &|ident| ident == name, &node, false)
+            .into_iter()
+            .next()
+            .ok_or_else(|| HandlerError::request_failed(format!("`{}` is not defined", name)))?;
+        let item = item.borrow();
+        if !matches!(
+            item.kind,
+            ItemKind::Module { .. } | ItemKind::Function { .. }
+        ) {
+            return Err(HandlerError::invalid_params(format!(
+                "`{}` is not a module or a function",
+                name
+            )));
+        }
+        let declared = item
+            .url
+            .clone()
+            .filter(|url| !item.is_builtin && url != &self.builtin_url);
+        let Some(declared) = declared else {
+            return Err(HandlerError::request_failed(format!(
+                "`{}` is a builtin",
+                name
+            )));
+        };
+        if &declared != url && self.is_outside_workspace(&declared) {
+            return Err(HandlerError::request_failed(format!(
+                "`{}` is defined in a library outside the workspace",
+                name
+            )));
+        }
+        Ok(Callable {
+            name: name.to_owned(),
+            url: declared,
+            range: item.range,
+        })
+    }
+
+    // The references to `callable` in the workspace, by file, with the range of their name. They
+    // are its calls, those which `call_arguments` doesn't find are skipped by the refactorings.
+    pub(crate) fn call_sites(&mut self, callable: &Callable) -> HashMap<Url, Vec<Range>> {
+        let mut result: HashMap<Url, Vec<Range>> = HashMap::new();
+        let references =
+            self.symbol_references(&callable.url, &callable.name, callable.range, false, None);
+        for location in references {
+            result.entry(location.uri).or_default().push(location.range);
+        }
+        result
+    }
+
+    // The declarations which the identifiers `names` refer to from `node`, to check that an
+    // expression moved there keeps its value.
+    fn bindings(
+        &mut self,
+        code: &ParsedCode,
+        node: &Node,
+        names: &[String],
+    ) -> Vec<Option<Location>> {
+        names
+            .iter()
+            .map(|name| {
+                let item = self
+                    .find_identities(code, &|ident| ident == name, node, false)
+                    .into_iter()
+                    .next()?;
+                let item = item.borrow();
+                Some(Location::new(item.url.clone()?, item.range))
+            })
+            .collect()
+    }
+
+    // The edits adding the parameter `name = default` to the end of the parameters of the
+    // callable at `position`, and the named argument to its calls with `update_calls`, except
+    // those with a syntax error and those where the identifiers of `default` refer to other
+    // declarations.
+    pub(crate) fn add_parameter_edits(
+        &mut self,
+        url: &Url,
+        position: Position,
+        name: &str,
+        default: &str,
+        update_calls: bool,
+    ) -> HandlerResult<Refactoring> {
+        lazy_static! {
+            static ref NAME_RE: Regex = Regex::new(r"^[A-Za-z_]\w*$").unwrap();
+        }
+        if !NAME_RE.is_match(name) {
+            return Err(HandlerError::invalid_params(format!(
+                "`{}` is not a valid parameter name",
+                name
+            )));
+        }
+        let text = format!("{} = {}", name, default.trim());
+        let identifiers = match parse_parameter(&text) {
+            Some((param, identifiers)) if param.name == name && param.default.is_some() => {
+                identifiers
+            }
+            _ => {
+                return Err(HandlerError::invalid_params(format!(
+                    "`{}` is not a single expression",
+                    default.trim()
+                )))
+            }
+        };
+        let callable = self.callable_at(url, position)?;
+
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        let declared_bindings = {
+            let file = self.request_code(&callable.url)?;
+            let code = file.borrow();
+            let params = declaration_parameters(&code, &callable)?;
+            if list_entries(&params)
+                .iter()
+                .any(|param| list_entry_name(&code.code, param) == Some(name))
+            {
+                return Err(HandlerError::invalid_params(format!(
+                    "`{}` already has a parameter `{}`",
+                    callable.name, name
+                )));
+            }
+            let edit = append_edit(&code.code, &params, &text).ok_or_else(|| {
+                HandlerError::request_failed("the declaration has a syntax error")
+            })?;
+            changes.insert(callable.url.clone(), vec![edit]);
+            self.bindings(&code, &params, &identifiers)
+        };
+
+        let (mut calls, mut skipped) = (0, vec![]);
+        if update_calls {
+            for (uri, ranges) in self.call_sites(&callable) {
+                let Some(file) = self.get_code(&uri) else {
+                    continue;
+                };
+                let code = file.borrow();
+                for range in ranges {
+                    let args = call_arguments(&code, range, &callable.name).filter(|args| {
+                        self.bindings(&code, args, &identifiers) == declared_bindings
+                    });
+                    let Some(args) = args else {
+                        skipped.push(Location::new(uri.clone(), range));
+                        continue;
+                    };
+                    let is_named = list_entries(&args).iter().any(|arg| {
+                        arg.node_kind() == NodeKind::Assignment
+                            && list_entry_name(&code.code, arg) == Some(name)
+                    });
+                    if is_named {
+                        continue;
+                    }
+                    if let Some(edit) = append_edit(&code.code, &args, &text) {
+                        changes.entry(uri.clone()).or_default().push(edit);
+                        calls += 1;
+                    }
+                }
+            }
+        }
+
+        let label = match calls {
+            0 => format!("Add the parameter `{}` to `{}`", name, callable.name),
+            _ => format!(
+                "Add the parameter `{}` to `{}` and {} calls",
+                name, callable.name, calls
+            ),
+        };
+        Ok(Refactoring {
+            label,
+            changes,
+            skipped,
+        })
+    }
+
+    // The edits changing the parameters of the callable at `position` to `params`, the same
+    // names in a new order with their new defaults, and rewriting its calls so that they keep
+    // passing the same values: the positional arguments which don't match the new order become
+    // named, and the calls omitting a parameter whose default is removed get the old default.
+    pub(crate) fn reorder_parameters_edits(
+        &mut self,
+        url: &Url,
+        position: Position,
+        params: &[String],
+    ) -> HandlerResult<Refactoring> {
+        let callable = self.callable_at(url, position)?;
+        let new_params = params
+            .iter()
+            .map(|text| {
+                SignatureParam::parse(text).ok_or_else(|| {
+                    HandlerError::invalid_params(format!("`{}` is not a parameter", text))
+                })
+            })
+            .collect::<HandlerResult<Vec<_>>>()?;
+
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        let (old_params, moved) = {
+            let file = self.request_code(&callable.url)?;
+            let code = file.borrow();
+            let params = declaration_parameters(&code, &callable)?;
+            if params
+                .named_children(&mut params.walk())
+                .any(|node| node.node_kind().is_comment())
+            {
+                return Err(HandlerError::request_failed(
+                    "the parameters have comments, move them before the declaration first",
+                ));
+            }
+            let entries = list_entries(&params);
+            let names: Vec<&str> = entries
+                .iter()
+                .filter_map(|param| list_entry_name(&code.code, param))
+                .collect();
+            let (mut old_params, mut moved) = (vec![], vec![]);
+            for param in &entries {
+                let Some(name) = list_entry_name(&code.code, param) else {
+                    continue;
+                };
+                let default = param.child_by_field_name("right");
+                // The default is evaluated in the scope of the parameters, not of the calls.
+                let uses_params = default.is_some_and(|default| {
+                    traverse(default.walk(), Order::Pre).any(|node| {
+                        node.node_kind() == NodeKind::Identifier
+                            && names.contains(&node_text(&code.code, &node))
+                    })
+                });
+                old_params.push(SignatureParam {
+                    name: name.to_owned(),
+                    default: default
+                        .filter(|_| !uses_params)
+                        .map(|default| node_text(&code.code, &default).to_owned()),
+                });
+                let is_removed = new_params
+                    .iter()
+                    .any(|param| param.name == name && param.default.is_none());
+                if uses_params && is_removed {
+                    return Err(HandlerError::request_failed(format!(
+                        "the default of `{}` uses the other parameters, it can't be moved to the \
+                         calls",
+                        name
+                    )));
+                }
+                // The calls get the default only where its identifiers refer to the same
+                // declarations.
+                if let Some(default) = default.filter(|_| is_removed) {
+                    let identifiers = expression_identifiers(&code.code, &default);
+                    moved.push(MovedDefault {
+                        name: name.to_owned(),
+                        bindings: self.bindings(&code, &params, &identifiers),
+                        identifiers,
+                    });
+                }
+            }
+
+            let mut old_names: Vec<&str> = old_params.iter().map(|p| p.name.as_str()).collect();
+            let mut new_names: Vec<&str> = new_params.iter().map(|p| p.name.as_str()).collect();
+            old_names.sort();
+            new_names.sort();
+            if old_names != new_names {
+                return Err(HandlerError::invalid_params(format!(
+                    "the parameters should be those of `{}` in a new order: {}",
+                    callable.name,
+                    old_params
+                        .iter()
+                        .map(|p| p.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+
+            changes.insert(
+                callable.url.clone(),
+                vec![TextEdit {
+                    range: params.lsp_range(&code.code),
+                    new_text: format!(
+                        "({})",
+                        new_params
+                            .iter()
+                            .map(SignatureParam::text)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }],
+            );
+            (old_params, moved)
+        };
+
+        let (mut calls, mut skipped) = (0, vec![]);
+        for (uri, ranges) in self.call_sites(&callable) {
+            let Some(file) = self.get_code(&uri) else {
+                continue;
+            };
+            let code = file.borrow();
+            for range in ranges {
+                let rewritten = call_arguments(&code, range, &callable.name).and_then(|args| {
+                    let movable: Vec<&str> = moved
+                        .iter()
+                        .filter(|moved| {
+                            self.bindings(&code, &args, &moved.identifiers) == moved.bindings
+                        })
+                        .map(|moved| moved.name.as_str())
+                        .collect();
+                    rewrite_arguments(&code.code, &args, &old_params, &new_params, &movable)
+                });
+                match rewritten {
+                    Some(Some(edit)) => {
+                        changes.entry(uri.clone()).or_default().push(edit);
+                        calls += 1;
+                    }
+                    Some(None) => {}
+                    None => skipped.push(Location::new(uri.clone(), range)),
+                }
+            }
+        }
+
+        let label = match calls {
+            0 => format!("Reorder the parameters of `{}`", callable.name),
+            _ => format!(
+                "Reorder the parameters of `{}` and rewrite {} calls",
+                callable.name, calls
+            ),
+        };
+        Ok(Refactoring {
+            label,
+            changes,
+            skipped,
+        })
+    }
+}
+
+// The edit of the arguments of a call to the callable whose parameters change from `old_params`
+// to `new_params`, none if the call is unchanged. None if the arguments can't be rewritten: they
+// have comments, more positional arguments than parameters, or the call relies on a removed
+// default which isn't `movable` to it.
+fn rewrite_arguments(
+    code: &str,
+    args: &Node,
+    old_params: &[SignatureParam],
+    new_params: &[SignatureParam],
+    movable: &[&str],
+) -> Option<Option<TextEdit>> {
+    let mut positional = vec![];
+    let mut named = vec![];
+    for arg in args.named_children(&mut args.walk()) {
+        match arg.node_kind() {
+            NodeKind::Comment => return None,
+            NodeKind::Assignment => named.push((
+                list_entry_name(code, &arg)?.to_owned(),
+                node_text(code, &arg).to_owned(),
+            )),
+            _ => positional.push(node_text(code, &arg).to_owned()),
+        }
+    }
+    if positional.len() > old_params.len() {
+        return None;
+    }
+
+    // The values of the parameters set by position, by name.
+    let mut values: HashMap<&str, String> = old_params
+        .iter()
+        .zip(positional.iter())
+        .map(|(param, value)| (param.name.as_str(), value.clone()))
+        .collect();
+    let is_set = |name: &str, values: &HashMap<&str, String>| {
+        values.contains_key(name) || named.iter().any(|(arg, _)| arg == name)
+    };
+    // The calls relying on a removed default get it explicitly.
+    let mut removed_defaults = vec![];
+    for old in old_params {
+        let Some(default) = &old.default else {
+            continue;
+        };
+        let new = new_params.iter().find(|new| new.name == old.name);
+        if new.is_some_and(|new| new.default.is_none()) && !is_set(&old.name, &values) {
+            if !movable.contains(&old.name.as_str()) {
+                return None;
+            }
+            removed_defaults.push(format!("{} = {}", old.name, default));
+        }
+    }
+
+    let same_positions = old_params
+        .iter()
+        .zip(new_params)
+        .take(positional.len())
+        .all(|(old, new)| old.name == new.name);
+    if same_positions && removed_defaults.is_empty() {
+        return Some(None);
+    }
+
+    let mut result = vec![];
+    let mut is_positional = true;
+    for param in new_params {
+        let Some(value) = values.remove(param.name.as_str()) else {
+            is_positional = false;
+            continue;
+        };
+        if is_positional {
+            result.push(value);
+        } else {
+            result.push(format!("{} = {}", param.name, value));
+        }
+    }
+    result.extend(named.into_iter().map(|(_, text)| text));
+    result.extend(removed_defaults);
+    Some(Some(TextEdit {
+        range: args.lsp_range(code),
+        new_text: format!("({})", result.join(", ")),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(texts: &[&str]) -> Vec<SignatureParam> {
+        texts
+            .iter()
+            .map(|text| SignatureParam::parse(text).unwrap())
+            .collect()
+    }
+
+    // The arguments of the call `call` rewritten from the `old` to the `new` parameters.
+    fn rewrite(call: &str, old: &[&str], new: &[&str], movable: &[&str]) -> Option<Option<String>> {
+        let tree = parse(call, None);
+        let node = tree.root_node().descendant_for_byte_range(0, 1).unwrap();
+        let args = node.parent()?.child_by_field_name("arguments")?;
+        rewrite_arguments(call, &args, &params(old), &params(new), movable)
+            .map(|edit| edit.map(|edit| edit.new_text))
+    }
+
+    #[test]
+    fn parse_signature_param() {
+        let param = SignatureParam::parse(" b = [1, 2] ").unwrap();
+        assert_eq!(
+            (param.name.as_str(), param.default),
+            ("b", Some("[1, 2]".to_owned()))
+        );
+        let param = SignatureParam::parse("$fn").unwrap();
+        assert_eq!((param.name.as_str(), param.default), ("$fn", None));
+        for text in [
+            "",
+            "a = ",
+            "a = 1, b",
+            "a = 1) cube(",
+            "a // b",
+            "a = 1 2",
+            "1 = a",
+        ] {
+            assert!(SignatureParam::parse(text).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_parameter_identifiers() {
+        let (_, identifiers) = parse_parameter("a = w + max(w, 2) * PI").unwrap();
+        assert_eq!(identifiers, ["PI", "max", "w"]);
+        let (_, identifiers) = parse_parameter("a = \"x\"").unwrap();
+        assert!(identifiers.is_empty());
+    }
+
+    #[test]
+    fn rewrite_swapped_order() {
+        assert_eq!(
+            rewrite("m(1, 2);", &["a", "b"], &["b", "a"], &[]),
+            Some(Some("(2, 1)".to_owned()))
+        );
+        assert_eq!(
+            rewrite("m(b = 2, a = 1);", &["a", "b"], &["b", "a"], &[]),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn rewrite_partial_positional() {
+        let (old, new) = (["a", "b", "c = 2"], ["c = 2", "a", "b"]);
+        assert_eq!(
+            rewrite("m(1);", &old, &new, &[]),
+            Some(Some("(a = 1)".to_owned()))
+        );
+        assert_eq!(
+            rewrite("m(1, 2);", &old, &new, &[]),
+            Some(Some("(a = 1, b = 2)".to_owned()))
+        );
+        // The first parameters keep their place.
+        assert_eq!(
+            rewrite("m(1, 2);", &["a", "b", "c"], &["a", "b", "c"], &[]),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn rewrite_removed_default() {
+        let (old, new) = (["a", "b = 1"], ["b", "a"]);
+        assert_eq!(
+            rewrite("m(5);", &old, &new, &["b"]),
+            Some(Some("(a = 5, b = 1)".to_owned()))
+        );
+        assert_eq!(
+            rewrite("m(5, 6);", &old, &new, &["b"]),
+            Some(Some("(6, 5)".to_owned()))
+        );
+        // The default refers to other declarations at the call.
+        assert_eq!(rewrite("m(5);", &old, &new, &[]), None);
+        assert_eq!(
+            rewrite("m(5, b = 2);", &old, &new, &[]),
+            Some(Some("(a = 5, b = 2)".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rewrite_extra_named_arguments() {
+        assert_eq!(
+            rewrite("m(1, 2, $fn = 8);", &["a", "b"], &["b", "a"], &[]),
+            Some(Some("(2, 1, $fn = 8)".to_owned()))
+        );
+        assert_eq!(
+            rewrite("m(1, $fn = 8, b = 2);", &["a", "b"], &["b", "a"], &[]),
+            Some(Some("(a = 1, $fn = 8, b = 2)".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rewrite_refused() {
+        assert_eq!(rewrite("m(1, 2, 3);", &["a", "b"], &["b", "a"], &[]), None);
+        assert_eq!(
+            rewrite("m(1, /* b */ 2);", &["a", "b"], &["b", "a"], &[]),
+            None
+        );
+    }
+}
@@ -1,45 +1,102 @@
+use std::rc::Rc;
+
 use lazy_static::lazy_static;
-use lsp_types::{CompletionItemKind, Range, SymbolKind, Url};
+use lsp_types::{
+    CompletionItemKind, DocumentSymbol, Location, Range, SymbolInformation, SymbolKind, SymbolTag,
+    Url,
+};
 use regex::Regex;
 use tree_sitter::Node;
 
+use crate::builtin_meta::{BuiltinFlags, BuiltinMeta};
+use crate::node_kind::{NodeKind, NodeKindExt};
 use crate::utils::*;
 
 use crate::Server;
 
-struct BuiltinFlags {}
-impl BuiltinFlags {
-    const IS_OPREATOR: u16 = 1;
-    const IGNORE_PARAM_NAME: u16 = 1 << 1;
-}
-
 #[derive(Clone, Debug)]
 pub(crate) struct Param {
-    pub name: String,
+    pub name: Rc<str>,
     pub default: Option<String>,
     pub range: Range,
+    // The allowed values, from a customizer comment such as `// [left, center, right]` after the
+    // parameter, or the builtin metadata.
+    pub choices: Option<Vec<String>>,
+    // The comment after the parameter on the same line, or the comment lines before it.
+    pub doc: Option<String>,
 }
 
 impl Param {
+    // The parameter as a variable of the module or function body declared in `url`.
+    pub(crate) fn to_item(&self, url: &Url) -> Item {
+        Item {
+            name: self.name.clone(),
+            kind: ItemKind::Variable,
+            range: self.range,
+            url: Some(url.clone()),
+            ..Default::default()
+        }
+    }
+
     pub(crate) fn parse_declaration(code: &str, node: &Node) -> Vec<Param> {
-        node.children(&mut node.walk())
-            .filter_map(|child| match child.kind() {
-                "identifier" => Some(Param {
-                    name: node_text(code, &child).to_owned(),
+        let mut result: Vec<Param> = vec![];
+        let mut last_row = None;
+        let mut pending_doc: Vec<&str> = vec![];
+        for child in node.children(&mut node.walk()) {
+            let param = match child.node_kind() {
+                NodeKind::Identifier | NodeKind::SpecialVariable => Some(Param {
+                    name: intern(node_text(code, &child)),
                     default: None,
-                    range: child.lsp_range(),
+                    range: child.lsp_range(code),
+                    choices: None,
+                    doc: None,
                 }),
-                "assignment" => child.child_by_field_name("left").and_then(|left| {
+                NodeKind::Assignment => child.child_by_field_name("left").and_then(|left| {
                     child.child_by_field_name("right").map(|right| Param {
-                        name: node_text(code, &left).to_owned(),
+                        name: intern(node_text(code, &left)),
                         default: Some(node_text(code, &right).to_owned()),
-                        range: right.lsp_range(),
+                        range: left.lsp_range(code),
+                        choices: None,
+                        doc: None,
                     })
                 }),
-                "special_variable" => None,
+                NodeKind::Comment if last_row == Some(child.start_position().row) => {
+                    if let Some(last) = result.last_mut() {
+                        let comment = node_text(code, &child);
+                        last.choices = parse_customizer_choices(comment, last.default.as_deref());
+                        last.doc = Some(comment_text(comment).to_owned());
+                    }
+                    None
+                }
+                NodeKind::Comment => {
+                    pending_doc.push(comment_text(node_text(code, &child)));
+                    None
+                }
                 _ => None,
-            })
-            .collect()
+            };
+            if let Some(mut param) = param {
+                last_row = Some(child.end_position().row);
+                if !pending_doc.is_empty() {
+                    param.doc = Some(pending_doc.join("\n"));
+                    pending_doc.clear();
+                }
+                result.push(param);
+            }
+        }
+        result
+    }
+
+    // The hover of a named argument, `callable` being the label of the called module or function.
+    pub(crate) fn make_hover(&self, callable: &str) -> String {
+        let mut hover = match &self.default {
+            Some(default) => format!("```scad\n{} = {}\n```", self.name, default),
+            None => format!("```scad\n{}\n```", self.name),
+        };
+        hover = format!("{}\nparameter of `{}`\n", hover, callable);
+        if let Some(doc) = &self.doc {
+            hover = format!("{}\n---\n\n{}\n", hover, doc);
+        }
+        hover
     }
 
     pub(crate) fn make_snippet(params: &[Param], ignore_name: bool) -> String {
@@ -48,6 +105,15 @@ impl Param {
             .filter(|p| p.default.is_none() || !Server::get_server().args.ignore_default)
             .enumerate()
             .map(|(i, p)| {
+                if let Some(choices) = &p.choices {
+                    let choice = make_choice(i + 1, choices, p.default.as_deref());
+                    return if ignore_name {
+                        choice
+                    } else {
+                        format!("{} = {}", p.name, choice)
+                    };
+                }
+
                 if !Server::get_server().args.ignore_default && p.default.as_ref().is_some() {
                     return format!("{} = {}", p.name, p.default.as_ref().unwrap());
                 }
@@ -63,6 +129,172 @@ impl Param {
     }
 }
 
+// Whether a module body calls children() or reads $children, not counting the nested modules.
+fn uses_children(code: &str, node: &Node) -> bool {
+    match node.node_kind() {
+        NodeKind::ModuleDeclaration => false,
+        NodeKind::ModuleCall => {
+            node.child_by_field_name("name")
+                .is_some_and(|name| node_text(code, &name) == "children")
+                || node
+                    .children(&mut node.walk())
+                    .any(|child| uses_children(code, &child))
+        }
+        NodeKind::SpecialVariable => node_text(code, node) == "$children",
+        _ => node
+            .children(&mut node.walk())
+            .any(|child| uses_children(code, &child)),
+    }
+}
+
+fn comment_text(comment: &str) -> &str {
+    comment
+        .trim_start_matches("//")
+        .trim_start_matches("/*")
+        .trim_end_matches("*/")
+        .trim()
+}
+
+// The dropdown values of a customizer comment, `[a, b, c]` or `[10:Small, 20:Large]`. The values
+// are quoted if the default value is a string.
+fn parse_customizer_choices(comment: &str, default: Option<&str>) -> Option<Vec<String>> {
+    let list = comment_text(comment).strip_prefix('[')?.strip_suffix(']')?;
+    let values: Vec<&str> = list
+        .split(',')
+        .map(|value| value.split(':').next().unwrap_or("").trim())
+        .filter(|value| !value.is_empty())
+        .collect();
+    // A single value is a slider range such as `[0:10]`.
+    if values.len() < 2 {
+        return None;
+    }
+
+    let is_string = default.is_some_and(|default| default.starts_with('"'));
+    Some(
+        values
+            .into_iter()
+            .map(|value| {
+                if is_string && !value.starts_with('"') {
+                    format!("\"{}\"", value)
+                } else {
+                    value.to_owned()
+                }
+            })
+            .collect(),
+    )
+}
+
+// A snippet choice placeholder, the default value first.
+fn make_choice(index: usize, choices: &[String], default: Option<&str>) -> String {
+    let escape = |value: &str| {
+        value
+            .replace('\\', "\\\\")
+            .replace(',', "\\,")
+            .replace('|', "\\|")
+    };
+    let mut values: Vec<&str> = default.into_iter().collect();
+    values.extend(
+        choices
+            .iter()
+            .map(String::as_str)
+            .filter(|value| Some(*value) != default),
+    );
+    format!(
+        "${{{}|{}|}}",
+        index,
+        values
+            .iter()
+            .map(|value| escape(value))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+// An example of the documentation of a symbol, with the title of its tag if any.
+pub(crate) struct DocExample {
+    pub title: Option<String>,
+    pub code: String,
+}
+
+// The `Example:` and `Examples:` blocks of the library doc comments, like those of BOSL2,
+// `Example(3D): Rounded`. The block ends at an empty line or the next tag, each line of an
+// `Examples:` block is an example of its own.
+fn tagged_examples(doc: &str) -> Vec<DocExample> {
+    lazy_static! {
+        static ref TAG_RE: Regex = Regex::new(r"^([A-Z][A-Za-z ]*)(\([^)]*\))?:\s*(.*)$").unwrap();
+    }
+    let mut examples = vec![];
+    let mut lines = doc.lines().map(str::trim_end).peekable();
+    while let Some(line) = lines.next() {
+        let Some(caps) = TAG_RE.captures(line.trim()) else {
+            continue;
+        };
+        let title = Some(caps[3].to_owned()).filter(|title| !title.is_empty());
+        let mut code = vec![];
+        while let Some(line) =
+            lines.next_if(|line| !line.trim().is_empty() && !TAG_RE.is_match(line.trim()))
+        {
+            code.push(line);
+        }
+        match &caps[1] {
+            "Example" if !code.is_empty() => examples.push(DocExample {
+                title,
+                code: code.join("\n"),
+            }),
+            "Examples" => examples.extend(code.into_iter().map(|line| DocExample {
+                title: title.clone(),
+                code: line.to_owned(),
+            })),
+            _ => {}
+        }
+    }
+    examples
+}
+
+// The lines without the indentation they have in common.
+fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    text.lines()
+        .map(|line| line.get(indent..).unwrap_or(line.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// The fenced code blocks following an `@example` line.
+fn doc_examples(doc: &str) -> Vec<String> {
+    let mut examples = vec![];
+    let mut lines = doc.lines();
+    while let Some(line) = lines.next() {
+        if !line.trim().starts_with("@example") {
+            continue;
+        }
+        if !lines
+            .next()
+            .is_some_and(|line| line.trim().starts_with("```"))
+        {
+            continue;
+        }
+        let code: Vec<&str> = lines
+            .by_ref()
+            .take_while(|line| !line.trim().starts_with("```"))
+            .collect();
+        examples.push(code.join("\n"));
+    }
+    examples
+}
+
+// Escape the text of a snippet so that it's inserted as is.
+pub(crate) fn escape_snippet(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('$', "\\$")
+        .replace('}', "\\}")
+}
+
 #[derive(Default)]
 pub(crate) enum ItemKind {
     #[default]
@@ -87,17 +319,36 @@ impl ItemKind {
             ItemKind::Module { .. } => CompletionItemKind::MODULE,
         }
     }
+
+    // The parameters of a module or function, none for the others.
+    pub(crate) fn params(&self) -> &[Param] {
+        match self {
+            ItemKind::Module { params, .. } | ItemKind::Function { params, .. } => params,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ItemKind::Variable => "variable",
+            ItemKind::Function { .. } => "function",
+            ItemKind::Keyword(_) => "keyword",
+            ItemKind::Module { .. } => "module",
+        }
+    }
 }
 
 #[derive(Default)]
 pub(crate) struct Item {
-    pub name: String,
+    pub name: Rc<str>,
     pub kind: ItemKind,
     pub range: Range,
     pub url: Option<Url>,
     pub is_builtin: bool,
 
     pub(crate) doc: Option<String>,
+    pub(crate) meta: Option<BuiltinMeta>,
+    pub(crate) bundle_doc: Option<String>,
     pub(crate) hover: Option<String>,
     pub(crate) label: Option<String>,
     pub(crate) snippet: Option<String>,
@@ -127,7 +378,7 @@ impl Item {
 
     pub(crate) fn make_snippet(&mut self) -> String {
         let snippet = match &self.kind {
-            ItemKind::Variable => self.name.clone(),
+            ItemKind::Variable => self.name.to_string(),
             ItemKind::Function { flags, params } => {
                 format!(
                     "{}({});$0",
@@ -140,7 +391,7 @@ impl Item {
                 let params =
                     Param::make_snippet(params, BuiltinFlags::IGNORE_PARAM_NAME & flags != 0);
                 if BuiltinFlags::IS_OPREATOR & flags != 0 {
-                    format!("{}({}) $0", self.name, params)
+                    format!("{}({}) {{ $0 }}", self.name, params)
                 } else {
                     format!("{}({});$0", self.name, params)
                 }
@@ -160,13 +411,20 @@ impl Item {
             ItemKind::Module { .. } => format!("```scad\nmodule {}\n```", label),
             _ => format!("```scad\n{}\n```", label),
         };
+        if let Some(meta) = self.meta.as_ref().and_then(|meta| meta.to_markdown()) {
+            label = format!("{}\n---\n\n{}\n", label, meta);
+        }
         if let Some(doc) = &self.doc {
             if self.is_builtin {
+                let doc = doc.replace("\n@example\n", "\n*Example*\n");
                 label = format!("{}\n---\n\n{}\n", label, doc);
             } else {
                 label = format!("{}\n---\n\n<pre>\n{}\n</pre>\n", label, doc);
             }
         }
+        if let Some(doc) = &self.bundle_doc {
+            label = format!("{}\n---\n\n{}\n", label, doc);
+        }
         // print!("{}", &label);
         label
     }
@@ -177,18 +435,18 @@ impl Item {
                 .iter()
                 .map(|p| match &p.default {
                     Some(d) => format!("{}={}", p.name, d),
-                    None => p.name.clone(),
+                    None => p.name.to_string(),
                 })
                 .collect::<Vec<_>>()
                 .join(", ")
         };
 
         match &self.kind {
-            ItemKind::Variable => self.name.to_owned(),
+            ItemKind::Variable => self.name.to_string(),
             ItemKind::Function { flags: _, params } => {
                 format!("{}({})", self.name, format_params(params))
             }
-            ItemKind::Keyword(_) => self.name.clone(),
+            ItemKind::Keyword(_) => self.name.to_string(),
             ItemKind::Module { params, .. } => {
                 format!("{}({})", self.name, format_params(params))
             }
@@ -196,78 +454,135 @@ impl Item {
     }
 
     pub(crate) fn parse(code: &str, node: &Node) -> Option<Self> {
-        lazy_static! {
-            static ref FLAG_RE: Regex =
-                Regex::new(r"(?m)builtin_flags\((?P<flags>[01]{16})\)").unwrap();
-        };
-
         let extract_name = |name| {
             node.child_by_field_name(name)
                 .map(|child| node_text(code, &child).to_owned())
         };
 
-        match node.kind() {
-            "module_declaration" => {
-                let flags: u16 = if let Some(child) = node
-                    .child_by_field_name("body")
+        match node.node_kind() {
+            NodeKind::ModuleDeclaration => {
+                let body = node.child_by_field_name("body");
+                let mut flags = body
                     .and_then(|body| body.named_child(0))
-                {
-                    let body = node_text(code, &child);
-                    if let Some(cap) = &FLAG_RE.captures(body) {
-                        let flag_str = &cap["flags"];
-                        u16::from_str_radix(flag_str, 2).unwrap()
-                    } else {
-                        0
-                    }
-                } else {
-                    0
-                };
+                    .map_or(0, |child| {
+                        BuiltinMeta::parse_legacy_flags(node_text(code, &child))
+                    });
+                if body.is_some_and(|body| uses_children(code, &body)) {
+                    flags |= BuiltinFlags::IS_OPREATOR;
+                }
                 Some(Self {
-                    name: extract_name("name")?,
+                    name: intern(&extract_name("name")?),
                     kind: ItemKind::Module {
                         flags,
                         params: node
                             .child_by_field_name("parameters")
                             .map_or(vec![], |params| Param::parse_declaration(code, &params)),
                     },
-                    range: node.lsp_range(),
+                    range: node.lsp_range(code),
                     ..Default::default()
                 })
             }
-            "function_declaration" => {
-                let flags = if let Some(child) = node.children(&mut node.walk()).last() {
-                    let body = node_text(code, &child);
-                    if let Some(cap) = &FLAG_RE.captures(body) {
-                        let flag_str = &cap["flags"];
-                        u16::from_str_radix(flag_str, 2).unwrap()
-                    } else {
-                        0
-                    }
-                } else {
-                    0
-                };
+            NodeKind::FunctionDeclaration => {
+                let flags = node.children(&mut node.walk()).last().map_or(0, |child| {
+                    BuiltinMeta::parse_legacy_flags(node_text(code, &child))
+                });
                 Some(Self {
-                    name: extract_name("name")?,
+                    name: intern(&extract_name("name")?),
                     kind: ItemKind::Function {
                         flags,
                         params: node
                             .child_by_field_name("parameters")
                             .map_or(vec![], |params| Param::parse_declaration(code, &params)),
                     },
-                    range: node.lsp_range(),
+                    range: node.lsp_range(code),
                     ..Default::default()
                 })
             }
-            "assignment" => Some(Self {
-                name: extract_name("left")?,
+            NodeKind::Assignment => Some(Self {
+                name: intern(&extract_name("left")?),
                 kind: ItemKind::Variable,
-                range: node.lsp_range(),
+                range: node.lsp_range(code),
                 ..Default::default()
             }),
             _ => None,
         }
     }
 
+    pub(crate) fn set_meta(&mut self, meta: BuiltinMeta) {
+        match &mut self.kind {
+            ItemKind::Function { flags, params } | ItemKind::Module { flags, params } => {
+                *flags |= meta.flags;
+                for param in params.iter_mut() {
+                    if let Some(values) = meta.values.get(&*param.name) {
+                        param.choices = Some(values.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.meta = Some(meta);
+    }
+
+    // An estimate of the memory used by the item, its name is shared.
+    pub(crate) fn memory_size(&self) -> usize {
+        let text = |text: &Option<String>| text.as_ref().map_or(0, String::len);
+        let params: usize = self
+            .kind
+            .params()
+            .iter()
+            .map(|param| std::mem::size_of::<Param>() + text(&param.default) + text(&param.doc))
+            .sum();
+        std::mem::size_of::<Item>()
+            + params
+            + text(&self.doc)
+            + text(&self.bundle_doc)
+            + text(&self.hover)
+            + text(&self.label)
+            + text(&self.snippet)
+    }
+
+    pub(crate) fn category(&self) -> Option<&str> {
+        self.meta.as_ref().and_then(|meta| meta.category.as_deref())
+    }
+
+    // The `@example` code blocks of the documentation, and the `Example:` blocks of the library
+    // doc comments.
+    pub(crate) fn examples(&self) -> Vec<DocExample> {
+        let Some(doc) = &self.doc else {
+            return vec![];
+        };
+        if self.is_builtin {
+            return doc_examples(doc)
+                .into_iter()
+                .map(|code| DocExample { title: None, code })
+                .collect();
+        }
+        // The `*` of the lines of the `/** */` comments are kept by `extract_doc`.
+        let doc = doc
+            .lines()
+            .map(|line| match line.trim_start().strip_prefix('*') {
+                Some(rest) => rest,
+                None => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        doc_examples(&doc)
+            .into_iter()
+            .map(|code| DocExample { title: None, code })
+            .chain(tagged_examples(&doc))
+            .map(|example| DocExample {
+                code: dedent(&example.code),
+                ..example
+            })
+            .collect()
+    }
+
+    pub(crate) fn is_deprecated(&self) -> bool {
+        self.meta
+            .as_ref()
+            .is_some_and(|meta| meta.deprecated.is_some())
+    }
+
     pub(crate) fn get_symbol_kind(&self) -> SymbolKind {
         match self.kind {
             ItemKind::Function { .. } => SymbolKind::FUNCTION,
@@ -276,4 +591,35 @@ impl Item {
             ItemKind::Keyword(_) => SymbolKind::KEY,
         }
     }
+
+    pub(crate) fn document_symbol(&self) -> DocumentSymbol {
+        #[allow(deprecated)]
+        DocumentSymbol {
+            name: self.name.to_string(),
+            detail: self.category().map(str::to_owned),
+            kind: self.get_symbol_kind(),
+            tags: self.is_deprecated().then(|| vec![SymbolTag::DEPRECATED]),
+            deprecated: None,
+            range: self.range,
+            selection_range: self.range,
+            children: None,
+        }
+    }
+
+    // The symbol of the item in its file `url`, the items of the embedded builtin file have no
+    // url of their own.
+    pub(crate) fn symbol_information(&self, url: &Url) -> SymbolInformation {
+        #[allow(deprecated)]
+        SymbolInformation {
+            name: self.name.to_string(),
+            kind: self.get_symbol_kind(),
+            tags: self.is_deprecated().then(|| vec![SymbolTag::DEPRECATED]),
+            deprecated: None,
+            location: Location {
+                uri: url.clone(),
+                range: self.range,
+            },
+            container_name: self.category().map(str::to_owned),
+        }
+    }
 }
@@ -0,0 +1,225 @@
+use std::{collections::HashMap, env};
+
+use lsp_types::MessageType;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{assets::DocImages, server::Server};
+
+// The `openscad` section of the settings, from `workspace/didChangeConfiguration` or the
+// `initializationOptions`.
+#[derive(Default, Deserialize)]
+struct Openscad {
+    search_paths: Option<Paths>,
+    fmt_style: Option<String>,
+    fmt_exe: Option<String>,
+    default_param: Option<bool>,
+    completion_parens: Option<bool>,
+    doc_bundles: Option<String>,
+    openscad_exe: Option<String>,
+    comment_continuation: Option<bool>,
+    hover_thumbnails: Option<bool>,
+    slow_request_ms: Option<u64>,
+    min_library_versions: Option<HashMap<String, String>>,
+    format_exclude: Option<Vec<String>>,
+    max_indexed_files: Option<usize>,
+    max_file_size: Option<u64>,
+    max_indexed_bytes: Option<u64>,
+    large_file_size: Option<u64>,
+    max_diagnostics: Option<usize>,
+    doc_images: Option<DocImages>,
+    format_on_save: Option<FormatOnSave>,
+    save_checks: Option<bool>,
+    project_fmt_exe: Option<bool>,
+}
+
+// What is formatted before the documents are saved, the format_on_save setting.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FormatOnSave {
+    // Nothing, the editors may still format on save with the formatting request.
+    #[default]
+    Off,
+    // The whole document.
+    Document,
+    // The top level statements edited since the document was opened or saved, the rest keeps
+    // the cursor positions, the folds and the undo history of the editor.
+    Modified,
+}
+
+// A list of paths, given as an array or as a string delimited like the PATH variable.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Paths {
+    Delimited(String),
+    List(Vec<String>),
+}
+
+impl Paths {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Paths::Delimited(paths) => env::split_paths(&paths)
+                .filter_map(|buf| buf.into_os_string().into_string().ok())
+                .collect(),
+            Paths::List(paths) => paths,
+        }
+    }
+}
+
+// The keys of `Openscad` and the description of their expected type.
+const SETTINGS: &[(&str, &str)] = &[
+    ("search_paths", "a string or an array of strings"),
+    ("fmt_style", "a string"),
+    ("fmt_exe", "a string"),
+    ("default_param", "a boolean"),
+    ("completion_parens", "a boolean"),
+    ("doc_bundles", "a string"),
+    ("openscad_exe", "a string"),
+    ("comment_continuation", "a boolean"),
+    ("hover_thumbnails", "a boolean"),
+    ("slow_request_ms", "a non-negative integer"),
+    ("min_library_versions", "an object of version strings"),
+    ("format_exclude", "an array of glob patterns"),
+    ("max_indexed_files", "a non-negative integer"),
+    ("max_file_size", "a number of bytes"),
+    ("max_indexed_bytes", "a number of bytes"),
+    ("large_file_size", "a number of bytes"),
+    ("max_diagnostics", "a non-negative integer"),
+    ("doc_images", "\"file\", \"data\" or \"off\""),
+    ("format_on_save", "\"off\", \"document\" or \"modified\""),
+    ("save_checks", "a boolean"),
+    ("project_fmt_exe", "a boolean"),
+];
+
+// Each setting is checked on its own, so that a wrong one doesn't discard the others.
+fn parse_settings(settings: &Value) -> (Openscad, Vec<String>) {
+    let mut errors = vec![];
+    let section = match settings.get("openscad") {
+        Some(Value::Object(section)) => section,
+        None | Some(Value::Null) => return (Openscad::default(), errors),
+        Some(_) => {
+            errors.push("openscad must be an object".to_owned());
+            return (Openscad::default(), errors);
+        }
+    };
+
+    let mut valid = Map::new();
+    for (key, value) in section {
+        if value.is_null() {
+            continue;
+        }
+        let Some((_, expected)) = SETTINGS.iter().find(|(name, _)| name == key) else {
+            errors.push(format!("unknown setting openscad.{}", key));
+            continue;
+        };
+        let single = Value::Object(Map::from_iter([(key.clone(), value.clone())]));
+        if serde_json::from_value::<Openscad>(single).is_ok() {
+            valid.insert(key.clone(), value.clone());
+        } else {
+            errors.push(format!("openscad.{} must be {}", key, expected));
+        }
+    }
+
+    let openscad = serde_json::from_value(Value::Object(valid)).unwrap_or_default();
+    (openscad, errors)
+}
+
+impl Server {
+    // Apply the valid settings and report the others to the user.
+    pub(crate) fn apply_settings(&mut self, settings: Value) {
+        let (openscad, errors) = parse_settings(&settings);
+        self.clear_response_cache();
+        if !errors.is_empty() {
+            self.show_message(
+                MessageType::WARNING,
+                format!("invalid settings: {}", errors.join(", ")),
+            );
+        }
+
+        if let Some(paths) = openscad.search_paths {
+            self.set_search_paths(paths.into_vec());
+        }
+
+        if let Some(style) = openscad.fmt_style {
+            if !style.trim().is_empty() && self.args.fmt_style != style {
+                self.args.fmt_style = style;
+            }
+        }
+
+        if let Some(fmt_exe) = openscad.fmt_exe {
+            if !fmt_exe.trim().is_empty() && self.args.fmt_exe != fmt_exe {
+                self.args.fmt_exe = fmt_exe;
+            }
+        }
+
+        if let Some(default_param) = openscad.default_param {
+            self.args.ignore_default = !default_param;
+        }
+
+        if let Some(completion_parens) = openscad.completion_parens {
+            self.args.completion_parens = completion_parens;
+        }
+
+        if let Some(openscad_exe) = openscad.openscad_exe {
+            if !openscad_exe.trim().is_empty() && self.args.openscad_exe != openscad_exe {
+                self.args.openscad_exe = openscad_exe;
+                self.openscad_version = None;
+            }
+        }
+
+        if let Some(comment_continuation) = openscad.comment_continuation {
+            self.args.comment_continuation = comment_continuation;
+        }
+
+        if let Some(hover_thumbnails) = openscad.hover_thumbnails {
+            self.args.hover_thumbnails = hover_thumbnails;
+        }
+
+        if let Some(slow_request_ms) = openscad.slow_request_ms {
+            self.args.slow_request_ms = slow_request_ms;
+        }
+
+        if let Some(versions) = openscad.min_library_versions {
+            self.min_library_versions = versions;
+        }
+
+        if let Some(patterns) = openscad.format_exclude {
+            self.format_exclude = patterns;
+        }
+
+        if let Some(max_files) = openscad.max_indexed_files {
+            self.index_limits.max_files = max_files;
+        }
+        if let Some(max_file_size) = openscad.max_file_size {
+            self.index_limits.max_file_size = max_file_size;
+        }
+        if let Some(max_total_bytes) = openscad.max_indexed_bytes {
+            self.index_limits.max_total_bytes = max_total_bytes;
+        }
+        if let Some(large_file_size) = openscad.large_file_size {
+            self.large_file_size = large_file_size;
+        }
+        if let Some(max_diagnostics) = openscad.max_diagnostics {
+            self.max_diagnostics = max_diagnostics;
+        }
+        if let Some(doc_images) = openscad.doc_images {
+            self.doc_images = doc_images;
+        }
+        if let Some(format_on_save) = openscad.format_on_save {
+            self.format_on_save = format_on_save;
+        }
+        if let Some(save_checks) = openscad.save_checks {
+            self.save_checks = save_checks;
+        }
+        if let Some(project_fmt_exe) = openscad.project_fmt_exe {
+            self.project_fmt_exe = project_fmt_exe;
+        }
+
+        if let Some(bundles) = openscad.doc_bundles {
+            self.doc_bundle_paths = env::split_paths(&bundles)
+                .filter_map(|buf| buf.into_os_string().into_string().ok())
+                .collect();
+        }
+        self.load_doc_bundles();
+    }
+}
@@ -0,0 +1,204 @@
+use lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel, SignatureHelp,
+    SignatureInformation,
+};
+
+// The label, the parameters and the documentation of a construct.
+type Construct = (&'static str, &'static [&'static str], &'static str);
+
+const FOR: Construct = (
+    "for (variable = values) children",
+    &["variable", "values"],
+    "Repeats the children for each element of `values`: a range `[start : step : end]`, a vector \
+     or a string. With several variables, `for (x = xs, y = ys)`, every combination is repeated.",
+);
+const INTERSECTION_FOR: Construct = (
+    "intersection_for (variable = values) children",
+    &["variable", "values"],
+    "Intersects the children repeated for each element of `values`, where `for` makes their \
+     union.",
+);
+const FOR_COMPREHENSION: Construct = (
+    "[for (variable = values) expression]",
+    &["variable", "values"],
+    "Generates an element for each element of `values`: a range, a vector or a string. `each` \
+     flattens a vector element, `if (condition)` filters the elements.",
+);
+const C_FOR_COMPREHENSION: Construct = (
+    "[for (init; condition; update) expression]",
+    &["init", "condition", "update"],
+    "Generates elements while `condition` is true, starting from the `init` assignments and \
+     applying the `update` assignments after each element: `[for (i = 0; i < 5; i = i + 1) i]`.",
+);
+const IF_COMPREHENSION: Construct = (
+    "[for (...) if (condition) expression else expression]",
+    &["condition"],
+    "Generates the element only when `condition` is true, or the `else` expression otherwise.",
+);
+const LET: Construct = (
+    "let (name = value) body",
+    &["name", "value"],
+    "Defines variables for `body`, each value can use the variables defined before it: \
+     `let (r = d / 2, a = r * r) ...`.",
+);
+const RANGE: Construct = (
+    "[start : step : end]",
+    &["start", "step", "end"],
+    "The numbers from `start` to `end` included, by `step`, 1 if omitted: `[0 : 5]` is 0, 1, 2, \
+     3, 4 and 5.",
+);
+
+// An open bracket before the cursor.
+#[derive(Default)]
+struct Frame {
+    bracket: char,
+    // The word before the bracket, `for` in `for (`.
+    keyword: String,
+    // Whether the bracket directly follows a `=`.
+    after_assign: bool,
+    colons: u32,
+    semicolons: u32,
+    // Whether the current comma separated part has a `=`.
+    has_assign: bool,
+}
+
+// The open brackets of `code` at its end, the strings and comments are skipped.
+fn open_frames(code: &str) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = vec![];
+    let mut word = String::new();
+    let mut last = ' ';
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                for c in chars.by_ref() {
+                    if star && c == '/' {
+                        break;
+                    }
+                    star = c == '*';
+                }
+                continue;
+            }
+            '(' | '[' | '{' => frames.push(Frame {
+                bracket: c,
+                keyword: std::mem::take(&mut word),
+                after_assign: last == '=',
+                ..Default::default()
+            }),
+            ')' | ']' | '}' => {
+                frames.pop();
+            }
+            _ => {}
+        }
+        if let Some(frame) = frames.last_mut() {
+            match c {
+                ',' => frame.has_assign = false,
+                ':' => frame.colons += 1,
+                ';' => frame.semicolons += 1,
+                '=' if !matches!(last, '=' | '<' | '>' | '!') && chars.peek() != Some(&'=') => {
+                    frame.has_assign = true
+                }
+                _ => {}
+            }
+        }
+
+        if c.is_alphanumeric() || c == '_' {
+            if !(last.is_alphanumeric() || last == '_') {
+                word.clear();
+            }
+            word.push(c);
+        } else if !c.is_whitespace() {
+            word.clear();
+        }
+        if !c.is_whitespace() {
+            last = c;
+        }
+    }
+    frames
+}
+
+// The explanation of the for, intersection_for, let, list comprehension or range construct at the
+// end of `code`, the text before the cursor.
+pub(crate) fn construct_signature(code: &str) -> Option<SignatureHelp> {
+    let frames = open_frames(code);
+    let mut found = None;
+    for (i, frame) in frames.iter().enumerate().rev() {
+        let below = i.checked_sub(1).map(|i| &frames[i]);
+        let in_list = below.is_some_and(|below| below.bracket == '[');
+        match frame.bracket {
+            '[' => {
+                let in_for = below.is_some_and(|below| {
+                    below.bracket == '('
+                        && matches!(below.keyword.as_str(), "for" | "intersection_for")
+                });
+                if frame.colons > 0 || (frame.after_assign && in_for) {
+                    found = Some((RANGE, frame.colons.min(2)));
+                    break;
+                }
+                // A vector, the construct may be around it.
+            }
+            '(' => {
+                let active = u32::from(frame.has_assign);
+                found = match frame.keyword.as_str() {
+                    "for" if in_list && frame.semicolons > 0 => {
+                        Some((C_FOR_COMPREHENSION, frame.semicolons.min(2)))
+                    }
+                    "for" if in_list => Some((FOR_COMPREHENSION, active)),
+                    "for" => Some((FOR, active)),
+                    "intersection_for" => Some((INTERSECTION_FOR, active)),
+                    "let" => Some((LET, active)),
+                    "if" if in_list => Some((IF_COMPREHENSION, 0)),
+                    _ => None,
+                };
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    let ((label, params, doc), active) = found?;
+    let parameters = params
+        .iter()
+        .filter_map(|param| {
+            let start = label.find(param)? as u32;
+            Some(ParameterInformation {
+                label: ParameterLabel::LabelOffsets([start, start + param.len() as u32]),
+                documentation: None,
+            })
+        })
+        .collect();
+    Some(SignatureHelp {
+        signatures: vec![SignatureInformation {
+            label: label.to_owned(),
+            documentation: Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: doc.to_owned(),
+            })),
+            parameters: Some(parameters),
+            active_parameter: Some(active),
+        }],
+        active_signature: Some(0),
+        active_parameter: Some(active),
+    })
+}
@@ -0,0 +1,200 @@
+use lazy_static::lazy_static;
+use lsp_types::{Diagnostic, DiagnosticSeverity, DiagnosticTag, NumberOrString, Range, TextEdit};
+use regex::Regex;
+use serde_json::{json, Value};
+use tree_sitter::Node;
+use tree_sitter_traversal::{traverse, Order};
+
+use crate::{
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    utils::*,
+};
+
+pub(crate) const EMPTY_STATEMENT: &str = "empty-statement";
+
+// A targeted message for a frequent mistake, instead of "syntax error", and its fix when it's
+// safe.
+pub(crate) struct Hint {
+    pub range: Range,
+    pub message: String,
+    pub fix: Option<(String, Vec<TextEdit>)>,
+}
+
+// The rules recognizing the frequent mistakes from the error nodes, tried in order.
+const RULES: &[fn(&ParsedCode, &Node) -> Option<Hint>] = &[
+    module_with_equals,
+    function_with_block,
+    function_without_equals,
+    comparison_statement,
+];
+
+// The hint of the first rule recognizing the error node.
+pub(crate) fn error_hint(code: &ParsedCode, node: &Node) -> Option<Hint> {
+    RULES.iter().find_map(|rule| rule(code, node))
+}
+
+// The data of a diagnostic with a fix, read by the quick fix code action.
+pub(crate) fn fix_data(title: String, edits: Vec<TextEdit>) -> Value {
+    json!({ "fix": { "title": title, "edits": edits } })
+}
+
+fn position(code: &ParsedCode, offset: usize) -> lsp_types::Position {
+    offset_to_position(&code.code, offset)
+}
+
+fn range(code: &ParsedCode, start: usize, end: usize) -> Range {
+    Range::new(position(code, start), position(code, end))
+}
+
+// `module m() = cube(1);`, the `=` of a function.
+fn module_with_equals(code: &ParsedCode, node: &Node) -> Option<Hint> {
+    let parent = node.parent()?;
+    let is_equals = node_text(&code.code, node) == "="
+        && parent.node_kind() == NodeKind::ModuleDeclaration
+        && node.prev_sibling()?.node_kind() == NodeKind::ParametersDeclaration;
+    if !is_equals {
+        return None;
+    }
+    let spaces =
+        code.code[node.end_byte()..].len() - code.code[node.end_byte()..].trim_start().len();
+    Some(Hint {
+        range: node.lsp_range(&code.code),
+        message: "modules don't return a value, their body follows the parameters without `=`"
+            .to_owned(),
+        fix: Some((
+            "Remove `=`".to_owned(),
+            vec![TextEdit {
+                range: range(code, node.start_byte(), node.end_byte() + spaces),
+                new_text: String::new(),
+            }],
+        )),
+    })
+}
+
+// The error node of a function declaration, `function f(x) ...`, and the offset of the text after
+// its parameters.
+fn function_error<'a>(code: &ParsedCode, node: &Node<'a>) -> Option<(Node<'a>, usize)> {
+    if !node_text(&code.code, node).starts_with("function") {
+        return None;
+    }
+    let params = node
+        .children(&mut node.walk())
+        .find(|child| child.node_kind() == NodeKind::ParametersDeclaration)?;
+    let after = &code.code[params.end_byte()..node.end_byte()];
+    let body = params.end_byte() + after.len() - after.trim_start().len();
+    Some((params, body))
+}
+
+// `function f(x) { return x * 2; }`, the functions are an expression.
+fn function_with_block(code: &ParsedCode, node: &Node) -> Option<Hint> {
+    lazy_static! {
+        static ref RETURN_BLOCK_RE: Regex =
+            Regex::new(r"^\{\s*(?:return\b\s*)?([^;{}]+?)\s*;?\s*\}$").unwrap();
+    }
+    let (_, body) = function_error(code, node)?;
+    let block = &code.code[body..node.end_byte()];
+    if !block.starts_with('{') {
+        return None;
+    }
+    // Only a single expression is moved after `=`.
+    let fix = RETURN_BLOCK_RE.captures(block).map(|caps| {
+        (
+            "Make the block an expression".to_owned(),
+            vec![TextEdit {
+                range: range(code, body, node.end_byte()),
+                new_text: format!("= {};", &caps[1]),
+            }],
+        )
+    });
+    Some(Hint {
+        range: range(code, body, node.end_byte()),
+        message: "the body of a function is an expression after `=`, not a block: \
+                  `function f(x) = x * 2;`"
+            .to_owned(),
+        fix,
+    })
+}
+
+// `function f(x) x * 2;`.
+fn function_without_equals(code: &ParsedCode, node: &Node) -> Option<Hint> {
+    let (params, body) = function_error(code, node)?;
+    let rest = &code.code[body..node.end_byte()];
+    if rest.is_empty() || rest.starts_with('=') || rest.starts_with('{') {
+        return None;
+    }
+    Some(Hint {
+        range: range(code, params.end_byte(), body),
+        message: "missing `=` before the body of the function".to_owned(),
+        fix: Some((
+            "Insert `=`".to_owned(),
+            vec![TextEdit {
+                range: range(code, body, body),
+                new_text: "= ".to_owned(),
+            }],
+        )),
+    })
+}
+
+// `x == 5;` as a statement, meant as an assignment.
+fn comparison_statement(code: &ParsedCode, node: &Node) -> Option<Hint> {
+    lazy_static! {
+        static ref COMPARISON_RE: Regex = Regex::new(r"^\$?[A-Za-z_]\w*\s*(==)").unwrap();
+    }
+    let is_statement = node.parent().is_some_and(|parent| {
+        matches!(
+            parent.node_kind(),
+            NodeKind::SourceFile | NodeKind::UnionBlock
+        )
+    });
+    let text = node_text(&code.code, node);
+    let operator = COMPARISON_RE
+        .captures(text)
+        .filter(|_| is_statement)?
+        .get(1)?;
+    let start = node.start_byte() + operator.start();
+    let end = node.start_byte() + operator.end();
+    Some(Hint {
+        range: range(code, start, end),
+        message: "`==` compares, an assignment is made with `=`".to_owned(),
+        fix: Some((
+            "Replace `==` with `=`".to_owned(),
+            vec![TextEdit {
+                range: range(code, start, end),
+                new_text: "=".to_owned(),
+            }],
+        )),
+    })
+}
+
+// The `;` after a block, `module m() { ... };`, an empty statement which OpenSCAD accepts.
+pub(crate) fn empty_statement_diagnostics(code: &ParsedCode) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    for node in traverse(code.tree.walk(), Order::Pre) {
+        if node.kind() != ";" || node.is_missing() {
+            continue;
+        }
+        let after_block = node.prev_sibling().is_some_and(|prev| {
+            prev.is_named() && !prev.is_error() && node_text(&code.code, &prev).ends_with('}')
+        });
+        if !after_block {
+            continue;
+        }
+        diags.push(Diagnostic {
+            range: node.lsp_range(&code.code),
+            severity: Some(DiagnosticSeverity::HINT),
+            code: Some(NumberOrString::String(EMPTY_STATEMENT.to_owned())),
+            message: "no `;` is needed after a block, this is an empty statement".to_owned(),
+            tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+            data: Some(fix_data(
+                "Remove `;`".to_owned(),
+                vec![TextEdit {
+                    range: node.lsp_range(&code.code),
+                    new_text: String::new(),
+                }],
+            )),
+            ..Default::default()
+        });
+    }
+    diags
+}
@@ -0,0 +1,756 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, DiagnosticTag, NumberOrString};
+use tree_sitter::{Node, Point};
+use tree_sitter_traversal::{traverse, Order};
+
+use crate::{
+    code_helper::call_name_node,
+    constants::Evaluation,
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    response_item::{Item, ItemKind},
+    server::Server,
+    utils::*,
+};
+
+pub(crate) const INDEXED_SCALAR: &str = "indexed-scalar";
+pub(crate) const UNDEF_OPERATION: &str = "undef-operation";
+pub(crate) const VECTOR_ARGUMENT: &str = "vector-argument";
+pub(crate) const FORMAT_ARGUMENTS: &str = "format-arguments";
+pub(crate) const DEAD_CODE: &str = "dead-code";
+
+// How far variables and function calls are followed.
+const MAX_INFER_DEPTH: u32 = 6;
+
+const NUMBER_FUNCTIONS: &[&str] = &[
+    "abs",
+    "sign",
+    "sin",
+    "cos",
+    "tan",
+    "asin",
+    "acos",
+    "atan",
+    "atan2",
+    "floor",
+    "round",
+    "ceil",
+    "ln",
+    "log",
+    "pow",
+    "sqrt",
+    "exp",
+    "min",
+    "max",
+    "norm",
+    "len",
+    "lookup",
+    "ord",
+    "version_num",
+];
+const STRING_FUNCTIONS: &[&str] = &["str", "chr"];
+const BOOLEAN_FUNCTIONS: &[&str] = &[
+    "is_undef",
+    "is_bool",
+    "is_num",
+    "is_string",
+    "is_list",
+    "is_function",
+];
+const VECTOR_FUNCTIONS: &[&str] = &["concat", "rands", "search"];
+
+// The vector arguments of the builtin transformations: the module, the parameter and its position,
+// whether a number is accepted too, and the valid vector lengths.
+const TRANSFORM_ARGUMENTS: &[(&str, &str, usize, bool, &[usize])] = &[
+    ("translate", "v", 0, false, &[2, 3]),
+    ("rotate", "a", 0, true, &[1, 2, 3]),
+    ("rotate", "v", 1, false, &[3]),
+    ("scale", "v", 0, true, &[2, 3]),
+    ("resize", "newsize", 0, false, &[2, 3]),
+];
+
+// The type of an expression, as far as it can be told without evaluating it.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ValueType {
+    Number,
+    String,
+    Boolean,
+    // The length of the vector, if known.
+    Vector(Option<usize>),
+    Range,
+    Undef,
+    Function,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Number => write!(f, "number"),
+            ValueType::String => write!(f, "string"),
+            ValueType::Boolean => write!(f, "boolean"),
+            ValueType::Vector(Some(len)) => write!(f, "vector[{}]", len),
+            ValueType::Vector(None) => write!(f, "vector"),
+            ValueType::Range => write!(f, "range"),
+            ValueType::Undef => write!(f, "undef"),
+            ValueType::Function => write!(f, "function"),
+        }
+    }
+}
+
+fn builtin_function_type(name: &str) -> Option<ValueType> {
+    if NUMBER_FUNCTIONS.contains(&name) {
+        Some(ValueType::Number)
+    } else if STRING_FUNCTIONS.contains(&name) {
+        Some(ValueType::String)
+    } else if BOOLEAN_FUNCTIONS.contains(&name) {
+        Some(ValueType::Boolean)
+    } else if VECTOR_FUNCTIONS.contains(&name) {
+        Some(ValueType::Vector(None))
+    } else {
+        match name {
+            "cross" | "version" => Some(ValueType::Vector(Some(3))),
+            _ => None,
+        }
+    }
+}
+
+fn special_variable_type(name: &str) -> Option<ValueType> {
+    match name {
+        "$fn" | "$fa" | "$fs" | "$t" | "$children" | "$vpd" | "$vpf" => Some(ValueType::Number),
+        "$vpr" | "$vpt" => Some(ValueType::Vector(Some(3))),
+        "$preview" => Some(ValueType::Boolean),
+        _ => None,
+    }
+}
+
+// The assignment or function declaration starting at `point`, the definition of an item.
+pub(crate) fn definition_node<'a>(code: &'a ParsedCode, point: Point) -> Option<Node<'a>> {
+    let mut node = code
+        .tree
+        .root_node()
+        .descendant_for_point_range(point, point)?;
+    loop {
+        if node.node_kind() == NodeKind::Assignment
+            || node.node_kind() == NodeKind::FunctionDeclaration
+        {
+            return Some(node);
+        }
+        match node.parent() {
+            Some(parent) if parent.start_position() == point => node = parent,
+            _ => return None,
+        }
+    }
+}
+
+// The expression of a function declaration, its last named child.
+pub(crate) fn function_body<'a>(node: &Node<'a>) -> Option<Node<'a>> {
+    let mut cursor = node.walk();
+    let body = node
+        .named_children(&mut cursor)
+        .filter(|child| child.node_kind() != NodeKind::Comment)
+        .last();
+    body.filter(|body| Some(*body) != node.child_by_field_name("parameters"))
+}
+
+impl Server {
+    // The type of the value of a variable or the result of a function.
+    pub(crate) fn item_type(&mut self, item: &Rc<RefCell<Item>>) -> Option<ValueType> {
+        self.item_type_with_depth(item, 0)
+    }
+
+    fn item_type_with_depth(&mut self, item: &Rc<RefCell<Item>>, depth: u32) -> Option<ValueType> {
+        let item = item.borrow();
+        if item.is_builtin {
+            return match item.kind {
+                ItemKind::Function { .. } => builtin_function_type(&item.name),
+                _ => None,
+            };
+        }
+
+        let file = self.get_code(item.url.as_ref()?)?;
+        let code = file.try_borrow().ok()?;
+        let node = definition_node(&code, to_point(&code.code, item.range.start))?;
+        match (&item.kind, node.node_kind()) {
+            (ItemKind::Variable, NodeKind::Assignment) => {
+                let value = node.child_by_field_name("right")?;
+                let value_type = self.infer_type(&code, &value, depth + 1)?;
+                // The variable of a for loop iterates over the range or vector.
+                let is_loop = node
+                    .parent()
+                    .filter(|parent| parent.node_kind() == NodeKind::ParenthesizedAssignments)
+                    .and_then(|parent| parent.parent())
+                    .is_some_and(|parent| {
+                        matches!(
+                            parent.node_kind(),
+                            NodeKind::ForBlock
+                                | NodeKind::IntersectionForBlock
+                                | NodeKind::ForClause
+                        )
+                    });
+                if is_loop {
+                    return match value_type {
+                        ValueType::Range => Some(ValueType::Number),
+                        _ => None,
+                    };
+                }
+                Some(value_type)
+            }
+            (ItemKind::Function { .. }, NodeKind::FunctionDeclaration) => {
+                let body = function_body(&node)?;
+                self.infer_type(&code, &body, depth + 1)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn infer_type(
+        &mut self,
+        code: &ParsedCode,
+        node: &Node,
+        depth: u32,
+    ) -> Option<ValueType> {
+        if depth > MAX_INFER_DEPTH {
+            return None;
+        }
+
+        let infer_field = |server: &mut Self, field: &str| {
+            node.child_by_field_name(field)
+                .and_then(|child| server.infer_type(code, &child, depth))
+        };
+
+        match node.node_kind() {
+            NodeKind::Number => Some(ValueType::Number),
+            NodeKind::String => Some(ValueType::String),
+            NodeKind::Boolean => Some(ValueType::Boolean),
+            NodeKind::Undef => Some(ValueType::Undef),
+            NodeKind::Range => Some(ValueType::Range),
+            NodeKind::Function => Some(ValueType::Function),
+            NodeKind::List => {
+                let mut cursor = node.walk();
+                let mut len = 0;
+                for child in node.named_children(&mut cursor) {
+                    match child.node_kind() {
+                        NodeKind::Comment => {}
+                        NodeKind::Each | NodeKind::ListComprehension => {
+                            return Some(ValueType::Vector(None))
+                        }
+                        _ => len += 1,
+                    }
+                }
+                Some(ValueType::Vector(Some(len)))
+            }
+            NodeKind::ParenthesizedExpression => {
+                let inner = node.named_child(0)?;
+                self.infer_type(code, &inner, depth)
+            }
+            NodeKind::LetExpression => infer_field(self, "body"),
+            NodeKind::SpecialVariable => special_variable_type(node_text(&code.code, node)),
+            NodeKind::UnaryExpression => {
+                let operator = node.child(0)?;
+                let operand = node.named_child(0)?;
+                match operator.kind() {
+                    "!" => Some(ValueType::Boolean),
+                    _ => match self.infer_type(code, &operand, depth)? {
+                        ValueType::Number => Some(ValueType::Number),
+                        ValueType::Vector(len) => Some(ValueType::Vector(len)),
+                        _ => Some(ValueType::Undef),
+                    },
+                }
+            }
+            NodeKind::BinaryExpression => {
+                let operator = node.child(1)?.kind();
+                match operator {
+                    "==" | "!=" | "<" | ">" | "<=" | ">=" | "&&" | "||" => {
+                        return Some(ValueType::Boolean)
+                    }
+                    _ => {}
+                }
+                let left = infer_field(self, "left")?;
+                let right = infer_field(self, "right")?;
+                binary_operation_type(operator, &left, &right)
+            }
+            NodeKind::TernaryExpression => {
+                let consequence = infer_field(self, "consequence")?;
+                let alternative = infer_field(self, "alternative")?;
+                match (consequence, alternative) {
+                    (ValueType::Vector(a), ValueType::Vector(b)) => {
+                        Some(ValueType::Vector(if a == b { a } else { None }))
+                    }
+                    (a, b) if a == b => Some(a),
+                    _ => None,
+                }
+            }
+            NodeKind::IndexExpression => match infer_field(self, "value")? {
+                ValueType::String => Some(ValueType::String),
+                ValueType::Number | ValueType::Boolean | ValueType::Undef => Some(ValueType::Undef),
+                _ => None,
+            },
+            NodeKind::Identifier => {
+                let name = node_text(&code.code, node);
+                let item = self
+                    .find_identities(code, &|item_name| item_name == name, node, true)
+                    .into_iter()
+                    .find(|item| matches!(item.borrow().kind, ItemKind::Variable))?;
+                self.item_type_with_depth(&item, depth)
+            }
+            NodeKind::FunctionCall => {
+                let name_node = call_name_node(node)?;
+                if name_node.node_kind() != NodeKind::Identifier {
+                    return None;
+                }
+                let name = node_text(&code.code, &name_node);
+                let item = self
+                    .find_identities(code, &|item_name| item_name == name, node, true)
+                    .into_iter()
+                    .find(|item| matches!(item.borrow().kind, ItemKind::Function { .. }))?;
+                self.item_type_with_depth(&item, depth)
+            }
+            _ => None,
+        }
+    }
+
+    // The truth of a condition which doesn't depend on the parameters: literals, `!`, `&&`, `||`,
+    // variables assigned once to such a value and the constant expressions.
+    pub(crate) fn constant_truth(
+        &mut self,
+        evaluation: &mut Evaluation,
+        code: &ParsedCode,
+        node: &Node,
+        depth: u32,
+    ) -> Option<bool> {
+        if depth > MAX_INFER_DEPTH {
+            return None;
+        }
+        let text = node_text(&code.code, node);
+        match node.node_kind() {
+            NodeKind::Boolean => Some(text == "true"),
+            NodeKind::Undef => Some(false),
+            NodeKind::Number => text.parse::<f64>().ok().map(|value| value != 0.0),
+            NodeKind::String => Some(!unquote(text).is_empty()),
+            NodeKind::ParenthesizedExpression => {
+                let inner = node.named_child(0)?;
+                self.constant_truth(evaluation, code, &inner, depth)
+            }
+            NodeKind::UnaryExpression if node.child(0)?.kind() == "!" => {
+                let operand = node.named_child(0)?;
+                self.constant_truth(evaluation, code, &operand, depth)
+                    .map(|truth| !truth)
+            }
+            NodeKind::BinaryExpression => {
+                let operator = node.child(1)?.kind();
+                let mut truth = |field: &str| {
+                    node.child_by_field_name(field)
+                        .and_then(|child| self.constant_truth(evaluation, code, &child, depth))
+                };
+                match (operator, truth("left")) {
+                    ("&&", Some(false)) => Some(false),
+                    ("||", Some(true)) => Some(true),
+                    ("&&", Some(true)) | ("||", Some(false)) => truth("right"),
+                    ("&&", None) => truth("right").filter(|truth| !truth),
+                    ("||", None) => truth("right").filter(|truth| *truth),
+                    _ => self
+                        .constant_value(evaluation, code, node, &HashMap::new(), 0)
+                        .map(|value| value.truth()),
+                }
+            }
+            NodeKind::Identifier => {
+                if let Some(value) = self.override_value(evaluation, code, node) {
+                    return Some(value.truth());
+                }
+                let (file, point) = self.constant_assignment(code, node)?;
+                let file = file.try_borrow().ok()?;
+                let value = definition_node(&file, point)?.child_by_field_name("right")?;
+                self.constant_truth(evaluation, &file, &value, depth + 1)
+            }
+            _ => self
+                .constant_value(evaluation, code, node, &HashMap::new(), 0)
+                .map(|value| value.truth()),
+        }
+    }
+
+    // The file and the position of the assignment of the variable `node`, when it's assigned
+    // once, not a parameter nor a loop variable.
+    pub(crate) fn constant_assignment(
+        &mut self,
+        code: &ParsedCode,
+        node: &Node,
+    ) -> Option<(Rc<RefCell<ParsedCode>>, Point)> {
+        let text = node_text(&code.code, node);
+        let item = self
+            .find_identities(code, &|item_name| item_name == text, node, true)
+            .into_iter()
+            .find(|item| matches!(item.borrow().kind, ItemKind::Variable))?;
+        let item = item.borrow();
+        let rc = self.get_code(item.url.as_ref()?)?;
+        let file = rc.try_borrow().ok()?;
+        let point = to_point(&file.code, item.range.start);
+        let definition = definition_node(&file, point)
+            .filter(|node| node.node_kind() == NodeKind::Assignment)?;
+        // Parameters and loop variables take other values.
+        let scope = definition.parent()?;
+        let is_constant = match scope.node_kind() {
+            NodeKind::SourceFile | NodeKind::UnionBlock => true,
+            NodeKind::ParenthesizedAssignments => scope.parent().is_some_and(|p| {
+                matches!(p.node_kind(), NodeKind::LetBlock | NodeKind::LetExpression)
+            }),
+            _ => false,
+        };
+        let reassigned = scope
+            .named_children(&mut scope.walk())
+            .filter(|other| other.node_kind() == NodeKind::Assignment && *other != definition)
+            .filter_map(|other| other.child_by_field_name("left"))
+            .any(|left| node_text(&file.code, &left) == text);
+        if !is_constant || reassigned {
+            return None;
+        }
+        drop(file);
+        Some((rc, point))
+    }
+
+    // The branches of if statements, if clauses and ternary expressions which are never taken.
+    pub(crate) fn dead_code_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let mut diags = vec![];
+        let mut evaluation = self.evaluation(&code.url);
+        for node in traverse(code.tree.walk(), Order::Pre) {
+            if !matches!(
+                node.node_kind(),
+                NodeKind::IfBlock | NodeKind::IfClause | NodeKind::TernaryExpression
+            ) {
+                continue;
+            }
+            let Some(condition) = node.child_by_field_name("condition") else {
+                continue;
+            };
+            let Some(truth) = self.constant_truth(&mut evaluation, code, &condition, 0) else {
+                continue;
+            };
+            let condition = match condition.node_kind() {
+                NodeKind::ParenthesizedExpression => condition.named_child(0).unwrap_or(condition),
+                _ => condition,
+            };
+            let dead = match truth {
+                false => node.child_by_field_name("consequence"),
+                true => node
+                    .children_by_field_name("alternative", &mut node.walk())
+                    .filter(|child| child.is_named())
+                    .last(),
+            };
+            let Some(dead) = dead else {
+                continue;
+            };
+
+            diags.push(Diagnostic {
+                range: dead.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::HINT),
+                code: Some(NumberOrString::String(DEAD_CODE.to_owned())),
+                message: format!(
+                    "`{}` is always {}, this code is never evaluated",
+                    node_text(&code.code, &condition),
+                    truth
+                ),
+                tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                ..Default::default()
+            });
+        }
+        diags
+    }
+
+    // Lints of the operations whose result is always undef.
+    pub(crate) fn type_diagnostics(&mut self, code: &ParsedCode) -> Vec<Diagnostic> {
+        let mut diags = vec![];
+        for node in traverse(code.tree.walk(), Order::Pre) {
+            let message = match node.node_kind() {
+                NodeKind::IndexExpression => node
+                    .child_by_field_name("value")
+                    .and_then(|value| self.infer_type(code, &value, 0))
+                    .filter(|value_type| {
+                        matches!(value_type, ValueType::Number | ValueType::Boolean)
+                    })
+                    .map(|value_type| (INDEXED_SCALAR, format!("indexing a {}", value_type))),
+                NodeKind::BinaryExpression => {
+                    let operator = node.child(1).map_or("", |op| op.kind());
+                    if !matches!(operator, "+" | "-" | "*" | "/" | "%" | "^") {
+                        continue;
+                    }
+                    let left = node
+                        .child_by_field_name("left")
+                        .and_then(|left| self.infer_type(code, &left, 0));
+                    let right = node
+                        .child_by_field_name("right")
+                        .and_then(|right| self.infer_type(code, &right, 0));
+                    match (left, right) {
+                        (Some(left), Some(right))
+                            if left != ValueType::Undef
+                                && right != ValueType::Undef
+                                && binary_operation_type(operator, &left, &right)
+                                    == Some(ValueType::Undef) =>
+                        {
+                            Some((
+                                UNDEF_OPERATION,
+                                format!("{} {} {} is undef", left, operator, right),
+                            ))
+                        }
+                        _ => None,
+                    }
+                }
+                NodeKind::ModuleCall => {
+                    diags.extend(self.transform_argument_diagnostics(code, &node));
+                    None
+                }
+                NodeKind::FunctionCall
+                    if call_name_node(&node)
+                        .is_some_and(|name| node_text(&code.code, &name) == "str")
+                        && node
+                            .child_by_field_name("arguments")
+                            .is_some_and(|args| args.named_child_count() == 0)
+                        && self.is_builtin_call(code, &node) =>
+                {
+                    Some((
+                        FORMAT_ARGUMENTS,
+                        "str() without arguments is an empty string".to_owned(),
+                    ))
+                }
+                NodeKind::AssertStatement | NodeKind::AssertExpression => node
+                    .child_by_field_name("condition")
+                    .filter(|condition| {
+                        self.infer_type(code, condition, 0) == Some(ValueType::String)
+                    })
+                    .map(|_| {
+                        (
+                            FORMAT_ARGUMENTS,
+                            "the condition of assert() is a string, always true unless empty, \
+                             the message goes after the condition"
+                                .to_owned(),
+                        )
+                    }),
+                _ => None,
+            };
+
+            if let Some((lint, message)) = message {
+                diags.push(Diagnostic {
+                    range: node.lsp_range(&code.code),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    code: Some(NumberOrString::String(lint.to_owned())),
+                    message,
+                    ..Default::default()
+                });
+            }
+        }
+        diags
+    }
+    // Whether a module or function call resolves to the builtin of its name.
+    fn is_builtin_call(&mut self, code: &ParsedCode, call: &Node) -> bool {
+        let Some(name_node) = call_name_node(call) else {
+            return false;
+        };
+        let name = node_text(&code.code, &name_node);
+        let is_module = call.node_kind() == NodeKind::ModuleCall;
+        self.find_identities(code, &|item_name| item_name == name, call, true)
+            .into_iter()
+            .find(|item| match item.borrow().kind {
+                ItemKind::Module { .. } => is_module,
+                ItemKind::Function { .. } => !is_module,
+                _ => false,
+            })
+            .is_some_and(|item| item.borrow().is_builtin)
+    }
+
+    // How the arguments of a str(), echo() or assert() call are printed, the values which aren't
+    // literals are shown as `{expression: type}`.
+    pub(crate) fn format_preview(&mut self, code: &ParsedCode, call: &Node) -> Option<String> {
+        match call.node_kind() {
+            NodeKind::AssertStatement | NodeKind::AssertExpression => {
+                let condition = call.child_by_field_name("condition")?;
+                let mut preview =
+                    format!("Assertion '{}' failed", node_text(&code.code, &condition));
+                if let Some(message) = call.child_by_field_name("message") {
+                    preview += ": ";
+                    preview += &self.value_preview(code, &message, true);
+                }
+                Some(preview)
+            }
+            NodeKind::ModuleCall | NodeKind::FunctionCall if self.is_builtin_call(code, call) => {
+                let name = node_text(&code.code, &call_name_node(call)?);
+                let args = call.child_by_field_name("arguments")?;
+                let mut cursor = args.walk();
+                let args: Vec<Node> = args
+                    .named_children(&mut cursor)
+                    .filter(|arg| arg.node_kind() != NodeKind::Comment)
+                    .collect();
+                match name {
+                    "str" => Some(format!(
+                        "\"{}\"",
+                        args.iter()
+                            .map(|arg| self.value_preview(code, &argument_value(arg), false))
+                            .collect::<String>()
+                    )),
+                    "echo" => Some(format!(
+                        "ECHO: {}",
+                        args.iter()
+                            .map(|arg| {
+                                let value = self.value_preview(code, &argument_value(arg), true);
+                                match arg
+                                    .child_by_field_name("left")
+                                    .filter(|_| arg.node_kind() == NodeKind::Assignment)
+                                {
+                                    Some(name) => {
+                                        format!("{} = {}", node_text(&code.code, &name), value)
+                                    }
+                                    None => value,
+                                }
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // A value as printed by str() or, `quoted`, by echo().
+    fn value_preview(&mut self, code: &ParsedCode, node: &Node, quoted: bool) -> String {
+        let text = node_text(&code.code, node);
+        match node.node_kind() {
+            NodeKind::String if !quoted => unquote(text).to_owned(),
+            NodeKind::String | NodeKind::Number | NodeKind::Boolean | NodeKind::Undef => {
+                text.to_owned()
+            }
+            NodeKind::FunctionCall
+                if call_name_node(node)
+                    .is_some_and(|name| node_text(&code.code, &name) == "str") =>
+            {
+                let preview = self.format_preview(code, node);
+                match preview {
+                    Some(preview) if !quoted => unquote(&preview).to_owned(),
+                    Some(preview) => preview,
+                    None => format!("{{{}}}", text),
+                }
+            }
+            _ => match self.infer_type(code, node, 0) {
+                Some(value_type) => format!("{{{}: {}}}", text, value_type),
+                None => format!("{{{}}}", text),
+            },
+        }
+    }
+
+    // Vectors of the wrong length, or scalars, passed to translate(), rotate(), scale() and
+    // resize().
+    fn transform_argument_diagnostics(
+        &mut self,
+        code: &ParsedCode,
+        call: &Node,
+    ) -> Vec<Diagnostic> {
+        let Some(name_node) = call_name_node(call) else {
+            return vec![];
+        };
+        let name = node_text(&code.code, &name_node);
+        if !TRANSFORM_ARGUMENTS
+            .iter()
+            .any(|(module, ..)| *module == name)
+        {
+            return vec![];
+        }
+        // The module may be overridden.
+        let is_builtin = self.is_builtin_call(code, call);
+        let Some(args) = call.child_by_field_name("arguments").filter(|_| is_builtin) else {
+            return vec![];
+        };
+
+        let mut diags = vec![];
+        let mut cursor = args.walk();
+        let args: Vec<Node> = args
+            .named_children(&mut cursor)
+            .filter(|arg| arg.node_kind() != NodeKind::Comment)
+            .collect();
+        for (i, arg) in args.iter().enumerate() {
+            let (param, value) = match arg.node_kind() {
+                NodeKind::Assignment => match (
+                    arg.child_by_field_name("left"),
+                    arg.child_by_field_name("right"),
+                ) {
+                    (Some(left), Some(right)) => (node_text(&code.code, &left), right),
+                    _ => continue,
+                },
+                _ => match TRANSFORM_ARGUMENTS
+                    .iter()
+                    .find(|(module, _, position, ..)| *module == name && *position == i)
+                {
+                    Some((_, param, ..)) => (*param, *arg),
+                    None => continue,
+                },
+            };
+            let Some(&(_, _, _, accepts_number, lengths)) = TRANSFORM_ARGUMENTS
+                .iter()
+                .find(|(module, p, ..)| *module == name && *p == param)
+            else {
+                continue;
+            };
+
+            let lengths_text = lengths
+                .iter()
+                .map(|len| len.to_string())
+                .collect::<Vec<_>>();
+            let expected = match lengths_text.split_last() {
+                Some((last, [])) => format!("a vector of {} numbers", last),
+                Some((last, rest)) => {
+                    format!("a vector of {} or {} numbers", rest.join(", "), last)
+                }
+                None => "a vector".to_owned(),
+            };
+            let message = match self.infer_type(code, &value, 0) {
+                Some(ValueType::Vector(Some(len))) if !lengths.contains(&len) => format!(
+                    "{}() expects {} for `{}`, got a vector of {}",
+                    name, expected, param, len
+                ),
+                Some(ValueType::Number) if accepts_number => continue,
+                Some(ValueType::Vector(_)) | Some(ValueType::Undef) | None => continue,
+                Some(value_type) => format!(
+                    "{}() expects {} for `{}`, got a {}",
+                    name, expected, param, value_type
+                ),
+            };
+            diags.push(Diagnostic {
+                range: value.lsp_range(&code.code),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(VECTOR_ARGUMENT.to_owned())),
+                message,
+                ..Default::default()
+            });
+        }
+        diags
+    }
+}
+
+fn unquote(text: &str) -> &str {
+    text.strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .unwrap_or(text)
+}
+
+// The value of a positional or named argument.
+fn argument_value<'a>(arg: &Node<'a>) -> Node<'a> {
+    match arg.node_kind() {
+        NodeKind::Assignment => arg.child_by_field_name("right").unwrap_or(*arg),
+        _ => *arg,
+    }
+}
+
+// The type of an arithmetic operation, following the OpenSCAD rules: only numbers and vectors can
+// be operands, anything else gives undef.
+fn binary_operation_type(operator: &str, left: &ValueType, right: &ValueType) -> Option<ValueType> {
+    use ValueType::*;
+    match (operator, left, right) {
+        (_, Number, Number) => Some(Number),
+        ("+" | "-", Vector(a), Vector(b)) => Some(Vector(match (a, b) {
+            (Some(a), Some(b)) => Some(*a.min(b)),
+            _ => None,
+        })),
+        ("*", Number, Vector(len)) | ("*" | "/", Vector(len), Number) => Some(Vector(*len)),
+        // Dot and matrix products, and the division of a number by each element.
+        ("*", Vector(_), Vector(_)) | ("/", Number, Vector(_)) => None,
+        _ => Some(Undef),
+    }
+}
@@ -1,8 +1,21 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    env,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use directories::UserDirs;
+use lazy_static::lazy_static;
 use lsp_server::{ExtractError, Request, RequestId};
 use lsp_types::Position;
-use lsp_types::Range;
+use lsp_types::{Range, TextEdit};
+use regex::{Captures, Regex};
 use tree_sitter::{Node, Point, TreeCursor};
 
+use crate::node_kind::{NodeKind, NodeKindExt};
+
 macro_rules! log_to_console {
         ($($arg:tt)*) => {
             eprint!("[server] ");
@@ -17,33 +30,103 @@ macro_rules! err_to_console {
         };
     }
 
+thread_local! {
+    static NAMES: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+// The shared copy of a symbol name, the same names are repeated in the items of all the cached
+// files. The names are never freed.
+pub(crate) fn intern(name: &str) -> Rc<str> {
+    NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        if let Some(name) = names.get(name) {
+            return name.clone();
+        }
+        let name: Rc<str> = Rc::from(name);
+        names.insert(name.clone());
+        name
+    })
+}
+
+pub(crate) fn interned_names() -> usize {
+    NAMES.with(|names| names.borrow().len())
+}
+
+// The byte offset of a position, the character is counted in UTF-16 code units like LSP does and
+// is clamped to the end of the line. None if the line is past the end of the text.
 pub(crate) fn find_offset(text: &str, pos: Position) -> Option<usize> {
     let mut offset = 0;
     for _ in 0..pos.line {
-        offset += text[offset..].find('\n').unwrap_or_default() + 1;
+        offset += text[offset..].find('\n')? + 1;
     }
 
-    let mut chars = text[offset..].chars();
-    for _ in 0..pos.character {
-        offset += chars.next()?.len_utf8();
+    let mut units = 0;
+    for c in text[offset..].chars() {
+        if units >= pos.character as usize || c == '\n' || c == '\r' {
+            break;
+        }
+        units += c.len_utf16();
+        offset += c.len_utf8();
     }
     Some(offset)
 }
 
+// The smallest node under the cursor. When the cursor is right after a word, before a space or a
+// punctuation, the word: `cube|(` is on `cube`.
+pub(crate) fn node_at_position<'a>(text: &str, root: &Node<'a>, pos: Position) -> Node<'a> {
+    let offset = find_offset(text, pos).unwrap_or(text.len());
+    let is_word = |node: &Node| node.is_named() && node.child_count() == 0;
+    let after = (offset < text.len())
+        .then(|| root.descendant_for_byte_range(offset, offset + 1))
+        .flatten();
+    if let Some(after) = after.filter(is_word) {
+        return after;
+    }
+    let before = (offset > 0)
+        .then(|| root.descendant_for_byte_range(offset - 1, offset))
+        .flatten();
+    before.filter(is_word).or(after).unwrap_or(*root)
+}
+
+// The token before the cursor, being typed, or the smallest node around the whitespace before
+// the cursor. The root at the start of the file.
+pub(crate) fn node_before_position<'a>(text: &str, root: &Node<'a>, pos: Position) -> Node<'a> {
+    let offset = find_offset(text, pos).unwrap_or(text.len());
+    (offset > 0)
+        .then(|| root.descendant_for_byte_range(offset - 1, offset))
+        .flatten()
+        .unwrap_or(*root)
+}
+
+// The tree-sitter point of a byte offset, its column is in bytes.
+pub(crate) fn offset_to_point(text: &str, offset: usize) -> Point {
+    let before = &text[..offset];
+    match before.rfind('\n') {
+        Some(index) => Point {
+            row: before.bytes().filter(|&c| c == b'\n').count(),
+            column: offset - index - 1,
+        },
+        None => Point {
+            row: 0,
+            column: offset,
+        },
+    }
+}
+
 // Find the closest parent scope to the given node.
 pub(crate) fn find_node_scope(node: Node) -> Option<Node> {
     let mut parent_scope = node;
     while let Some(parent_node) = parent_scope.parent() {
         parent_scope = parent_node;
         if matches!(
-            parent_node.kind(),
-            "source_file" | "module_declaration" | "union_block"
+            parent_node.node_kind(),
+            NodeKind::SourceFile | NodeKind::ModuleDeclaration | NodeKind::UnionBlock
         ) {
             // If this is a module_declaration, the module will detect itself as
             // its scope. So we need to check for that and get its scope's scope.
             return if node
                 .parent()
-                .is_some_and(|parent| parent.kind() == "module_declaration")
+                .is_some_and(|parent| parent.node_kind() == NodeKind::ModuleDeclaration)
             {
                 find_node_scope(parent_scope)
             } else {
@@ -54,20 +137,163 @@ pub(crate) fn find_node_scope(node: Node) -> Option<Node> {
     None
 }
 
-pub(crate) fn to_position(p: Point) -> Position {
-    Position {
-        line: p.row as u32,
-        character: p.column as u32,
+// All the .scad files of a directory and its sub directories, or the path itself if it is a file.
+pub(crate) fn find_scad_files(path: &Path) -> Vec<PathBuf> {
+    let mut result = vec![];
+    if path.is_file() {
+        result.push(path.to_owned());
+        return result;
+    }
+
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = dir.read_dir() else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "scad") {
+                result.push(path);
+            }
+        }
     }
+    result.sort();
+    result
 }
 
-pub(crate) fn to_point(p: Position) -> Point {
-    Point {
-        row: p.line as usize,
-        column: p.character as usize,
+// The limits of the files read from the workspace folders and the library locations, so that a
+// search path pointing at a home directory doesn't take all the memory.
+#[derive(Clone)]
+pub(crate) struct IndexLimits {
+    pub max_files: usize,
+    pub max_file_size: u64,
+    pub max_total_bytes: u64,
+}
+
+impl Default for IndexLimits {
+    fn default() -> Self {
+        Self {
+            max_files: 5000,
+            max_file_size: 1 << 20,
+            max_total_bytes: 100 << 20,
+        }
     }
 }
 
+// The .scad files of the directories, like `find_scad_files`, until the limits are reached. The
+// files larger than `max_file_size` are skipped. The second element explains what was skipped.
+pub(crate) fn find_scad_files_limited(
+    dirs: &[PathBuf],
+    limits: &IndexLimits,
+) -> (Vec<PathBuf>, Vec<String>) {
+    let (mut result, mut skipped) = (vec![], vec![]);
+    let mut total_bytes = 0;
+    for root in dirs {
+        let mut dirs = vec![root.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = dir.read_dir() else {
+                continue;
+            };
+            let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+            paths.sort();
+            let mut sub_dirs = vec![];
+            for path in paths {
+                if path.is_dir() {
+                    sub_dirs.push(path);
+                    continue;
+                }
+                if path.extension().is_none_or(|ext| ext != "scad") {
+                    continue;
+                }
+                let size = path.metadata().map_or(0, |meta| meta.len());
+                if size > limits.max_file_size {
+                    skipped.push(format!(
+                        "skipped {}, {} bytes is more than max_file_size",
+                        path.display(),
+                        size
+                    ));
+                    continue;
+                }
+                if result.len() >= limits.max_files || total_bytes + size > limits.max_total_bytes {
+                    skipped.push(format!(
+                        "stopped listing the files of {} after {} files and {} bytes, see \
+                         max_indexed_files and max_indexed_bytes",
+                        root.display(),
+                        result.len(),
+                        total_bytes
+                    ));
+                    return (result, skipped);
+                }
+                total_bytes += size;
+                result.push(path);
+            }
+            // Popped in order.
+            dirs.extend(sub_dirs.into_iter().rev());
+        }
+    }
+    (result, skipped)
+}
+
+// Whether a relative path, `/` separated, matches a glob pattern with `*`, `?` and `**` for any
+// number of directories. A pattern without `/` matches the name at any depth, `build` excludes
+// the build directories and `*.gen.scad` the generated files.
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+    fn match_segment(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|i| match_segment(rest, &name[i..])),
+            Some(('?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+        }
+    }
+    // A directory matches with all its content.
+    fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => true,
+            Some((&"**", rest)) => (0..=path.len()).any(|i| match_segments(rest, &path[i..])),
+            Some((segment, rest)) => path.split_first().is_some_and(|(name, path)| {
+                let segment: Vec<char> = segment.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                match_segment(&segment, &name) && match_segments(rest, path)
+            }),
+        }
+    }
+
+    let pattern = pattern.trim_matches('/');
+    let pattern = match pattern.contains('/') {
+        true => pattern.to_owned(),
+        false => format!("**/{}", pattern),
+    };
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    match_segments(&pattern, &path)
+}
+
+// The LSP position of a byte offset, the character is counted in UTF-16 code units.
+pub(crate) fn offset_to_position(text: &str, offset: usize) -> Position {
+    let point = offset_to_point(text, offset);
+    Position {
+        line: point.row as u32,
+        character: utf16_column(text, offset, point.column),
+    }
+}
+
+// The tree-sitter point of an LSP position, whose character is in UTF-16 code units.
+pub(crate) fn to_point(text: &str, p: Position) -> Point {
+    offset_to_point(text, find_offset(text, p).unwrap_or(text.len()))
+}
+
+// The column of the byte offset in UTF-16 code units, from the byte column of its line. The byte
+// column itself if the offset doesn't fall on the text, when the tree is older than the text.
+fn utf16_column(text: &str, offset: usize, column: usize) -> u32 {
+    offset
+        .checked_sub(column)
+        .and_then(|start| text.get(start..offset))
+        .map_or(column, |line| line.encode_utf16().count()) as u32
+}
+
 pub(crate) fn node_text<'a>(code: &'a str, node: &Node) -> &'a str {
     &code[node.byte_range()]
 }
@@ -89,12 +315,17 @@ pub(crate) fn for_each_child<'a>(
     }
 }
 
+// The error and missing nodes, only the subtrees containing errors are visited so that the large
+// files without errors are quick to check.
 pub(crate) fn error_nodes(mut cursor: TreeCursor) -> Vec<Node> {
     pub(crate) fn helper<'a>(ret: &mut Vec<Node<'a>>, cursor: &mut TreeCursor<'a>) {
         let node = cursor.node();
         if node.is_error() || node.is_missing() {
             ret.push(node);
         }
+        if !node.has_error() {
+            return;
+        }
         for_each_child(cursor, |cursor| {
             helper(ret, cursor);
         });
@@ -122,41 +353,473 @@ where
 }
 
 pub(crate) trait NodeExt {
-    fn lsp_range(&self) -> Range;
+    // The LSP range of the node in the text it was parsed from.
+    fn lsp_range(&self, text: &str) -> Range;
 }
 
 impl NodeExt for Node<'_> {
-    fn lsp_range(&self) -> Range {
+    fn lsp_range(&self, text: &str) -> Range {
         let r = self.range();
         Range {
             start: Position {
                 line: r.start_point.row as u32,
-                character: r.start_point.column as u32,
+                character: utf16_column(text, r.start_byte, r.start_point.column),
             },
             end: Position {
                 line: r.end_point.row as u32,
-                character: r.end_point.column as u32,
+                character: utf16_column(text, r.end_byte, r.end_point.column),
             },
         }
     }
 }
 
-pub(crate) trait KindExt {
-    fn is_include_statement(&self) -> bool;
-    fn is_comment(&self) -> bool;
-    fn is_callable(&self) -> bool;
+// Expand `~`, `$VAR`, `${VAR}` and `%VAR%` in a user supplied path, unknown variables are kept
+// as is.
+pub(crate) fn expand_path(path: &str) -> String {
+    lazy_static! {
+        static ref PERCENT_VAR_RE: Regex = Regex::new(r"%(\w+)%").unwrap();
+    }
+
+    let path = PERCENT_VAR_RE.replace_all(path, |caps: &Captures| {
+        env::var(&caps[1]).unwrap_or_else(|_| caps[0].to_owned())
+    });
+    shellexpand::full_with_context_no_errors(
+        path.as_ref(),
+        || UserDirs::new().and_then(|dirs| dirs.home_dir().to_str().map(str::to_owned)),
+        |var| env::var(var).ok(),
+    )
+    .into_owned()
+}
+
+// Expand a user supplied path, and log it when it doesn't exist.
+pub(crate) fn resolve_path(path: &str, what: &str) -> Option<PathBuf> {
+    let expanded = expand_path(path);
+    let result = PathBuf::from(&expanded);
+    if result.exists() {
+        return Some(result);
+    }
+    if expanded == path {
+        err_to_console!("{} {} doesn't exist", what, path);
+    } else {
+        err_to_console!(
+            "{} {} (expanded from {}) doesn't exist",
+            what,
+            expanded,
+            path
+        );
+    }
+    None
+}
+
+// Expand an executable path, bare names are looked up in PATH when run.
+pub(crate) fn resolve_exe(exe: &str) -> String {
+    let expanded = expand_path(exe);
+    if expanded.contains(std::path::is_separator) {
+        resolve_path(exe, "executable");
+    }
+    expanded
+}
+
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+// Whether the characters of `query` appear in `name` in order, ignoring the case.
+pub(crate) fn fuzzy_match(query: &str, name: &str) -> bool {
+    let mut chars = name.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|q| chars.any(|c| c == q))
+}
+
+// The number of inserted, removed, replaced or swapped characters turning `a` into `b`, ignoring
+// the case.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().flat_map(char::to_lowercase).collect();
+    let b: Vec<char> = b.chars().flat_map(char::to_lowercase).collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            row[j] = (rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]))
+                .min(rows[i - 1][j] + 1)
+                .min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+// The candidate closest to a misspelled `name`, if it's close enough to be a typo. Among the
+// closest ones, the one sharing the longest prefix with `name` wins.
+pub(crate) fn closest_name<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max = (name.chars().count() / 3).max(1);
+    let prefix = |candidate: &str| {
+        name.chars()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max)
+        .min_by_key(|(distance, candidate)| {
+            (*distance, std::cmp::Reverse(prefix(candidate)), *candidate)
+        })
+        .map(|(_, candidate)| candidate)
+}
+
+// The most different lines compared line by line, past them the changed lines are replaced by a
+// single edit.
+const MAX_DIFF_LINES: usize = 1000;
+
+// The edits turning `old` into `new`, one per block of changed lines, so that the editors keep the
+// cursor, the folds and the undo history of the unchanged lines.
+pub(crate) fn line_edits(old: &str, new: &str) -> Vec<TextEdit> {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let prefix = old_lines
+        .iter()
+        .zip(&new_lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_changed = &old_lines[prefix..old_lines.len() - suffix];
+    let new_changed = &new_lines[prefix..new_lines.len() - suffix];
+    if old_changed.is_empty() && new_changed.is_empty() {
+        return vec![];
+    }
+
+    // The end of a line, or the end of the text when it has no final newline.
+    let position = |line: usize| match old_lines.get(line) {
+        Some(_) => Position::new(line as u32, 0),
+        None if old.is_empty() || old.ends_with('\n') => Position::new(line as u32, 0),
+        None => {
+            let last = old_lines[line - 1];
+            Position::new((line - 1) as u32, last.encode_utf16().count() as u32)
+        }
+    };
+    diff_blocks(old_changed, new_changed)
+        .unwrap_or_else(|| vec![(0..old_changed.len(), 0..new_changed.len())])
+        .into_iter()
+        .map(|(old_range, new_range)| TextEdit {
+            range: Range::new(
+                position(prefix + old_range.start),
+                position(prefix + old_range.end),
+            ),
+            new_text: new_changed[new_range].concat(),
+        })
+        .collect()
+}
+
+// An edit of `line_edits` replacing whole lines by as many lines, split into an edit per line so
+// that the lines can be picked one by one. The other edits are returned as is.
+pub(crate) fn split_lines(edit: TextEdit) -> Vec<TextEdit> {
+    let Range { start, end } = edit.range;
+    let lines = edit.new_text.split_inclusive('\n').count();
+    let is_whole_lines =
+        start.character == 0 && end.character == 0 && edit.new_text.ends_with('\n');
+    if !is_whole_lines || lines < 2 || lines != (end.line - start.line) as usize {
+        return vec![edit];
+    }
+    edit.new_text
+        .split_inclusive('\n')
+        .zip(start.line..)
+        .map(|(text, line)| TextEdit {
+            range: Range::new(Position::new(line, 0), Position::new(line + 1, 0)),
+            new_text: text.to_owned(),
+        })
+        .collect()
 }
 
-impl KindExt for str {
-    fn is_include_statement(&self) -> bool {
-        self == "include_statement" || self == "use_statement"
+// The blocks of lines of `old` replaced by lines of `new`, with the Myers algorithm. None when
+// more than MAX_DIFF_LINES lines differ.
+fn diff_blocks(
+    old: &[&str],
+    new: &[&str],
+) -> Option<Vec<(std::ops::Range<usize>, std::ops::Range<usize>)>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (old.len() + new.len()).min(MAX_DIFF_LINES) as isize;
+    // The furthest x of each diagonal k = x - y, k is in -d..=d at step d.
+    let index = |k: isize| (k + max + 1) as usize;
+    let mut v = vec![0; 2 * max as usize + 3];
+    let mut trace = vec![];
+    'search: {
+        for d in 0..=max {
+            trace.push(v.clone());
+            for k in (-d..=d).step_by(2) {
+                let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                    v[index(k + 1)]
+                } else {
+                    v[index(k - 1)] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && old[x as usize] == new[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                v[index(k)] = x;
+                if x >= n && y >= m {
+                    break 'search;
+                }
+            }
+        }
+        return None;
+    }
+
+    // Back from the end, each step is a deleted or an inserted line after the equal ones.
+    let (mut x, mut y) = (n, m);
+    let mut blocks: Vec<(std::ops::Range<usize>, std::ops::Range<usize>)> = vec![];
+    for (d, v) in trace.iter().enumerate().skip(1).rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[index(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+        }
+        let (old_line, new_line) = (prev_x as usize, prev_y as usize);
+        let step = match x == prev_x {
+            true => (old_line..old_line, new_line..new_line + 1),
+            false => (old_line..old_line + 1, new_line..new_line),
+        };
+        match blocks.last_mut() {
+            Some(block) if block.0.start == step.0.end && block.1.start == step.1.end => {
+                block.0.start = step.0.start;
+                block.1.start = step.1.start;
+            }
+            _ => blocks.push(step),
+        }
+        (x, y) = (prev_x, prev_y);
     }
+    blocks.reverse();
+    Some(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use tree_sitter::{Parser, Tree};
+
+    use super::*;
 
-    fn is_comment(&self) -> bool {
-        self == "comment"
+    fn parse(text: &str) -> Tree {
+        let mut parser = Parser::new();
+        parser
+            .set_language(tree_sitter_openscad::language())
+            .unwrap();
+        parser.parse(text, None).unwrap()
+    }
+
+    fn identifier_range(text: &str, name: &str) -> Range {
+        let tree = parse(text);
+        let offset = text.find(name).unwrap();
+        let node = tree
+            .root_node()
+            .descendant_for_byte_range(offset, offset + name.len())
+            .unwrap();
+        node.lsp_range(text)
+    }
+
+    #[test]
+    fn lsp_range_counts_utf16_units() {
+        // `é` is 2 bytes and 1 unit, `😀` 4 bytes and 2 units.
+        let text = "s = \"é😀\"; cube(x);\n";
+        assert_eq!(
+            identifier_range(text, "cube"),
+            Range::new(Position::new(0, 11), Position::new(0, 15))
+        );
+    }
+
+    #[test]
+    fn lsp_range_after_multibyte_lines() {
+        let text = "// ééé\r\nt = \"😀\"; x = 1;\r\ncube(x);\r\n";
+        assert_eq!(
+            identifier_range(text, "x = 1"),
+            Range::new(Position::new(1, 10), Position::new(1, 15))
+        );
+        assert_eq!(
+            identifier_range(text, "cube"),
+            Range::new(Position::new(2, 0), Position::new(2, 4))
+        );
+    }
+
+    #[test]
+    fn to_point_counts_bytes() {
+        let text = "s = \"é😀\"; cube(x);\r\n  y = 1;";
+        assert_eq!(
+            to_point(text, Position::new(0, 11)),
+            Point { row: 0, column: 14 }
+        );
+        assert_eq!(
+            to_point(text, Position::new(1, 2)),
+            Point { row: 1, column: 2 }
+        );
+        // The character is clamped before the `\r\n`.
+        assert_eq!(
+            to_point(text, Position::new(0, 100)),
+            Point { row: 0, column: 22 }
+        );
+        // Past the end of the text.
+        assert_eq!(
+            to_point(text, Position::new(5, 0)),
+            Point { row: 1, column: 8 }
+        );
+    }
+
+    #[test]
+    fn position_round_trip() {
+        let text = "a = \"é\";\r\nb = \"😀\"; c = 2;\n";
+        for (offset, _) in text.char_indices() {
+            let position = offset_to_position(text, offset);
+            if text[offset..].starts_with('\n') {
+                continue;
+            }
+            assert_eq!(find_offset(text, position), Some(offset), "{}", offset);
+            assert_eq!(to_point(text, position), offset_to_point(text, offset));
+        }
+    }
+
+    // `text` with the edits applied, they don't overlap.
+    fn apply(text: &str, edits: &[TextEdit]) -> String {
+        let mut result = text.to_owned();
+        let mut edits = edits.to_vec();
+        edits.sort_by_key(|edit| edit.range.start);
+        for edit in edits.iter().rev() {
+            let start = find_offset(text, edit.range.start).unwrap();
+            let end = find_offset(text, edit.range.end).unwrap();
+            result.replace_range(start..end, &edit.new_text);
+        }
+        result
+    }
+
+    // The start, the end and the new text of an edit.
+    type LineEdit = ((u32, u32), (u32, u32), String);
+
+    // The edits of `old` to `new`, checked to give `new`.
+    fn edits(old: &str, new: &str) -> Vec<LineEdit> {
+        let edits = line_edits(old, new);
+        assert_eq!(apply(old, &edits), new, "{:?} to {:?}", old, new);
+        edits
+            .into_iter()
+            .map(|edit| {
+                let Range { start, end } = edit.range;
+                (
+                    (start.line, start.character),
+                    (end.line, end.character),
+                    edit.new_text,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn line_edits_at_the_ends() {
+        let old = "a\nb\nc\n";
+        assert_eq!(edits(old, "x\na\nb\nc\n"), [((0, 0), (0, 0), "x\n".into())]);
+        assert_eq!(edits(old, "a\nb\nc\nx\n"), [((3, 0), (3, 0), "x\n".into())]);
+        assert_eq!(edits(old, "b\nc\n"), [((0, 0), (1, 0), "".into())]);
+        assert_eq!(edits(old, "a\nb\n"), [((2, 0), (3, 0), "".into())]);
+        assert_eq!(edits(old, "x\nb\nc\n"), [((0, 0), (1, 0), "x\n".into())]);
+        assert_eq!(edits(old, "a\nb\nx\n"), [((2, 0), (3, 0), "x\n".into())]);
+        assert!(edits(old, old).is_empty());
+    }
+
+    #[test]
+    fn line_edits_blocks() {
+        assert_eq!(
+            edits("a\nb\nc\nd\ne\n", "x\nb\nc\ny\nz\ne\n"),
+            [
+                ((0, 0), (1, 0), "x\n".into()),
+                ((3, 0), (4, 0), "y\nz\n".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn line_edits_empty_texts() {
+        assert_eq!(edits("", "a\nb\n"), [((0, 0), (0, 0), "a\nb\n".into())]);
+        assert_eq!(edits("a\nb\n", ""), [((0, 0), (2, 0), "".into())]);
+        assert!(edits("", "").is_empty());
+    }
+
+    #[test]
+    fn line_edits_without_final_newline() {
+        assert_eq!(edits("a\nb", "a\nc"), [((1, 0), (1, 1), "c".into())]);
+        assert_eq!(edits("a\nb", "a\nb\nc"), [((1, 0), (1, 1), "b\nc".into())]);
+        assert_eq!(edits("a\nb", "a\nb\n"), [((1, 0), (1, 1), "b\n".into())]);
+        assert_eq!(edits("a\nb\n", "a\nb"), [((1, 0), (2, 0), "b".into())]);
+        assert_eq!(edits("a\né😀", "a"), [((0, 0), (1, 3), "a".into())]);
+    }
+
+    #[test]
+    fn line_edits_crlf() {
+        assert_eq!(
+            edits("a\r\nb\r\nc\r\n", "a\r\nx\r\nc\r\n"),
+            [((1, 0), (2, 0), "x\r\n".into())]
+        );
+        // The line endings changed.
+        assert_eq!(
+            edits("a\r\nb\n", "a\nb\n"),
+            [((0, 0), (1, 0), "a\n".into())]
+        );
+    }
+
+    #[test]
+    fn split_line_edits() {
+        let split = |old: &str, new: &str| {
+            let edits: Vec<TextEdit> = line_edits(old, new)
+                .into_iter()
+                .flat_map(split_lines)
+                .collect();
+            assert_eq!(apply(old, &edits), new);
+            edits.len()
+        };
+        assert_eq!(split("a\nb\nc\n", "x\ny\nz\n"), 3);
+        assert_eq!(split("a\nb\nc\n", "x\ny\n"), 1);
+        assert_eq!(split("a\nb", "x\ny"), 1);
     }
 
-    fn is_callable(&self) -> bool {
-        self == "module_declaration" || self == "function_declaration"
+    #[test]
+    fn line_edits_past_the_diff_limit() {
+        let old: String = (0..MAX_DIFF_LINES).map(|i| format!("{}\n", i)).collect();
+        let new: String = (0..MAX_DIFF_LINES).map(|i| format!("{}x\n", i)).collect();
+        assert_eq!(edits(&old, &new).len(), 1);
     }
 }
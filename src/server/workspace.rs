@@ -0,0 +1,914 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use lsp_types::{
+    notification::{DidChangeWatchedFiles, Notification},
+    AnnotatedTextEdit, ChangeAnnotation, Diagnostic, DiagnosticSeverity,
+    DidChangeWatchedFilesRegistrationOptions, DocumentChanges, FileSystemWatcher, GlobPattern,
+    InitializeParams, Location, MessageType, NumberOrString, OneOf,
+    OptionalVersionedTextDocumentIdentifier, Position, ProgressToken, Range, Registration,
+    RegistrationParams, SymbolInformation, TextDocumentEdit, TextEdit, TraceValue, Unregistration,
+    UnregistrationParams, Url, WorkspaceEdit, WorkspaceEditClientCapabilities,
+};
+use serde_json::Value;
+use tree_sitter::Node;
+use tree_sitter_traversal::{traverse, Order};
+
+use crate::{
+    diagnostics::is_reference,
+    handler::ext::LibraryExample,
+    node_kind::{NodeKind, NodeKindExt},
+    parse_code::ParsedCode,
+    response_item::{Item, ItemKind},
+    server::Server,
+    utils::*,
+};
+
+const WATCHED_FILES_REGISTRATION: &str = "openscad-watched-files";
+// The annotation of the edits of the workspace edits in the files which aren't open.
+const CLOSED_FILES_ANNOTATION: &str = "closed-files";
+// The include paths completed from the whole workspace and libraries, the client asks again as
+// the path is typed.
+const MAX_PROJECT_INCLUDE_PATHS: usize = 200;
+
+pub(crate) const UNUSED_PUBLIC: &str = "unused-public";
+
+// Workspace-wide helpers.
+impl Server {
+    pub(crate) fn set_workspace(&mut self, params: Value) {
+        let params = match serde_json::from_value::<InitializeParams>(params) {
+            Ok(params) => params,
+            Err(err) => {
+                err_to_console!("invalid initialize params: {}", err);
+                return;
+            }
+        };
+
+        #[allow(deprecated)]
+        let roots = match params.workspace_folders {
+            Some(folders) => folders.into_iter().map(|folder| folder.uri).collect(),
+            None => params.root_uri.into_iter().collect(),
+        };
+        self.workspace_roots = roots;
+        self.client_capabilities = params.capabilities;
+        self.trace = params.trace.unwrap_or(TraceValue::Off);
+        if let Some(options) = params.initialization_options {
+            self.apply_settings(options);
+        }
+    }
+
+    // Ask the client to notify the changes of the .scad files made outside of the editor.
+    pub(crate) fn register_file_watchers(&mut self) {
+        let dynamic = self
+            .client_capabilities
+            .workspace
+            .as_ref()
+            .and_then(|ws| ws.did_change_watched_files)
+            .and_then(|caps| caps.dynamic_registration)
+            .unwrap_or(false);
+        if !dynamic {
+            return;
+        }
+
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: GlobPattern::String("**/*.scad".to_owned()),
+                kind: None,
+            }],
+        };
+        self.send_request(
+            "client/registerCapability",
+            RegistrationParams {
+                registrations: vec![Registration {
+                    id: WATCHED_FILES_REGISTRATION.to_owned(),
+                    method: DidChangeWatchedFiles::METHOD.to_owned(),
+                    register_options: serde_json::to_value(options).ok(),
+                }],
+            },
+        );
+        self.watchers_registered = true;
+    }
+
+    pub(crate) fn unregister_file_watchers(&mut self) {
+        if !self.watchers_registered {
+            return;
+        }
+        self.watchers_registered = false;
+        self.send_request(
+            "client/unregisterCapability",
+            UnregistrationParams {
+                unregisterations: vec![Unregistration {
+                    id: WATCHED_FILES_REGISTRATION.to_owned(),
+                    method: DidChangeWatchedFiles::METHOD.to_owned(),
+                }],
+            },
+        );
+    }
+
+    pub(crate) fn hierarchical_document_symbol_support(&self) -> bool {
+        self.client_capabilities
+            .text_document
+            .as_ref()
+            .and_then(|doc| doc.document_symbol.as_ref())
+            .and_then(|symbol| symbol.hierarchical_document_symbol_support)
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn completion_label_details_support(&self) -> bool {
+        self.client_capabilities
+            .text_document
+            .as_ref()
+            .and_then(|doc| doc.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref())
+            .and_then(|item| item.label_details_support)
+            .unwrap_or(false)
+    }
+
+    // Whether the client asked for the openscad/inactiveRegions notifications.
+    pub(crate) fn inactive_regions_support(&self) -> bool {
+        self.client_capabilities
+            .experimental
+            .as_ref()
+            .and_then(|experimental| experimental.get("inactiveRegions"))
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false)
+    }
+
+    // The completions are only names with --plain-completions, and for the clients which can't
+    // insert snippets.
+    pub(crate) fn plain_completions(&self) -> bool {
+        let snippet_support = self
+            .client_capabilities
+            .text_document
+            .as_ref()
+            .and_then(|doc| doc.completion.as_ref())
+            .and_then(|completion| completion.completion_item.as_ref())
+            .and_then(|item| item.snippet_support)
+            .unwrap_or(false);
+        self.args.plain_completions || !snippet_support
+    }
+
+    fn workspace_edit_capabilities(&self) -> Option<&WorkspaceEditClientCapabilities> {
+        self.client_capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.workspace_edit.as_ref())
+    }
+
+    // A workspace edit of `changes`. The edits of the open files are for their current version,
+    // and those in the files which aren't open need a confirmation of the user, labelled `label`,
+    // when the client supports it.
+    pub(crate) fn workspace_edit(
+        &self,
+        changes: HashMap<Url, Vec<TextEdit>>,
+        label: &str,
+    ) -> WorkspaceEdit {
+        let Some(capabilities) = self
+            .workspace_edit_capabilities()
+            .filter(|edit| edit.document_changes == Some(true))
+        else {
+            return WorkspaceEdit {
+                changes: Some(changes),
+                ..Default::default()
+            };
+        };
+        let annotate = capabilities.change_annotation_support.is_some()
+            && changes
+                .iter()
+                .any(|(url, edits)| !edits.is_empty() && !self.open_documents.contains(url));
+
+        let mut urls: Vec<Url> = changes.keys().cloned().collect();
+        urls.sort();
+        let mut changes = changes;
+        let edits = urls
+            .into_iter()
+            .map(|url| {
+                let edits = changes.remove(&url).unwrap_or_default();
+                // The edits of a closed file apply to the file on the disk, without version.
+                let version = self
+                    .open_documents
+                    .contains(&url)
+                    .then(|| self.codes.get(&url).and_then(|code| code.borrow().version))
+                    .flatten();
+                let is_annotated = annotate && !self.open_documents.contains(&url);
+                TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier { uri: url, version },
+                    edits: edits
+                        .into_iter()
+                        .map(|text_edit| {
+                            if is_annotated {
+                                OneOf::Right(AnnotatedTextEdit {
+                                    text_edit,
+                                    annotation_id: CLOSED_FILES_ANNOTATION.to_owned(),
+                                })
+                            } else {
+                                OneOf::Left(text_edit)
+                            }
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(edits)),
+            change_annotations: annotate.then(|| {
+                HashMap::from([(
+                    CLOSED_FILES_ANNOTATION.to_owned(),
+                    ChangeAnnotation {
+                        label: label.to_owned(),
+                        needs_confirmation: Some(true),
+                        description: Some("Changes in files which aren't open".to_owned()),
+                    },
+                )])
+            }),
+            ..Default::default()
+        }
+    }
+
+    // Record the files included by `code` once its items were generated again.
+    pub(crate) fn record_includes(&mut self, code: &mut ParsedCode) {
+        if !code.includes_changed {
+            return;
+        }
+        code.includes_changed = false;
+        if let Some(includes) = &code.includes {
+            self.update_dependents(&code.url, includes);
+        }
+    }
+
+    pub(crate) fn update_dependents(&mut self, url: &Url, includes: &[Url]) {
+        for files in self.dependents.values_mut() {
+            files.remove(url);
+        }
+        for inc in includes {
+            self.dependents
+                .entry(inc.clone())
+                .or_default()
+                .insert(url.clone());
+        }
+    }
+
+    // The files depending on `url`, directly or through other includes.
+    pub(crate) fn dependents_of(&self, url: &Url) -> HashSet<Url> {
+        let mut result = HashSet::new();
+        let mut pending = vec![url];
+        while let Some(url) = pending.pop() {
+            for file in self.dependents.get(url).into_iter().flatten() {
+                if result.insert(file.clone()) {
+                    pending.push(file);
+                }
+            }
+        }
+        result
+    }
+
+    // The files included by `urls`, directly or through other includes, except `skip`.
+    pub(crate) fn included_codes(
+        &mut self,
+        urls: Vec<Url>,
+        skip: &Url,
+    ) -> Vec<Rc<RefCell<ParsedCode>>> {
+        let mut result = vec![];
+        let mut visited = HashSet::new();
+        let mut pending = urls;
+        while let Some(url) = pending.pop() {
+            if &url == skip || !visited.insert(url.clone()) {
+                continue;
+            }
+            let Some(file) = self.get_code(&url) else {
+                continue;
+            };
+            let Ok(mut code) = file.try_borrow_mut() else {
+                continue;
+            };
+            code.gen_top_level_items_if_needed();
+            self.record_includes(&mut code);
+            pending.extend(code.includes.clone().unwrap_or_default());
+            drop(code);
+            result.push(file);
+        }
+        result
+    }
+
+    // The .scad files of the directories within the index limits, what is skipped is logged once.
+    fn scad_files_within_limits(&mut self, roots: &[Url]) -> Vec<PathBuf> {
+        let dirs: Vec<PathBuf> = roots
+            .iter()
+            .filter_map(|root| root.to_file_path().ok())
+            .collect();
+        let (files, skipped) = find_scad_files_limited(&dirs, &self.index_limits);
+        for message in skipped {
+            self.warn_index_limit(message);
+        }
+        files
+    }
+
+    // Log a file skipped because of the index limits, once.
+    pub(crate) fn warn_index_limit(&mut self, message: String) {
+        if self.index_warnings.insert(message.clone()) {
+            self.log_message(MessageType::WARNING, message);
+        }
+    }
+
+    // Whether `code` is above the large_file_size setting, its formatting and lint diagnostics would
+    // make each keystroke slow. The user is told once per file when `notify` is set.
+    pub(crate) fn is_large_file(&mut self, code: &ParsedCode, notify: bool) -> bool {
+        let is_large = code.code.len() as u64 > self.large_file_size;
+        if is_large && notify && self.large_files.insert(code.url.clone()) {
+            let name = code
+                .url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back());
+            self.show_message(
+                MessageType::INFO,
+                format!(
+                    "{} is larger than large_file_size ({} bytes), its formatting and lint \
+                     diagnostics are disabled",
+                    name.unwrap_or(code.url.as_str()),
+                    self.large_file_size
+                ),
+            );
+        }
+        is_large
+    }
+
+    // The .scad files of the workspace folders which are not cached yet.
+    fn unindexed_workspace_files(&mut self) -> Vec<Url> {
+        self.scad_files_within_limits(&self.workspace_roots.clone())
+            .iter()
+            .filter_map(|path| Url::from_file_path(path).ok())
+            .filter(|url| !self.codes.contains_key(url))
+            .collect()
+    }
+
+    // The version constants of the library `target` and of the files it includes.
+    pub(crate) fn library_versions(&mut self, target: &Url, skip: &Url) -> Vec<(String, String)> {
+        self.included_codes(vec![target.clone()], skip)
+            .iter()
+            .flat_map(|code| code.borrow().version_constants())
+            .collect()
+    }
+
+    // The cached files and the .scad files of the workspace folders.
+    pub(crate) fn indexed_codes(&mut self) -> Vec<Rc<RefCell<ParsedCode>>> {
+        let pending = self.unindexed_workspace_files();
+
+        if !pending.is_empty() {
+            let progress = self.begin_progress("Indexing OpenSCAD files");
+            let mut last_percentage = 0;
+            for (i, url) in pending.iter().enumerate() {
+                self.get_code(url);
+                let percentage = (i + 1) * 100 / pending.len();
+                if percentage != last_percentage {
+                    last_percentage = percentage;
+                    self.report_progress(progress.as_ref(), i + 1, pending.len());
+                }
+            }
+            self.end_progress(progress, None);
+        }
+
+        self.codes
+            .values()
+            .filter(|code| !code.borrow().is_builtin)
+            .cloned()
+            .collect()
+    }
+
+    // The top level modules, or functions, named `name` in all the files. The last definition
+    // included wins in OpenSCAD, so each file may override the others.
+    pub(crate) fn implementations(&mut self, name: &str, is_module: bool) -> Vec<Location> {
+        let mut result: Vec<Location> = self
+            .indexed_codes()
+            .iter()
+            .flat_map(|file| {
+                file.borrow_mut().gen_top_level_items_if_needed();
+                let file = file.borrow();
+                file.root_items
+                    .iter()
+                    .flatten()
+                    .filter_map(|item| {
+                        let item = item.borrow();
+                        let matches = match item.kind {
+                            ItemKind::Module { .. } => is_module,
+                            ItemKind::Function { .. } => !is_module,
+                            _ => false,
+                        };
+                        (matches && &*item.name == name).then(|| Location {
+                            uri: file.url.clone(),
+                            range: item.range,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        result.sort_by(|a, b| {
+            (a.uri.as_str(), a.range.start.line).cmp(&(b.uri.as_str(), b.range.start.line))
+        });
+        result
+    }
+
+    // The top level declarations and the uses of `name`, a "module", "function" or "variable", in
+    // the indexed files, matched by name without resolving them.
+    pub(crate) fn symbol_usage(&mut self, name: &str, kind: &str) -> (Vec<Location>, usize) {
+        let mut definitions = vec![];
+        let mut references = 0;
+        for file in self.indexed_codes() {
+            file.borrow_mut().gen_top_level_items_if_needed();
+            let code = file.borrow();
+            definitions.extend(
+                code.root_items
+                    .iter()
+                    .flatten()
+                    .filter(|item| {
+                        let item = item.borrow();
+                        &*item.name == name && item.kind.name() == kind
+                    })
+                    .map(|item| Location {
+                        uri: code.url.clone(),
+                        range: item.borrow().range,
+                    }),
+            );
+            references += traverse(code.tree.walk(), Order::Pre)
+                .filter(|node| {
+                    node.node_kind() == NodeKind::Identifier
+                        && is_reference(node)
+                        && node_namespace(node) == kind
+                        && node_text(&code.code, node) == name
+                })
+                .count();
+        }
+        (definitions, references)
+    }
+
+    // The top level symbols of the cached files and the workspace files matching `query`. The
+    // cached files are searched first, then the workspace files as they are parsed, so that each
+    // file can be streamed to the client with a partial result `token`.
+    pub(crate) fn workspace_symbols(
+        &mut self,
+        query: &str,
+        token: Option<&ProgressToken>,
+    ) -> Vec<SymbolInformation> {
+        let mut urls: Vec<Url> = self
+            .codes
+            .iter()
+            .filter(|(_, code)| !code.borrow().is_builtin)
+            .map(|(url, _)| url.clone())
+            .collect();
+        urls.sort();
+        urls.extend(self.unindexed_workspace_files());
+
+        let mut result = vec![];
+        for url in urls {
+            let Some(file) = self.get_code(&url) else {
+                continue;
+            };
+            let mut code = file.borrow_mut();
+            code.gen_top_level_items_if_needed();
+            let batch = code
+                .root_items
+                .iter()
+                .flatten()
+                .filter(|item| fuzzy_match(query, &item.borrow().name))
+                .map(|item| item.borrow().symbol_information(&url))
+                .collect();
+            drop(code);
+            self.partial_result(token, batch, &mut result);
+        }
+        result
+    }
+
+    // The examples of the doc comments of the top level symbols of the .scad files of `roots`,
+    // whose symbol or title matches `query`, in the order of the files.
+    pub(crate) fn library_examples(&mut self, roots: &[Url], query: &str) -> Vec<LibraryExample> {
+        let mut result = vec![];
+        for path in self.scad_files_within_limits(roots) {
+            let Some(file) = Url::from_file_path(&path)
+                .ok()
+                .and_then(|url| self.get_code(&url))
+            else {
+                continue;
+            };
+            let mut code = file.borrow_mut();
+            code.gen_top_level_items_if_needed();
+            for item in code.root_items.iter().flatten() {
+                let item = item.borrow();
+                let is_symbol_match = fuzzy_match(query, &item.name);
+                for example in item.examples() {
+                    let is_title_match = example
+                        .title
+                        .as_ref()
+                        .is_some_and(|title| fuzzy_match(query, title));
+                    if !is_symbol_match && !is_title_match {
+                        continue;
+                    }
+                    result.push(LibraryExample {
+                        symbol: item.name.to_string(),
+                        title: example.title,
+                        code: example.code,
+                        location: Location::new(code.url.clone(), item.range),
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    // The top level modules and functions of the loaded library files which `code` doesn't include
+    // yet.
+    pub(crate) fn library_items(&mut self, code: &ParsedCode) -> Vec<Rc<RefCell<Item>>> {
+        let reachable: HashSet<Url> = self
+            .included_codes(code.includes.clone().unwrap_or_default(), &code.url)
+            .iter()
+            .map(|file| file.borrow().url.clone())
+            .collect();
+        let libs = self.library_locations.borrow().clone();
+
+        let mut result = vec![];
+        for (url, file) in self.codes.iter() {
+            if url == &code.url
+                || reachable.contains(url)
+                || !libs
+                    .iter()
+                    .any(|lib| url.as_str().starts_with(lib.as_str()))
+            {
+                continue;
+            }
+            let Ok(mut file) = file.try_borrow_mut() else {
+                continue;
+            };
+            file.gen_top_level_items_if_needed();
+            result.extend(
+                file.root_items
+                    .iter()
+                    .flatten()
+                    .filter(|item| {
+                        matches!(
+                            item.borrow().kind,
+                            ItemKind::Module { .. } | ItemKind::Function { .. }
+                        )
+                    })
+                    .cloned(),
+            );
+        }
+        result
+    }
+
+    // The path of an include statement of `url` resolving to `target`, relative to a library
+    // location when possible.
+    pub(crate) fn include_path_to(&self, url: &Url, target: &Url) -> Option<String> {
+        let path = self
+            .library_locations
+            .borrow()
+            .iter()
+            .find_map(|lib| target.as_str().strip_prefix(lib.as_str()))
+            .map(str::to_owned)
+            .or_else(|| url.make_relative(target))?;
+        Some(percent_decode(&path))
+    }
+
+    // The .scad files of the workspace folders and the library locations, as include paths of
+    // `url` starting with `typed`, `BOSL2/gears.scad` for `gea`. Only the paths in sub
+    // directories, the entries of the directories are completed on their own.
+    pub(crate) fn project_include_paths(&mut self, url: &Url, typed: &str) -> Vec<(String, bool)> {
+        let typed = typed.to_lowercase();
+        let libs = self.library_locations.borrow().clone();
+        let mut result = vec![];
+        for (roots, is_library) in [(self.workspace_roots.clone(), false), (libs, true)] {
+            for path in self.scad_files_within_limits(&roots) {
+                let Some(target) = Url::from_file_path(&path)
+                    .ok()
+                    .filter(|target| target != url)
+                else {
+                    continue;
+                };
+                let Some(include) = self.include_path_to(url, &target) else {
+                    continue;
+                };
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if include.contains('/')
+                    && (include.to_lowercase().starts_with(&typed)
+                        || name.to_lowercase().starts_with(&typed))
+                    && !result.iter().any(|(path, _)| path == &include)
+                {
+                    result.push((include, is_library));
+                }
+                if result.len() >= MAX_PROJECT_INCLUDE_PATHS {
+                    return result;
+                }
+            }
+        }
+        result
+    }
+
+    // An edit including `target` in `url`, after its include/use statements.
+    pub(crate) fn include_edit(&mut self, url: &Url, target: &Url) -> Option<TextEdit> {
+        let file = self.get_code(url)?;
+        let code = file.borrow();
+        self.includes_edit(&code, std::slice::from_ref(target))
+    }
+
+    // An edit including the `targets` in `code`, after its include/use statements.
+    pub(crate) fn includes_edit(&self, code: &ParsedCode, targets: &[Url]) -> Option<TextEdit> {
+        let paths = targets
+            .iter()
+            .map(|target| self.include_path_to(&code.url, target))
+            .collect::<Option<Vec<_>>>()?;
+        let root = code.tree.root_node();
+        let line = root
+            .children(&mut root.walk())
+            .filter(|node| node.node_kind().is_include_statement())
+            .map(|node| node.end_position().row as u32 + 1)
+            .max()
+            .unwrap_or(0);
+        let position = Position::new(line, 0);
+        Some(TextEdit {
+            range: Range::new(position, position),
+            new_text: paths
+                .iter()
+                .map(|path| format!("include <{}>\n", path))
+                .collect(),
+        })
+    }
+
+    // The paths of the include/use statements of the indexed files referencing `target`, streamed
+    // by file with a partial result `token`.
+    pub(crate) fn include_references(
+        &mut self,
+        target: &Url,
+        token: Option<&ProgressToken>,
+    ) -> Vec<Location> {
+        for code in self.indexed_codes() {
+            code.borrow_mut().gen_top_level_items_if_needed();
+            self.record_includes(&mut code.borrow_mut());
+        }
+
+        let mut files: Vec<Url> = self
+            .dependents
+            .get(target)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        files.sort();
+
+        let mut result = vec![];
+        for url in files {
+            let Some(code) = self.codes.get(&url) else {
+                continue;
+            };
+            let code = code.borrow();
+            let mut batch = vec![];
+            for node in traverse(code.tree.walk(), Order::Pre) {
+                if !node.node_kind().is_include_statement() {
+                    continue;
+                }
+                if code
+                    .resolve_include(&node)
+                    .is_none_or(|(_, inc)| &inc != target)
+                {
+                    continue;
+                }
+                if let Some(path_node) = node.child(1) {
+                    batch.push(Location {
+                        uri: url.clone(),
+                        range: path_node.lsp_range(&code.code),
+                    });
+                }
+            }
+            self.partial_result(token, batch, &mut result);
+        }
+        result
+    }
+
+    // The identifiers named `name` of the files depending on `target`, which resolve to its symbol
+    // at `range`. Only the files `only` are searched if given, without indexing the workspace.
+    pub(crate) fn dependent_references(
+        &mut self,
+        target: &Url,
+        name: &str,
+        range: Range,
+        only: Option<&HashSet<Url>>,
+    ) -> Vec<Location> {
+        if only.is_none() {
+            for code in self.indexed_codes() {
+                code.borrow_mut().gen_top_level_items_if_needed();
+                self.record_includes(&mut code.borrow_mut());
+            }
+        }
+
+        let mut files: Vec<Url> = self
+            .dependents_of(target)
+            .into_iter()
+            .filter(|url| only.is_none_or(|only| only.contains(url)))
+            .collect();
+        files.sort();
+
+        let mut result = vec![];
+        for url in files {
+            let Some(file) = self.codes.get(&url).cloned() else {
+                continue;
+            };
+            let code = file.borrow();
+            for node in traverse(code.tree.walk(), Order::Pre) {
+                if node.node_kind() != NodeKind::Identifier || node_text(&code.code, &node) != name
+                {
+                    continue;
+                }
+                let items = self.find_identities(&code, &|ident| ident == name, &node, false);
+                let resolved = items.first().is_some_and(|item| {
+                    let item = item.borrow();
+                    item.url.as_ref() == Some(target) && item.range == range
+                });
+                if resolved {
+                    result.push(Location {
+                        uri: url.clone(),
+                        range: node.lsp_range(&code.code),
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    // Whether `url` is outside the workspace folders, or in a library location without workspace
+    // folders.
+    pub(crate) fn is_outside_workspace(&self, url: &Url) -> bool {
+        let Ok(path) = url.to_file_path() else {
+            return true;
+        };
+        let is_under = |roots: &[Url]| {
+            roots
+                .iter()
+                .filter_map(|root| root.to_file_path().ok())
+                .any(|root| path.starts_with(root))
+        };
+        if self.workspace_roots.is_empty() {
+            is_under(&self.library_locations.borrow())
+        } else {
+            !is_under(&self.workspace_roots)
+        }
+    }
+
+    // The top level modules and functions of `codes` which are never referenced by them, except
+    // from their own declaration.
+    pub(crate) fn unused_public_symbols(
+        &mut self,
+        codes: &[Rc<RefCell<ParsedCode>>],
+    ) -> HashMap<Url, Vec<Diagnostic>> {
+        let mut used = HashSet::new();
+        for file in codes {
+            file.borrow_mut().gen_top_level_items_if_needed();
+            let code = file.borrow();
+            for node in traverse(code.tree.walk(), Order::Pre) {
+                if node.node_kind() != NodeKind::Identifier || !is_reference(&node) {
+                    continue;
+                }
+                let name = node_text(&code.code, &node);
+                let items =
+                    self.find_identities(&code, &|item_name| item_name == name, &node, false);
+                let Some(item) = items.first() else {
+                    continue;
+                };
+                let item = item.borrow();
+                let Some(url) = &item.url else {
+                    continue;
+                };
+                let position = node.lsp_range(&code.code).start;
+                let is_recursive =
+                    url == &code.url && item.range.start <= position && position < item.range.end;
+                if !is_recursive {
+                    used.insert((
+                        url.clone(),
+                        item.range.start.line,
+                        item.range.start.character,
+                    ));
+                }
+            }
+        }
+
+        let mut result: HashMap<Url, Vec<Diagnostic>> = HashMap::new();
+        for file in codes {
+            let code = file.borrow();
+            for item in code.root_items.iter().flatten() {
+                let item = item.borrow();
+                let kind = match item.kind {
+                    ItemKind::Module { .. } => "module",
+                    ItemKind::Function { .. } => "function",
+                    _ => continue,
+                };
+                let start = item.range.start;
+                if used.contains(&(code.url.clone(), start.line, start.character)) {
+                    continue;
+                }
+                result
+                    .entry(code.url.clone())
+                    .or_default()
+                    .push(Diagnostic {
+                        range: item.range,
+                        severity: Some(DiagnosticSeverity::WARNING),
+                        code: Some(NumberOrString::String(UNUSED_PUBLIC.to_owned())),
+                        message: format!("{} `{}` is never used", kind, item.name),
+                        ..Default::default()
+                    });
+            }
+        }
+        result
+    }
+
+    // Edits of the include/use statements of all the indexed files referencing `old`, a file or
+    // a directory, so that they reference `new` instead.
+    pub(crate) fn include_rename_edits(
+        &mut self,
+        old: &Url,
+        new: &Url,
+    ) -> HashMap<Url, Vec<TextEdit>> {
+        let old_dir = format!("{}/", old.as_str().trim_end_matches('/'));
+        let new_dir = format!("{}/", new.as_str().trim_end_matches('/'));
+
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for code in self.indexed_codes() {
+            let code = code.borrow();
+            for node in traverse(code.tree.walk(), Order::Pre) {
+                if !node.node_kind().is_include_statement() {
+                    continue;
+                }
+                let Some((base, target)) = code.resolve_include(&node) else {
+                    continue;
+                };
+
+                let new_target = if &target == old {
+                    new.clone()
+                } else if let Some(rest) = target.as_str().strip_prefix(&old_dir) {
+                    match Url::parse(&format!("{}{}", new_dir, rest)) {
+                        Ok(url) => url,
+                        Err(_) => continue,
+                    }
+                } else {
+                    continue;
+                };
+
+                // Keep library includes relative to the library location when possible.
+                let new_path = if base != code.url && new_target.as_str().starts_with(base.as_str())
+                {
+                    new_target.as_str()[base.as_str().len()..].to_owned()
+                } else {
+                    match code.url.make_relative(&new_target) {
+                        Some(path) => path,
+                        None => continue,
+                    }
+                };
+
+                let Some(path_node) = node.child(1) else {
+                    continue;
+                };
+                let mut range: Range = path_node.lsp_range(&code.code);
+                range.start.character += 1;
+                range.end.character = range.end.character.saturating_sub(1);
+
+                changes.entry(code.url.clone()).or_default().push(TextEdit {
+                    range,
+                    new_text: percent_decode(&new_path),
+                });
+            }
+        }
+        changes
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                result.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+// The namespace of an identifier: "module" for the module names, "function" for the function
+// names, "variable" otherwise.
+pub(crate) fn node_namespace(node: &Node) -> &'static str {
+    let Some(parent) = node.parent() else {
+        return "variable";
+    };
+    let is_field = |field: &str| parent.child_by_field_name(field) == Some(*node);
+    match parent.node_kind() {
+        NodeKind::ModuleCall | NodeKind::ModuleDeclaration if is_field("name") => "module",
+        NodeKind::FunctionCall if is_field("function") => "function",
+        NodeKind::FunctionDeclaration if is_field("name") => "function",
+        _ => "variable",
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use lsp_server::{Connection, Message};
+use lsp_types::notification::{Exit, Notification};
+use serde_json::{json, Value};
+
+use crate::{serve, Cli};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Clients {
+    // The server exits when its client disconnects.
+    Single,
+    // The clients connect one after the other, each one starts with a new state.
+    Sequential,
+    // Each client has its own state, in its own thread.
+    Concurrent,
+}
+
+// The idle timeout of the server shared with --single-instance, in seconds.
+const SINGLE_INSTANCE_IDLE_TIMEOUT: u64 = 300;
+// How long a --single-instance launcher waits for the shared server to listen.
+const SINGLE_INSTANCE_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The --port-file, or the default one when the port is chosen by the system or shared.
+fn port_file_path(args: &Cli) -> Option<PathBuf> {
+    match &args.port_file {
+        Some(path) => Some(PathBuf::from(path)),
+        None if args.port == "0" || args.single_instance => Some(default_port_file()),
+        None => None,
+    }
+}
+
+// The default port file, private to the user so that another user can't point the launchers to
+// its own server: in $XDG_RUNTIME_DIR, or in the temporary directory with the user in its name,
+// the temporary directory is shared by the users on Linux.
+fn default_port_file() -> PathBuf {
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir).join("openscad-lsp.port");
+    }
+    let user = current_uid()
+        .map(|uid| uid.to_string())
+        .or_else(|| env::var("USER").or_else(|_| env::var("USERNAME")).ok())
+        .unwrap_or_default();
+    env::temp_dir().join(format!("openscad-lsp-{}.port", user))
+}
+
+// The uid of the user running the server, on Linux.
+fn current_uid() -> Option<u32> {
+    #[cfg(unix)]
+    return {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata("/proc/self").ok().map(|meta| meta.uid())
+    };
+    #[cfg(not(unix))]
+    return None;
+}
+
+// Create `path`, or open it truncated, readable and writable by the user only.
+fn create_private(path: &Path, create_new: bool) -> io::Result<fs::File> {
+    let mut options = fs::File::options();
+    options.write(true);
+    if create_new {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(true);
+    }
+    #[cfg(unix)]
+    return {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // The mode only applies to a new file.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    };
+    #[cfg(not(unix))]
+    return options.open(path);
+}
+
+// The file the port and the pid are written to, removed when the server exits.
+struct PortFile(PathBuf);
+
+impl PortFile {
+    fn create(args: &Cli, port: u16) -> io::Result<Option<Self>> {
+        let Some(path) = port_file_path(args) else {
+            return Ok(None);
+        };
+        let content = json!({ "port": port, "pid": process::id() });
+        writeln!(create_private(&path, false)?, "{}", content)?;
+        log_to_console!("port file {}", path.display());
+        Ok(Some(Self(path)))
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// The connected clients and the time the last one disconnected, for --idle-timeout.
+#[derive(Clone)]
+struct Activity(Arc<Mutex<(usize, Instant)>>);
+
+impl Activity {
+    // Exit when no client is connected for `timeout` seconds, removing the port file.
+    fn watch(timeout: u64, port_file: Option<PathBuf>) -> Self {
+        let activity = Self(Arc::new(Mutex::new((0, Instant::now()))));
+        let watched = activity.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let (clients, since) = *watched.0.lock().unwrap();
+            if clients == 0 && since.elapsed().as_secs() >= timeout {
+                log_to_console!("no client for {} seconds, exit", timeout);
+                if let Some(path) = &port_file {
+                    let _ = fs::remove_file(path);
+                }
+                process::exit(0);
+            }
+        });
+        activity
+    }
+
+    fn connected(&self) {
+        self.0.lock().unwrap().0 += 1;
+    }
+
+    fn disconnected(&self) {
+        let mut activity = self.0.lock().unwrap();
+        *activity = (activity.0 - 1, Instant::now());
+    }
+}
+
+// Serve the clients connecting to the address, the first one only, or the next ones one after the
+// other or at the same time until the process is stopped. Whether the single client asked for a
+// shutdown before exit.
+pub(crate) fn serve_clients(mut args: Cli) -> Result<bool, Box<dyn Error + Sync + Send>> {
+    if args.single_instance {
+        if let Some((port, pid)) = port_file_path(&args).and_then(|path| running_server(&path)) {
+            log_to_console!("already running on port {}, pid {}", port, pid);
+            return Ok(true);
+        }
+        args.clients = Clients::Concurrent;
+    }
+    let listener = TcpListener::bind(format!("{}:{}", args.ip, args.port))?;
+    // With port 0, the system chooses a free port.
+    let port = listener.local_addr()?.port();
+    let port_file = PortFile::create(&args, port)?;
+    log_to_console!("Start successful, listening on port {}", port);
+
+    let idle_timeout = args
+        .idle_timeout
+        .or(args.single_instance.then_some(SINGLE_INSTANCE_IDLE_TIMEOUT))
+        .filter(|&timeout| timeout > 0);
+    let activity = idle_timeout
+        .map(|timeout| Activity::watch(timeout, port_file.as_ref().map(|file| file.0.clone())));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                err_to_console!("{}", err);
+                continue;
+            }
+        };
+        let (connection, io_threads) = match socket_connection(stream) {
+            Ok(connection) => connection,
+            Err(err) => {
+                err_to_console!("{}", err);
+                continue;
+            }
+        };
+        let args = args.clone();
+        let clients = args.clients;
+        let activity = activity.clone();
+        let client = move || {
+            activity.iter().for_each(Activity::connected);
+            let result = serve(connection, args);
+            for thread in io_threads {
+                let _ = thread.join();
+            }
+            activity.iter().for_each(Activity::disconnected);
+            result
+        };
+        match clients {
+            Clients::Single => return client(),
+            Clients::Sequential => {
+                if let Err(err) = client() {
+                    err_to_console!("{}", err);
+                }
+            }
+            Clients::Concurrent => {
+                thread::spawn(move || {
+                    if let Err(err) = client() {
+                        err_to_console!("{}", err);
+                    }
+                });
+            }
+        }
+    }
+    Ok(true)
+}
+
+// The port and the pid of the server of the port file, if it accepts connections. A port file of
+// another user is ignored.
+fn running_server(path: &Path) -> Option<(u16, u32)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let owner = fs::metadata(path).ok()?.uid();
+        if current_uid().is_some_and(|uid| uid != owner) {
+            return None;
+        }
+    }
+    let content: Value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    let port = u16::try_from(content.get("port")?.as_u64()?).ok()?;
+    let pid = u32::try_from(content.get("pid")?.as_u64()?).ok()?;
+    TcpStream::connect(("127.0.0.1", port)).ok()?;
+    Some((port, pid))
+}
+
+// With --single-instance and --stdio, forward the messages of the editor to the shared server,
+// started in the background with the same options if none is running.
+pub(crate) fn forward_to_shared_server(args: &Cli) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let path = port_file_path(args).ok_or("no port file")?;
+    let lock = path.with_extension("lock");
+    let start = Instant::now();
+    let port = loop {
+        if let Some((port, _)) = running_server(&path) {
+            break port;
+        }
+        // Only one of the launchers starting at the same time starts the server, a lock left by
+        // a crashed launcher is ignored after the timeout.
+        let is_stale = fs::metadata(&lock)
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|time| time.elapsed().unwrap_or_default() > SINGLE_INSTANCE_START_TIMEOUT);
+        if is_stale {
+            let _ = fs::remove_file(&lock);
+        }
+        if create_private(&lock, true).is_ok() {
+            let server_args = env::args().skip(1).filter(|arg| arg != "--stdio");
+            Command::new(env::current_exe()?)
+                .args(server_args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?;
+        }
+        if start.elapsed() > SINGLE_INSTANCE_START_TIMEOUT {
+            let _ = fs::remove_file(&lock);
+            return Err("the shared server didn't start".into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    let _ = fs::remove_file(&lock);
+
+    let stream = TcpStream::connect(("127.0.0.1", port))?;
+    let mut to_server = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin(), &mut to_server);
+        // The editor closed the pipe without exit.
+        to_server.shutdown(Shutdown::Write)
+    });
+    // Flushed as it comes, stdout is line buffered and the messages don't end with a new line.
+    let mut stdout = io::stdout();
+    let mut buf = [0; 8192];
+    loop {
+        let len = (&stream).read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buf[..len])?;
+        stdout.flush()?;
+    }
+}
+
+// The connection of an accepted client, like the one of `Connection::listen` which only accepts
+// one. The threads finish when the client disconnects or the server is dropped.
+fn socket_connection(stream: TcpStream) -> io::Result<(Connection, [JoinHandle<()>; 2])> {
+    let (connection, client) = Connection::memory();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let reader = thread::spawn(move || {
+        while let Ok(Some(msg)) = Message::read(&mut reader) {
+            let is_exit =
+                matches!(&msg, Message::Notification(noti) if noti.method == Exit::METHOD);
+            if client.sender.send(msg).is_err() || is_exit {
+                break;
+            }
+        }
+    });
+    let writer = thread::spawn(move || {
+        for msg in client.receiver {
+            if msg.write(&mut writer).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((connection, [reader, writer]))
+}